@@ -0,0 +1,110 @@
+//! `#[derive(ToPoint)]` for the measurement structs of the collector, which
+//! turns them into a `point::Point` without the `influxdb2` crate.
+//!
+//! The struct gets the measurement name with `#[measurement = "..."]` (its
+//! name otherwise) and every field its role with `#[influxdb(tag)]`,
+//! `#[influxdb(field)]` (the default), `#[influxdb(timestamp)]` or
+//! `#[influxdb(ignore)]`. Fields that are `None` are left out of the point.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, ExprLit, Fields, Ident, Lit, Meta};
+
+#[proc_macro_derive(ToPoint, attributes(measurement, influxdb))]
+pub fn to_point(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+enum Role {
+    Tag,
+    Field,
+    Timestamp,
+    Ignore,
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    let measurement = measurement(input)?.unwrap_or_else(|| ident.to_string());
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(ident, "only a struct can be a measurement"));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(ident, "a measurement needs named fields"));
+    };
+    let mut tags: Vec<&Ident> = Vec::new();
+    let mut values: Vec<&Ident> = Vec::new();
+    let mut time = None;
+    for field in &fields.named {
+        let Some(name) = &field.ident else {
+            continue;
+        };
+        match role(field)? {
+            Role::Tag => tags.push(name),
+            Role::Field => values.push(name),
+            Role::Timestamp if time.is_some() => return Err(syn::Error::new_spanned(name, "a measurement has one timestamp")),
+            Role::Timestamp => time = Some(name),
+            Role::Ignore => {}
+        }
+    }
+    let Some(time) = time else {
+        return Err(syn::Error::new_spanned(ident, "a measurement needs a #[influxdb(timestamp)] field"));
+    };
+    let tag_keys = tags.iter().map(|name| name.to_string());
+    let field_keys = values.iter().map(|name| name.to_string());
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::point::ToPoint for #ident #ty_generics #where_clause {
+            fn to_point(&self) -> crate::point::Point {
+                let mut fields = Vec::new();
+                #(
+                    if let Some(value) = crate::point::ToFieldValue::to_field_value(&self.#values) {
+                        fields.push((#field_keys.to_owned(), value));
+                    }
+                )*
+                crate::point::Point {
+                    measurement: #measurement.to_owned(),
+                    tags: vec![#((#tag_keys.to_owned(), self.#tags.to_string())),*],
+                    fields,
+                    time: self.#time,
+                }
+            }
+        }
+    })
+}
+
+/// The name given by `#[measurement = "..."]`.
+fn measurement(input: &DeriveInput) -> syn::Result<Option<String>> {
+    let Some(attr) = input.attrs.iter().find(|attr| attr.path().is_ident("measurement")) else {
+        return Ok(None);
+    };
+    if let Meta::NameValue(name_value) = &attr.meta {
+        if let Expr::Lit(ExprLit { lit: Lit::Str(name), .. }) = &name_value.value {
+            return Ok(Some(name.value()));
+        }
+    }
+    Err(syn::Error::new_spanned(attr, "expected #[measurement = \"...\"]"))
+}
+
+/// The role given by `#[influxdb(...)]`, a field without one is a field.
+fn role(field: &syn::Field) -> syn::Result<Role> {
+    let mut role = Role::Field;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("influxdb")) {
+        attr.parse_nested_meta(|meta| {
+            role = if meta.path.is_ident("tag") {
+                Role::Tag
+            } else if meta.path.is_ident("field") {
+                Role::Field
+            } else if meta.path.is_ident("timestamp") {
+                Role::Timestamp
+            } else if meta.path.is_ident("ignore") {
+                Role::Ignore
+            } else {
+                return Err(meta.error("expected tag, field, timestamp or ignore"));
+            };
+            Ok(())
+        })?;
+    }
+    Ok(role)
+}
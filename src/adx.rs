@@ -0,0 +1,209 @@
+//! Writing of the points to Azure Data Explorer (Kusto).
+//!
+//! Only available with the `adx` cargo feature and enabled by
+//! `ADX_CLUSTER_URL`. All measurements go to one table (`ADX_TABLE`, default
+//! `fronius`) of `ADX_DATABASE` with one row per field, like the ClickHouse
+//! sink. The table and its JSON ingestion mapping are created (or extended)
+//! at startup:
+//!
+//! ```kusto
+//! .create-merge table fronius (time: datetime, measurement: string, tags: dynamic, field: string, value: real, text: string)
+//! ```
+//!
+//! The rows are sent via streaming ingestion, which has to be enabled on the
+//! cluster. The collector authenticates as an Azure AD application with the
+//! client credentials `ADX_TENANT_ID`, `ADX_CLIENT_ID` and
+//! `ADX_CLIENT_SECRET`; the application needs the `ingestor` and, for the
+//! table creation, the `admin` role of the database.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use log::{error, info};
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_TABLE: &str = "fronius";
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Tokens are renewed this long before they expire.
+const TOKEN_MARGIN: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Serialize)]
+struct Row<'a> {
+    time: String,
+    measurement: &'a str,
+    tags: serde_json::Map<String, serde_json::Value>,
+    field: &'a str,
+    value: Option<f64>,
+    text: Option<&'a str>,
+}
+
+pub struct AdxSink {
+    client: Client,
+    cluster: String,
+    tenant_id: String,
+    client_id: String,
+    client_secret: String,
+    database: String,
+    table: String,
+    /// The current access token and until when it is used.
+    token: Mutex<Option<(String, Instant)>>,
+    /// Whether the table and the mapping are known to exist.
+    created: AtomicBool,
+    routes: Routes,
+}
+
+impl AdxSink {
+    /// Creates the sink for the cluster `url` and the table, if the cluster
+    /// is reachable. Required are `ADX_DATABASE`, `ADX_TENANT_ID`,
+    /// `ADX_CLIENT_ID` and `ADX_CLIENT_SECRET`, optional is `ADX_TABLE`.
+    pub fn from_env(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let sink = AdxSink {
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            cluster: url.trim_end_matches('/').to_owned(),
+            tenant_id: std::env::var("ADX_TENANT_ID")?,
+            client_id: std::env::var("ADX_CLIENT_ID")?,
+            client_secret: std::env::var("ADX_CLIENT_SECRET")?,
+            database: std::env::var("ADX_DATABASE")?,
+            table: std::env::var("ADX_TABLE").unwrap_or_else(|_| DEFAULT_TABLE.to_owned()),
+            token: Mutex::new(None),
+            created: AtomicBool::new(false),
+            routes: Routes::from_env()?,
+        };
+        if let Err(error) = sink.create_table() {
+            error!("Error during creation of the Azure Data Explorer table occured: {:?}", error);
+        }
+        info!("Writing points to Azure Data Explorer table {}.{} at {}", sink.database, sink.table, sink.cluster);
+        Ok(sink)
+    }
+
+    /// An access token for the cluster, requested again shortly before the
+    /// current one expires.
+    fn token(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut token = self.token.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((access_token, valid_until)) = token.as_ref() {
+            if Instant::now() < *valid_until {
+                return Ok(access_token.clone());
+            }
+        }
+        let scope = format!("{}/.default", self.cluster);
+        let response = self
+            .client
+            .post(format!("https://login.microsoftonline.com/{}/oauth2/v2.0/token", self.tenant_id))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("scope", &scope),
+            ])
+            .send()?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("token request failed with {status}: {}", response.text().unwrap_or_default()).into());
+        }
+        let response: Token = response.json()?;
+        let valid_until = Instant::now() + Duration::from_secs(response.expires_in).saturating_sub(TOKEN_MARGIN);
+        *token = Some((response.access_token.clone(), valid_until));
+        Ok(response.access_token)
+    }
+
+    /// Sends `body` to `path` of the cluster.
+    fn post(&self, path: &str, body: String) -> Result<(), Box<dyn std::error::Error>> {
+        let response = self
+            .client
+            .post(format!("{}{path}", self.cluster))
+            .bearer_auth(self.token()?)
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("{status}: {}", response.text().unwrap_or_default()).into());
+        }
+        Ok(())
+    }
+
+    fn create_table(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let table = format!("['{}']", self.table.replace('\'', "\\'"));
+        let mapping = json!(["time", "measurement", "tags", "field", "value", "text"]
+            .map(|column| json!({ "column": column, "Properties": { "Path": format!("$.{column}") } })));
+        for csl in [
+            format!(".create-merge table {table} (time: datetime, measurement: string, tags: dynamic, field: string, value: real, text: string)"),
+            format!(".create-or-alter table {table} ingestion json mapping '{}' '{mapping}'", self.mapping_name()),
+        ] {
+            self.post("/v1/rest/mgmt", json!({ "db": self.database, "csl": csl }).to_string())?;
+        }
+        self.created.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn mapping_name(&self) -> String {
+        format!("{}_mapping", self.table)
+    }
+}
+
+impl Sink for AdxSink {
+    fn name(&self) -> &'static str {
+        "Azure Data Explorer"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut body = String::new();
+        for point in batch.points() {
+            if !self.routes.targets(&point.measurement).contains(&Target::Adx) {
+                continue;
+            }
+            let time = DateTime::from_timestamp_nanos(point.time).to_rfc3339_opts(SecondsFormat::AutoSi, true);
+            let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+            for (field, value) in &point.fields {
+                let (value, text) = value.to_columns();
+                let row = Row {
+                    time: time.clone(),
+                    measurement: &point.measurement,
+                    tags: tags.clone(),
+                    field,
+                    value,
+                    text,
+                };
+                match serde_json::to_string(&row) {
+                    Ok(row) => {
+                        body.push_str(&row);
+                        body.push('\n');
+                    }
+                    Err(error) => error!("Error during encoding of Azure Data Explorer row occured: {:?}", error),
+                }
+            }
+        }
+        if body.is_empty() {
+            return Ok(());
+        }
+        if !self.created.load(Ordering::Relaxed) {
+            self.create_table()?;
+        }
+        let path = format!(
+            "/v1/rest/ingest/{}/{}?streamFormat=MultiJSON&mappingName={}",
+            self.database,
+            self.table,
+            self.mapping_name()
+        );
+        self.post(&path, body)
+    }
+}
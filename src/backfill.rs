@@ -0,0 +1,256 @@
+//! Backfill of the history from the archive of the Datamanager, e.g. after
+//! an outage of the collector or to seed a new installation.
+//!
+//! The archived values of the given channels are written to the
+//! `archive_data` measurement, one point per device, channel and time. The
+//! range is fetched in chunks of one day (in the time zone of the site), each
+//! is retried a few times before the backfill is given up. After every chunk
+//! that was written the next day is saved to the progress file, if the write
+//! fails the backfill is given up. `--resume` continues an
+//! interrupted backfill from there. Points of inverters with a custom name get
+//! it as `device_name` tag, like the realtime data. Running a day twice doesn't duplicate anything, the points have the
+//! same timestamps and tags, so InfluxDB replaces them.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use fronius_api::fronius::{
+    blocking::{Fronius, FroniusBuilder},
+    ArchiveQuery,
+};
+use log::{info, warn};
+use point_derive::ToPoint;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    influx::InfluxSink,
+    pipeline::Pipeline,
+    sink::{Batch, Sink},
+    timestamp,
+};
+
+const DEFAULT_CHANNELS: &str = "EnergyReal_WAC_Sum_Produced";
+const DEFAULT_PROGRESS_FILE: &str = "backfill-progress.json";
+/// Attempts per chunk.
+const ATTEMPTS: u32 = 3;
+/// Archive requests take much longer than the realtime ones.
+const ARCHIVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Error)]
+pub enum BackfillError {
+    #[error("invalid date {0:?}, expected YYYY-MM-DD")]
+    InvalidDate(String),
+    #[error("{0} is not a valid local time")]
+    InvalidLocalTime(NaiveDate),
+    #[error("no progress file {0:?} to resume from")]
+    NothingToResume(PathBuf),
+}
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "archive_data"]
+struct ArchiveData {
+    #[influxdb(tag)]
+    device: String,
+    #[influxdb(tag)]
+    channel: String,
+    #[influxdb(tag)]
+    unit: String,
+    #[influxdb(field)]
+    value: f64,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Range and options of a backfill and the next day to fetch.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Progress {
+    to: NaiveDate,
+    channels: Vec<String>,
+    site: Option<String>,
+    pub(crate) next: NaiveDate,
+}
+
+impl Progress {
+    /// A backfill from `from` to `to` of the default channels.
+    #[cfg(test)]
+    pub(crate) fn new(from: NaiveDate, to: NaiveDate) -> Self {
+        Progress {
+            to,
+            channels: vec![DEFAULT_CHANNELS.to_owned()],
+            site: None,
+            next: from,
+        }
+    }
+
+    fn load(path: &PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        match std::fs::read(path) {
+            Ok(content) => Ok(serde_json::from_slice(&content)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Err(BackfillError::NothingToResume(path.clone()).into()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Saves the progress, the file is replaced atomically.
+    fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tmp = path.to_owned().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+fn parse_date(text: &str) -> Result<NaiveDate, BackfillError> {
+    NaiveDate::parse_from_str(text, "%Y-%m-%d").map_err(|_| BackfillError::InvalidDate(text.to_owned()))
+}
+
+/// Start of the day in the time zone of the site.
+fn local_midnight(day: NaiveDate) -> Result<time::OffsetDateTime, Box<dyn std::error::Error>> {
+    let start = crate::timezone::midnight(day).ok_or(BackfillError::InvalidLocalTime(day))?;
+    let offset = time::UtcOffset::from_whole_seconds(start.offset().local_minus_utc())?;
+    Ok(time::OffsetDateTime::from_unix_timestamp(start.timestamp())?.to_offset(offset))
+}
+
+/// The custom names of the inverters by their device in the archive, e.g.
+/// `inverter/1`.
+fn inverter_names(fronius: &Fronius) -> HashMap<String, String> {
+    match fronius.get_inverter_info() {
+        Ok(infos) => infos
+            .into_iter()
+            .filter_map(|(id, info)| Some((format!("inverter/{id}"), info?.custom_name.trim().to_owned())))
+            .filter(|(_, name)| !name.is_empty())
+            .collect(),
+        Err(error) => {
+            warn!("Error during lookup of the inverter names occured: {:?}", error);
+            HashMap::new()
+        }
+    }
+}
+
+/// Fetches the archive of one day and returns its points.
+fn fetch_day(fronius: &Fronius, day: NaiveDate, progress: &Progress, names: &HashMap<String, String>) -> Result<Batch, Box<dyn std::error::Error>> {
+    let start = local_midnight(day)?;
+    let next_day = day.succ_opt().ok_or(BackfillError::InvalidLocalTime(day))?;
+    let query = ArchiveQuery {
+        start,
+        end: local_midnight(next_day)? - time::Duration::SECOND,
+        channels: progress.channels.clone(),
+        daily_sums: false,
+    };
+    let mut batch = Batch::for_site(progress.site.as_deref());
+    for (device, archive) in fronius.get_archive_data(&query)? {
+        for (channel, values) in &archive.data {
+            for (time, value) in values.samples(archive.start) {
+                let time = DateTime::<Utc>::from_timestamp(time.unix_timestamp(), 0).ok_or(timestamp::TimestampError)?;
+                batch.push(&ArchiveData {
+                    device: device.clone(),
+                    channel: channel.clone(),
+                    unit: values.unit.clone(),
+                    value,
+                    time: timestamp::from_datetime(time)?,
+                });
+            }
+        }
+    }
+    for point in batch.points_mut() {
+        if let Some(name) = point.tag("device").and_then(|device| names.get(device)) {
+            point.set_tag("device_name", &name.clone());
+        }
+    }
+    Ok(batch)
+}
+
+const USAGE: &str = "usage: backfill --from <date> --to <date> [--channels <a,b>] [--site <name>] [--progress <file>] | backfill --resume [--progress <file>]";
+
+/// Runs the `backfill` command.
+/// Writes the points of `day`, only once they were written the next day is
+/// saved as the progress.
+pub(crate) fn write_day(sink: &dyn Sink, batch: &Batch, day: NaiveDate, progress: &mut Progress, progress_file: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    sink.write(batch)?;
+    progress.next = day.succ_opt().ok_or(BackfillError::InvalidLocalTime(day))?;
+    progress.save(progress_file)
+}
+
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    timestamp::init_from_env()?;
+    crate::schema::init_from_env()?;
+    let mut from = None;
+    let mut to = None;
+    let mut channels = DEFAULT_CHANNELS.to_owned();
+    let mut site = None;
+    let mut progress_file = PathBuf::from(DEFAULT_PROGRESS_FILE);
+    let mut resume = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(USAGE);
+        match arg.as_str() {
+            "--from" => from = Some(parse_date(value()?)?),
+            "--to" => to = Some(parse_date(value()?)?),
+            "--channels" => channels = value()?.clone(),
+            "--site" => site = Some(value()?.clone()),
+            "--progress" => progress_file = PathBuf::from(value()?),
+            "--resume" => resume = true,
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let mut progress = match (resume, from, to) {
+        (true, None, None) => {
+            let progress = Progress::load(&progress_file)?;
+            info!("Resuming backfill at {}", progress.next);
+            progress
+        }
+        (false, Some(from), Some(to)) => Progress {
+            to,
+            channels: channels.split(',').map(str::trim).filter(|channel| !channel.is_empty()).map(str::to_owned).collect(),
+            site,
+            next: from,
+        },
+        _ => return Err(USAGE.into()),
+    };
+    let to = progress.to.min(crate::timezone::now().date_naive());
+
+    let fronius = FroniusBuilder::new(std::env::var("FRONIUS_IP")?)
+        .connect_timeout(crate::duration_from_env("FRONIUS_CONNECT_TIMEOUT", 5)?)
+        .timeout(ARCHIVE_TIMEOUT)
+        .build()?;
+    crate::timezone::init_from_env()?;
+    crate::timezone::refresh(&fronius);
+    let names = inverter_names(&fronius);
+    let mut pipeline = Pipeline::from_env()?;
+    let sink = InfluxSink::from_env(false)?;
+    while progress.next <= to {
+        let day = progress.next;
+        let mut attempt = 1;
+        let mut batch = loop {
+            match fetch_day(&fronius, day, &progress, &names) {
+                Ok(batch) => break batch,
+                Err(error) if attempt < ATTEMPTS => {
+                    warn!("Error during backfill of {day} occured, retrying: {:?}", error);
+                    std::thread::sleep(Duration::from_secs(10) * attempt);
+                    attempt += 1;
+                }
+                Err(error) => {
+                    warn!("Giving up the backfill at {day}, continue it with --resume");
+                    return Err(error);
+                }
+            }
+        };
+        let points = batch.len();
+        pipeline.process(&mut batch);
+        if let Err(error) = write_day(&sink, &batch, day, &mut progress, &progress_file) {
+            warn!("Giving up the backfill at {day}, continue it with --resume");
+            return Err(error);
+        }
+        info!("Backfilled {points} values of {day}");
+    }
+    if let Err(error) = std::fs::remove_file(&progress_file) {
+        warn!("Error during removal of {:?} occured: {:?}", progress_file, error);
+    }
+    info!("Backfill finished");
+    Ok(())
+}
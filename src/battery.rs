@@ -0,0 +1,55 @@
+//! Sign convention of the battery values.
+//!
+//! Fronius documents `P_Akku` as positive while the battery is discharging,
+//! but not all firmwares stick to it. The battery power of the power flow and
+//! the DC current of the storage are normalized to "positive = discharging"
+//! right after they are fetched, so everything derived from them (charge and
+//! discharge power, PV production, energies) uses the same convention.
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid battery sign {0:?}, expected discharging or charging")]
+pub struct InvalidBatterySign(String);
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BatterySign {
+    /// The firmware reports positive values while charging.
+    inverted: bool,
+    /// Write the values as reported as well.
+    keep_raw: bool,
+}
+
+impl BatterySign {
+    /// Reads `BATTERY_SIGN`, the direction the firmware reports as positive
+    /// (`discharging` or `charging`), and `BATTERY_RAW`.
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let inverted = match std::env::var("BATTERY_SIGN") {
+            Ok(val) => match val.trim() {
+                "discharging" => false,
+                "charging" => true,
+                _ => return Err(InvalidBatterySign(val).into()),
+            },
+            Err(_) => false,
+        };
+        Ok(BatterySign {
+            inverted,
+            keep_raw: crate::flag_from_env("BATTERY_RAW")?,
+        })
+    }
+
+    /// Returns a reported battery power or current with positive values while
+    /// discharging.
+    pub fn normalize(self, value: f64) -> f64 {
+        if self.inverted {
+            -value
+        } else {
+            value
+        }
+    }
+
+    /// Returns the reported value, if it is written as well.
+    pub fn raw(self, value: Option<f64>) -> Option<f64> {
+        value.filter(|_| self.keep_raw)
+    }
+}
@@ -0,0 +1,180 @@
+//! The `check` command: a pre-flight check of the configuration, which
+//! connects to the Datamanagers of all sites and to the configured InfluxDB
+//! and MQTT broker and prints a report. It exits with an error if a check
+//! failed, warnings (e.g. a wrong clock) don't count as failures.
+
+use std::{collections::BTreeMap, fmt::Display, io::IsTerminal};
+
+use chrono::prelude::*;
+use fronius_api::fronius::{blocking::Fronius, DeviceType};
+
+/// Larger differences between the clocks of the Datamanager and this host
+/// are reported.
+const CLOCK_TOLERANCE: i64 = 30;
+/// Timeout of the connection to the MQTT broker.
+#[cfg(feature = "mqtt")]
+const MQTT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+struct Report {
+    color: bool,
+    failed: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn line(&self, color: &str, mark: char, check: &str, detail: impl Display) {
+        if self.color {
+            println!("\x1b[{color}m{mark}\x1b[0m {check:<28} {detail}");
+        } else {
+            println!("{mark} {check:<28} {detail}");
+        }
+    }
+
+    fn ok(&self, check: &str, detail: impl Display) {
+        self.line("32", '✓', check, detail);
+    }
+
+    fn warn(&mut self, check: &str, detail: impl Display) {
+        self.warnings += 1;
+        self.line("33", '!', check, detail);
+    }
+
+    fn fail(&mut self, check: &str, detail: impl Display) {
+        self.failed += 1;
+        self.line("31", '✗', check, detail);
+    }
+
+    fn skip(&self, check: &str, detail: impl Display) {
+        self.line("2", '-', check, detail);
+    }
+}
+
+/// Runs the `check` command.
+pub fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut report = Report {
+        color: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        failed: 0,
+        warnings: 0,
+    };
+    match crate::site_addresses() {
+        Ok(sites) => {
+            for (name, ip) in sites {
+                check_site(&mut report, name.as_deref(), &ip);
+            }
+        }
+        Err(error) => report.fail("Fronius", format!("invalid configuration: {error}")),
+    }
+    check_influx(&mut report);
+    check_mqtt(&mut report);
+
+    println!();
+    if report.failed > 0 {
+        return Err(format!("{} checks failed, {} warnings", report.failed, report.warnings).into());
+    }
+    println!("All checks passed, {} warnings", report.warnings);
+    Ok(())
+}
+
+fn check_site(report: &mut Report, name: Option<&str>, ip: &str) {
+    let check = match name {
+        Some(name) => format!("Fronius {name} ({ip})"),
+        None => format!("Fronius {ip}"),
+    };
+    let fronius = match crate::fronius_builder(name, ip).and_then(|builder| Ok(builder.build()?)) {
+        Ok(fronius) => fronius,
+        Err(error) => return report.fail(&check, format!("not reachable: {error}")),
+    };
+    report.ok(&check, "reachable, Solar API v1");
+
+    match fronius.get_logger_info() {
+        Ok(logger) => report.ok(
+            "  Datamanager",
+            format!(
+                "{} firmware {}, time zone {}",
+                logger.product_id.as_deref().unwrap_or("unknown product"),
+                logger.sw_version.as_deref().unwrap_or("unknown"),
+                logger.timezone_name
+            ),
+        ),
+        Err(error) => report.fail("  Datamanager", format!("no logger info: {error}")),
+    }
+    check_devices(report, &fronius);
+    check_clock(report, ip);
+}
+
+fn check_devices(report: &mut Report, fronius: &Fronius) {
+    let devices = match fronius.get_active_device_info() {
+        Ok(devices) => devices,
+        Err(error) => return report.fail("  Devices", format!("no device inventory: {error}")),
+    };
+    let counts: BTreeMap<String, usize> = devices
+        .iter()
+        .filter(|(_, devices)| !devices.is_empty())
+        .map(|(device_type, devices)| (format!("{device_type:?}"), devices.len()))
+        .collect();
+    let inventory = counts.iter().map(|(device_type, count)| format!("{count} {device_type}")).collect::<Vec<_>>().join(", ");
+    if devices.get(&DeviceType::Inverter).is_none_or(|inverters| inverters.is_empty()) {
+        report.warn("  Devices", format!("no inverter found ({inventory})"));
+    } else {
+        report.ok("  Devices", inventory);
+    }
+}
+
+/// Compares the time in the response header of the Datamanager with the
+/// clock of this host.
+fn check_clock(report: &mut Report, ip: &str) {
+    let datamanager_time = || -> Result<DateTime<FixedOffset>, Box<dyn std::error::Error>> {
+        let client = reqwest::blocking::Client::builder().timeout(crate::duration_from_env("FRONIUS_READ_TIMEOUT", 10)?).build()?;
+        let response: serde_json::Value = client.get(format!("http://{ip}/solar_api/v1/GetLoggerInfo.cgi")).send()?.json()?;
+        let timestamp = response["Head"]["Timestamp"].as_str().ok_or("no timestamp in the response")?;
+        Ok(DateTime::parse_from_rfc3339(timestamp)?)
+    };
+    let time = match datamanager_time() {
+        Ok(time) => time,
+        Err(error) => return report.fail("  Clock", format!("no time from the Datamanager: {error}")),
+    };
+    let offset = Utc::now().signed_duration_since(time).num_seconds();
+    if offset.abs() > CLOCK_TOLERANCE {
+        report.warn("  Clock", format!("Datamanager is {offset} s off the clock of this host, check NTP"));
+    } else {
+        report.ok("  Clock", format!("in sync ({offset} s)"));
+    }
+}
+
+#[cfg(feature = "influxdb2")]
+fn check_influx(report: &mut Report) {
+    if std::env::var_os("INFLUX_DB_URL").is_none() {
+        return report.skip("InfluxDB", "not configured");
+    }
+    let sink = match crate::influx::InfluxSink::from_env(true) {
+        Ok(sink) => sink,
+        Err(error) => return report.fail("InfluxDB", format!("invalid configuration: {error}")),
+    };
+    match sink.check_health() {
+        Ok(version) => report.ok("InfluxDB", format!("reachable, version {version}")),
+        Err(error) => return report.fail("InfluxDB", format!("not reachable: {error}")),
+    }
+    match sink.check_buckets() {
+        Ok(buckets) => report.ok("  Buckets", format!("writable: {}", buckets.join(", "))),
+        Err(error) => report.fail("  Buckets", error),
+    }
+}
+
+#[cfg(not(feature = "influxdb2"))]
+fn check_influx(report: &mut Report) {
+    report.skip("InfluxDB", "not in this build");
+}
+
+#[cfg(feature = "mqtt")]
+fn check_mqtt(report: &mut Report) {
+    match crate::mqtt::check(MQTT_TIMEOUT) {
+        Ok(Some(broker)) => report.ok("MQTT", format!("connected to {broker}")),
+        Ok(None) => report.skip("MQTT", "not configured"),
+        Err(error) => report.fail("MQTT", format!("no connection: {error}")),
+    }
+}
+
+#[cfg(not(feature = "mqtt"))]
+fn check_mqtt(report: &mut Report) {
+    report.skip("MQTT", "not in this build");
+}
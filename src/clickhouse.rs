@@ -0,0 +1,175 @@
+//! Writing of the points to ClickHouse over its HTTP interface.
+//!
+//! Only available with the `clickhouse` cargo feature and enabled by
+//! `CLICKHOUSE_URL` (e.g. `http://clickhouse:8123`). All measurements go to
+//! one table (`CLICKHOUSE_TABLE`, default `fronius`) with one row per field,
+//! which is created at startup if it doesn't exist:
+//!
+//! ```sql
+//! CREATE TABLE fronius (
+//!     time DateTime64(9, 'UTC'),
+//!     measurement LowCardinality(String),
+//!     tags Map(LowCardinality(String), String),
+//!     field LowCardinality(String),
+//!     value Nullable(Float64),
+//!     text Nullable(String)
+//! ) ENGINE = MergeTree ORDER BY (measurement, field, time)
+//! ```
+//!
+//! Numbers and booleans are written to `value`, strings to `text`. The rows
+//! are sent as async inserts, so ClickHouse collects the small batches of
+//! every cycle into larger parts instead of creating a part per cycle. The
+//! write waits until the rows are flushed, so errors are still reported.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use log::{error, info};
+use reqwest::blocking::Client;
+use serde::Serialize;
+
+use crate::{
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_TABLE: &str = "fronius";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize)]
+struct Row<'a> {
+    time: i64,
+    measurement: &'a str,
+    tags: serde_json::Map<String, serde_json::Value>,
+    field: &'a str,
+    value: Option<f64>,
+    text: Option<&'a str>,
+}
+
+pub struct ClickHouseSink {
+    client: Client,
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+    /// Table name, qualified with the database if `CLICKHOUSE_DATABASE` is set.
+    table: String,
+    /// Whether the table is known to exist.
+    created: AtomicBool,
+    routes: Routes,
+}
+
+impl ClickHouseSink {
+    /// Creates the sink for `url` and the table, if ClickHouse is reachable.
+    /// Optional are `CLICKHOUSE_USER`, `CLICKHOUSE_PASSWORD`,
+    /// `CLICKHOUSE_DATABASE` and `CLICKHOUSE_TABLE`.
+    pub fn from_env(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let table = std::env::var("CLICKHOUSE_TABLE").unwrap_or_else(|_| DEFAULT_TABLE.to_owned());
+        let table = match std::env::var("CLICKHOUSE_DATABASE") {
+            Ok(database) => format!("{}.{}", quote(&database), quote(&table)),
+            Err(_) => quote(&table),
+        };
+        let sink = ClickHouseSink {
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            url: url.trim_end_matches('/').to_owned(),
+            user: std::env::var("CLICKHOUSE_USER").ok(),
+            password: std::env::var("CLICKHOUSE_PASSWORD").ok(),
+            table,
+            created: AtomicBool::new(false),
+            routes: Routes::from_env()?,
+        };
+        // ClickHouse may start after the collector, the table is created at
+        // the first write then
+        if let Err(error) = sink.create_table() {
+            error!("Error during creation of the ClickHouse table occured: {:?}", error);
+        }
+        info!("Writing points to ClickHouse table {} at {}", sink.table, sink.url);
+        Ok(sink)
+    }
+
+    fn create_table(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.execute(
+            &[],
+            format!(
+                "CREATE TABLE IF NOT EXISTS {} (\
+                 time DateTime64(9, 'UTC'), \
+                 measurement LowCardinality(String), \
+                 tags Map(LowCardinality(String), String), \
+                 field LowCardinality(String), \
+                 value Nullable(Float64), \
+                 text Nullable(String)\
+                 ) ENGINE = MergeTree ORDER BY (measurement, field, time)",
+                self.table
+            ),
+        )?;
+        self.created.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sends a request with the URL parameters `params`. The body is the
+    /// query or, if the query is one of the parameters, its data.
+    fn execute(&self, params: &[(&str, &str)], body: String) -> Result<(), Box<dyn std::error::Error>> {
+        let mut request = self.client.post(&self.url).query(params).body(body);
+        if let Some(user) = &self.user {
+            request = request.header("X-ClickHouse-User", user);
+        }
+        if let Some(password) = &self.password {
+            request = request.header("X-ClickHouse-Key", password);
+        }
+        let response = request.send()?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("{status}: {}", response.text().unwrap_or_default().trim()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Quotes a database or table name.
+fn quote(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+impl Sink for ClickHouseSink {
+    fn name(&self) -> &'static str {
+        "ClickHouse"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut body = String::new();
+        for point in batch.points() {
+            if !self.routes.targets(&point.measurement).contains(&Target::ClickHouse) {
+                continue;
+            }
+            let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+            for (field, value) in &point.fields {
+                let (value, text) = value.to_columns();
+                let row = Row {
+                    time: point.time,
+                    measurement: &point.measurement,
+                    tags: tags.clone(),
+                    field,
+                    value,
+                    text,
+                };
+                match serde_json::to_string(&row) {
+                    Ok(row) => {
+                        body.push_str(&row);
+                        body.push('\n');
+                    }
+                    Err(error) => error!("Error during encoding of ClickHouse row occured: {:?}", error),
+                }
+            }
+        }
+        if body.is_empty() {
+            return Ok(());
+        }
+        if !self.created.load(Ordering::Relaxed) {
+            self.create_table()?;
+        }
+        let query = format!("INSERT INTO {} FORMAT JSONEachRow", self.table);
+        let params = [("query", query.as_str()), ("async_insert", "1"), ("wait_for_async_insert", "1")];
+        self.execute(&params, body)
+    }
+}
@@ -0,0 +1,26 @@
+//! The clock the collector takes the time of its points and its days from.
+//! It is the system clock, only the soak test runs it ahead of real time to
+//! simulate days of polling in minutes.
+
+use chrono::prelude::*;
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// How far the clock is ahead of the system clock, in milliseconds.
+#[cfg(test)]
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+
+/// The current time.
+pub fn now() -> DateTime<Utc> {
+    #[cfg(test)]
+    return Utc::now() + chrono::Duration::milliseconds(OFFSET.load(Ordering::Relaxed));
+    #[cfg(not(test))]
+    Utc::now()
+}
+
+/// Runs the clock ahead by `by`.
+#[cfg(test)]
+pub fn advance(by: std::time::Duration) {
+    OFFSET.fetch_add(by.as_millis() as i64, Ordering::Relaxed);
+}
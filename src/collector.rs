@@ -0,0 +1,427 @@
+//! Polling of a Fronius system for embedding in other programs, without the
+//! sinks and derived measurements of the collector binary, which polls
+//! through it as well.
+//!
+//! ```no_run
+//! use fronius_api::{collector::{Collector, RunContext}, fronius::blocking::Fronius};
+//!
+//! let fronius = Fronius::connect("192.168.0.10".parse()?)?;
+//! let mut collector = Collector::new(fronius);
+//! collector.on_snapshot(|snapshot| {
+//!     if let Some(power_flow) = &snapshot.power_flow {
+//!         println!("PV power: {} W", power_flow.site.p_pv);
+//!     }
+//! });
+//! collector.run(&RunContext::new());
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+mod unsupported;
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use log::warn;
+use thiserror::Error;
+
+use crate::fronius::{
+    self, blocking::Fronius, CommonInverterData, DeviceId, DeviceType, InverterInfo, MeterData, OhmPilotData, PowerFlowData, StorageData,
+    ThreePhaseInverterData,
+};
+use unsupported::Unsupported;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_DISABLE_UNSUPPORTED_AFTER: Duration = Duration::from_secs(24 * 3600);
+
+/// The devices to poll.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Devices {
+    pub inverters: Vec<DeviceId>,
+    pub meters: Vec<DeviceId>,
+    pub storages: Vec<DeviceId>,
+    pub ohm_pilots: Vec<DeviceId>,
+}
+
+impl Default for Devices {
+    /// The first inverter, meter, storage and Ohmpilot.
+    fn default() -> Self {
+        Devices {
+            inverters: vec![DeviceId::FIRST_INVERTER],
+            meters: vec![DeviceId::FIRST_COMPONENT],
+            storages: vec![DeviceId::FIRST_COMPONENT],
+            ohm_pilots: vec![DeviceId::FIRST_COMPONENT],
+        }
+    }
+}
+
+/// A polled device is missing in a response of the Datamanager, e.g. because
+/// it was removed or renumbered.
+#[derive(Debug, Error)]
+#[error("{device_type:?} {device_id} is missing in the response")]
+pub struct MissingDeviceError {
+    pub device_type: DeviceType,
+    pub device_id: DeviceId,
+}
+
+/// A request of a cycle that failed.
+#[derive(Debug)]
+pub struct FetchError {
+    /// Name of the request, e.g. `meter_data`.
+    pub dataset: &'static str,
+    /// The device the request was sent for, `None` for the whole system.
+    pub device: Option<DeviceId>,
+    /// A `fronius::Error` or a `MissingDeviceError`.
+    pub error: Box<dyn std::error::Error + Send + Sync>,
+}
+
+/// The data of one poll cycle, by device. Data that couldn't be fetched is
+/// missing and the reason is listed in `errors`, which includes inverters
+/// that are offline at night. Requests that were disabled as unsupported or
+/// skipped because the deadline passed leave no error.
+#[derive(Debug, Default)]
+pub struct CycleSnapshot {
+    pub time: DateTime<Utc>,
+    pub power_flow: Option<PowerFlowData>,
+    pub inverter_infos: BTreeMap<DeviceId, InverterInfo>,
+    pub inverters: BTreeMap<DeviceId, CommonInverterData>,
+    pub inverter_phases: BTreeMap<DeviceId, ThreePhaseInverterData>,
+    pub meters: BTreeMap<DeviceId, MeterData>,
+    pub storages: BTreeMap<DeviceId, StorageData>,
+    pub ohm_pilots: BTreeMap<DeviceId, OhmPilotData>,
+    pub errors: Vec<FetchError>,
+}
+
+/// Stops a running collector, can be cloned to another thread.
+#[derive(Debug, Clone, Default)]
+pub struct RunContext {
+    stopped: Arc<AtomicBool>,
+}
+
+impl RunContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets `Collector::run` return after the current cycle.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+}
+
+type Callback = Box<dyn FnMut(&CycleSnapshot) + Send>;
+type Wrapper = Box<dyn Fn(&'static str, &mut dyn FnMut()) + Send>;
+
+pub struct Collector {
+    fronius: Fronius,
+    devices: Devices,
+    interval: Duration,
+    deadline: Option<Instant>,
+    unsupported: Unsupported,
+    wrapper: Option<Wrapper>,
+    callbacks: Vec<Callback>,
+    subscribers: Vec<mpsc::Sender<Arc<CycleSnapshot>>>,
+}
+
+impl Collector {
+    /// A collector that polls the first inverter, meter, storage and Ohmpilot
+    /// every 15 seconds. Requests that are unsupported for 24 hours are
+    /// disabled.
+    pub fn new(fronius: Fronius) -> Self {
+        Collector {
+            fronius,
+            devices: Devices::default(),
+            interval: DEFAULT_INTERVAL,
+            deadline: None,
+            unsupported: Unsupported::new(Some(DEFAULT_DISABLE_UNSUPPORTED_AFTER)),
+            wrapper: None,
+            callbacks: Vec::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// Sets the inverters to poll.
+    pub fn inverters(mut self, inverters: Vec<DeviceId>) -> Self {
+        self.devices.inverters = inverters;
+        self
+    }
+
+    /// Sets the meters to poll, the first one is the primary meter.
+    pub fn meters(mut self, meters: Vec<DeviceId>) -> Self {
+        self.devices.meters = meters;
+        self
+    }
+
+    /// Sets the storages to poll.
+    pub fn storages(mut self, storages: Vec<DeviceId>) -> Self {
+        self.devices.storages = storages;
+        self
+    }
+
+    /// Sets the Ohmpilots to poll.
+    pub fn ohm_pilots(mut self, ohm_pilots: Vec<DeviceId>) -> Self {
+        self.devices.ohm_pilots = ohm_pilots;
+        self
+    }
+
+    /// Sets the time between the start of two cycles of `run`.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets after how long a request that keeps answering "not supported"
+    /// (or "device not available" for meters, storages and Ohmpilots) isn't
+    /// sent anymore, `None` never disables one.
+    pub fn disable_unsupported_after(mut self, disable_after: Option<Duration>) -> Self {
+        self.unsupported = Unsupported::new(disable_after);
+        self
+    }
+
+    /// Runs every request through `wrapper` with its name, e.g. to time it.
+    /// The wrapper has to call the request it is given.
+    pub fn wrap_requests(mut self, wrapper: impl Fn(&'static str, &mut dyn FnMut()) + Send + 'static) -> Self {
+        self.wrapper = Some(Box::new(wrapper));
+        self
+    }
+
+    pub fn devices(&self) -> &Devices {
+        &self.devices
+    }
+
+    /// Replaces the devices to poll, e.g. after they were discovered again.
+    pub fn set_devices(&mut self, devices: Devices) {
+        self.devices = devices;
+    }
+
+    pub fn fronius(&self) -> &Fronius {
+        &self.fronius
+    }
+
+    /// Replaces the client, e.g. after a reconnect. The devices and the
+    /// disabled requests are kept.
+    pub fn set_fronius(&mut self, mut fronius: Fronius) {
+        fronius.set_deadline(self.deadline);
+        self.fronius = fronius;
+    }
+
+    /// Ends the cycles at `deadline`: the remaining requests are skipped and
+    /// a pending one is cut off. `None` removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.fronius.set_deadline(deadline);
+    }
+
+    /// Sends the requests again that were disabled as unsupported.
+    pub fn enable_unsupported(&mut self) {
+        self.unsupported.enable_all();
+    }
+
+    /// Calls `callback` with every snapshot of `run`.
+    pub fn on_snapshot(&mut self, callback: impl FnMut(&CycleSnapshot) + Send + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Returns a channel that receives every snapshot of `run`.
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Arc<CycleSnapshot>> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Fetches the data of one cycle: the inverters, meters, storages,
+    /// Ohmpilots and, last, the power flow.
+    pub fn poll_once(&mut self) -> CycleSnapshot {
+        let mut snapshot = CycleSnapshot {
+            time: Utc::now(),
+            ..Default::default()
+        };
+        self.poll_inverters(&mut snapshot);
+        self.poll_meters(&mut snapshot);
+        self.poll_storages(&mut snapshot);
+        self.poll_ohm_pilots(&mut snapshot);
+        if !self.deadline_exceeded("power_flow") {
+            let power_flow = self.request("power_flow_data", Fronius::get_power_flow_realtime_data);
+            snapshot.power_flow = record(&mut snapshot.errors, "power_flow_data", None, power_flow);
+        }
+        snapshot
+    }
+
+    fn poll_inverters(&mut self, snapshot: &mut CycleSnapshot) {
+        if self.devices.inverters.is_empty() || self.deadline_exceeded("inverters") {
+            return;
+        }
+        // one request answers for all inverters
+        let infos = self.request("inverter_info", Fronius::get_inverter_info);
+        let mut infos = record(&mut snapshot.errors, "inverter_info", None, infos);
+        for id in &self.devices.inverters {
+            if self.deadline_exceeded("inverters") {
+                break;
+            }
+            if let Some(infos) = &mut infos {
+                match infos.remove(&id.to_string()).flatten() {
+                    Some(info) => {
+                        snapshot.inverter_infos.insert(*id, info);
+                    }
+                    None => snapshot.errors.push(FetchError::missing("inverter_info", DeviceType::Inverter, id)),
+                }
+            }
+            let data = self.request("inverter_data", |fronius| fronius.get_inverter_realtime_data_device::<CommonInverterData>(id));
+            if let Some(data) = record(&mut snapshot.errors, "inverter_data", Some(id), data) {
+                snapshot.inverters.insert(*id, data);
+            }
+            if self.unsupported.is_disabled("inverter_phase_data", id) {
+                continue;
+            }
+            let data = self.request("inverter_phase_data", |fronius| fronius.get_inverter_realtime_data_device::<ThreePhaseInverterData>(id));
+            self.unsupported.observe("inverter_phase_data", id, data.as_ref().err(), false);
+            if let Some(data) = record(&mut snapshot.errors, "inverter_phase_data", Some(id), data) {
+                snapshot.inverter_phases.insert(*id, data);
+            }
+        }
+    }
+
+    fn poll_meters(&mut self, snapshot: &mut CycleSnapshot) {
+        // with several meters one request for all of them is cheaper than one
+        // per meter, if it fails the meters are requested one by one
+        let mut system = None;
+        if self.devices.meters.len() > 1 && !self.deadline_exceeded("meters") {
+            let data = self.request("meter_data_system", Fronius::get_meter_realtime_data_system);
+            system = record(&mut snapshot.errors, "meter_data_system", None, data);
+        }
+        for id in &self.devices.meters {
+            if self.deadline_exceeded("meters") {
+                break;
+            }
+            if self.unsupported.is_disabled("meter_data", id) {
+                continue;
+            }
+            let Some(system) = &mut system else {
+                let data = self.request("meter_data", |fronius| fronius.get_meter_realtime_data_device(id));
+                self.unsupported.observe("meter_data", id, data.as_ref().err(), true);
+                if let Some(data) = record(&mut snapshot.errors, "meter_data", Some(id), data) {
+                    snapshot.meters.insert(*id, data);
+                }
+                continue;
+            };
+            self.unsupported.observe("meter_data", id, None, true);
+            match system.remove(&id.to_string()) {
+                Some(data) => {
+                    snapshot.meters.insert(*id, data);
+                }
+                None => snapshot.errors.push(FetchError::missing("meter_data", DeviceType::Meter, id)),
+            }
+        }
+    }
+
+    fn poll_storages(&mut self, snapshot: &mut CycleSnapshot) {
+        for id in &self.devices.storages {
+            if self.deadline_exceeded("storages") {
+                break;
+            }
+            if self.unsupported.is_disabled("storage_data", id) {
+                continue;
+            }
+            let data = self.request("storage_data", |fronius| fronius.get_storage_realtime_data_device(id));
+            self.unsupported.observe("storage_data", id, data.as_ref().err(), true);
+            if let Some(data) = record(&mut snapshot.errors, "storage_data", Some(id), data) {
+                snapshot.storages.insert(*id, data);
+            }
+        }
+    }
+
+    fn poll_ohm_pilots(&mut self, snapshot: &mut CycleSnapshot) {
+        for id in &self.devices.ohm_pilots {
+            if self.deadline_exceeded("ohm_pilots") {
+                break;
+            }
+            if self.unsupported.is_disabled("ohm_pilot_data", id) {
+                continue;
+            }
+            let data = self.request("ohm_pilot_data", |fronius| fronius.get_ohm_pilot_realtime_data_device(id));
+            self.unsupported.observe("ohm_pilot_data", id, data.as_ref().err(), true);
+            if let Some(data) = record(&mut snapshot.errors, "ohm_pilot_data", Some(id), data) {
+                snapshot.ohm_pilots.insert(*id, data);
+            }
+        }
+    }
+
+    /// Sends a request, through the wrapper if there is one.
+    fn request<T>(&self, dataset: &'static str, request: impl Fn(&Fronius) -> Result<T, fronius::Error>) -> Result<T, fronius::Error> {
+        let Some(wrapper) = &self.wrapper else {
+            return request(&self.fronius);
+        };
+        let mut result = None;
+        wrapper(dataset, &mut || result = Some(request(&self.fronius)));
+        result.unwrap_or_else(|| request(&self.fronius))
+    }
+
+    /// Returns true (and logs it) if the deadline has passed, in this case
+    /// the remaining requests of the cycle are skipped.
+    fn deadline_exceeded(&self, dataset: &str) -> bool {
+        let exceeded = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if exceeded {
+            warn!("Cycle deadline exceeded, skipping fetch of {dataset}");
+        }
+        exceeded
+    }
+
+    /// Polls every `interval` until the context is stopped and hands the
+    /// snapshots to the callbacks and subscribers. Subscribers that dropped
+    /// their receiver are removed.
+    pub fn run(&mut self, context: &RunContext) {
+        while !context.is_stopped() {
+            let cycle_start = Instant::now();
+            let snapshot = Arc::new(self.poll_once());
+            for callback in &mut self.callbacks {
+                callback(&snapshot);
+            }
+            self.subscribers.retain(|subscriber| subscriber.send(snapshot.clone()).is_ok());
+            while !context.is_stopped() {
+                let remaining = (cycle_start + self.interval).saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                std::thread::sleep(remaining.min(Duration::from_millis(100)));
+            }
+        }
+    }
+}
+
+impl FetchError {
+    fn missing(dataset: &'static str, device_type: DeviceType, device_id: &DeviceId) -> Self {
+        FetchError {
+            dataset,
+            device: Some(*device_id),
+            error: Box::new(MissingDeviceError {
+                device_type,
+                device_id: *device_id,
+            }),
+        }
+    }
+}
+
+/// Returns the data or adds the error to `errors`.
+fn record<T>(errors: &mut Vec<FetchError>, dataset: &'static str, device: Option<&DeviceId>, res: Result<T, fronius::Error>) -> Option<T> {
+    match res {
+        Ok(data) => Some(data),
+        Err(error) => {
+            errors.push(FetchError {
+                dataset,
+                device: device.copied(),
+                error: Box::new(error),
+            });
+            None
+        }
+    }
+}
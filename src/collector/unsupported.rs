@@ -0,0 +1,76 @@
+//! Automatic disabling of device requests that keep failing because the
+//! device or the request isn't supported, e.g. a storage or an Ohmpilot that
+//! isn't installed, so such systems configure themselves.
+//!
+//! If a request answers "not supported" (or "device not available" for
+//! meters, storages and Ohmpilots) without interruption for the time given
+//! to `Collector::disable_unsupported_after`, it isn't sent anymore.
+//! `Collector::enable_unsupported` enables it again.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::fronius::{self, DeviceId, StatusCode};
+
+/// A request: the dataset and the device it is sent for.
+type Request = (&'static str, DeviceId);
+
+pub struct Unsupported {
+    disable_after: Option<Duration>,
+    /// Since when the requests have been failing as unsupported.
+    failing: HashMap<Request, Instant>,
+    disabled: HashSet<Request>,
+}
+
+impl Unsupported {
+    /// Disables the requests after `disable_after`, never with `None`.
+    pub fn new(disable_after: Option<Duration>) -> Self {
+        Unsupported {
+            disable_after,
+            failing: HashMap::new(),
+            disabled: HashSet::new(),
+        }
+    }
+
+    pub fn is_disabled(&self, dataset: &'static str, id: &DeviceId) -> bool {
+        self.disabled.contains(&(dataset, *id))
+    }
+
+    /// Tracks the outcome of a request, `error` is `None` if it succeeded.
+    /// `offline_unsupported` tells whether "device not available" means the
+    /// device isn't installed; inverters answer so while they sleep.
+    pub fn observe(&mut self, dataset: &'static str, id: &DeviceId, error: Option<&fronius::Error>, offline_unsupported: bool) {
+        let Some(disable_after) = self.disable_after else {
+            return;
+        };
+        let unsupported = match error {
+            Some(fronius::Error::Response(status)) => status.code() == StatusCode::NotSupported,
+            Some(fronius::Error::DeviceOffline(_)) => offline_unsupported,
+            _ => false,
+        };
+        if !unsupported {
+            self.failing.remove(&(dataset, *id));
+            return;
+        }
+        let since = *self.failing.entry((dataset, *id)).or_insert_with(Instant::now);
+        if since.elapsed() >= disable_after {
+            warn!(
+                "Disabling {dataset} of device {id}, it has not been supported for {}h; send the enable command to request it again",
+                disable_after.as_secs() / 3600
+            );
+            self.failing.remove(&(dataset, *id));
+            self.disabled.insert((dataset, *id));
+        }
+    }
+
+    /// Enables all disabled requests again.
+    pub fn enable_all(&mut self) {
+        for (dataset, id) in self.disabled.drain() {
+            info!("Enabling {dataset} of device {id} again");
+        }
+    }
+}
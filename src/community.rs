@@ -0,0 +1,55 @@
+//! Aggregation of several sites, e.g. family houses or the members of an
+//! energy community, to one virtual `community` site.
+
+use point_derive::ToPoint;
+use serde::Serialize;
+
+use crate::{
+    timestamp::{self, TimestampError},
+    PowerFlowData,
+};
+
+/// Name of the virtual site the combined measurements are tagged with.
+pub const SITE: &str = "community";
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "community"]
+pub struct CommunityData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    sites: i64,
+    #[influxdb(field)]
+    photovoltaik: f64,
+    #[influxdb(field)]
+    load: f64,
+    #[influxdb(field)]
+    grid: f64,
+    #[influxdb(field)]
+    akku: f64,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Sums the power flow of the sites that reported one in this cycle. Sites
+/// without a meter don't report load and grid, they only add their PV.
+/// Returns `None` if no site reported.
+pub fn aggregate<'a>(power_flows: impl IntoIterator<Item = &'a PowerFlowData>) -> Result<Option<CommunityData>, TimestampError> {
+    let mut data = CommunityData {
+        device: "Community",
+        ..Default::default()
+    };
+    for power_flow in power_flows {
+        data.sites += 1;
+        data.photovoltaik += power_flow.photovoltaik;
+        // P_Load is negative while power is consumed
+        data.load -= power_flow.load.unwrap_or(0.0);
+        data.grid += power_flow.grid.unwrap_or(0.0);
+        data.akku += power_flow.akku.unwrap_or(0.0);
+    }
+    if data.sites == 0 {
+        return Ok(None);
+    }
+    data.time = timestamp::now()?;
+    Ok(Some(data))
+}
@@ -0,0 +1,240 @@
+//! Runtime control of the polling by the MQTT command topic and the REST API.
+//! In read-only mode (`READ_ONLY`) every command is rejected.
+
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+use std::str::FromStr;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+use log::{info, warn};
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+use serde::Serialize;
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+use thiserror::Error;
+
+/// Interval between the cycles while burst mode is active.
+const BURST_INTERVAL: Duration = Duration::from_secs(2);
+/// Longest burst that can be requested.
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+const MAX_BURST: Duration = Duration::from_secs(3600);
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+const MAX_INTERVAL: Duration = Duration::from_secs(86400);
+
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CommandError {
+    #[error("unknown command {0:?}, expected one of: poll, burst <seconds>, interval <seconds>, reload, enable, ack")]
+    Unknown(String),
+    #[error("invalid duration {0:?}")]
+    InvalidDuration(String),
+    #[error("commands are disabled in read-only mode")]
+    ReadOnly,
+}
+
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Starts the next cycle right away.
+    Poll,
+    /// Polls every `BURST_INTERVAL` for the given time.
+    Burst(Duration),
+    /// Changes the polling interval.
+    Interval(Duration),
+    /// Rebuilds the processing pipeline, e.g. to load a changed script.
+    Reload,
+    /// Requests the data of devices again that were disabled as unsupported.
+    Enable,
+    /// Acknowledges the active alerts, e.g. an outage.
+    Ack,
+}
+
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let command = words.next().unwrap_or_default().to_ascii_lowercase();
+        let mut seconds = |range: std::ops::RangeInclusive<Duration>| {
+            let text = words.next().unwrap_or_default();
+            text.parse()
+                .ok()
+                .map(Duration::from_secs)
+                .filter(|duration| range.contains(duration))
+                .ok_or_else(|| CommandError::InvalidDuration(text.to_owned()))
+        };
+        match command.as_str() {
+            "poll" => Ok(Command::Poll),
+            "burst" => Ok(Command::Burst(seconds(MIN_INTERVAL..=MAX_BURST)?)),
+            "interval" => Ok(Command::Interval(seconds(MIN_INTERVAL..=MAX_INTERVAL)?)),
+            "reload" => Ok(Command::Reload),
+            "enable" => Ok(Command::Enable),
+            "ack" => Ok(Command::Ack),
+            _ => Err(CommandError::Unknown(s.trim().to_owned())),
+        }
+    }
+}
+
+/// Answer to a command, published or returned as JSON.
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+#[derive(Debug, Clone, Serialize)]
+pub struct Ack {
+    command: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[cfg(any(test, feature = "mqtt", feature = "http"))]
+impl Ack {
+    #[cfg(any(test, feature = "http"))]
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+}
+
+struct State {
+    interval: Duration,
+    burst_until: Option<Instant>,
+    poll_now: bool,
+    reload: bool,
+    enable: bool,
+    ack: bool,
+}
+
+impl State {
+    /// The current polling interval, shorter during a burst.
+    fn interval(&self) -> Duration {
+        match self.burst_until {
+            Some(until) if Instant::now() < until => BURST_INTERVAL.min(self.interval),
+            _ => self.interval,
+        }
+    }
+}
+
+/// Shared between the poll loop and the command sources.
+pub struct Control {
+    state: Mutex<State>,
+    changed: Condvar,
+    #[cfg(any(test, feature = "mqtt", feature = "http"))]
+    read_only: bool,
+}
+
+impl Control {
+    /// With `read_only` all commands received via MQTT or HTTP are rejected.
+    pub fn new(interval: Duration, read_only: bool) -> Self {
+        #[cfg(not(any(test, feature = "mqtt", feature = "http")))]
+        let _ = read_only;
+        Control {
+            state: Mutex::new(State {
+                interval,
+                burst_until: None,
+                poll_now: false,
+                reload: false,
+                enable: false,
+                ack: false,
+            }),
+            changed: Condvar::new(),
+            #[cfg(any(test, feature = "mqtt", feature = "http"))]
+            read_only,
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    #[cfg(any(test, feature = "mqtt", feature = "http"))]
+    pub fn apply(&self, command: Command) {
+        let mut state = self.state();
+        match command {
+            Command::Poll => state.poll_now = true,
+            Command::Burst(duration) => state.burst_until = Some(Instant::now() + duration),
+            Command::Interval(interval) => state.interval = interval,
+            Command::Reload => state.reload = true,
+            Command::Enable => state.enable = true,
+            Command::Ack => state.ack = true,
+        }
+        self.changed.notify_all();
+    }
+
+    /// Parses and applies a command received from `source`.
+    #[cfg(any(test, feature = "mqtt", feature = "http"))]
+    pub fn execute(&self, text: &str, source: &str) -> Ack {
+        let text = text.trim();
+        let command = if self.read_only {
+            Err(CommandError::ReadOnly)
+        } else {
+            text.parse()
+        };
+        match command {
+            Ok(command) => {
+                info!("Received command via {source}: {command:?}");
+                self.apply(command);
+                Ack {
+                    command: text.to_owned(),
+                    ok: true,
+                    error: None,
+                }
+            }
+            Err(error) => {
+                warn!("Invalid command received via {source}: {error}");
+                Ack {
+                    command: text.to_owned(),
+                    ok: false,
+                    error: Some(error.to_string()),
+                }
+            }
+        }
+    }
+
+    /// The current polling interval, shorter during a burst.
+    #[cfg(feature = "http")]
+    pub fn interval(&self) -> Duration {
+        self.state().interval()
+    }
+
+    /// Whether a reload was requested since the last call.
+    pub fn take_reload(&self) -> bool {
+        std::mem::take(&mut self.state().reload)
+    }
+
+    /// Whether the disabled devices should be enabled again, since the last
+    /// call.
+    pub fn take_enable(&self) -> bool {
+        std::mem::take(&mut self.state().enable)
+    }
+
+    /// Whether the active alerts should be acknowledged, since the last call.
+    pub fn take_ack(&self) -> bool {
+        std::mem::take(&mut self.state().ack)
+    }
+
+    /// Sleeps until the next cycle is due `interval()` after `cycle_start`,
+    /// but wakes up early for a poll command, a changed interval or a
+    /// shutdown request.
+    pub fn wait_for_cycle(&self, cycle_start: Instant, shutdown: &AtomicBool) {
+        let mut state = self.state();
+        while !shutdown.load(Ordering::Relaxed) && !state.poll_now {
+            let remaining = (cycle_start + state.interval()).saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            // the shutdown flag is set by a signal handler, so it is checked periodically
+            state = self
+                .changed
+                .wait_timeout(state, remaining.min(Duration::from_millis(500)))
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+        state.poll_now = false;
+    }
+}
@@ -0,0 +1,72 @@
+//! Writing of the points to a CSV file, for spreadsheets and tools that
+//! don't read line protocol.
+//!
+//! Only available with the `csv` cargo feature and enabled by `CSV_FILE`.
+//! The file has one row per field with the columns `time` (RFC 3339, UTC),
+//! `measurement`, `tags` (a JSON object), `field`, `value` and `text`, the
+//! same layout as the ClickHouse table: numbers and booleans are written to
+//! `value`, strings to `text`. The header is written when the file is
+//! created. Like the line protocol file, the file is opened again for every
+//! write, so it can be rotated by logrotate.
+
+use std::{fs::OpenOptions, path::PathBuf};
+
+use chrono::{DateTime, SecondsFormat};
+use log::info;
+
+use crate::{
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const HEADER: [&str; 6] = ["time", "measurement", "tags", "field", "value", "text"];
+
+pub struct CsvFileSink {
+    path: PathBuf,
+    routes: Routes,
+}
+
+impl CsvFileSink {
+    pub fn from_env(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        info!("Writing points to CSV file {path}");
+        Ok(CsvFileSink {
+            path: PathBuf::from(path),
+            routes: Routes::from_env()?,
+        })
+    }
+}
+
+impl Sink for CsvFileSink {
+    fn name(&self) -> &'static str {
+        "CSV file"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let points: Vec<_> = batch
+            .points()
+            .iter()
+            .filter(|point| self.routes.targets(&point.measurement).contains(&Target::Csv))
+            .collect();
+        if points.is_empty() {
+            return Ok(());
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let new = file.metadata()?.len() == 0;
+        let mut writer = csv::Writer::from_writer(file);
+        if new {
+            writer.write_record(HEADER)?;
+        }
+        for point in points {
+            let time = DateTime::from_timestamp_nanos(point.time).to_rfc3339_opts(SecondsFormat::AutoSi, true);
+            let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+            let tags = serde_json::Value::Object(tags).to_string();
+            for (field, value) in &point.fields {
+                let (value, text) = value.to_columns();
+                let value = value.map(|value| value.to_string()).unwrap_or_default();
+                writer.write_record([time.as_str(), &point.measurement, &tags, field, &value, text.unwrap_or_default()])?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
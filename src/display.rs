@@ -0,0 +1,300 @@
+//! A minimal status screen for small displays: the power flow and today's
+//! yield of a site in black and white, rendered from the snapshot. It is
+//! served as PNG at `/display.png` of the REST API, e.g. for e-paper picture
+//! frames that fetch an image, and with `DISPLAY_FRAMEBUFFER` drawn to a
+//! Linux framebuffer. The kernel drivers of the common I2C and SPI displays
+//! (SSD1306 OLEDs, ILI9341 or ST7735 LCDs, ...) provide one.
+//!
+//! `DISPLAY_SIZE` sets the size of the PNG (default `400x300`, the size of
+//! 4.2" e-paper displays), the framebuffer has its own. The screen is scaled
+//! to the largest multiple of 128x64 that fits. `DISPLAY_SITE` selects the
+//! site if there are several.
+
+use std::{
+    convert::Infallible,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_6X10, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use log::{error, info};
+use thiserror::Error;
+
+use crate::{snapshot::Snapshot, status};
+
+/// Size of the screen before it is scaled, that of the smallest displays.
+const BASE_WIDTH: u32 = 128;
+const BASE_HEIGHT: u32 = 64;
+const LINE_HEIGHT: i32 = 10;
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid DISPLAY_SIZE {0:?}, expected e.g. 400x300")]
+pub struct InvalidSize(String);
+
+#[derive(Debug, Clone)]
+struct Options {
+    width: u32,
+    height: u32,
+    site: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            width: 400,
+            height: 300,
+            site: None,
+        }
+    }
+}
+
+/// Reads `DISPLAY_SIZE` and `DISPLAY_SITE`.
+pub fn init_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = match std::env::var("DISPLAY_SIZE") {
+        Ok(val) => parse_size(&val).ok_or(InvalidSize(val))?,
+        Err(_) => (Options::default().width, Options::default().height),
+    };
+    OPTIONS.get_or_init(|| Options {
+        width,
+        height,
+        site: std::env::var("DISPLAY_SITE").ok(),
+    });
+    Ok(())
+}
+
+fn parse_size(size: &str) -> Option<(u32, u32)> {
+    let (width, height) = size.trim().split_once(['x', 'X'])?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+    (width >= BASE_WIDTH && height >= BASE_HEIGHT).then_some((width, height))
+}
+
+/// A black and white image, `true` for the pixels of the text.
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<bool>,
+}
+
+impl Canvas {
+    pub fn new(width: u32, height: u32) -> Self {
+        Canvas {
+            width,
+            height,
+            pixels: vec![false; (width * height) as usize],
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height && self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// The canvas as PNG with black text on white, as e-paper shows it.
+    #[cfg(any(test, feature = "http"))]
+    pub fn to_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut data = Vec::new();
+        for y in 0..self.height {
+            // rows of 1 bit per pixel, padded to a byte
+            let mut row = vec![0u8; self.width.div_ceil(8) as usize];
+            for x in 0..self.width {
+                if !self.pixel(x, y) {
+                    row[(x / 8) as usize] |= 0x80 >> (x % 8);
+                }
+            }
+            data.extend(row);
+        }
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+/// Draws on the canvas with every pixel as a square of `scale` pixels, at an
+/// offset that centers the screen.
+struct Scaled<'a> {
+    canvas: &'a mut Canvas,
+    scale: u32,
+    offset: (u32, u32),
+}
+
+impl OriginDimensions for Scaled<'_> {
+    fn size(&self) -> Size {
+        Size::new(BASE_WIDTH, BASE_HEIGHT)
+    }
+}
+
+impl DrawTarget for Scaled<'_> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let canvas = &mut *self.canvas;
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) else {
+                continue;
+            };
+            let (left, top) = (self.offset.0 + x * self.scale, self.offset.1 + y * self.scale);
+            for y in top..(top + self.scale).min(canvas.height) {
+                for x in left..(left + self.scale).min(canvas.width) {
+                    canvas.pixels[(y * canvas.width + x) as usize] = color.is_on();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders the screen of the configured site.
+pub fn render(snapshot: &Snapshot, width: u32, height: u32) -> Canvas {
+    let site = OPTIONS.get().and_then(|options| options.site.as_deref());
+    let sites = status::overviews(&snapshot.to_json(None).unwrap_or_default());
+    let overview = match site {
+        Some(site) => sites.get(site),
+        None => sites.values().next(),
+    };
+
+    let mut canvas = Canvas::new(width, height);
+    let scale = (width / BASE_WIDTH).min(height / BASE_HEIGHT).max(1);
+    let offset = (width.saturating_sub(BASE_WIDTH * scale) / 2, height.saturating_sub(BASE_HEIGHT * scale) / 2);
+    let mut target = Scaled {
+        canvas: &mut canvas,
+        scale,
+        offset,
+    };
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    for (i, line) in (0..).zip(lines(overview)) {
+        // the font only has ASCII, e.g. no narrow space of French numbers
+        let line = line.replace('\u{202f}', " ");
+        // drawing on the canvas can't fail
+        let _ = Text::with_baseline(&line, Point::new(1, 2 + i * LINE_HEIGHT), style, Baseline::Top).draw(&mut target);
+    }
+    canvas
+}
+
+/// The lines of the screen, at most 21 characters to fit 128 pixels.
+fn lines(overview: Option<&status::Overview>) -> Vec<String> {
+    let Some(overview) = overview else {
+        return vec!["Waiting for data".to_owned()];
+    };
+    let locale = crate::locale::get();
+    let power = |watts: Option<f64>| watts.map_or_else(|| "-".to_owned(), |watts| locale.power(watts));
+    let mut lines = vec![
+        format!("PV    {}", power(overview.pv)),
+        format!("Load  {}", power(overview.load)),
+        format!("Grid  {}", power(overview.grid)),
+    ];
+    if overview.battery.is_some() || overview.state_of_charge.is_some() {
+        let state_of_charge = overview.state_of_charge.map(|percent| format!(" {}", locale.percent(percent))).unwrap_or_default();
+        lines.push(format!("Batt  {}{state_of_charge}", power(overview.battery)));
+    }
+    lines.push(format!("Today {}", overview.energy_day.map_or_else(|| "-".to_owned(), |energy| locale.energy(energy))));
+    let age = chrono::Duration::milliseconds((overview.age.unwrap_or_default() * 1000.0) as i64);
+    lines.push(format!("      {}", (crate::timezone::now() - age).format("%H:%M")));
+    lines
+}
+
+/// The screen of the configured site and size as PNG.
+#[cfg(feature = "http")]
+pub fn png(snapshot: &Snapshot) -> Result<Vec<u8>, png::EncodingError> {
+    let options = OPTIONS.get().cloned().unwrap_or_default();
+    render(snapshot, options.width, options.height).to_png()
+}
+
+/// A Linux framebuffer like `/dev/fb1`, with the geometry from sysfs.
+struct Framebuffer {
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    bits_per_pixel: u32,
+    line_length: u32,
+}
+
+impl Framebuffer {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let invalid = |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid {what} of {path:?}"));
+        let name = path.file_name().ok_or_else(|| invalid("name"))?;
+        let sysfs = Path::new("/sys/class/graphics").join(name);
+        let read = |attribute: &str| std::fs::read_to_string(sysfs.join(attribute)).map(|value| value.trim().to_owned());
+        let size = read("virtual_size")?;
+        let (width, height): (u32, u32) = size
+            .split_once(',')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or_else(|| invalid("size"))?;
+        let bits_per_pixel: u32 = read("bits_per_pixel")?.parse().map_err(|_| invalid("depth"))?;
+        if bits_per_pixel != 1 && !bits_per_pixel.is_multiple_of(8) {
+            return Err(invalid("depth"));
+        }
+        let line_length = match read("stride") {
+            Ok(stride) => stride.parse().map_err(|_| invalid("stride"))?,
+            Err(_) => (width * bits_per_pixel).div_ceil(8),
+        };
+        Ok(Framebuffer {
+            path: path.to_owned(),
+            width,
+            height,
+            bits_per_pixel,
+            line_length,
+        })
+    }
+
+    /// Writes the canvas with the text lit on a dark background, as OLEDs and
+    /// LCDs show it best.
+    fn write(&self, canvas: &Canvas) -> std::io::Result<()> {
+        let mut data = vec![0u8; (self.line_length * self.height) as usize];
+        for y in 0..self.height {
+            let row = &mut data[(y * self.line_length) as usize..((y + 1) * self.line_length) as usize];
+            for x in (0..self.width).filter(|&x| canvas.pixel(x, y)) {
+                if self.bits_per_pixel == 1 {
+                    // the monochrome drivers take the leftmost pixel in the lowest bit
+                    row[(x / 8) as usize] |= 1 << (x % 8);
+                } else {
+                    let bytes = (self.bits_per_pixel / 8) as usize;
+                    let start = x as usize * bytes;
+                    if let Some(pixel) = row.get_mut(start..start + bytes) {
+                        pixel.fill(0xff);
+                    }
+                }
+            }
+        }
+        std::fs::OpenOptions::new().write(true).open(&self.path)?.write_all(&data)
+    }
+}
+
+/// Draws the screen to the framebuffer every `interval`, if it changed.
+pub fn spawn(path: &Path, snapshot: Arc<Snapshot>, interval: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let framebuffer = Framebuffer::open(path)?;
+    info!(
+        "Drawing the status screen to {:?} ({}x{}, {} bits per pixel)",
+        path, framebuffer.width, framebuffer.height, framebuffer.bits_per_pixel
+    );
+    std::thread::Builder::new().name("display".to_owned()).spawn(move || {
+        let mut drawn = None;
+        loop {
+            let canvas = render(&snapshot, framebuffer.width, framebuffer.height);
+            if drawn.as_ref() != Some(&canvas.pixels) {
+                match framebuffer.write(&canvas) {
+                    Ok(()) => drawn = Some(canvas.pixels),
+                    Err(error) => error!("Error during display update occured: {:?}", error),
+                }
+            }
+            std::thread::sleep(interval);
+        }
+    })?;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use chrono::prelude::*;
+use point_derive::ToPoint;
+use serde::Serialize;
+
+use crate::timestamp::{self, TimestampError};
+
+/// Below this DC power the efficiency is not meaningful (e.g. at dawn).
+const MIN_DC_POWER: f64 = 100.0;
+/// Range of plausible efficiencies. Values outside of it are caused by
+/// measurement errors or, on hybrid inverters, by battery flows.
+const PLAUSIBLE: std::ops::RangeInclusive<f64> = 0.5..=1.0;
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "inverter_efficiency"]
+pub struct EfficiencyData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    ac_power: f64,
+    #[influxdb(field)]
+    dc_power: f64,
+    #[influxdb(field)]
+    efficiency: Option<f64>,
+    #[influxdb(field)]
+    daily_efficiency: Option<f64>,
+    #[influxdb(field)]
+    plausible: bool,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+#[derive(Default)]
+struct DailySums {
+    date: Option<NaiveDate>,
+    ac: f64,
+    dc: f64,
+}
+
+/// DC to AC conversion efficiency of the inverters. The daily efficiency is
+/// the ratio of the summed AC and DC power of all plausible samples of the day.
+#[derive(Default)]
+pub struct Efficiency {
+    days: HashMap<String, DailySums>,
+}
+
+impl Efficiency {
+    /// Adds a sample of the inverter `key`. Returns `None` if the inverter
+    /// doesn't report AC or DC power.
+    pub fn add(&mut self, device: &'static str, key: &str, ac_power: Option<f64>, dc_power: Option<f64>) -> Result<Option<EfficiencyData>, TimestampError> {
+        let (Some(ac_power), Some(dc_power)) = (ac_power, dc_power) else {
+            return Ok(None);
+        };
+        let efficiency = (dc_power >= MIN_DC_POWER).then(|| ac_power / dc_power);
+        let plausible = efficiency.is_none_or(|efficiency| PLAUSIBLE.contains(&efficiency));
+
+        let today = crate::timezone::now().date_naive();
+        let sums = self.days.entry(key.to_owned()).or_default();
+        if sums.date != Some(today) {
+            *sums = DailySums {
+                date: Some(today),
+                ..Default::default()
+            };
+        }
+        if efficiency.is_some() && plausible {
+            sums.ac += ac_power;
+            sums.dc += dc_power;
+        }
+
+        Ok(Some(EfficiencyData {
+            device,
+            ac_power,
+            dc_power,
+            efficiency,
+            daily_efficiency: (sums.dc > 0.0).then(|| sums.ac / sums.dc),
+            plausible,
+            time: timestamp::now()?,
+        }))
+    }
+}
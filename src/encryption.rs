@@ -0,0 +1,176 @@
+//! Encryption of the files with site data that stay on the host: the spool
+//! and the state file, e.g. on a host shared with others. With
+//! `FILE_ENCRYPTION_KEY` (32 bytes in base64) or `FILE_ENCRYPTION_KEY_FILE`
+//! (a file with such a key) they are encrypted with ChaCha20-Poly1305.
+//!
+//! Every write is sealed on its own, with a random nonce, into one line, so
+//! lines can still be appended to the spool. Lines that aren't sealed, e.g.
+//! written before the key was set, are read as they are. A sealed line that
+//! can't be decrypted, e.g. torn by a crash during the append, doesn't make
+//! the rest of the file unreadable.
+//!
+//! The line protocol files of `LINE_FILE` aren't encrypted: they are an export
+//! for other tools like `influx write`, which can't read sealed lines.
+//!
+//! Only available with the `encryption` cargo feature, without it the files
+//! with sealed lines can't be read and setting a key is an error.
+
+#[cfg(feature = "encryption")]
+use base64::Engine;
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use thiserror::Error;
+
+/// Marks a sealed line, followed by the nonce and the ciphertext in base64.
+const SEALED: &str = "#sealed:";
+#[cfg(feature = "encryption")]
+const KEY_LEN: usize = 32;
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[cfg(feature = "encryption")]
+    #[error("invalid FILE_ENCRYPTION_KEY, expected {KEY_LEN} bytes in base64")]
+    InvalidKey,
+    #[error("the file is encrypted, but FILE_ENCRYPTION_KEY isn't set")]
+    MissingKey,
+    #[error("the file can't be decrypted, the key is wrong or the file damaged")]
+    Decrypt,
+    #[cfg(feature = "encryption")]
+    #[error("the data can't be encrypted")]
+    Encrypt,
+    #[cfg(not(feature = "encryption"))]
+    #[error("FILE_ENCRYPTION_KEY needs a build with the encryption feature")]
+    Unsupported,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub struct FileKey(ChaCha20Poly1305);
+
+/// Without the `encryption` feature there is no key.
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub enum FileKey {}
+
+#[cfg(feature = "encryption")]
+impl FileKey {
+    /// The key given by `FILE_ENCRYPTION_KEY` or `FILE_ENCRYPTION_KEY_FILE`,
+    /// `None` if the files aren't encrypted.
+    pub fn from_env() -> Result<Option<Self>, EncryptionError> {
+        let encoded = match (std::env::var("FILE_ENCRYPTION_KEY"), std::env::var_os("FILE_ENCRYPTION_KEY_FILE")) {
+            (Ok(key), _) => key,
+            (Err(_), Some(path)) => std::fs::read_to_string(path)?,
+            (Err(_), None) => return Ok(None),
+        };
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| EncryptionError::InvalidKey)?;
+        if key.len() != KEY_LEN {
+            return Err(EncryptionError::InvalidKey);
+        }
+        Ok(Some(FileKey(ChaCha20Poly1305::new(Key::from_slice(&key)))))
+    }
+
+    #[cfg(test)]
+    pub fn new(key: [u8; KEY_LEN]) -> Self {
+        FileKey(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    /// Encrypts `plaintext` into one line, without the line break.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String, EncryptionError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        // only fails for more than 256 GB
+        let ciphertext = self.0.encrypt(&nonce, plaintext).map_err(|_| EncryptionError::Encrypt)?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!("{SEALED}{}", base64::engine::general_purpose::STANDARD.encode(sealed)))
+    }
+
+    fn open_line(&self, sealed: &str) -> Result<String, EncryptionError> {
+        let sealed = base64::engine::general_purpose::STANDARD
+            .decode(sealed.trim())
+            .map_err(|_| EncryptionError::Decrypt)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(EncryptionError::Decrypt);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self.0.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| EncryptionError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| EncryptionError::Decrypt)
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl FileKey {
+    pub fn from_env() -> Result<Option<Self>, EncryptionError> {
+        if std::env::var_os("FILE_ENCRYPTION_KEY").is_some() || std::env::var_os("FILE_ENCRYPTION_KEY_FILE").is_some() {
+            return Err(EncryptionError::Unsupported);
+        }
+        Ok(None)
+    }
+
+    pub fn seal(&self, _plaintext: &[u8]) -> Result<String, EncryptionError> {
+        match *self {}
+    }
+
+    fn open_line(&self, _sealed: &str) -> Result<String, EncryptionError> {
+        match *self {}
+    }
+}
+
+/// The readable part of a file and the sealed lines that couldn't be
+/// decrypted.
+pub struct Opened {
+    pub plaintext: String,
+    /// Damaged lines, e.g. torn by a crash during the append.
+    pub damaged: Vec<String>,
+}
+
+impl Opened {
+    /// The plaintext, an error if a line is damaged. For files that are only
+    /// readable as a whole, like the state file.
+    pub fn complete(self) -> Result<String, EncryptionError> {
+        if self.damaged.is_empty() {
+            Ok(self.plaintext)
+        } else {
+            Err(EncryptionError::Decrypt)
+        }
+    }
+}
+
+/// The plaintext of a file: sealed lines are decrypted, the other lines are
+/// kept as they are. Sealed lines that can't be decrypted are skipped, they
+/// are returned as damaged. Without a key a file with sealed lines is an
+/// error, nothing of it is readable.
+pub fn open(content: &str, key: Option<&FileKey>) -> Result<Opened, EncryptionError> {
+    if !content.contains(SEALED) {
+        return Ok(Opened {
+            plaintext: content.to_owned(),
+            damaged: Vec::new(),
+        });
+    }
+    let key = key.ok_or(EncryptionError::MissingKey)?;
+    let mut opened = Opened {
+        plaintext: String::with_capacity(content.len()),
+        damaged: Vec::new(),
+    };
+    for line in content.lines() {
+        match line.strip_prefix(SEALED) {
+            Some(sealed) => match key.open_line(sealed) {
+                Ok(plaintext) => opened.plaintext.push_str(&plaintext),
+                Err(_) => opened.damaged.push(line.to_owned()),
+            },
+            None => {
+                opened.plaintext.push_str(line);
+                opened.plaintext.push('\n');
+            }
+        }
+    }
+    Ok(opened)
+}
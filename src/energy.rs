@@ -0,0 +1,132 @@
+use chrono::prelude::*;
+use point_derive::ToPoint;
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp::{self, TimestampError};
+
+/// Gaps between two samples longer than this (e.g. after a restart) are not
+/// integrated, as the power in between is unknown.
+const MAX_GAP_SECONDS: f64 = 300.0;
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "derived_energy"]
+pub struct DerivedEnergyData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    pv_energy: f64,
+    #[influxdb(field)]
+    load_energy: f64,
+    #[influxdb(field)]
+    grid_import_energy: f64,
+    #[influxdb(field)]
+    grid_export_energy: f64,
+    #[influxdb(field)]
+    battery_charge_energy: f64,
+    #[influxdb(field)]
+    battery_discharge_energy: f64,
+    #[influxdb(field)]
+    self_consumption: Option<f64>,
+    #[influxdb(field)]
+    autonomy: Option<f64>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Energies of an interval in Wh.
+#[cfg(feature = "influxdb2")]
+#[derive(Default, Debug, Clone, Copy)]
+pub struct Energies {
+    pub pv: f64,
+    pub load: f64,
+    pub grid_import: f64,
+    pub grid_export: f64,
+    pub battery_charge: f64,
+    pub battery_discharge: f64,
+}
+
+/// Energies of the current day in Wh, integrated from the power flow.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct DailyEnergy {
+    date: Option<NaiveDate>,
+    last_sample: Option<DateTime<Utc>>,
+    pv: f64,
+    load: f64,
+    grid_import: f64,
+    grid_export: f64,
+    battery_charge: f64,
+    battery_discharge: f64,
+}
+
+impl DailyEnergy {
+    /// Adds a power flow sample in W. `grid` is positive while importing,
+    /// `load` positive while consuming and `akku` positive while discharging.
+    pub fn add(&mut self, pv: f64, load: f64, grid: f64, akku: f64) -> Result<DerivedEnergyData, TimestampError> {
+        self.add_at(pv, load, grid, akku, crate::timezone::now())
+    }
+
+    /// Adds a power flow sample measured at `local`, the time in the time
+    /// zone of the site, which the days are counted in.
+    pub fn add_at(&mut self, pv: f64, load: f64, grid: f64, akku: f64, local: DateTime<FixedOffset>) -> Result<DerivedEnergyData, TimestampError> {
+        let now = local.with_timezone(&Utc);
+        self.start_day(local.date_naive());
+
+        let hours = self
+            .last_sample
+            .map(|last| (now - last).num_milliseconds() as f64 / 1000.0)
+            .filter(|seconds| *seconds > 0.0 && *seconds <= MAX_GAP_SECONDS)
+            .map_or(0.0, |seconds| seconds / 3600.0);
+        self.last_sample = Some(now);
+
+        self.pv += pv.max(0.0) * hours;
+        self.load += load.max(0.0) * hours;
+        self.grid_import += grid.max(0.0) * hours;
+        self.grid_export += (-grid).max(0.0) * hours;
+        self.battery_charge += (-akku).max(0.0) * hours;
+        self.battery_discharge += akku.max(0.0) * hours;
+        self.data(now)
+    }
+
+    /// Adds the energies of an interval that ended at `time`, e.g. from an
+    /// export of Solar.web. The intervals have to be added in order.
+    #[cfg(feature = "influxdb2")]
+    pub fn add_interval(&mut self, time: DateTime<Utc>, energies: &Energies) -> Result<DerivedEnergyData, TimestampError> {
+        self.start_day(crate::timezone::local(time).date_naive());
+        self.last_sample = Some(time);
+        self.pv += energies.pv;
+        self.load += energies.load;
+        self.grid_import += energies.grid_import;
+        self.grid_export += energies.grid_export;
+        self.battery_charge += energies.battery_charge;
+        self.battery_discharge += energies.battery_discharge;
+        self.data(time)
+    }
+
+    /// Starts a new day if `day` is another day than the current one.
+    fn start_day(&mut self, day: NaiveDate) {
+        if self.date != Some(day) {
+            *self = DailyEnergy {
+                date: Some(day),
+                last_sample: self.last_sample,
+                ..Default::default()
+            };
+        }
+    }
+
+    fn data(&self, time: DateTime<Utc>) -> Result<DerivedEnergyData, TimestampError> {
+        Ok(DerivedEnergyData {
+            device: "Site",
+            pv_energy: self.pv,
+            load_energy: self.load,
+            grid_import_energy: self.grid_import,
+            grid_export_energy: self.grid_export,
+            battery_charge_energy: self.battery_charge,
+            battery_discharge_energy: self.battery_discharge,
+            // PV that was not exported was used on site, directly or via the battery
+            self_consumption: (self.pv > 0.0).then(|| ((self.pv - self.grid_export) / self.pv).clamp(0.0, 1.0)),
+            // load that was not imported was covered by PV or the battery
+            autonomy: (self.load > 0.0).then(|| ((self.load - self.grid_import) / self.load).clamp(0.0, 1.0)),
+            time: timestamp::from_datetime(time)?,
+        })
+    }
+}
@@ -0,0 +1,99 @@
+//! Aggregation of repeated fetch errors, so an endpoint that fails every
+//! cycle (e.g. a storage that isn't installed) doesn't fill the log with the
+//! same error every 15 seconds.
+//!
+//! The first error of a dataset of a device is logged in full, the following
+//! ones are only counted. Once `ERROR_SUMMARY_INTERVAL` seconds (default 3600)
+//! have passed, a summary like "Fetch of storage_data of device 0 failed 240
+//! times in the last 3600s" is logged. If a dataset didn't fail during a whole
+//! interval, its next error is logged in full again.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+const DEFAULT_INTERVAL: u64 = 3600;
+
+/// Summary interval in seconds.
+static INTERVAL: AtomicU64 = AtomicU64::new(DEFAULT_INTERVAL);
+
+/// The failures by dataset and device.
+static FAILURES: Mutex<BTreeMap<(String, String), Failures>> = Mutex::new(BTreeMap::new());
+
+/// The failures of a dataset of a device since the last summary.
+struct Failures {
+    since: Instant,
+    count: u64,
+    last_error: String,
+}
+
+/// Reads `ERROR_SUMMARY_INTERVAL`.
+pub fn init_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    if let Ok(val) = std::env::var("ERROR_SUMMARY_INTERVAL") {
+        INTERVAL.store(val.parse::<u64>()?.max(1), Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+fn interval() -> Duration {
+    Duration::from_secs(INTERVAL.load(Ordering::Relaxed))
+}
+
+/// Logs the error of the fetch of `dataset` from `device` (empty if the
+/// dataset isn't per device), unless the dataset of the device failed before
+/// in the current interval.
+pub fn report(dataset: &str, device: &str, error: &(dyn std::error::Error + 'static)) {
+    let mut failures = FAILURES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let key = (dataset.to_owned(), device.to_owned());
+    match failures.get_mut(&key) {
+        Some(failures) => {
+            failures.count += 1;
+            failures.last_error = format!("{error:?}");
+        }
+        None => {
+            let error_kind = error.downcast_ref::<fronius_api::fronius::Error>().map_or("other", |error| error.kind());
+            error!(endpoint = dataset, device = device, error_kind = error_kind; "Error during fetch of {dataset} occured: {:?}", error);
+            failures.insert(
+                key,
+                Failures {
+                    since: Instant::now(),
+                    count: 0,
+                    last_error: String::new(),
+                },
+            );
+        }
+    }
+}
+
+/// Logs the summaries of the datasets whose interval is over. Called once
+/// per cycle.
+pub fn log_summaries() {
+    let mut failures = FAILURES.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let interval = interval();
+    failures.retain(|(dataset, device), failures| {
+        if failures.since.elapsed() < interval {
+            return true;
+        }
+        if failures.count == 0 {
+            return false;
+        }
+        let of_device = if device.is_empty() { String::new() } else { format!(" of device {device}") };
+        error!(
+            endpoint = dataset.as_str(), device = device.as_str();
+            "Fetch of {dataset}{of_device} failed {} times in the last {}s, last error: {}",
+            failures.count,
+            interval.as_secs(),
+            failures.last_error
+        );
+        failures.since = Instant::now();
+        failures.count = 0;
+        true
+    });
+}
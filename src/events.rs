@@ -0,0 +1,176 @@
+use std::collections::{HashMap, HashSet};
+
+use log::{info, warn};
+use point_derive::ToPoint;
+use serde::Serialize;
+
+use crate::{
+    grafana::Grafana,
+    timestamp::{self, TimestampError},
+};
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "events"]
+pub struct EventData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(tag)]
+    severity: String,
+    #[influxdb(field)]
+    code: i64,
+    #[influxdb(field)]
+    text: String,
+    #[influxdb(field)]
+    active: bool,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+// only the tray reads the events, the collector just writes them
+#[cfg(all(feature = "tray", target_os = "linux"))]
+impl EventData {
+    pub fn severity(&self) -> &str {
+        &self.severity
+    }
+
+    pub fn code(&self) -> i64 {
+        self.code
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether the code was set, `false` if it was cleared.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+/// Turns changes of the state codes of the devices into events. Events and
+/// other notable changes are also pushed to Grafana as annotations, if enabled.
+#[derive(Default)]
+pub struct Events {
+    codes: HashMap<String, i64>,
+    flags: HashMap<String, bool>,
+    /// Active flags that were acknowledged by the `ack` command.
+    acknowledged: HashSet<String>,
+    grafana: Option<Grafana>,
+    /// Name of the site, prefixed to the texts if several sites are polled.
+    site: Option<String>,
+}
+
+impl Events {
+    pub fn from_env(site: Option<&str>) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Events {
+            grafana: Grafana::from_env()?,
+            site: site.map(str::to_owned),
+            ..Default::default()
+        })
+    }
+
+    fn prefixed(&self, text: String) -> String {
+        match &self.site {
+            Some(site) => format!("{site}: {text}"),
+            None => text,
+        }
+    }
+
+    /// Pushes an annotation to Grafana, if enabled.
+    pub fn annotate(&self, tags: &[&str], text: &str) {
+        if let Some(grafana) = &self.grafana {
+            grafana.annotate(tags, text);
+        }
+    }
+
+    /// Records whether the condition `key` of the kind `tag` (e.g. an outage)
+    /// is active, logs and annotates when it starts and ends. A condition that
+    /// is inactive at startup is not reported. These conditions are the
+    /// alerts, an acknowledgement lasts until the condition ends.
+    pub fn flag(&mut self, tag: &str, key: &str, active: bool, text: &str) {
+        let previous = self.flags.insert(key.to_owned(), active).unwrap_or(false);
+        if previous != active {
+            self.acknowledged.remove(key);
+            let text = self.prefixed(if active { text.to_owned() } else { format!("{text} ended") });
+            if active {
+                warn!("{text}");
+            } else {
+                info!("{text}");
+            }
+            self.annotate(&["fronius", tag], &text);
+        }
+    }
+
+    /// Acknowledges the active alerts, returns how many weren't acknowledged
+    /// yet.
+    pub fn acknowledge(&mut self) -> usize {
+        let active: Vec<String> = self.flags.iter().filter(|(_, active)| **active).map(|(key, _)| key.clone()).collect();
+        let count = active.into_iter().filter(|key| self.acknowledged.insert(key.clone())).count();
+        if count > 0 {
+            self.annotate(&["fronius", "ack"], &self.prefixed(format!("{count} alerts acknowledged")));
+        }
+        count
+    }
+
+    /// The number of active alerts and of those not acknowledged yet.
+    pub fn alerts(&self) -> (usize, usize) {
+        let active = self.flags.values().filter(|active| **active).count();
+        (active, active - self.acknowledged.len())
+    }
+
+    /// Records the current state `code` of the device `key`. Returns an event
+    /// if the code changed: the new code if one is set, otherwise the cleared
+    /// previous code. A device without a code at startup creates no event.
+    pub fn observe(&mut self, device: &'static str, key: &str, code: i64) -> Result<Option<EventData>, TimestampError> {
+        let previous = self.codes.insert(key.to_owned(), code).unwrap_or(0);
+        if previous == code {
+            return Ok(None);
+        }
+        let (code, active) = if code == 0 { (previous, false) } else { (code, true) };
+        let event = EventData {
+            device,
+            severity: if active { severity(code) } else { "info" }.to_owned(),
+            code,
+            text: text(code),
+            active,
+            time: timestamp::now()?,
+        };
+        let text = self.prefixed(match active {
+            true => format!("{device} {key}: {} ({code})", event.text),
+            false => format!("{device} {key}: {} ({code}) cleared", event.text),
+        });
+        self.annotate(&["fronius", "event", &event.severity], &text);
+        Ok(Some(event))
+    }
+}
+
+/// Severity by the class of the state code. Codes of class 4 and 6 need
+/// service, the others are temporary or informational.
+fn severity(code: i64) -> &'static str {
+    match code / 100 {
+        4 | 6 => "error",
+        _ => "warning",
+    }
+}
+
+/// Description of the most common state codes of Symo and Primo inverters.
+fn text(code: i64) -> String {
+    let text = match code {
+        102 => "AC voltage too high",
+        103 => "AC voltage too low",
+        105 => "AC frequency too high",
+        106 => "AC frequency too low",
+        107 => "No AC grid detected",
+        108 => "Islanding detected",
+        301 => "Overcurrent (AC)",
+        302 => "Overcurrent (DC)",
+        303 => "DC module overtemperature",
+        304 => "AC module overtemperature",
+        509 => "No energy fed in for 24 hours",
+        crate::STATE_CODE_TEMPERATURE_DERATING => "Power derating caused by too high a temperature",
+        522 => "DC input 1 voltage too low",
+        523 => "DC input 2 voltage too low",
+        _ => return format!("State code {code}"),
+    };
+    text.to_owned()
+}
@@ -0,0 +1,313 @@
+//! Export of the stored data for a date range to CSV or Parquet files, one
+//! file per measurement.
+//!
+//! The columns of a measurement are taken from the schema, so every file of a
+//! measurement has the same columns, even if some fields were never written.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{prelude::*, Duration};
+use influxdb2_structmap::value::Value;
+use log::info;
+use thiserror::Error;
+
+use crate::{
+    influx::{flux_string, InfluxSink},
+    schema::{self, FieldType, Measurement},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ExportError {
+    #[error("unknown measurement {0:?}, see the output of the schema command")]
+    UnknownMeasurement(String),
+    #[error("invalid date {0:?}, expected e.g. 2024-01-31")]
+    InvalidDate(String),
+    #[error("unknown format {0:?}, expected csv or parquet")]
+    UnknownFormat(String),
+    #[cfg(not(feature = "parquet"))]
+    #[error("Parquet is not supported by this build, enable the parquet feature")]
+    ParquetDisabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = ExportError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            _ => Err(ExportError::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// A column of the exported files, the time is always the first one.
+struct Column {
+    name: &'static str,
+    #[cfg_attr(not(feature = "parquet"), allow(dead_code))]
+    kind: FieldType,
+}
+
+fn columns(measurement: &Measurement) -> Vec<Column> {
+    let tags = schema::COMMON_TAGS.iter().chain(measurement.tags()).map(|&name| Column { name, kind: FieldType::String });
+    let fields = measurement.fields().iter().map(|field| Column {
+        name: field.name(),
+        kind: field.kind(),
+    });
+    tags.chain(fields).collect()
+}
+
+/// A pivoted record: the time and the tags and fields by name.
+struct Row {
+    time: DateTime<Utc>,
+    values: BTreeMap<String, Value>,
+}
+
+/// Fetches the points of a measurement in the range `start..stop`, with one
+/// row per series and time.
+fn query(sink: &InfluxSink, bucket: &str, measurement: &str, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<Vec<Row>, Box<dyn std::error::Error>> {
+    let flux = format!(
+        r#"from(bucket: {bucket})
+  |> range(start: {start}, stop: {stop})
+  |> filter(fn: (r) => r._measurement == {measurement})
+  |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")"#,
+        bucket = flux_string(bucket),
+        measurement = flux_string(measurement),
+        start = start.to_rfc3339_opts(SecondsFormat::Secs, true),
+        stop = stop.to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    let mut rows: Vec<Row> = sink
+        .query(flux)?
+        .into_iter()
+        .filter_map(|record| {
+            let mut values = record.values;
+            let time = match values.remove("_time") {
+                Some(Value::TimeRFC(time)) => time.with_timezone(&Utc),
+                _ => return None,
+            };
+            Some(Row { time, values })
+        })
+        .collect();
+    // the records are grouped by series, the files are sorted by time
+    rows.sort_by_key(|row| row.time);
+    Ok(rows)
+}
+
+fn cell_to_string(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(value)) => value.clone(),
+        Some(Value::Double(value)) => value.to_string(),
+        Some(Value::Bool(value)) => value.to_string(),
+        Some(Value::Long(value)) => value.to_string(),
+        Some(Value::UnsignedLong(value)) => value.to_string(),
+        Some(Value::TimeRFC(value)) => value.to_rfc3339(),
+        _ => String::new(),
+    }
+}
+
+enum Writer {
+    Csv(csv::Writer<File>),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_writer::ParquetWriter),
+}
+
+impl Writer {
+    fn create(format: Format, path: &Path, columns: &[Column]) -> Result<Self, Box<dyn std::error::Error>> {
+        match format {
+            Format::Csv => {
+                let mut writer = csv::Writer::from_path(path)?;
+                writer.write_record(std::iter::once("time").chain(columns.iter().map(|column| column.name)))?;
+                Ok(Writer::Csv(writer))
+            }
+            #[cfg(feature = "parquet")]
+            Format::Parquet => Ok(Writer::Parquet(parquet_writer::ParquetWriter::create(path, columns)?)),
+            #[cfg(not(feature = "parquet"))]
+            Format::Parquet => Err(ExportError::ParquetDisabled.into()),
+        }
+    }
+
+    fn write(&mut self, columns: &[Column], rows: &[Row]) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Writer::Csv(writer) => {
+                for row in rows {
+                    let time = row.time.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+                    let cells = columns.iter().map(|column| cell_to_string(row.values.get(column.name)));
+                    writer.write_record(std::iter::once(time).chain(cells))?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "parquet")]
+            Writer::Parquet(writer) => writer.write(columns, rows),
+        }
+    }
+
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Writer::Csv(mut writer) => Ok(writer.flush()?),
+            #[cfg(feature = "parquet")]
+            Writer::Parquet(writer) => writer.finish(),
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use std::{fs::File, path::Path, sync::Arc};
+
+    use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, StringArray, TimestampNanosecondArray};
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    use influxdb2_structmap::value::Value;
+    use parquet::arrow::ArrowWriter;
+
+    use super::{Column, Row};
+    use crate::schema::FieldType;
+
+    pub struct ParquetWriter {
+        writer: ArrowWriter<File>,
+        schema: Arc<Schema>,
+    }
+
+    impl ParquetWriter {
+        pub fn create(path: &Path, columns: &[Column]) -> Result<Self, Box<dyn std::error::Error>> {
+            let time = Field::new("time", DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())), false);
+            let fields = columns.iter().map(|column| {
+                let data_type = match column.kind {
+                    FieldType::Float => DataType::Float64,
+                    FieldType::Integer => DataType::Int64,
+                    FieldType::Boolean => DataType::Boolean,
+                    FieldType::String => DataType::Utf8,
+                };
+                Field::new(column.name, data_type, true)
+            });
+            let schema = Arc::new(Schema::new(std::iter::once(time).chain(fields).collect::<Vec<_>>()));
+            let writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), None)?;
+            Ok(ParquetWriter { writer, schema })
+        }
+
+        pub fn write(&mut self, columns: &[Column], rows: &[Row]) -> Result<(), Box<dyn std::error::Error>> {
+            if rows.is_empty() {
+                return Ok(());
+            }
+            let time = rows.iter().map(|row| row.time.timestamp_nanos_opt().unwrap_or_default()).collect::<Vec<_>>();
+            let mut arrays: Vec<ArrayRef> = vec![Arc::new(TimestampNanosecondArray::from(time).with_timezone("UTC"))];
+            for column in columns {
+                let values = rows.iter().map(|row| row.values.get(column.name));
+                let array: ArrayRef = match column.kind {
+                    FieldType::Float => Arc::new(Float64Array::from_iter(values.map(|value| match value {
+                        Some(Value::Double(value)) => Some(value.into_inner()),
+                        Some(Value::Long(value)) => Some(*value as f64),
+                        _ => None,
+                    }))),
+                    FieldType::Integer => Arc::new(Int64Array::from_iter(values.map(|value| match value {
+                        Some(Value::Long(value)) => Some(*value),
+                        Some(Value::UnsignedLong(value)) => i64::try_from(*value).ok(),
+                        _ => None,
+                    }))),
+                    FieldType::Boolean => Arc::new(BooleanArray::from_iter(values.map(|value| match value {
+                        Some(Value::Bool(value)) => Some(*value),
+                        _ => None,
+                    }))),
+                    FieldType::String => Arc::new(StringArray::from_iter(values.map(|value| match value {
+                        Some(Value::String(value)) => Some(value.clone()),
+                        _ => None,
+                    }))),
+                };
+                arrays.push(array);
+            }
+            self.writer.write(&RecordBatch::try_new(self.schema.clone(), arrays)?)?;
+            Ok(())
+        }
+
+        pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+}
+
+/// Start of a day in the time zone of the site.
+fn start_of_day(date: NaiveDate) -> Result<DateTime<Utc>, ExportError> {
+    crate::timezone::midnight(date)
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| ExportError::InvalidDate(date.to_string()))
+}
+
+fn parse_date(text: &str) -> Result<NaiveDate, ExportError> {
+    NaiveDate::parse_from_str(text, "%Y-%m-%d").map_err(|_| ExportError::InvalidDate(text.to_owned()))
+}
+
+/// Runs the `export` command:
+/// `export --from <date> --to <date> [--measurements <a,b>] [--format csv|parquet] [--output <dir>]`
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    crate::timezone::init_from_env()?;
+    let mut from = None;
+    let mut to = None;
+    let mut measurements: Vec<&Measurement> = schema::MEASUREMENTS.iter().collect();
+    let mut format = Format::Csv;
+    let mut output = PathBuf::from(".");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--from" => from = Some(parse_date(value()?)?),
+            "--to" => to = Some(parse_date(value()?)?),
+            "--measurements" => {
+                measurements = value()?
+                    .split(',')
+                    .map(|name| schema::measurement(name.trim()).ok_or_else(|| ExportError::UnknownMeasurement(name.to_owned())))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--format" => format = value()?.parse()?,
+            "--output" => output = PathBuf::from(value()?),
+            _ => return Err(format!("unknown argument {arg:?}").into()),
+        }
+    }
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err("usage: export --from <date> --to <date> [--measurements <a,b>] [--format csv|parquet] [--output <dir>]".into());
+    };
+
+    let sink = InfluxSink::from_env(false)?;
+    std::fs::create_dir_all(&output)?;
+    for measurement in measurements {
+        let Some(bucket) = sink.bucket(measurement.name()) else {
+            info!("Skipping {}, it is not written to InfluxDB (ROUTES)", measurement.name());
+            continue;
+        };
+        let columns = columns(measurement);
+        let path = output.join(format!("{}.{}", measurement.name(), format.extension()));
+        let mut writer = Writer::create(format, &path, &columns)?;
+        let mut count = 0;
+        // one query per day, so long ranges don't have to fit into memory
+        let mut date = from;
+        while date <= to {
+            let next = date + Duration::days(1);
+            let rows = query(&sink, bucket, measurement.name(), start_of_day(date)?, start_of_day(next)?)?;
+            writer.write(&columns, &rows)?;
+            count += rows.len();
+            date = next;
+        }
+        writer.finish()?;
+        info!("Exported {count} rows of {} to {:?}", measurement.name(), path);
+    }
+    Ok(())
+}
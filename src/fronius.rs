@@ -1,62 +1,466 @@
-use reqwest::{blocking::Client, Url};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(feature = "async")]
+use reqwest::Client;
+#[cfg(any(feature = "async", feature = "blocking"))]
+use reqwest::{
+    header::{HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    Url,
+};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+#[cfg(any(feature = "async", feature = "blocking"))]
+use serde_json::value::RawValue;
 use serde_repr::{Deserialize_repr, Serialize_repr};
-use std::{borrow::Borrow, collections::HashMap, net::IpAddr};
+use std::{collections::HashMap, hash::Hash};
+#[cfg(any(feature = "async", feature = "blocking"))]
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    hash::{DefaultHasher, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 use time::OffsetDateTime;
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+// the tests cover the parsing of the clients as well
+#[cfg(all(test, any(feature = "async", feature = "blocking")))]
+mod tests;
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("unsupported API version {0}")]
     UnsupportedApiVersion(u64),
+    #[error("invalid host {0:?}")]
+    InvalidHost(String),
     #[error("invalid endpoint {0:?}")]
     InvalidEndpoint(String),
     #[error("request failed")]
     Request(#[from] reqwest::Error),
+    #[error("received 304 Not Modified without a cached response")]
+    UnexpectedNotModified,
+    #[error("invalid archive query: {0}")]
+    InvalidArchiveQuery(&'static str),
     #[error("decoding response body failed")]
     Decode(#[from] serde_json::Error),
     #[error("received error response {:?}: {}", .0.code, .0.reason)]
     Response(Status),
+    #[error("device is offline (standby or night mode): {}", .0.reason)]
+    DeviceOffline(Status),
+    #[error("deadline exceeded before the request")]
+    DeadlineExceeded,
 }
 
-pub struct Fronius {
-    client: Client,
-    base_url: Url,
+impl Error {
+    /// A short name of the kind of the error, e.g. for structured logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::UnsupportedApiVersion(_) => "unsupported_api_version",
+            Error::InvalidHost(_) | Error::InvalidEndpoint(_) | Error::InvalidArchiveQuery(_) => "invalid_request",
+            Error::Request(error) if error.is_timeout() => "timeout",
+            Error::DeadlineExceeded => "deadline_exceeded",
+            Error::Request(error) if error.is_connect() => "connect",
+            Error::Request(_) => "request",
+            Error::UnexpectedNotModified => "unexpected_not_modified",
+            Error::Decode(_) => "decode",
+            Error::Response(_) => "error_response",
+            Error::DeviceOffline(_) => "device_offline",
+        }
+    }
 }
 
-impl Fronius {
-    pub fn connect(ip: IpAddr) -> Result<Self, Error> {
-        let client = Client::new();
+/// Sent as `User-Agent` unless the builder sets another one.
+#[cfg(any(feature = "async", feature = "blocking"))]
+const DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+/// Longest part of a response body that is logged.
+#[cfg(any(feature = "async", feature = "blocking"))]
+const MAX_LOGGED_BODY: usize = 2048;
+
+/// Settings shared by the async and the blocking builder.
+#[cfg(any(feature = "async", feature = "blocking"))]
+#[derive(Debug, Clone)]
+struct Config {
+    host: String,
+    https: bool,
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    api_version: Option<u64>,
+    cached_endpoints: HashSet<String>,
+    user_agent: Option<String>,
+}
+
+#[cfg(any(feature = "async", feature = "blocking"))]
+impl Config {
+    fn new(host: String) -> Self {
+        Config {
+            host,
+            https: false,
+            connect_timeout: None,
+            timeout: None,
+            api_version: None,
+            cached_endpoints: HashSet::new(),
+            user_agent: None,
+        }
+    }
 
-        let mut url = reqwest::Url::parse("http://placeholder.local/solar_api/GetAPIVersion.cgi")
-            .expect("Initial base URL should be valid");
-        url.set_ip_host(ip)
-            .expect("Base URL should be a valid base");
-        let api_version: ApiVersion = client.get(url.clone()).send()?.json()?;
+    fn user_agent(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT)
+    }
 
-        if api_version.api_version != 1 {
-            return Err(Error::UnsupportedApiVersion(api_version.api_version));
+    /// URL of `GetAPIVersion.cgi` on the configured host.
+    fn api_version_url(&self) -> Result<Url, Error> {
+        let scheme = if self.https { "https" } else { "http" };
+        let invalid_host = || Error::InvalidHost(self.host.clone());
+        let mut url = Url::parse(&format!("{scheme}://placeholder.local{API_VERSION_PATH}")).map_err(|_e| invalid_host())?;
+        // an IPv6 address with a port has to be in brackets, e.g. `[::1]:8080`
+        let (host, port) = match self.host.parse::<SocketAddr>() {
+            Ok(addr) => (addr.ip().to_string(), Some(addr.port())),
+            Err(_) => match self.host.rsplit_once(':') {
+                Some((host, port)) if !host.contains(':') => (host.to_owned(), Some(port.parse().map_err(|_e| invalid_host())?)),
+                _ => (self.host.clone(), None),
+            },
+        };
+        match host.parse::<IpAddr>() {
+            Ok(ip) => url.set_ip_host(ip).map_err(|_e| invalid_host())?,
+            Err(_) => url.set_host(Some(&host)).map_err(|_e| invalid_host())?,
         }
+        url.set_port(port).map_err(|_e| invalid_host())?;
+        Ok(url)
+    }
 
-        url.set_path(&api_version.base_url);
+    /// Base URL for the given API version, `None` if the version has to be
+    /// requested from the device.
+    fn base_url(&self, url: &Url) -> Result<Option<Url>, Error> {
+        match self.api_version {
+            Some(1) => Ok(Some(with_path(url, "/solar_api/v1/"))),
+            Some(api_version) => Err(Error::UnsupportedApiVersion(api_version)),
+            None => Ok(None),
+        }
+    }
+}
 
-        Ok(Self {
-            client,
-            base_url: url,
-        })
+#[cfg(any(feature = "async", feature = "blocking"))]
+const API_VERSION_PATH: &str = "/solar_api/GetAPIVersion.cgi";
+
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn with_path(url: &Url, path: &str) -> Url {
+    let mut url = url.clone();
+    url.set_path(path);
+    url
+}
+
+/// Base URL from the answer of `GetAPIVersion.cgi`.
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn discovered_base_url(url: &Url, api_version: ApiVersion) -> Result<Url, Error> {
+    if api_version.api_version != 1 {
+        return Err(Error::UnsupportedApiVersion(api_version.api_version));
+    }
+    Ok(with_path(url, &api_version.base_url))
+}
+
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn endpoint_url<I, K, V>(base_url: &Url, endpoint: &str, params: I) -> Result<Url, Error>
+where
+    I: IntoIterator,
+    I::Item: Borrow<(K, V)>,
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut url = base_url
+        .join(endpoint)
+        .map_err(|_e| Error::InvalidEndpoint(endpoint.to_string()))?;
+    url.query_pairs_mut().extend_pairs(params);
+    Ok(url)
+}
+
+/// Logs a response at `debug` level and its body, truncated, at `trace`
+/// level, e.g. to report a quirk of a firmware to the vendor.
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn log_response(url: &Url, status: reqwest::StatusCode, start: Instant, body: Option<&[u8]>) {
+    log::debug!("GET {url}: {status} after {:?}", start.elapsed());
+    if let Some(body) = body.filter(|_| log::log_enabled!(log::Level::Trace)) {
+        let text = String::from_utf8_lossy(&body[..body.len().min(MAX_LOGGED_BODY)]);
+        let ellipsis = if body.len() > MAX_LOGGED_BODY { "..." } else { "" };
+        log::trace!("Body of {url} ({} bytes): {text}{ellipsis}", body.len());
+    }
+}
+
+/// Parses a response of the Solar API, e.g. one saved to a file, into the
+/// type of its body. The head is checked first, as the body of an error
+/// response doesn't match `T`. The body is then parsed straight into `T`,
+/// without an intermediate `serde_json::Value`.
+pub fn parse_response<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    let response: FroniusResponse<IgnoredAny> = serde_json::from_slice(bytes)?;
+    response_body(response)?;
+    let response: ResponseBody<T> = serde_json::from_slice(bytes)?;
+    Ok(response.body)
+}
+
+/// A response without its head, which is skipped.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ResponseBody<T> {
+    body: T,
+}
+
+fn response_body<T>(response: FroniusResponse<T>) -> Result<T, Error> {
+    match response.head.status.code {
+        StatusCode::Okay => Ok(response.body),
+        StatusCode::DeviceNotAvailable => Err(Error::DeviceOffline(response.head.status)),
+        _ => Err(Error::Response(response.head.status)),
     }
+}
+
+/// A sleeping inverter does not answer the Datamanager at all.
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn inverter_offline(error: Error) -> Error {
+    match error {
+        Error::Response(status) if status.code == StatusCode::LNRequestTimeout => {
+            Error::DeviceOffline(status)
+        }
+        error => error,
+    }
+}
+
+/// The body of a response, unparsed, and its hash. The head isn't hashed:
+/// its timestamp changes with every response, even if the data doesn't.
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn raw_body(bytes: &[u8]) -> Result<(&RawValue, u64), Error> {
+    let body = response_body(serde_json::from_slice::<FroniusResponse<&RawValue>>(bytes)?)?;
+    let mut hasher = DefaultHasher::new();
+    body.get().hash(&mut hasher);
+    Ok((body, hasher.finish()))
+}
+
+/// Last response of a cached endpoint.
+#[cfg(any(feature = "async", feature = "blocking"))]
+struct CachedResponse {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    hash: u64,
+    body: serde_json::Value,
+}
 
-    fn make_request_inner(&self, url: Url) -> Result<serde_json::Value, Error> {
-        let response: FroniusResponse<serde_json::Value> = self.client.get(url).send()?.json()?;
+/// Responses of the endpoints given by `FroniusBuilder::cache`, by URL.
+///
+/// Requests of these endpoints are sent with `If-None-Match` and
+/// `If-Modified-Since` if the Datamanager sent an `ETag` or `Last-Modified`
+/// before. If it answers with 304 Not Modified or with the same body again
+/// (compared by hash, without the head), the body of the last response is
+/// used without parsing it again.
+#[cfg(any(feature = "async", feature = "blocking"))]
+struct ResponseCache {
+    endpoints: HashSet<String>,
+    responses: Mutex<HashMap<Url, CachedResponse>>,
+}
 
-        if response.head.status.code != StatusCode::Okay {
-            return Err(Error::Response(response.head.status));
+#[cfg(any(feature = "async", feature = "blocking"))]
+impl ResponseCache {
+    fn new(endpoints: HashSet<String>) -> Self {
+        ResponseCache {
+            endpoints,
+            responses: Mutex::new(HashMap::new()),
         }
+    }
 
-        Ok(response.body)
+    fn is_cached(&self, endpoint: &str) -> bool {
+        self.endpoints.contains(endpoint)
     }
 
-    pub fn make_request<T, I, K, V>(&self, endpoint: &str, params: I) -> Result<T, Error>
+    fn responses(&self) -> std::sync::MutexGuard<'_, HashMap<Url, CachedResponse>> {
+        self.responses.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Validators of the last response of `url` for a conditional request.
+    fn conditional_headers(&self, url: &Url) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(cached) = self.responses().get(url) {
+            if let Some(etag) = &cached.etag {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+        headers
+    }
+
+    /// The body of the last response of `url`, after a 304 Not Modified.
+    fn not_modified(&self, url: &Url) -> Result<serde_json::Value, Error> {
+        self.responses()
+            .get(url)
+            .map(|cached| cached.body.clone())
+            .ok_or(Error::UnexpectedNotModified)
+    }
+
+    /// Parses the body of the response, unless it equals the last one of
+    /// `url`, and keeps it if the response isn't an error.
+    fn update(&self, url: &Url, headers: &HeaderMap, bytes: &[u8]) -> Result<serde_json::Value, Error> {
+        let (raw, hash) = raw_body(bytes)?;
+        if let Some(cached) = self.responses().get(url).filter(|cached| cached.hash == hash) {
+            return Ok(cached.body.clone());
+        }
+        let body: serde_json::Value = serde_json::from_str(raw.get())?;
+        self.responses().insert(
+            url.clone(),
+            CachedResponse {
+                etag: headers.get(ETAG).cloned(),
+                last_modified: headers.get(LAST_MODIFIED).cloned(),
+                hash,
+                body: body.clone(),
+            },
+        );
+        Ok(body)
+    }
+}
+
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn archive_params(query: &ArchiveQuery) -> Result<Vec<(&'static str, String)>, Error> {
+    let format = |date: OffsetDateTime| {
+        date.format(&time::format_description::well_known::Rfc3339)
+            .map_err(|_e| Error::InvalidArchiveQuery("date out of range"))
+    };
+    if query.channels.is_empty() {
+        return Err(Error::InvalidArchiveQuery("no channels"));
+    }
+    if query.end < query.start || query.end - query.start > ARCHIVE_MAX_RANGE {
+        return Err(Error::InvalidArchiveQuery("the range has to be at most 16 days"));
+    }
+    let series_type = if query.daily_sums { "DailySum" } else { "Detail" };
+    let mut params = vec![
+        ("Scope", "System".to_owned()),
+        ("StartDate", format(query.start)?),
+        ("EndDate", format(query.end)?),
+        ("SeriesType", series_type.to_owned()),
+        ("HumanReadable", "false".to_owned()),
+    ];
+    params.extend(query.channels.iter().map(|channel| ("Channel", channel.clone())));
+    Ok(params)
+}
+
+/// IDs of the active devices of a type, sorted.
+#[cfg(any(feature = "async", feature = "blocking"))]
+fn device_ids(mut devices: DeviceInfos, device_type: DeviceType) -> impl Iterator<Item = DeviceId> {
+    let mut ids: Vec<DeviceId> = devices
+        .remove(&device_type)
+        .unwrap_or_default()
+        .into_keys()
+        .filter_map(|id| id.parse().ok())
+        .collect();
+    ids.sort();
+    ids.into_iter()
+}
+
+/// Configures the async client before connecting, see `FroniusBuilder::new`.
+#[cfg(feature = "async")]
+#[derive(Debug, Clone)]
+pub struct FroniusBuilder {
+    config: Config,
+    client: Option<Client>,
+}
+
+#[cfg(feature = "async")]
+impl FroniusBuilder {
+    /// The `host` is an IP address or a host name, optionally with a port
+    /// like `10.0.0.1:8080`.
+    pub fn new(host: impl Into<String>) -> Self {
+        FroniusBuilder {
+            config: Config::new(host.into()),
+            client: None,
+        }
+    }
+
+    /// Uses HTTPS instead of HTTP.
+    pub fn https(mut self, https: bool) -> Self {
+        self.config.https = https;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Timeout for the whole request (until the response body has been read).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Uses the given API version instead of asking the device for it, which
+    /// saves the `GetAPIVersion.cgi` request. Only version 1 is supported.
+    pub fn api_version(mut self, api_version: u64) -> Self {
+        self.config.api_version = Some(api_version);
+        self
+    }
+
+    /// Caches the responses of `endpoint` (e.g. `GetInverterInfo.cgi`): the
+    /// request is sent conditionally and an unchanged response isn't parsed
+    /// again. Meant for slow endpoints whose data rarely changes.
+    pub fn cache(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.cached_endpoints.insert(endpoint.into());
+        self
+    }
+
+    /// Sets the `User-Agent` header, by default the crate name and version.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Uses a preconfigured client, the timeouts of the builder are ignored.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub async fn build(self) -> Result<Fronius, Error> {
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                let mut builder = Client::builder().user_agent(self.config.user_agent());
+                if let Some(connect_timeout) = self.config.connect_timeout {
+                    builder = builder.connect_timeout(connect_timeout);
+                }
+                if let Some(timeout) = self.config.timeout {
+                    builder = builder.timeout(timeout);
+                }
+                builder.build()?
+            }
+        };
+        let url = self.config.api_version_url()?;
+        let base_url = match self.config.base_url(&url)? {
+            Some(base_url) => base_url,
+            None => discovered_base_url(&url, client.get(url.clone()).send().await?.json().await?)?,
+        };
+        Ok(Fronius {
+            client,
+            base_url,
+            cache: ResponseCache::new(self.config.cached_endpoints),
+        })
+    }
+}
+
+/// Async client of the Solar API, the blocking one is `blocking::Fronius`.
+/// Needs the `async` cargo feature (enabled by default).
+#[cfg(feature = "async")]
+pub struct Fronius {
+    client: Client,
+    base_url: Url,
+    cache: ResponseCache,
+}
+
+#[cfg(feature = "async")]
+impl Fronius {
+    pub async fn connect(ip: IpAddr) -> Result<Self, Error> {
+        FroniusBuilder::new(ip.to_string()).build().await
+    }
+
+    pub async fn make_request<T, I, K, V>(&self, endpoint: &str, params: I) -> Result<T, Error>
     where
         T: DeserializeOwned,
         I: IntoIterator,
@@ -64,108 +468,166 @@ impl Fronius {
         K: AsRef<str>,
         V: AsRef<str>,
     {
-        let mut url = self
-            .base_url
-            .join(endpoint)
-            .map_err(|_e| Error::InvalidEndpoint(endpoint.to_string()))?;
-        url.query_pairs_mut().extend_pairs(params);
-        let body = self.make_request_inner(url)?;
+        let url = endpoint_url(&self.base_url, endpoint, params)?;
+        let start = Instant::now();
+        if !self.cache.is_cached(endpoint) {
+            let response = self.client.get(url.clone()).send().await?;
+            let status = response.status();
+            let bytes = response.bytes().await?;
+            log_response(&url, status, start, Some(&bytes));
+            return parse_response(&bytes);
+        }
 
+        let headers = self.cache.conditional_headers(&url);
+        let response = self.client.get(url.clone()).headers(headers).send().await?;
+        let status = response.status();
+        let body = if status == reqwest::StatusCode::NOT_MODIFIED {
+            log_response(&url, status, start, None);
+            self.cache.not_modified(&url)?
+        } else {
+            let headers = response.headers().clone();
+            let bytes = response.bytes().await?;
+            log_response(&url, status, start, Some(&bytes));
+            self.cache.update(&url, &headers, &bytes)?
+        };
         Ok(T::deserialize(body)?)
     }
 
-    pub fn get_inverter_realtime_data_device<C: DataCollection>(
+    pub async fn get_inverter_realtime_data_device<C: DataCollection>(
         &self,
         device_id: &DeviceId,
     ) -> Result<C, Error> {
         let device_id = u8::from(device_id).to_string();
 
-        let response: CommonResponseBody<_> = self.make_request(
-            "GetInverterRealtimeData.cgi",
-            [
-                ("Scope", "Device"),
-                ("DeviceId", &device_id),
-                ("DataCollection", C::param_value()),
-            ],
-        )?;
+        let response: CommonResponseBody<_> = self
+            .make_request(
+                "GetInverterRealtimeData.cgi",
+                [
+                    ("Scope", "Device"),
+                    ("DeviceId", &device_id),
+                    ("DataCollection", C::param_value()),
+                ],
+            )
+            .await
+            .map_err(inverter_offline)?;
 
         Ok(response.data)
     }
 
-    pub fn get_inverter_realtime_data_system(&self) -> Result<CumulationInverterDataSystem, Error> {
+    pub async fn get_inverter_realtime_data_system(&self) -> Result<CumulationInverterDataSystem, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetInverterRealtimeData.cgi", [("Scope", "System")])?;
+            self.make_request("GetInverterRealtimeData.cgi", [("Scope", "System")]).await?;
         Ok(response.data)
     }
 
-    pub fn get_inverter_info(&self) -> Result<InverterInfos, Error> {
+    pub async fn get_inverter_info(&self) -> Result<InverterInfos, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetInverterInfo.cgi", [] as [(&str, &str); 0])?;
+            self.make_request("GetInverterInfo.cgi", [] as [(&str, &str); 0]).await?;
         Ok(response.data)
     }
 
-    pub fn get_active_device_info(&self) -> Result<DeviceInfos, Error> {
+    pub async fn get_api_version(&self) -> Result<ApiVersion, Error> {
+        let url = with_path(&self.base_url, API_VERSION_PATH);
+        Ok(self.client.get(url).send().await?.json().await?)
+    }
+
+    pub async fn get_logger_info(&self) -> Result<LoggerInfo, Error> {
+        let response: LoggerInfoBody =
+            self.make_request("GetLoggerInfo.cgi", [] as [(&str, &str); 0]).await?;
+        Ok(response.logger_info)
+    }
+
+    pub async fn get_active_device_info(&self) -> Result<DeviceInfos, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetActiveDeviceInfo.cgi", [] as [(&str, &str); 0])?;
+            self.make_request("GetActiveDeviceInfo.cgi", [] as [(&str, &str); 0]).await?;
         Ok(response.data)
     }
 
-    pub fn get_meter_realtime_data_system(&self) -> Result<MeterDataSystem, Error> {
+    /// Returns the IDs of all devices of the given type that the Datamanager
+    /// reports as active.
+    pub async fn active_device_ids(
+        &self,
+        device_type: DeviceType,
+    ) -> Result<impl Iterator<Item = DeviceId>, Error> {
+        Ok(device_ids(self.get_active_device_info().await?, device_type))
+    }
+
+    pub async fn get_meter_realtime_data_system(&self) -> Result<MeterDataSystem, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetMeterRealtimeData.cgi", [("Scope", "System")])?;
+            self.make_request("GetMeterRealtimeData.cgi", [("Scope", "System")]).await?;
         Ok(response.data)
     }
 
-    pub fn get_meter_realtime_data_device(&self, device_id: &DeviceId) -> Result<MeterData, Error> {
+    pub async fn get_meter_realtime_data_device(&self, device_id: &DeviceId) -> Result<MeterData, Error> {
         let device_id = u8::from(device_id).to_string();
-        let response: CommonResponseBody<_> = self.make_request(
-            "GetMeterRealtimeData.cgi",
-            [("Scope", "Device"), ("DeviceId", &device_id)],
-        )?;
+        let response: CommonResponseBody<_> = self
+            .make_request(
+                "GetMeterRealtimeData.cgi",
+                [("Scope", "Device"), ("DeviceId", &device_id)],
+            )
+            .await?;
         Ok(response.data)
     }
 
-    pub fn get_storage_realtime_data_system(&self) -> Result<StorageDataSystem, Error> {
+    pub async fn get_storage_realtime_data_system(&self) -> Result<StorageDataSystem, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetStorageRealtimeData.cgi", [("Scope", "System")])?;
+            self.make_request("GetStorageRealtimeData.cgi", [("Scope", "System")]).await?;
         Ok(response.data)
     }
 
-    pub fn get_storage_realtime_data_device(
+    pub async fn get_storage_realtime_data_device(
         &self,
         device_id: &DeviceId,
     ) -> Result<StorageData, Error> {
         let device_id = u8::from(device_id).to_string();
-        let response: CommonResponseBody<_> = self.make_request(
-            "GetStorageRealtimeData.cgi",
-            [("Scope", "Device"), ("DeviceId", &device_id)],
-        )?;
+        let response: CommonResponseBody<_> = self
+            .make_request(
+                "GetStorageRealtimeData.cgi",
+                [("Scope", "Device"), ("DeviceId", &device_id)],
+            )
+            .await?;
         Ok(response.data)
     }
 
-    pub fn get_ohm_pilot_realtime_data_system(&self) -> Result<OhmPilotDataSystem, Error> {
+    pub async fn get_ohm_pilot_realtime_data_system(&self) -> Result<OhmPilotDataSystem, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetOhmPilotRealtimeData.cgi", [("Scope", "System")])?;
+            self.make_request("GetOhmPilotRealtimeData.cgi", [("Scope", "System")]).await?;
         Ok(response.data)
     }
 
-    pub fn get_ohm_pilot_realtime_data_device(
+    pub async fn get_ohm_pilot_realtime_data_device(
         &self,
         device_id: &DeviceId,
     ) -> Result<OhmPilotData, Error> {
         let device_id = u8::from(device_id).to_string();
-        let response: CommonResponseBody<_> = self.make_request(
-            "GetOhmPilotRealtimeData.cgi",
-            [("Scope", "Device"), ("DeviceId", &device_id)],
-        )?;
+        let response: CommonResponseBody<_> = self
+            .make_request(
+                "GetOhmPilotRealtimeData.cgi",
+                [("Scope", "Device"), ("DeviceId", &device_id)],
+            )
+            .await?;
         Ok(response.data)
     }
 
-    pub fn get_power_flow_realtime_data(&self) -> Result<PowerFlowData, Error> {
+    pub async fn get_power_flow_realtime_data(&self) -> Result<PowerFlowData, Error> {
         let response: CommonResponseBody<_> =
-            self.make_request("GetPowerFlowRealtimeData.fcgi", [] as [(&str, &str); 0])?;
+            self.make_request("GetPowerFlowRealtimeData.fcgi", [] as [(&str, &str); 0]).await?;
         Ok(response.data)
     }
+
+    /// Returns the archived values of the channels of all devices. The
+    /// response, which is several megabytes for long ranges, is decoded into
+    /// `ArchiveData` without an intermediate JSON tree.
+    pub async fn get_archive_data(&self, query: &ArchiveQuery) -> Result<ArchiveData, Error> {
+        let url = endpoint_url(&self.base_url, "GetArchiveData.cgi", archive_params(query)?)?;
+        let start = Instant::now();
+        let response = self.client.get(url.clone()).send().await?;
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        log_response(&url, status, start, Some(&bytes));
+        let response: FroniusResponse<ArchiveBody> = serde_json::from_slice(&bytes)?;
+        Ok(response_body(response)?.data)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -202,10 +664,16 @@ pub struct Status {
     user_message: String,
 }
 
+impl Status {
+    pub fn code(&self) -> StatusCode {
+        self.code
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct CommonResponseHeader {
-    request_arguments: HashMap<String, String>,
+    request_arguments: HashMap<String, serde_json::Value>,
     status: Status,
     #[serde(with = "time::serde::rfc3339")]
     timestamp: OffsetDateTime,
@@ -249,22 +717,86 @@ pub struct CommonResponseBody<T> {
     data: T,
 }
 
+/// Answer of `GetAPIVersion.cgi`, which every firmware generation has. The
+/// compatibility range (e.g. `1.5-18`) changes with the firmware.
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
-struct ApiVersion {
+pub struct ApiVersion {
     #[serde(rename = "APIVersion")]
-    api_version: u64,
+    pub api_version: u64,
     #[serde(rename = "BaseURL")]
-    base_url: String,
-    compatibility_range: String,
+    pub base_url: String,
+    pub compatibility_range: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct DeviceId(u8);
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
 #[error("invalid device ID, must be less than 100: {0}")]
 pub struct InvalidDeviceId(u8);
 
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseDeviceIdError {
+    #[error("invalid device ID {0:?}")]
+    InvalidNumber(String),
+    #[error(transparent)]
+    OutOfRange(#[from] InvalidDeviceId),
+    #[error("invalid device ID range {0:?}")]
+    InvalidRange(String),
+}
+
+impl DeviceId {
+    /// The first inverter, which every system has.
+    pub const FIRST_INVERTER: DeviceId = DeviceId(1);
+    /// The first meter, storage or Ohmpilot, which are counted from 0.
+    pub const FIRST_COMPONENT: DeviceId = DeviceId(0);
+
+    /// Returns all device IDs from `start` up to and including `end`.
+    pub fn range(start: DeviceId, end: DeviceId) -> impl Iterator<Item = DeviceId> {
+        (start.0..=end.0).map(DeviceId)
+    }
+
+    /// Parses a comma separated list of device IDs and inclusive ranges,
+    /// e.g. `"1-3"` or `"0,2,5-6"`.
+    pub fn parse_list(list: &str) -> Result<Vec<DeviceId>, ParseDeviceIdError> {
+        let mut ids = Vec::new();
+        for part in list.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let start: DeviceId = start.trim().parse()?;
+                    let end: DeviceId = end.trim().parse()?;
+                    if start > end {
+                        return Err(ParseDeviceIdError::InvalidRange(part.to_string()));
+                    }
+                    ids.extend(DeviceId::range(start, end));
+                }
+                None => ids.push(part.parse()?),
+            }
+        }
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+}
+
+impl std::str::FromStr for DeviceId {
+    type Err = ParseDeviceIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let device_id: u8 = s
+            .parse()
+            .map_err(|_e| ParseDeviceIdError::InvalidNumber(s.to_string()))?;
+        Ok(DeviceId::try_from(device_id)?)
+    }
+}
+
+impl std::fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl TryFrom<u8> for DeviceId {
     type Error = InvalidDeviceId;
 
@@ -321,19 +853,52 @@ pub struct CommonInverterData {
     pub uac: UnitAndValue<f64>,
     pub fac: Option<UnitAndValue<f64>>,
     pub idc: UnitAndValue<f64>,
-    pub idc_2: UnitAndValue<f64>,
-    pub idc_3: UnitAndValue<f64>,
-    pub idc_4: UnitAndValue<f64>,
+    pub idc_2: Option<UnitAndValue<f64>>,
+    pub idc_3: Option<UnitAndValue<f64>>,
+    pub idc_4: Option<UnitAndValue<f64>>,
     pub udc: UnitAndValue<f64>,
-    pub udc_2: UnitAndValue<f64>,
-    pub udc_3: UnitAndValue<f64>,
-    pub udc_4: UnitAndValue<f64>,
+    pub udc_2: Option<UnitAndValue<f64>>,
+    pub udc_3: Option<UnitAndValue<f64>>,
+    pub udc_4: Option<UnitAndValue<f64>>,
     pub day_energy: UnitAndValue<f64>,
     pub year_energy: UnitAndValue<f64>,
     pub total_energy: UnitAndValue<f64>,
+    #[serde(rename = "DeviceStatus")]
     pub device_status: DeviceStatus,
 }
 
+/// DC current and voltage of one MPPT tracker.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpptChannel {
+    pub tracker: u8,
+    pub current: Option<f64>,
+    pub voltage: Option<f64>,
+}
+
+impl CommonInverterData {
+    /// Returns the DC values of every MPPT tracker the inverter reports,
+    /// starting with tracker 1.
+    pub fn mppt_channels(&self) -> Vec<MpptChannel> {
+        let value = |v: &Option<UnitAndValue<f64>>| v.as_ref().and_then(|v| v.value);
+        let mut channels = vec![MpptChannel {
+            tracker: 1,
+            current: self.idc.value,
+            voltage: self.udc.value,
+        }];
+        let others = [(&self.idc_2, &self.udc_2), (&self.idc_3, &self.udc_3), (&self.idc_4, &self.udc_4)];
+        for (tracker, (current, voltage)) in (2..).zip(others) {
+            if current.is_some() || voltage.is_some() {
+                channels.push(MpptChannel {
+                    tracker,
+                    current: value(current),
+                    voltage: value(voltage),
+                });
+            }
+        }
+        channels
+    }
+}
+
 pub type ThreePInverterData = ThreePhaseInverterData;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -345,6 +910,12 @@ pub struct ThreePhaseInverterData {
     pub uac_l1: UnitAndValue<f64>,
     pub uac_l2: UnitAndValue<f64>,
     pub uac_l3: UnitAndValue<f64>,
+    pub pac_l1: Option<UnitAndValue<f64>>,
+    pub pac_l2: Option<UnitAndValue<f64>>,
+    pub pac_l3: Option<UnitAndValue<f64>>,
+    pub sac_l1: Option<UnitAndValue<f64>>,
+    pub sac_l2: Option<UnitAndValue<f64>>,
+    pub sac_l3: Option<UnitAndValue<f64>>,
     pub t_ambient: Option<UnitAndValue<f64>>,
     pub rotation_speed_fan_fl: Option<UnitAndValue<f64>>,
     pub rotation_speed_fan_fr: Option<UnitAndValue<f64>>,
@@ -418,6 +989,34 @@ impl std::fmt::Display for InverterStatusCode {
     }
 }
 
+#[cfg(any(feature = "async", feature = "blocking"))]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct LoggerInfoBody {
+    logger_info: LoggerInfo,
+}
+
+/// Information about the Datamanager, from `GetLoggerInfo.cgi`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LoggerInfo {
+    #[serde(rename = "UniqueID")]
+    pub unique_id: String,
+    #[serde(rename = "ProductID")]
+    pub product_id: Option<String>,
+    #[serde(rename = "HWVersion")]
+    pub hw_version: Option<String>,
+    #[serde(rename = "SWVersion")]
+    pub sw_version: Option<String>,
+    /// Location of the configured time zone, e.g. `Vienna`.
+    pub timezone_location: String,
+    /// Abbreviation of the time zone, e.g. `CEST`.
+    pub timezone_name: String,
+    /// Current offset of the time zone to UTC in seconds, including DST.
+    #[serde(rename = "UTCOffset")]
+    pub utc_offset: i32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum DeviceType {
     Inverter,
@@ -435,8 +1034,8 @@ pub type DeviceInfos = HashMap<DeviceType, HashMap<String, Option<DeviceInfo>>>;
 #[serde(rename_all = "PascalCase")]
 pub struct DeviceInfo {
     #[serde(rename = "DT")]
-    dt: i64,
-    serial: String,
+    pub dt: i64,
+    pub serial: String,
 }
 
 pub type MeterDataSystem = HashMap<String, MeterData>;
@@ -540,9 +1139,9 @@ pub struct MeterData {
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct DeviceDetails {
-    manufacturer: String,
-    model: String,
-    serial: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub serial: String,
 }
 
 pub type StorageDataSystem = HashMap<String, StorageData>;
@@ -571,6 +1170,14 @@ pub struct StorageController {
     pub voltage_dc: f64,
     #[serde(rename = "Temperature_Cell")]
     pub temperature_cell: f64,
+    #[serde(rename = "DesignedCapacity")]
+    pub designed_capacity: Option<f64>,
+    #[serde(rename = "Voltage_DC_Maximum_Cell")]
+    pub voltage_dc_maximum_cell: Option<f64>,
+    #[serde(rename = "Voltage_DC_Minimum_Cell")]
+    pub voltage_dc_minimum_cell: Option<f64>,
+    #[serde(rename = "Status_BatteryCell")]
+    pub status_battery_cell: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -628,6 +1235,18 @@ pub struct OhmPilotData {
     pub code_of_error: Option<i64>,
     #[serde(rename = "PowerReal_PAC_Sum")]
     pub power_real_pac_sum: f64,
+    #[serde(rename = "PowerReal_PAC_Phase_1")]
+    pub power_real_pac_phase_1: Option<f64>,
+    #[serde(rename = "PowerReal_PAC_Phase_2")]
+    pub power_real_pac_phase_2: Option<f64>,
+    #[serde(rename = "PowerReal_PAC_Phase_3")]
+    pub power_real_pac_phase_3: Option<f64>,
+    #[serde(rename = "Voltage_AC_Phase_1")]
+    pub voltage_ac_phase_1: Option<f64>,
+    #[serde(rename = "Voltage_AC_Phase_2")]
+    pub voltage_ac_phase_2: Option<f64>,
+    #[serde(rename = "Voltage_AC_Phase_3")]
+    pub voltage_ac_phase_3: Option<f64>,
     #[serde(rename = "Temperature_Channel_1")]
     pub temperature_channel_1: f64,
 }
@@ -731,3 +1350,89 @@ pub struct PowerFlowSecondaryMeters {
     pub label: String,
     pub category: String,
 }
+
+/// Longest range of one `GetArchiveData.cgi` request.
+pub const ARCHIVE_MAX_RANGE: time::Duration = time::Duration::days(16);
+
+/// Request of archived values, see `Fronius::get_archive_data`.
+#[derive(Debug, Clone)]
+pub struct ArchiveQuery {
+    pub start: OffsetDateTime,
+    /// At most `ARCHIVE_MAX_RANGE` after `start`.
+    pub end: OffsetDateTime,
+    /// Channels like `EnergyReal_WAC_Sum_Produced`.
+    pub channels: Vec<String>,
+    /// Requests the daily sums instead of the detailed (5 minute) values.
+    pub daily_sums: bool,
+}
+
+#[cfg(any(feature = "async", feature = "blocking"))]
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ArchiveBody {
+    // missing in error responses
+    #[serde(default)]
+    data: ArchiveData,
+}
+
+/// Archived values by device (e.g. `inverter/1`, `meter:16250020`).
+pub type ArchiveData = HashMap<String, ArchiveDevice>;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchiveDevice {
+    #[serde(with = "time::serde::rfc3339")]
+    pub start: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub end: OffsetDateTime,
+    #[serde(default)]
+    pub data: HashMap<String, ArchiveChannel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ArchiveChannel {
+    #[serde(default)]
+    pub unit: String,
+    /// Values by seconds since the start of the device, sorted.
+    #[serde(deserialize_with = "archive_values")]
+    pub values: Vec<(u32, f64)>,
+}
+
+impl ArchiveChannel {
+    /// The values with their time, `start` is the one of the device. Values
+    /// whose time is out of range are skipped.
+    pub fn samples(&self, start: OffsetDateTime) -> impl Iterator<Item = (OffsetDateTime, f64)> + '_ {
+        self.values.iter().filter_map(move |(offset, value)| {
+            Some((start.checked_add(time::Duration::seconds(i64::from(*offset)))?, *value))
+        })
+    }
+}
+
+/// Reads the `Values` object (`{"0": 1.5, "300": 2.0}`) entry by entry into
+/// a vector, skipping `null` values.
+fn archive_values<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<(u32, f64)>, D::Error> {
+    struct Visitor;
+
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+        type Value = Vec<(u32, f64)>;
+
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("a map of seconds to values")
+        }
+
+        fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut values = Vec::with_capacity(map.size_hint().unwrap_or_default());
+            while let Some((offset, value)) = map.next_entry::<std::borrow::Cow<'de, str>, Option<f64>>()? {
+                let offset = offset.parse().map_err(serde::de::Error::custom)?;
+                if let Some(value) = value {
+                    values.push((offset, value));
+                }
+            }
+            values.sort_unstable_by_key(|(offset, _)| *offset);
+            Ok(values)
+        }
+    }
+
+    deserializer.deserialize_map(Visitor)
+}
@@ -0,0 +1,339 @@
+//! Blocking client of the Solar API, for programs without an async runtime.
+//! Needs the `blocking` cargo feature (enabled by default).
+
+use reqwest::blocking::{Client, RequestBuilder};
+use serde::de::DeserializeOwned;
+use std::{
+    borrow::Borrow,
+    io::BufReader,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use super::{
+    archive_params, device_ids, discovered_base_url, endpoint_url, inverter_offline, log_response, parse_response, response_body,
+    with_path, ApiVersion, ArchiveBody, ArchiveData, ArchiveQuery, CommonResponseBody, Config, CumulationInverterDataSystem,
+    DataCollection, DeviceId, DeviceInfos, DeviceType, Error, FroniusResponse, InverterInfos, LoggerInfo, LoggerInfoBody, MeterData,
+    MeterDataSystem, OhmPilotData, OhmPilotDataSystem, PowerFlowData, ResponseCache, StorageData, StorageDataSystem, Url,
+    API_VERSION_PATH,
+};
+
+/// Configures the blocking client before connecting, see `FroniusBuilder::new`.
+#[derive(Debug, Clone)]
+pub struct FroniusBuilder {
+    config: Config,
+    client: Option<Client>,
+    deadline: Option<Instant>,
+}
+
+impl FroniusBuilder {
+    /// The `host` is an IP address or a host name, optionally with a port
+    /// like `10.0.0.1:8080`.
+    pub fn new(host: impl Into<String>) -> Self {
+        FroniusBuilder {
+            config: Config::new(host.into()),
+            client: None,
+            deadline: None,
+        }
+    }
+
+    /// Uses HTTPS instead of HTTP.
+    pub fn https(mut self, https: bool) -> Self {
+        self.config.https = https;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Timeout for the whole request (until the response body has been read).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Uses the given API version instead of asking the device for it, which
+    /// saves the `GetAPIVersion.cgi` request. Only version 1 is supported.
+    pub fn api_version(mut self, api_version: u64) -> Self {
+        self.config.api_version = Some(api_version);
+        self
+    }
+
+    /// Caches the responses of `endpoint` (e.g. `GetInverterInfo.cgi`): the
+    /// request is sent conditionally and an unchanged response isn't parsed
+    /// again. Meant for slow endpoints whose data rarely changes.
+    pub fn cache(mut self, endpoint: impl Into<String>) -> Self {
+        self.config.cached_endpoints.insert(endpoint.into());
+        self
+    }
+
+    /// Sets the `User-Agent` header, see `FroniusBuilder::user_agent`.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Uses a preconfigured client, the timeouts of the builder are ignored.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Ends the requests at `deadline`, the request of the API version as
+    /// well, see `Fronius::set_deadline`.
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn build(self) -> Result<Fronius, Error> {
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                let mut builder = Client::builder().user_agent(self.config.user_agent());
+                if let Some(connect_timeout) = self.config.connect_timeout {
+                    builder = builder.connect_timeout(connect_timeout);
+                }
+                if let Some(timeout) = self.config.timeout {
+                    builder = builder.timeout(timeout);
+                }
+                builder.build()?
+            }
+        };
+        let url = self.config.api_version_url()?;
+        let base_url = match self.config.base_url(&url)? {
+            Some(base_url) => base_url,
+            None => {
+                let request = until_deadline(client.get(url.clone()), self.config.timeout, self.deadline)?;
+                discovered_base_url(&url, request.send()?.json()?)?
+            }
+        };
+        Ok(Fronius {
+            client,
+            base_url,
+            cache: ResponseCache::new(self.config.cached_endpoints),
+            timeout: self.config.timeout,
+            deadline: self.deadline,
+        })
+    }
+}
+
+pub struct Fronius {
+    client: Client,
+    base_url: Url,
+    cache: ResponseCache,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl Fronius {
+    pub fn connect(ip: IpAddr) -> Result<Self, Error> {
+        FroniusBuilder::new(ip.to_string()).build()
+    }
+
+    /// Connects with a connect timeout and a timeout for the whole request
+    /// (until the response body has been read).
+    pub fn connect_with_timeouts(
+        ip: IpAddr,
+        connect_timeout: Duration,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        FroniusBuilder::new(ip.to_string())
+            .connect_timeout(connect_timeout)
+            .timeout(timeout)
+            .build()
+    }
+
+    pub fn connect_with_client(ip: IpAddr, client: Client) -> Result<Self, Error> {
+        FroniusBuilder::new(ip.to_string()).client(client).build()
+    }
+
+    /// Ends the requests at `deadline`: the timeout of a request is cut to
+    /// the remaining time and once it has passed requests fail right away
+    /// with `Error::DeadlineExceeded`. `None` removes the deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// A GET request of `url`, with a timeout of at most the time left until
+    /// the deadline.
+    fn get(&self, url: Url) -> Result<RequestBuilder, Error> {
+        until_deadline(self.client.get(url), self.timeout, self.deadline)
+    }
+
+    pub fn make_request<T, I, K, V>(&self, endpoint: &str, params: I) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+        I: IntoIterator,
+        I::Item: Borrow<(K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let url = endpoint_url(&self.base_url, endpoint, params)?;
+        let start = Instant::now();
+        if !self.cache.is_cached(endpoint) {
+            let response = self.get(url.clone())?.send()?;
+            let status = response.status();
+            let bytes = response.bytes()?;
+            log_response(&url, status, start, Some(&bytes));
+            return parse_response(&bytes);
+        }
+
+        let headers = self.cache.conditional_headers(&url);
+        let response = self.get(url.clone())?.headers(headers).send()?;
+        let status = response.status();
+        let body = if status == reqwest::StatusCode::NOT_MODIFIED {
+            log_response(&url, status, start, None);
+            self.cache.not_modified(&url)?
+        } else {
+            let headers = response.headers().clone();
+            let bytes = response.bytes()?;
+            log_response(&url, status, start, Some(&bytes));
+            self.cache.update(&url, &headers, &bytes)?
+        };
+        Ok(T::deserialize(body)?)
+    }
+
+    pub fn get_inverter_realtime_data_device<C: DataCollection>(
+        &self,
+        device_id: &DeviceId,
+    ) -> Result<C, Error> {
+        let device_id = u8::from(device_id).to_string();
+
+        let response: CommonResponseBody<_> = self
+            .make_request(
+                "GetInverterRealtimeData.cgi",
+                [
+                    ("Scope", "Device"),
+                    ("DeviceId", &device_id),
+                    ("DataCollection", C::param_value()),
+                ],
+            )
+            .map_err(inverter_offline)?;
+
+        Ok(response.data)
+    }
+
+    pub fn get_inverter_realtime_data_system(&self) -> Result<CumulationInverterDataSystem, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetInverterRealtimeData.cgi", [("Scope", "System")])?;
+        Ok(response.data)
+    }
+
+    pub fn get_inverter_info(&self) -> Result<InverterInfos, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetInverterInfo.cgi", [] as [(&str, &str); 0])?;
+        Ok(response.data)
+    }
+
+    pub fn get_api_version(&self) -> Result<ApiVersion, Error> {
+        let url = with_path(&self.base_url, API_VERSION_PATH);
+        Ok(self.get(url)?.send()?.json()?)
+    }
+
+    pub fn get_logger_info(&self) -> Result<LoggerInfo, Error> {
+        let response: LoggerInfoBody =
+            self.make_request("GetLoggerInfo.cgi", [] as [(&str, &str); 0])?;
+        Ok(response.logger_info)
+    }
+
+    pub fn get_active_device_info(&self) -> Result<DeviceInfos, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetActiveDeviceInfo.cgi", [] as [(&str, &str); 0])?;
+        Ok(response.data)
+    }
+
+    /// Returns the IDs of all devices of the given type that the Datamanager
+    /// reports as active.
+    pub fn active_device_ids(
+        &self,
+        device_type: DeviceType,
+    ) -> Result<impl Iterator<Item = DeviceId>, Error> {
+        Ok(device_ids(self.get_active_device_info()?, device_type))
+    }
+
+    pub fn get_meter_realtime_data_system(&self) -> Result<MeterDataSystem, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetMeterRealtimeData.cgi", [("Scope", "System")])?;
+        Ok(response.data)
+    }
+
+    pub fn get_meter_realtime_data_device(&self, device_id: &DeviceId) -> Result<MeterData, Error> {
+        let device_id = u8::from(device_id).to_string();
+        let response: CommonResponseBody<_> = self.make_request(
+            "GetMeterRealtimeData.cgi",
+            [("Scope", "Device"), ("DeviceId", &device_id)],
+        )?;
+        Ok(response.data)
+    }
+
+    pub fn get_storage_realtime_data_system(&self) -> Result<StorageDataSystem, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetStorageRealtimeData.cgi", [("Scope", "System")])?;
+        Ok(response.data)
+    }
+
+    pub fn get_storage_realtime_data_device(
+        &self,
+        device_id: &DeviceId,
+    ) -> Result<StorageData, Error> {
+        let device_id = u8::from(device_id).to_string();
+        let response: CommonResponseBody<_> = self.make_request(
+            "GetStorageRealtimeData.cgi",
+            [("Scope", "Device"), ("DeviceId", &device_id)],
+        )?;
+        Ok(response.data)
+    }
+
+    pub fn get_ohm_pilot_realtime_data_system(&self) -> Result<OhmPilotDataSystem, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetOhmPilotRealtimeData.cgi", [("Scope", "System")])?;
+        Ok(response.data)
+    }
+
+    pub fn get_ohm_pilot_realtime_data_device(
+        &self,
+        device_id: &DeviceId,
+    ) -> Result<OhmPilotData, Error> {
+        let device_id = u8::from(device_id).to_string();
+        let response: CommonResponseBody<_> = self.make_request(
+            "GetOhmPilotRealtimeData.cgi",
+            [("Scope", "Device"), ("DeviceId", &device_id)],
+        )?;
+        Ok(response.data)
+    }
+
+    pub fn get_power_flow_realtime_data(&self) -> Result<PowerFlowData, Error> {
+        let response: CommonResponseBody<_> =
+            self.make_request("GetPowerFlowRealtimeData.fcgi", [] as [(&str, &str); 0])?;
+        Ok(response.data)
+    }
+
+    /// Returns the archived values of the channels of all devices. The
+    /// response, which is several megabytes for long ranges, is decoded
+    /// while it is read, without holding its text or a JSON tree.
+    pub fn get_archive_data(&self, query: &ArchiveQuery) -> Result<ArchiveData, Error> {
+        let url = endpoint_url(&self.base_url, "GetArchiveData.cgi", archive_params(query)?)?;
+        let start = Instant::now();
+        let response = self.get(url.clone())?.send()?;
+        // the body is decoded while it is read, so it isn't logged
+        log_response(&url, response.status(), start, None);
+        let response: FroniusResponse<ArchiveBody> = serde_json::from_reader(BufReader::new(response))?;
+        Ok(response_body(response)?.data)
+    }
+}
+
+/// Cuts the `timeout` of `request` to the time left until `deadline`, an
+/// error once it has passed.
+fn until_deadline(request: RequestBuilder, timeout: Option<Duration>, deadline: Option<Instant>) -> Result<RequestBuilder, Error> {
+    let Some(deadline) = deadline else {
+        return Ok(request);
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(Error::DeadlineExceeded);
+    }
+    Ok(request.timeout(timeout.map_or(remaining, |timeout| timeout.min(remaining))))
+}
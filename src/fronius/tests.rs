@@ -0,0 +1,198 @@
+//! Parsing of the example responses in `tests/fixtures`, one directory per
+//! firmware generation.
+//!
+//! Every fixture is parsed like `Fronius::make_request` does it and the
+//! result is compared with its snapshot in `tests/snapshots`: the parsed
+//! struct serialized as JSON. A change of a model that changes what is parsed
+//! from any firmware fails here. If the change is intended, run the tests
+//! with `UPDATE_SNAPSHOTS=1` and review the changed snapshots.
+
+use std::path::{Path, PathBuf};
+
+use super::*;
+
+mod mutations;
+
+const GENERATIONS: &[&str] = &["datamanager-3", "gen24-1"];
+
+fn tests_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests")
+}
+
+fn fixture(generation: &str, name: &str) -> Option<Vec<u8>> {
+    std::fs::read(tests_dir().join("fixtures").join(generation).join(format!("{name}.json"))).ok()
+}
+
+/// Parses a response like `Fronius::make_request`.
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    parse_response(body)
+}
+
+/// Sorts the keys of all objects, so the snapshots don't depend on the
+/// iteration order of the `HashMap`s.
+fn sorted(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            serde_json::Value::Object(entries.into_iter().map(|(key, value)| (key, sorted(value))).collect())
+        }
+        serde_json::Value::Array(values) => serde_json::Value::Array(values.into_iter().map(sorted).collect()),
+        value => value,
+    }
+}
+
+fn assert_snapshot(generation: &str, name: &str, parsed: &impl Serialize) {
+    let value = sorted(serde_json::to_value(parsed).expect("parsed responses can be serialized"));
+    let actual = serde_json::to_string_pretty(&value).expect("values can be serialized") + "\n";
+    let path = tests_dir().join("snapshots").join(generation).join(format!("{name}.json"));
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::create_dir_all(path.parent().expect("snapshots are in a directory")).expect("snapshot directory can be created");
+        std::fs::write(&path, actual).expect("snapshot can be written");
+        return;
+    }
+    let expected = std::fs::read_to_string(&path).unwrap_or_else(|_| panic!("no snapshot {path:?}, run the tests with UPDATE_SNAPSHOTS=1"));
+    assert_eq!(expected, actual, "{generation}/{name} is parsed differently than in its snapshot");
+}
+
+/// Parses the fixture `name` of every generation that has one, compares it
+/// with its snapshot and returns the generations.
+fn check<T: DeserializeOwned + Serialize>(name: &str) -> Vec<&'static str> {
+    let mut checked = Vec::new();
+    for generation in GENERATIONS {
+        let Some(body) = fixture(generation, name) else {
+            continue;
+        };
+        let parsed: T = parse(&body).unwrap_or_else(|error| panic!("{generation}/{name} can't be parsed: {error:?}"));
+        assert_snapshot(generation, name, &parsed);
+        checked.push(*generation);
+    }
+    checked
+}
+
+/// Parses the fixture `name` of `generation`, which is an error response.
+fn error(generation: &str, name: &str) -> Error {
+    let body = fixture(generation, name).unwrap_or_else(|| panic!("no fixture {generation}/{name}"));
+    match parse::<serde_json::Value>(&body) {
+        Ok(_) => panic!("{generation}/{name} is no error response"),
+        Err(error) => error,
+    }
+}
+
+#[test]
+fn api_version() {
+    for generation in GENERATIONS {
+        let body = fixture(generation, "GetAPIVersion").expect("every generation has an API version");
+        let api_version: ApiVersion = serde_json::from_slice(&body).expect("API version can be parsed");
+        let url = Url::parse("http://192.168.1.10/solar_api/GetAPIVersion.cgi").expect("URL is valid");
+        let base_url = discovered_base_url(&url, api_version).expect("API version 1 is supported");
+        assert_eq!(base_url.as_str(), "http://192.168.1.10/solar_api/v1/");
+    }
+}
+
+#[test]
+fn common_inverter_data() {
+    assert_eq!(check::<CommonResponseBody<CommonInverterData>>("GetInverterRealtimeData.CommonInverterData"), GENERATIONS);
+}
+
+#[test]
+fn mppt_channels() {
+    let body = fixture("datamanager-3", "GetInverterRealtimeData.CommonInverterData").expect("fixture exists");
+    let data: CommonResponseBody<CommonInverterData> = parse(&body).expect("fixture can be parsed");
+    let channels = data.data.mppt_channels();
+    assert_eq!(channels.len(), 2);
+    assert_eq!(channels[1], MpptChannel { tracker: 2, current: Some(3.11), voltage: Some(598.7) });
+}
+
+#[test]
+fn three_phase_inverter_data() {
+    assert_eq!(check::<CommonResponseBody<ThreePhaseInverterData>>("GetInverterRealtimeData.3PInverterData"), GENERATIONS);
+}
+
+#[test]
+fn cumulation_inverter_data() {
+    assert_eq!(check::<CommonResponseBody<CumulationInverterData>>("GetInverterRealtimeData.CumulationInverterData"), ["datamanager-3"]);
+    assert_eq!(check::<CommonResponseBody<CumulationInverterDataSystem>>("GetInverterRealtimeData.System"), ["datamanager-3"]);
+}
+
+#[test]
+fn sleeping_inverter() {
+    let error = inverter_offline(error("datamanager-3", "GetInverterRealtimeData.Sleeping"));
+    assert!(matches!(error, Error::DeviceOffline(status) if status.code() == StatusCode::LNRequestTimeout));
+}
+
+#[test]
+fn inverter_info() {
+    assert_eq!(check::<CommonResponseBody<InverterInfos>>("GetInverterInfo"), GENERATIONS);
+}
+
+#[test]
+fn cache_ignores_the_timestamp() {
+    let first = fixture("datamanager-3", "GetInverterInfo").expect("fixture exists");
+    let later = fixture("datamanager-3", "GetInverterInfo.Later").expect("fixture exists");
+    let (_, hash) = raw_body(&first).expect("fixture is no error response");
+    assert_eq!(raw_body(&later).expect("fixture is no error response").1, hash, "only the timestamp differs");
+    let other = fixture("gen24-1", "GetInverterInfo").expect("fixture exists");
+    assert_ne!(raw_body(&other).expect("fixture is no error response").1, hash);
+
+    let cache = ResponseCache::new(HashSet::from(["GetInverterInfo.cgi".to_owned()]));
+    let url = Url::parse("http://192.168.1.10/solar_api/v1/GetInverterInfo.cgi").expect("URL is valid");
+    let mut headers = HeaderMap::new();
+    headers.insert(ETAG, HeaderValue::from_static("\"first\""));
+    let body = cache.update(&url, &headers, &first).expect("fixture is no error response");
+    assert_eq!(cache.update(&url, &HeaderMap::new(), &later).expect("fixture is no error response"), body);
+    // the cached response is kept, not replaced by the later one
+    assert_eq!(cache.conditional_headers(&url).get(IF_NONE_MATCH), Some(&HeaderValue::from_static("\"first\"")));
+}
+
+#[test]
+fn logger_info() {
+    assert_eq!(check::<LoggerInfoBody>("GetLoggerInfo"), ["datamanager-3"]);
+}
+
+#[test]
+fn active_device_info() {
+    assert_eq!(check::<CommonResponseBody<DeviceInfos>>("GetActiveDeviceInfo"), GENERATIONS);
+    let body = fixture("datamanager-3", "GetActiveDeviceInfo").expect("fixture exists");
+    let infos: CommonResponseBody<DeviceInfos> = parse(&body).expect("fixture can be parsed");
+    let inverters: Vec<u8> = device_ids(infos.data, DeviceType::Inverter).map(u8::from).collect();
+    assert_eq!(inverters, [1, 2]);
+}
+
+#[test]
+fn meter_data() {
+    assert_eq!(check::<CommonResponseBody<MeterDataSystem>>("GetMeterRealtimeData.System"), GENERATIONS);
+}
+
+#[test]
+fn storage_data() {
+    assert_eq!(check::<CommonResponseBody<StorageDataSystem>>("GetStorageRealtimeData.System"), GENERATIONS);
+}
+
+#[test]
+fn ohm_pilot_data() {
+    assert_eq!(check::<CommonResponseBody<OhmPilotDataSystem>>("GetOhmPilotRealtimeData.System"), ["datamanager-3"]);
+    let error = error("gen24-1", "GetOhmPilotRealtimeData.NotSupported");
+    assert!(matches!(error, Error::Response(status) if status.code() == StatusCode::NotSupported));
+}
+
+#[test]
+fn power_flow_data() {
+    assert_eq!(check::<CommonResponseBody<PowerFlowData>>("GetPowerFlowRealtimeData"), GENERATIONS);
+}
+
+#[test]
+fn archive_data() {
+    let body = fixture("datamanager-3", "GetArchiveData").expect("fixture exists");
+    let response: FroniusResponse<ArchiveBody> = serde_json::from_slice(&body).expect("fixture can be parsed");
+    let archive = response_body(response).expect("fixture is no error response").data;
+    let inverter = &archive["inverter/1"];
+    let channel = &inverter.data["EnergyReal_WAC_Sum_Produced"];
+    assert_eq!(channel.unit, "Wh");
+    // null values are skipped
+    assert_eq!(channel.values, [(0, 0.0), (21600, 1.5), (21900, 12.25), (22500, 31.0)]);
+    let (time, value) = channel.samples(inverter.start).nth(1).expect("sample exists");
+    assert_eq!(time, inverter.start + time::Duration::hours(6));
+    assert_eq!(value, 1.5);
+    assert_eq!(archive["meter:17382050"].data["EnergyReal_WAC_Sum_Produced"].values.len(), 2);
+}
@@ -0,0 +1,170 @@
+//! Robustness of the parsing against unexpected payloads: every fixture is
+//! mutated node by node (set to `null`, to other types and to numbers at the
+//! limits, or removed) and truncated at every byte. Each variant has to be
+//! parsed or rejected with an `Error`, none may panic.
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use serde_json::{json, Value};
+
+use super::*;
+
+/// Parses a response and uses what the collector derives from it.
+type Target = fn(&[u8]) -> Result<(), Error>;
+
+const TARGETS: &[(&str, Target)] = &[
+    ("GetInverterRealtimeData.CommonInverterData", |body| {
+        parse::<CommonResponseBody<CommonInverterData>>(body).map(|data| drop(data.data.mppt_channels()))
+    }),
+    ("GetInverterRealtimeData.3PInverterData", |body| parse::<CommonResponseBody<ThreePhaseInverterData>>(body).map(drop)),
+    ("GetInverterRealtimeData.CumulationInverterData", |body| parse::<CommonResponseBody<CumulationInverterData>>(body).map(drop)),
+    ("GetInverterRealtimeData.System", |body| parse::<CommonResponseBody<CumulationInverterDataSystem>>(body).map(drop)),
+    ("GetInverterRealtimeData.Sleeping", |body| {
+        parse::<CommonResponseBody<CommonInverterData>>(body).map_err(inverter_offline).map(drop)
+    }),
+    ("GetInverterInfo", |body| parse::<CommonResponseBody<InverterInfos>>(body).map(drop)),
+    ("GetLoggerInfo", |body| parse::<LoggerInfoBody>(body).map(drop)),
+    ("GetActiveDeviceInfo", |body| {
+        let infos = parse::<CommonResponseBody<DeviceInfos>>(body)?;
+        let _ = device_ids(infos.data, DeviceType::Inverter).count();
+        Ok(())
+    }),
+    ("GetMeterRealtimeData.System", |body| parse::<CommonResponseBody<MeterDataSystem>>(body).map(drop)),
+    ("GetStorageRealtimeData.System", |body| parse::<CommonResponseBody<StorageDataSystem>>(body).map(drop)),
+    ("GetOhmPilotRealtimeData.System", |body| parse::<CommonResponseBody<OhmPilotDataSystem>>(body).map(drop)),
+    ("GetOhmPilotRealtimeData.NotSupported", |body| parse::<CommonResponseBody<OhmPilotDataSystem>>(body).map(drop)),
+    ("GetPowerFlowRealtimeData", |body| parse::<CommonResponseBody<PowerFlowData>>(body).map(drop)),
+    ("GetArchiveData", |body| {
+        let response: FroniusResponse<ArchiveBody> = serde_json::from_slice(body)?;
+        for device in response_body(response)?.data.values() {
+            for channel in device.data.values() {
+                let _ = channel.samples(device.start).count();
+            }
+        }
+        Ok(())
+    }),
+];
+
+/// Values a node is replaced with: other types and numbers at the limits of
+/// the integer and float types of the models.
+fn replacements() -> Vec<Value> {
+    vec![
+        Value::Null,
+        json!(true),
+        json!(""),
+        json!("1.5"),
+        json!("9999-12-31T23:59:59+00:00"),
+        json!([]),
+        json!({}),
+        json!(0),
+        json!(-1),
+        json!(1.5),
+        json!(256),
+        json!(u64::MAX),
+        json!(i64::MIN),
+        json!(f64::MAX),
+        json!(-f64::MAX),
+        json!(f64::MIN_POSITIVE),
+    ]
+}
+
+/// Every variant of `value` with one node replaced or removed.
+fn mutations(value: &Value) -> Vec<Value> {
+    let mut variants = replacements();
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                let mut removed = map.clone();
+                removed.remove(key);
+                variants.push(Value::Object(removed));
+                for mutated in mutations(child) {
+                    let mut map = map.clone();
+                    map.insert(key.clone(), mutated);
+                    variants.push(Value::Object(map));
+                }
+            }
+        }
+        Value::Array(values) => {
+            for (i, child) in values.iter().enumerate() {
+                let mut removed = values.clone();
+                removed.remove(i);
+                variants.push(Value::Array(removed));
+                for mutated in mutations(child) {
+                    let mut values = values.clone();
+                    values[i] = mutated;
+                    variants.push(Value::Array(values));
+                }
+            }
+        }
+        _ => {}
+    }
+    variants
+}
+
+/// Runs `target` on every body, returns how many were parsed and how many
+/// rejected. Panics with the first body that made the target panic.
+fn run(name: &str, target: Target, bodies: impl Iterator<Item = Vec<u8>>) -> (usize, usize) {
+    let (mut parsed, mut rejected) = (0, 0);
+    for body in bodies {
+        match catch_unwind(AssertUnwindSafe(|| target(&body))) {
+            Ok(Ok(())) => parsed += 1,
+            Ok(Err(_)) => rejected += 1,
+            Err(_) => panic!("parsing {name} panicked on {}", String::from_utf8_lossy(&body)),
+        }
+    }
+    (parsed, rejected)
+}
+
+/// All fixtures with their target.
+fn fixtures() -> Vec<(String, Target, Vec<u8>)> {
+    let mut fixtures = Vec::new();
+    for (name, target) in TARGETS {
+        for generation in GENERATIONS {
+            if let Some(body) = fixture(generation, name) {
+                fixtures.push((format!("{generation}/{name}"), *target, body));
+            }
+        }
+    }
+    fixtures
+}
+
+#[test]
+fn every_target_has_a_fixture() {
+    for (name, _) in TARGETS {
+        assert!(GENERATIONS.iter().any(|generation| fixture(generation, name).is_some()), "no fixture {name}");
+    }
+}
+
+#[test]
+fn mutated_nodes() {
+    for (name, target, body) in fixtures() {
+        let value: Value = serde_json::from_slice(&body).expect("fixtures are valid JSON");
+        let bodies = mutations(&value).into_iter().map(|value| serde_json::to_vec(&value).expect("values can be serialized"));
+        run(&name, target, bodies);
+    }
+}
+
+#[test]
+fn truncated_bodies() {
+    for (name, target, body) in fixtures() {
+        let body = body.trim_ascii_end();
+        let bodies = (0..body.len()).map(|len| body[..len].to_vec());
+        let (parsed, _) = run(&name, target, bodies);
+        assert_eq!(parsed, 0, "{name}: truncated body parsed");
+    }
+}
+
+#[test]
+fn invalid_numbers() {
+    // not valid JSON, or out of range of every number type
+    for number in ["NaN", "Infinity", "1e400", "-1e400", "0x10", "01", "1.", ".5", "+1"] {
+        for (name, target, body) in fixtures() {
+            let text = String::from_utf8(body).expect("fixtures are UTF-8");
+            let Some(position) = text.find(": 0").or_else(|| text.find(": 1")) else {
+                continue;
+            };
+            let body = format!("{}: {number}{}", &text[..position], &text[position + 3..]);
+            run(&name, target, std::iter::once(body.into_bytes()));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use log::{debug, error, info};
+use serde_json::json;
+
+use crate::sink::{Batch, Sink};
+
+/// Cycles queued for Grafana Live, older ones are dropped as they aren't live
+/// anymore.
+const LIVE_QUEUE: usize = 2;
+
+/// Pushes annotations to Grafana via its HTTP API.
+pub struct Grafana {
+    client: reqwest::blocking::Client,
+    url: String,
+    token: String,
+    dashboard_uid: Option<String>,
+}
+
+impl Grafana {
+    /// Creates the client from `GRAFANA_URL` and `GRAFANA_TOKEN` (a service
+    /// account token). If `GRAFANA_DASHBOARD_UID` is set, the annotations are
+    /// only shown on this dashboard, otherwise on all of them.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(url) = std::env::var("GRAFANA_URL") else {
+            return Ok(None);
+        };
+        Ok(Some(Grafana {
+            client: reqwest::blocking::Client::builder().timeout(Duration::from_secs(5)).build()?,
+            url: format!("{}/api/annotations", url.trim_end_matches('/')),
+            token: std::env::var("GRAFANA_TOKEN")?,
+            dashboard_uid: std::env::var("GRAFANA_DASHBOARD_UID").ok(),
+        }))
+    }
+
+    /// Creates an annotation at the current time. Failures are only logged.
+    pub fn annotate(&self, tags: &[&str], text: &str) {
+        let mut body = json!({
+            "time": Utc::now().timestamp_millis(),
+            "tags": tags,
+            "text": text,
+        });
+        if let Some(uid) = &self.dashboard_uid {
+            body["dashboardUID"] = json!(uid);
+        }
+        let res = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.token)
+            .json(&body)
+            .send()
+            .and_then(|response| response.error_for_status());
+        if let Err(error) = res {
+            error!("Error during creation of Grafana annotation occured: {:?}", error);
+        }
+    }
+}
+
+/// Pushes the points of every cycle to Grafana Live, so panels subscribed to
+/// the stream update right away instead of querying a datasource.
+pub struct LiveSink {
+    sender: SyncSender<String>,
+}
+
+impl LiveSink {
+    /// Creates the sink if `GRAFANA_LIVE_STREAM` is set. The points are
+    /// pushed as line protocol to `GRAFANA_URL` with `GRAFANA_TOKEN`, every
+    /// measurement becomes the channel `stream/<stream>/<measurement>`.
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(stream) = std::env::var("GRAFANA_LIVE_STREAM") else {
+            return Ok(None);
+        };
+        let url = std::env::var("GRAFANA_URL").map_err(|_| "GRAFANA_LIVE_STREAM needs GRAFANA_URL")?;
+        let pusher = LivePusher {
+            client: reqwest::blocking::Client::builder().timeout(Duration::from_secs(5)).build()?,
+            url: format!("{}/api/live/push/{stream}", url.trim_end_matches('/')),
+            token: std::env::var("GRAFANA_TOKEN")?,
+        };
+        let (sender, receiver) = mpsc::sync_channel(LIVE_QUEUE);
+        std::thread::Builder::new()
+            .name("grafana-live".to_owned())
+            .spawn(move || pusher.run(&receiver))?;
+        info!("Pushing the points to Grafana Live stream {stream}");
+        Ok(Some(LiveSink { sender }))
+    }
+}
+
+impl Sink for LiveSink {
+    fn name(&self) -> &'static str {
+        "Grafana Live"
+    }
+
+    /// Queues the points, a slow Grafana doesn't delay the cycle.
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let body = batch.points().iter().map(|point| point.to_line()).collect::<Vec<_>>().join("\n");
+        if self.sender.try_send(body).is_err() {
+            debug!("Grafana Live is too slow, dropping the points of a cycle");
+        }
+        Ok(())
+    }
+
+    fn live(&self) -> bool {
+        true
+    }
+}
+
+struct LivePusher {
+    client: reqwest::blocking::Client,
+    url: String,
+    token: String,
+}
+
+impl LivePusher {
+    fn run(&self, receiver: &Receiver<String>) {
+        while let Ok(body) = receiver.recv() {
+            let res = self
+                .client
+                .post(&self.url)
+                .bearer_auth(&self.token)
+                .body(body)
+                .send()
+                .and_then(|response| response.error_for_status());
+            if let Err(error) = res {
+                error!("Error during push to Grafana Live occured: {:?}", error);
+            }
+        }
+    }
+}
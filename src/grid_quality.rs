@@ -0,0 +1,208 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+use point_derive::ToPoint;
+use serde::{Deserialize, Serialize};
+
+use crate::{timestamp, MeterData};
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "grid_quality"]
+pub struct GridQualityData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    l1_voltage_min: Option<f64>,
+    #[influxdb(field)]
+    l1_voltage_max: Option<f64>,
+    #[influxdb(field)]
+    l1_voltage_avg: Option<f64>,
+    #[influxdb(field)]
+    l2_voltage_min: Option<f64>,
+    #[influxdb(field)]
+    l2_voltage_max: Option<f64>,
+    #[influxdb(field)]
+    l2_voltage_avg: Option<f64>,
+    #[influxdb(field)]
+    l3_voltage_min: Option<f64>,
+    #[influxdb(field)]
+    l3_voltage_max: Option<f64>,
+    #[influxdb(field)]
+    l3_voltage_avg: Option<f64>,
+    #[influxdb(field)]
+    frequency_min: f64,
+    #[influxdb(field)]
+    frequency_max: f64,
+    #[influxdb(field)]
+    frequency_deviation: f64,
+    #[influxdb(field)]
+    voltage_unbalance: Option<f64>,
+    #[influxdb(field)]
+    samples: i64,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+#[derive(Default, Debug, Clone, Copy, Serialize, Deserialize)]
+struct Stats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u32,
+}
+
+impl Stats {
+    fn add(&mut self, value: f64) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn add_opt(&mut self, value: Option<f64>) {
+        if let Some(value) = value {
+            self.add(value);
+        }
+    }
+
+    fn min(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    fn max(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    fn avg(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+/// Samples of the current interval. Kept separate from the settings so it
+/// can be persisted across restarts.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Accumulator {
+    started: Option<DateTime<Utc>>,
+    samples: i64,
+    voltages: [Stats; 3],
+    frequency: Stats,
+    frequency_deviation: f64,
+    voltage_unbalance: Stats,
+}
+
+/// Collects meter samples over an interval and condenses them into one
+/// `grid_quality` point once the interval is over.
+pub struct GridQuality {
+    interval: Duration,
+    nominal_frequency: f64,
+    acc: Accumulator,
+}
+
+impl GridQuality {
+    pub fn new(interval: Duration, nominal_frequency: f64) -> Self {
+        GridQuality {
+            interval,
+            nominal_frequency,
+            acc: Accumulator::default(),
+        }
+    }
+
+    /// Reads the grid quality settings from the environment.
+    /// `GRID_QUALITY_INTERVAL` is given in seconds (default 300) and
+    /// `GRID_NOMINAL_FREQUENCY` in Hz (default 50).
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let interval = match std::env::var("GRID_QUALITY_INTERVAL") {
+            Ok(val) => val.parse()?,
+            Err(_) => 300,
+        };
+        let nominal_frequency = match std::env::var("GRID_NOMINAL_FREQUENCY") {
+            Ok(val) => val.parse()?,
+            Err(_) => 50.0,
+        };
+        Ok(GridQuality::new(Duration::from_secs(interval), nominal_frequency))
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn accumulator(&self) -> &Accumulator {
+        &self.acc
+    }
+
+    pub fn restore(&mut self, acc: Accumulator) {
+        self.acc = acc;
+    }
+
+    pub fn add(&mut self, meter: &MeterData) {
+        let acc = &mut self.acc;
+        acc.started.get_or_insert_with(crate::clock::now);
+        acc.samples += 1;
+
+        let voltages = [meter.l1_voltage, meter.l2_voltage, meter.l3_voltage];
+        for (stats, voltage) in acc.voltages.iter_mut().zip(voltages) {
+            stats.add_opt(voltage);
+        }
+
+        acc.frequency.add(meter.frequency_average);
+        let deviation = meter.frequency_average - self.nominal_frequency;
+        if deviation.abs() > acc.frequency_deviation.abs() {
+            acc.frequency_deviation = deviation;
+        }
+
+        acc.voltage_unbalance.add_opt(voltage_unbalance(&voltages));
+    }
+
+    /// Returns the aggregated point if the interval has elapsed and starts a new one.
+    pub fn take(&mut self) -> Result<Option<GridQualityData>, timestamp::TimestampError> {
+        match self.acc.started {
+            Some(started) if (crate::clock::now() - started).to_std().unwrap_or_default() >= self.interval => {}
+            _ => return Ok(None),
+        }
+
+        let acc = &self.acc;
+        let [l1, l2, l3] = acc.voltages;
+        let data = GridQualityData {
+            device: "Meter",
+            l1_voltage_min: l1.min(),
+            l1_voltage_max: l1.max(),
+            l1_voltage_avg: l1.avg(),
+            l2_voltage_min: l2.min(),
+            l2_voltage_max: l2.max(),
+            l2_voltage_avg: l2.avg(),
+            l3_voltage_min: l3.min(),
+            l3_voltage_max: l3.max(),
+            l3_voltage_avg: l3.avg(),
+            frequency_min: acc.frequency.min,
+            frequency_max: acc.frequency.max,
+            frequency_deviation: acc.frequency_deviation,
+            voltage_unbalance: acc.voltage_unbalance.max(),
+            samples: acc.samples,
+            time: timestamp::now()?,
+        };
+        self.acc = Accumulator::default();
+        Ok(Some(data))
+    }
+}
+
+/// Voltage unbalance in percent: the largest deviation of a phase from the
+/// phase average, relative to that average (NEMA definition).
+fn voltage_unbalance(voltages: &[Option<f64>; 3]) -> Option<f64> {
+    let [Some(l1), Some(l2), Some(l3)] = *voltages else {
+        return None;
+    };
+    let avg = (l1 + l2 + l3) / 3.0;
+    if avg <= 0.0 {
+        return None;
+    }
+    let max_deviation = [l1, l2, l3]
+        .iter()
+        .map(|v| (v - avg).abs())
+        .fold(0.0, f64::max);
+    Some(max_deviation / avg * 100.0)
+}
@@ -0,0 +1,162 @@
+//! gRPC interface for other services, defined by `proto/fronius.proto`.
+//!
+//! Only available with the `grpc` cargo feature and enabled by
+//! `GRPC_LISTEN`. The service gets every point after the processing
+//! pipeline, independent of the routes, like the REST API. If one of the
+//! REST API tokens is set, calls need it as `authorization: Bearer <token>`
+//! metadata.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use log::{error, info, warn};
+use tokio::sync::broadcast;
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tonic::{metadata::MetadataValue, Request, Response, Status};
+
+use crate::{
+    metrics::METRICS,
+    point::{self, FieldValue},
+    sink::{Batch, Sink},
+};
+
+pub mod proto {
+    tonic::include_proto!("fronius.v1");
+}
+
+use proto::{
+    collector_server::{Collector, CollectorServer},
+    value::Kind,
+    GetLatestRequest, GetLatestResponse, SubscribeRequest,
+};
+
+/// Points buffered per subscriber, a slower subscriber misses points.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Last point by series.
+type Latest = Arc<Mutex<BTreeMap<String, proto::Point>>>;
+
+/// Feeds the points of every cycle to the gRPC service.
+pub struct GrpcSink {
+    latest: Latest,
+    sender: broadcast::Sender<proto::Point>,
+}
+
+impl Sink for GrpcSink {
+    fn name(&self) -> &'static str {
+        "gRPC"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut latest = self.latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for point in batch.points() {
+            let message = to_message(point);
+            latest.insert(point.series(), message.clone());
+            // fails only without subscribers
+            let _ = self.sender.send(message);
+        }
+        Ok(())
+    }
+
+    fn live(&self) -> bool {
+        true
+    }
+}
+
+fn to_message(point: &point::Point) -> proto::Point {
+    let fields = point
+        .fields
+        .iter()
+        .map(|(key, value)| {
+            let kind = match value {
+                FieldValue::Float(value) => Kind::Float(*value),
+                FieldValue::Integer(value) => Kind::Integer(*value),
+                FieldValue::UInteger(value) => Kind::Unsigned(*value),
+                FieldValue::Boolean(value) => Kind::Boolean(*value),
+                FieldValue::String(value) => Kind::String(value.clone()),
+            };
+            (key.clone(), proto::Value { kind: Some(kind) })
+        })
+        .collect();
+    proto::Point {
+        measurement: point.measurement.clone(),
+        tags: point.tags.iter().cloned().collect(),
+        fields,
+        time: point.time,
+    }
+}
+
+struct Service {
+    latest: Latest,
+    sender: broadcast::Sender<proto::Point>,
+}
+
+#[tonic::async_trait]
+impl Collector for Service {
+    async fn get_latest(&self, request: Request<GetLatestRequest>) -> Result<Response<GetLatestResponse>, Status> {
+        let measurement = request.into_inner().measurement;
+        let latest = self.latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let points = latest
+            .values()
+            .filter(|point| measurement.is_empty() || point.measurement == measurement)
+            .cloned()
+            .collect();
+        Ok(Response::new(GetLatestResponse { points }))
+    }
+
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<proto::Point, Status>> + Send>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let measurements = request.into_inner().measurements;
+        let stream = BroadcastStream::new(self.sender.subscribe()).filter_map(move |point| match point {
+            Ok(point) if measurements.is_empty() || measurements.contains(&point.measurement) => Some(Ok(point)),
+            Ok(_) => None,
+            Err(error) => {
+                warn!("gRPC subscriber is too slow: {error}");
+                None
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+/// Starts the gRPC server on `addr` in a background thread and returns the
+/// sink that feeds it.
+pub fn spawn(addr: &str, tokens: Vec<String>) -> Result<GrpcSink, Box<dyn std::error::Error>> {
+    let addr: SocketAddr = addr.parse()?;
+    let latest = Latest::default();
+    let (sender, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+    let service = Service {
+        latest: latest.clone(),
+        sender: sender.clone(),
+    };
+    let tokens = tokens
+        .iter()
+        .map(|token| MetadataValue::try_from(format!("Bearer {token}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    // the signature is given by tonic
+    #[allow(clippy::result_large_err)]
+    let authorize = move |request: Request<()>| {
+        if tokens.is_empty() || request.metadata().get("authorization").is_some_and(|given| tokens.contains(given)) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("invalid token"))
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    METRICS.add_runtime(runtime.handle().clone());
+    std::thread::Builder::new().name("grpc-server".to_owned()).spawn(move || {
+        info!("gRPC server listening on {addr}");
+        let server = tonic::transport::Server::builder()
+            .add_service(CollectorServer::with_interceptor(service, authorize))
+            .serve(addr);
+        if let Err(error) = runtime.block_on(server) {
+            error!("Error in gRPC server occured: {:?}", error);
+        }
+    })?;
+    Ok(GrpcSink { latest, sender })
+}
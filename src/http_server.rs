@@ -0,0 +1,351 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{control::Control, metrics::METRICS, snapshot::Snapshot};
+
+const LOW_MEMORY_STACK_SIZE: usize = 128 * 1024;
+
+/// Number of commands accepted per `RATE_WINDOW`.
+const RATE_LIMIT: u32 = 10;
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+/// The collector is unhealthy once no cycle finished for this many intervals.
+const HEALTH_INTERVALS: u32 = 3;
+/// Least time without a finished cycle that is unhealthy, for short intervals.
+const MIN_HEALTH_AGE: Duration = Duration::from_secs(60);
+
+/// Limits the number of commands in a fixed window.
+struct RateLimit {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimit {
+    fn allow(&mut self) -> bool {
+        if self.window_start.elapsed() >= RATE_WINDOW {
+            self.window_start = Instant::now();
+            self.count = 0;
+        }
+        self.count += 1;
+        self.count <= RATE_LIMIT
+    }
+}
+
+struct Context {
+    snapshot: Arc<Snapshot>,
+    control: Arc<Control>,
+    /// Token that allows everything, required for the commands.
+    control_token: Option<String>,
+    /// Token that only allows to read.
+    read_token: Option<String>,
+    rate_limit: RateLimit,
+    /// Prefix of all paths, e.g. `/fronius`, empty if served at the root.
+    prefix: String,
+    #[cfg(feature = "sqlite")]
+    history: Option<crate::sqlite::History>,
+}
+
+/// Starts the HTTP server on `addr` in a background thread.
+///
+/// Routes:
+/// - `GET /` lists the other routes as relative URLs
+/// - `GET /health` returns whether cycles still finish, `503` otherwise
+/// - `GET /api/latest` returns the last known value of every measurement
+/// - `GET /api/latest/<measurement>` returns the last known values of one measurement
+/// - `GET /api/summary` returns the power flow of every site formatted for
+///   people, see the `locale` module
+/// - `GET /api/history/<measurement>?field=<field>&hours=<hours>` returns the
+///   values of the last hours (default 24) from SQLite, if `SQLITE_FILE` is set
+/// - `GET /metrics` returns metrics about the collector in the Prometheus format
+/// - `GET /display.png` returns the status screen of the `display` module, in
+///   builds with the `display` feature
+/// - `POST /api/poll`, `/api/burst/<seconds>`, `/api/interval/<seconds>`,
+///   `/api/reload`, `/api/enable` and `/api/ack` run the commands of the
+///   `control` module
+///
+/// With `HTTP_PATH_PREFIX` all routes are served below the prefix instead,
+/// e.g. behind a reverse proxy. No response contains an absolute URL, so the
+/// API also works behind the Home Assistant ingress.
+///
+/// If `HTTP_TOKEN` (control) or `HTTP_READ_TOKEN` (read-only) is set, all
+/// requests but `/health` need one of them as bearer token. The commands are
+/// only available with the control token and limited to `RATE_LIMIT` per
+/// minute.
+///
+/// In low-memory mode the server thread gets a small stack.
+pub fn spawn(addr: &str, snapshot: Arc<Snapshot>, control: Arc<Control>, low_memory: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let server = Server::http(addr).map_err(|e| e.to_string())?;
+    info!("HTTP server listening on {addr}");
+    let mut context = Context {
+        snapshot,
+        control,
+        control_token: token_from_env("HTTP_TOKEN"),
+        read_token: token_from_env("HTTP_READ_TOKEN"),
+        rate_limit: RateLimit {
+            window_start: Instant::now(),
+            count: 0,
+        },
+        prefix: std::env::var("HTTP_PATH_PREFIX")
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_owned(),
+        #[cfg(feature = "sqlite")]
+        history: crate::sqlite::History::from_env(),
+    };
+
+    let mut builder = std::thread::Builder::new().name("http-server".to_owned());
+    if low_memory {
+        builder = builder.stack_size(LOW_MEMORY_STACK_SIZE);
+    }
+    builder.spawn(move || {
+        for request in server.incoming_requests() {
+            if let Err(error) = handle(request, &mut context) {
+                error!("Error during HTTP response occured: {:?}", error);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+fn token_from_env(variable_name: &str) -> Option<String> {
+    std::env::var(variable_name).ok().filter(|token| !token.is_empty())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    None,
+    Read,
+    Control,
+}
+
+/// The role given by the bearer token of the request. Without any token
+/// configured, everybody can read.
+fn role(request: &Request, context: &Context) -> Role {
+    if context.control_token.is_none() && context.read_token.is_none() {
+        return Role::Read;
+    }
+    let given = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "));
+    let Some(given) = given else {
+        return Role::None;
+    };
+    if context.control_token.as_deref().is_some_and(|token| crate::secret::token_eq(given, token)) {
+        Role::Control
+    } else if context.read_token.as_deref().is_some_and(|token| crate::secret::token_eq(given, token)) {
+        Role::Read
+    } else {
+        Role::None
+    }
+}
+
+/// The path of the request below the prefix, without the query and the
+/// trailing slash. `None` if it isn't below the prefix.
+fn route<'a>(url: &'a str, prefix: &str) -> Option<&'a str> {
+    let path = url.split('?').next().unwrap_or_default().strip_prefix(prefix)?;
+    if !path.is_empty() && !path.starts_with('/') {
+        return None;
+    }
+    Some(path.trim_end_matches('/'))
+}
+
+fn handle(request: Request, context: &mut Context) -> std::io::Result<()> {
+    let Some(path) = route(request.url(), &context.prefix).map(str::to_owned) else {
+        return request.respond(Response::empty(404));
+    };
+    // the health is needed by supervisors that don't know the token
+    if path == "/health" && *request.method() == Method::Get {
+        return handle_health(request, context);
+    }
+    let role = role(&request, context);
+    if role == Role::None {
+        warn!("Unauthorized HTTP request from {:?}", request.remote_addr());
+        return request.respond(Response::empty(401));
+    }
+    match *request.method() {
+        Method::Get => handle_get(request, context, &path),
+        Method::Post if role == Role::Control => handle_post(request, context, &path),
+        Method::Post if context.control_token.is_some() => {
+            warn!("HTTP command from {:?} with the read-only token", request.remote_addr());
+            request.respond(Response::empty(403))
+        }
+        _ => request.respond(Response::empty(405)),
+    }
+}
+
+fn handle_post(request: Request, context: &mut Context, path: &str) -> std::io::Result<()> {
+    let Some(command) = path.strip_prefix("/api/") else {
+        return request.respond(Response::empty(404));
+    };
+    if !context.rate_limit.allow() {
+        warn!("Too many commands via HTTP, rejecting {command:?}");
+        return request.respond(Response::empty(429));
+    }
+    // `/api/burst/60` is the command `burst 60`
+    let ack = context.control.execute(&command.replace('/', " "), "HTTP");
+    let status = if ack.ok() { 200 } else { 400 };
+    let body = serde_json::to_string(&ack).unwrap_or_default();
+    request.respond(json_response(body).with_status_code(status))
+}
+
+/// `200` while the cycles finish in time (or before the first one), `503`
+/// once no cycle finished for `HEALTH_INTERVALS` intervals.
+fn handle_health(request: Request, context: &Context) -> std::io::Result<()> {
+    let max_age = (context.control.interval() * HEALTH_INTERVALS).max(MIN_HEALTH_AGE);
+    let age = METRICS.last_cycle_age();
+    let healthy = age.is_none_or(|age| age <= max_age);
+    let body = serde_json::json!({
+        "status": match (healthy, age) {
+            (false, _) => "unhealthy",
+            (true, None) => "starting",
+            (true, Some(_)) => "ok",
+        },
+        "cycles": METRICS.cycles(),
+        "last_cycle_age_seconds": age.map(|age| age.as_secs_f64()),
+    });
+    request.respond(json_response(body.to_string()).with_status_code(if healthy { 200 } else { 503 }))
+}
+
+/// The routes, relative to the root of the API so they resolve below any
+/// prefix or ingress path.
+fn index() -> serde_json::Value {
+    #[allow(unused_mut)]
+    let mut index = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "health": "health",
+        "metrics": "metrics",
+        "latest": "api/latest",
+        "summary": "api/summary",
+    });
+    #[cfg(feature = "display")]
+    {
+        index["display"] = "display.png".into();
+    }
+    index
+}
+
+/// The power flow and the state of charge of every site, formatted with the
+/// configured locale. `None` before the first power flow.
+fn summary(snapshot: &Snapshot) -> Option<serde_json::Value> {
+    let locale = crate::locale::get();
+    let power_flow = snapshot.to_json(Some("power_flow"))?;
+    let storages = snapshot.to_json(Some("storage")).unwrap_or_default();
+    let sites = power_flow.as_object()?.iter().map(|(device, entry)| {
+        // filed as `<site>/<device>` with several sites
+        let site = device.split_once('/').map(|(site, _)| site);
+        let values = &entry["values"];
+        let power = |field: &str| values[field].as_f64().map(|watts| locale.power(watts));
+        let state_of_charge = storages
+            .as_object()
+            .and_then(|storages| storages.iter().find(|(device, _)| device.split_once('/').map(|(site, _)| site) == site))
+            .and_then(|(_, storage)| storage["values"]["charge_percentage"].as_f64())
+            .map(|percent| locale.percent(percent));
+        let summary = serde_json::json!({
+            "updated": entry["updated"],
+            "pv": power("photovoltaik"),
+            "grid": power("grid"),
+            // P_Load is negative while power is consumed
+            "load": values["load"].as_f64().map(|watts| locale.power(-watts)),
+            "battery": power("akku"),
+            "state_of_charge": state_of_charge,
+            "energy_day": values["energy_day"].as_f64().map(|watt_hours| locale.energy(watt_hours)),
+        });
+        (site.unwrap_or("site").to_owned(), summary)
+    });
+    Some(sites.collect::<serde_json::Map<_, _>>().into())
+}
+
+fn handle_get(request: Request, context: &Context, path: &str) -> std::io::Result<()> {
+    let snapshot = &context.snapshot;
+    if path.is_empty() {
+        // the relative URLs only resolve below the root with a trailing slash
+        let url = request.url().split('?').next().unwrap_or_default();
+        if !url.ends_with('/') {
+            let location = format!("{}/", url.rsplit('/').next().unwrap_or_default());
+            return match Header::from_bytes(&b"Location"[..], location.as_bytes()) {
+                Ok(header) => request.respond(Response::empty(308).with_header(header)),
+                Err(()) => request.respond(Response::empty(404)),
+            };
+        }
+        return request.respond(json_response(index().to_string()));
+    }
+    if path == "/metrics" {
+        return request.respond(with_content_type(Response::from_string(METRICS.render()), "text/plain; version=0.0.4"));
+    }
+
+    #[cfg(feature = "display")]
+    if path == "/display.png" {
+        return match crate::display::png(snapshot) {
+            Ok(png) => request.respond(with_content_type(Response::from_data(png), "image/png")),
+            Err(error) => {
+                error!("Error during display rendering occured: {:?}", error);
+                request.respond(Response::empty(500))
+            }
+        };
+    }
+
+    #[cfg(feature = "sqlite")]
+    if let (Some(measurement), Some(history)) = (path.strip_prefix("/api/history/"), &context.history) {
+        let measurement = measurement.to_owned();
+        return handle_history(request, history, &measurement);
+    }
+
+    let body = match path {
+        "/api/latest" => snapshot.to_json(None),
+        "/api/summary" => summary(snapshot),
+        _ => match path.strip_prefix("/api/latest/") {
+            Some(measurement) => snapshot.to_json(Some(measurement)),
+            None => None,
+        },
+    };
+
+    match body {
+        Some(body) => request.respond(json_response(body.to_string())),
+        None => request.respond(Response::empty(404)),
+    }
+}
+
+/// Value of the query parameter `name`.
+#[cfg(feature = "sqlite")]
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    url.split_once('?')?
+        .1
+        .split('&')
+        .find_map(|param| param.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value))
+}
+
+#[cfg(feature = "sqlite")]
+fn handle_history(request: Request, history: &crate::sqlite::History, measurement: &str) -> std::io::Result<()> {
+    let hours = match query_param(request.url(), "hours").map(str::parse::<u32>) {
+        Some(Ok(hours)) => hours,
+        Some(Err(_)) => return request.respond(Response::empty(400)),
+        None => 24,
+    };
+    let since = chrono::Utc::now() - chrono::Duration::hours(hours.into());
+    let field = query_param(request.url(), "field").map(str::to_owned);
+    match history.query(measurement, field.as_deref(), since.timestamp_nanos_opt().unwrap_or(0)) {
+        Ok(Some(body)) => request.respond(json_response(body.to_string())),
+        Ok(None) => request.respond(Response::empty(404)),
+        Err(error) => {
+            error!("Error during read of the history occured: {:?}", error);
+            request.respond(Response::empty(500))
+        }
+    }
+}
+
+fn json_response(body: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    with_content_type(Response::from_string(body), "application/json")
+}
+
+fn with_content_type<R: std::io::Read>(response: Response<R>, content_type: &str) -> Response<R> {
+    match Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()) {
+        Ok(header) => response.with_header(header),
+        Err(()) => response,
+    }
+}
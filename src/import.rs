@@ -0,0 +1,309 @@
+//! Import of Solar.web data exports, to seed the history from before the
+//! collector was running.
+//!
+//! Solar.web exports energies per interval (e.g. 5 minutes or a day) as CSV
+//! or XLSX. The first column is the start of the interval, the energy columns
+//! are recognized by their (English or German) name. The energies are summed
+//! up per day and written to the `derived_energy` measurement, the same way
+//! the collector integrates them from the power flow.
+//!
+//! Importing an export twice doesn't duplicate anything: the points have the
+//! same timestamps and tags, so InfluxDB replaces them. Days that already have
+//! points (e.g. written by the collector) are skipped unless `--overwrite` is
+//! given, so imported and live values of a day are not mixed.
+
+use std::{collections::BTreeSet, path::Path};
+
+use calamine::{Data, DataType, Reader};
+use chrono::{prelude::*, Duration};
+use influxdb2_structmap::value::Value;
+use log::{info, warn};
+use thiserror::Error;
+
+use crate::{
+    energy::{DailyEnergy, Energies},
+    influx::{flux_string, InfluxSink},
+    pipeline::Pipeline,
+    sink::Batch,
+    timestamp::TimestampError,
+};
+
+/// Points per InfluxDB write.
+const CHUNK_SIZE: usize = 5000;
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("could not read the export")]
+    Io(#[from] std::io::Error),
+    #[error("invalid CSV file")]
+    Csv(#[from] csv::Error),
+    #[error("invalid XLSX file")]
+    Xlsx(#[from] calamine::XlsxError),
+    #[error("the XLSX file has no sheet")]
+    NoSheet,
+    #[error("no energy columns found, expected e.g. \"PV production\" or \"Consumption\"")]
+    NoColumns,
+    #[error("row {0}: invalid time {1:?}")]
+    InvalidTime(usize, String),
+    #[error("row {0}: invalid number {1:?}")]
+    InvalidNumber(usize, String),
+    #[error(transparent)]
+    Timestamp(#[from] TimestampError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quantity {
+    Pv,
+    Load,
+    GridImport,
+    GridExport,
+    BatteryCharge,
+    BatteryDischarge,
+}
+
+impl Quantity {
+    /// Recognizes a column by its name. Columns of ratios like the self
+    /// consumption are ignored.
+    fn from_header(header: &str) -> Option<Self> {
+        let header = header.to_lowercase();
+        let contains = |keywords: &[&str]| keywords.iter().any(|keyword| header.contains(keyword));
+        if contains(&["eigen", "self", "direkt", "direct", "autarkie", "autonomy", "%"]) {
+            None
+        } else if contains(&["discharg", "entlad"]) {
+            Some(Quantity::BatteryDischarge)
+        } else if contains(&["battery charg", "batterie lad", "akku lad", "ladung"]) {
+            Some(Quantity::BatteryCharge)
+        } else if contains(&["fed into", "feed-in", "feed in", "einspeisung"]) {
+            Some(Quantity::GridExport)
+        } else if contains(&["from grid", "purchased", "bezug"]) {
+            Some(Quantity::GridImport)
+        } else if contains(&["consumption", "verbrauch"]) {
+            Some(Quantity::Load)
+        } else if contains(&["production", "produktion", "erzeugung"]) {
+            Some(Quantity::Pv)
+        } else {
+            None
+        }
+    }
+
+    fn add(self, energies: &mut Energies, value: f64) {
+        let energy = match self {
+            Quantity::Pv => &mut energies.pv,
+            Quantity::Load => &mut energies.load,
+            Quantity::GridImport => &mut energies.grid_import,
+            Quantity::GridExport => &mut energies.grid_export,
+            Quantity::BatteryCharge => &mut energies.battery_charge,
+            Quantity::BatteryDischarge => &mut energies.battery_discharge,
+        };
+        *energy += value;
+    }
+}
+
+/// Factor to Wh of the unit given in a header or unit cell like `[kWh]`.
+fn unit_factor(text: &str) -> Option<f64> {
+    let text = text.to_lowercase();
+    if text.contains("mwh") {
+        Some(1_000_000.0)
+    } else if text.contains("kwh") {
+        Some(1_000.0)
+    } else if text.contains("wh") {
+        Some(1.0)
+    } else {
+        None
+    }
+}
+
+/// Parses the time of a row, a row without time of day covers the whole day.
+fn parse_time(text: &str) -> Option<(NaiveDateTime, bool)> {
+    const DATE_TIMES: &[&str] = &["%d.%m.%Y %H:%M", "%d.%m.%Y %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S", "%m/%d/%Y %H:%M", "%m/%d/%Y %I:%M %p"];
+    const DATES: &[&str] = &["%d.%m.%Y", "%Y-%m-%d", "%m/%d/%Y"];
+    let text = text.trim();
+    DATE_TIMES
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .map(|time| (time, false))
+        .or_else(|| {
+            DATES
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+                .map(|date| (date.and_time(NaiveTime::MIN), true))
+        })
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Some(0.0);
+    }
+    // exports with German settings use a decimal comma
+    let text = if text.contains('.') { text.to_owned() } else { text.replace(',', ".") };
+    text.parse().ok()
+}
+
+/// Reads the rows of a CSV or XLSX export as text.
+fn read_rows(path: &Path) -> Result<Vec<Vec<String>>, ImportError> {
+    let is_xlsx = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("xlsx"));
+    if is_xlsx {
+        let mut workbook: calamine::Xlsx<_> = calamine::open_workbook(path)?;
+        let range = workbook.worksheet_range_at(0).ok_or(ImportError::NoSheet)??;
+        let cell_to_string = |cell: &Data| match cell.as_datetime() {
+            Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            None => cell.to_string(),
+        };
+        return Ok(range.rows().map(|row| row.iter().map(cell_to_string).collect()).collect());
+    }
+
+    let content = std::fs::read_to_string(path)?;
+    let content = content.trim_start_matches('\u{feff}');
+    let first_line = content.lines().next().unwrap_or_default();
+    let delimiter = if first_line.matches(';').count() > first_line.matches(',').count() { b';' } else { b',' };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(content.as_bytes());
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        rows.push(record?.iter().map(str::to_owned).collect());
+    }
+    Ok(rows)
+}
+
+/// Parses the rows of an export to the energies per interval. Returns the
+/// end of every interval with its energies in Wh.
+fn parse(rows: &[Vec<String>]) -> Result<Vec<(DateTime<Utc>, Energies)>, ImportError> {
+    let header = rows.first().ok_or(ImportError::NoColumns)?;
+    // the first column is the time, the first column of every quantity is used
+    let mut columns: Vec<(usize, Quantity, f64)> = Vec::new();
+    for (index, name) in header.iter().enumerate().skip(1) {
+        if let Some(quantity) = Quantity::from_header(name) {
+            if columns.iter().any(|(_, other, _)| *other == quantity) {
+                info!("Ignoring column {name:?}, {quantity:?} is already imported");
+            } else {
+                columns.push((index, quantity, unit_factor(name).unwrap_or(1.0)));
+            }
+        }
+    }
+    if columns.is_empty() {
+        return Err(ImportError::NoColumns);
+    }
+
+    let mut intervals: Vec<(NaiveDateTime, bool, Energies)> = Vec::new();
+    for (number, row) in rows.iter().enumerate().skip(1) {
+        let time = row.first().map(String::as_str).unwrap_or_default();
+        // Solar.web puts the units in the row below the header
+        if time.trim().is_empty() || time.trim_start().starts_with('[') {
+            for (index, _, factor) in &mut columns {
+                if let Some(unit) = row.get(*index).and_then(|cell| unit_factor(cell)) {
+                    *factor = unit;
+                }
+            }
+            continue;
+        }
+        let (time, whole_day) = parse_time(time).ok_or_else(|| ImportError::InvalidTime(number + 1, time.to_owned()))?;
+        let mut energies = Energies::default();
+        for (index, quantity, factor) in &columns {
+            let cell = row.get(*index).map(String::as_str).unwrap_or_default();
+            let value = parse_number(cell).ok_or_else(|| ImportError::InvalidNumber(number + 1, cell.to_owned()))?;
+            quantity.add(&mut energies, value * factor);
+        }
+        intervals.push((time, whole_day, energies));
+    }
+    intervals.sort_by_key(|(time, _, _)| *time);
+
+    // an interval lasts until the next one starts, the last one as long as
+    // the one before
+    let mut result = Vec::with_capacity(intervals.len());
+    let mut length = Duration::days(1);
+    for (i, (start, whole_day, energies)) in intervals.iter().enumerate() {
+        if *whole_day {
+            length = Duration::days(1);
+        } else if let Some((next, _, _)) = intervals.get(i + 1) {
+            length = *next - *start;
+        }
+        // the point is placed at the last second of the interval, so it
+        // counts to the day the interval belongs to
+        let end = *start + length - Duration::seconds(1);
+        let end = crate::timezone::from_local(end).ok_or_else(|| ImportError::InvalidTime(i + 2, end.to_string()))?;
+        result.push((end.with_timezone(&Utc), *energies));
+    }
+    Ok(result)
+}
+
+/// Returns the days (in the time zone of the site) in `start..=stop` that already have
+/// `derived_energy` points of the site.
+fn existing_days(sink: &InfluxSink, site: Option<&str>, start: DateTime<Utc>, stop: DateTime<Utc>) -> Result<BTreeSet<NaiveDate>, Box<dyn std::error::Error>> {
+    let Some(bucket) = sink.bucket("derived_energy") else {
+        return Ok(BTreeSet::new());
+    };
+    let site_filter = match site {
+        Some(site) => format!("r.site == {}", flux_string(site)),
+        None => "not exists r.site".to_owned(),
+    };
+    // counted in windows of 15 minutes, as the days are in the time zone of the site
+    let flux = format!(
+        r#"from(bucket: {bucket})
+  |> range(start: {start}, stop: {stop})
+  |> filter(fn: (r) => r._measurement == "derived_energy" and r._field == "pv_energy" and {site_filter})
+  |> aggregateWindow(every: 15m, fn: count, createEmpty: false, timeSrc: "_start")"#,
+        bucket = flux_string(bucket),
+        start = start.to_rfc3339_opts(SecondsFormat::Secs, true),
+        stop = (stop + Duration::seconds(1)).to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    Ok(sink
+        .query(flux)?
+        .into_iter()
+        .filter_map(|record| match record.values.get("_time") {
+            Some(Value::TimeRFC(time)) => Some(crate::timezone::local(time.with_timezone(&Utc)).date_naive()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Runs the `import` command: `import [--site <name>] [--overwrite] <file>...`
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    crate::timestamp::init_from_env()?;
+    crate::schema::init_from_env()?;
+    crate::timezone::init_from_env()?;
+    let mut site = None;
+    let mut overwrite = false;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--site" => site = Some(args.next().ok_or("--site needs a name")?.as_str()),
+            "--overwrite" => overwrite = true,
+            _ => files.push(Path::new(arg)),
+        }
+    }
+    if files.is_empty() {
+        return Err("usage: import [--site <name>] [--overwrite] <file>...".into());
+    }
+
+    let mut pipeline = Pipeline::from_env()?;
+    let sink = InfluxSink::from_env(false)?;
+    for file in files {
+        let mut intervals = parse(&read_rows(file)?)?;
+        if let (false, Some((first, _)), Some((last, _))) = (overwrite, intervals.first(), intervals.last()) {
+            let existing = existing_days(&sink, site, *first, *last)?;
+            if !existing.is_empty() {
+                warn!("Skipping {} days of {:?} that already have data, use --overwrite to import them anyway", existing.len(), file);
+                intervals.retain(|(time, _)| !existing.contains(&crate::timezone::local(*time).date_naive()));
+            }
+        }
+        let mut energy = DailyEnergy::default();
+        let mut batch = Batch::for_site(site);
+        for (time, energies) in &intervals {
+            batch.push(&energy.add_interval(*time, energies)?);
+            if batch.len() >= CHUNK_SIZE {
+                let mut full = std::mem::replace(&mut batch, Batch::for_site(site));
+                pipeline.process(&mut full);
+                sink.write(&full)?;
+            }
+        }
+        pipeline.process(&mut batch);
+        sink.write(&batch)?;
+        info!("Imported {} intervals from {:?}", intervals.len(), file);
+    }
+    Ok(())
+}
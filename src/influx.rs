@@ -0,0 +1,916 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use flate2::{write::GzEncoder, Compression};
+use influxdb2::{
+    api::{query::FluxRecord, write::TimestampPrecision},
+    models::Query,
+    Client, RequestError,
+};
+use log::{error, warn};
+use thiserror::Error;
+
+use crate::{
+    encryption::{self, FileKey},
+    metrics::METRICS,
+    point::split_unescaped,
+    routing::Routes,
+    secret::Secret,
+    sink::{Batch, Sink},
+    telemetry,
+    timestamp::{self, Precision},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid point: {0}")]
+pub struct InvalidPoint(&'static str);
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid overflow policy {0:?} in SPOOL_OVERFLOW, expected drop-oldest, drop-newest or block")]
+pub struct InvalidOverflow(String);
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid {0} {1}, the limit is too large")]
+pub struct LimitTooLarge(&'static str, u64);
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid priority {0:?} in SPOOL_PRIORITIES, expected measurement=high, normal or low")]
+pub struct InvalidPriority(String);
+
+/// Points of a batch that couldn't be written, they are spooled (if enabled).
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{0} points couldn't be written")]
+pub struct NotWritten(usize);
+
+/// Error of a write request.
+#[derive(Debug, Error)]
+enum WriteError {
+    /// InfluxDB answered with an error status.
+    #[error("HTTP status {status}: {text}")]
+    Http { status: u16, text: String },
+    #[error(transparent)]
+    Client(RequestError),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<RequestError> for WriteError {
+    fn from(error: RequestError) -> Self {
+        match error {
+            RequestError::Http { status, text } => WriteError::Http { status: status.as_u16(), text },
+            error => WriteError::Client(error),
+        }
+    }
+}
+
+/// Quotes a string for a Flux query.
+pub fn flux_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Maximum number of lines per request in low-memory mode, so a large spool
+/// isn't sent (and held) as one request body.
+const LOW_MEMORY_MAX_LINES: usize = 500;
+
+/// Marks the bucket of the following lines in the spool file.
+const SPOOL_BUCKET: &str = "# bucket=";
+const DEFAULT_SPOOL_MAX_SIZE_MB: u64 = 100;
+/// Spooled lines that are sent again per request.
+const SPOOL_CHUNK_LINES: usize = 5000;
+
+/// What happens once the spool reaches its maximum size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Overflow {
+    /// The oldest points are dropped.
+    DropOldest,
+    /// The newest points are dropped.
+    DropNewest,
+    /// The polling pauses until the spool could be written.
+    Block,
+}
+
+/// Which points are dropped first once the spool is full: low before
+/// normal before high priority.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+/// Measurements that are kept longest: daily summaries, events and energy
+/// counters. Their points are few, but can't be recovered from other ones.
+const HIGH_PRIORITY: &[&str] = &["events", "derived_energy", "peak_demand", "inventory", "inverter_efficiency", "battery_plan"];
+/// Measurements that are dropped first: the high-volume values of every cycle.
+const LOW_PRIORITY: &[&str] = &["power_flow", "meter", "inverter_phase", "inverter_mppt", "load_phase", "community"];
+
+/// The priorities of the measurements in the spool.
+#[derive(Debug, Clone, Default)]
+struct Priorities(HashMap<String, Priority>);
+
+impl Priorities {
+    /// The default priorities, changed by `SPOOL_PRIORITIES`, e.g.
+    /// `meter=normal,inverter_info=high`.
+    fn from_env() -> Result<Self, InvalidPriority> {
+        let mut priorities = HashMap::new();
+        for measurement in HIGH_PRIORITY {
+            priorities.insert((*measurement).to_owned(), Priority::High);
+        }
+        for measurement in LOW_PRIORITY {
+            priorities.insert((*measurement).to_owned(), Priority::Low);
+        }
+        let list = std::env::var("SPOOL_PRIORITIES").unwrap_or_default();
+        for rule in list.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let invalid = || InvalidPriority(rule.to_owned());
+            let (measurement, priority) = rule.split_once('=').ok_or_else(invalid)?;
+            let priority = match priority.trim() {
+                "high" => Priority::High,
+                "normal" => Priority::Normal,
+                "low" => Priority::Low,
+                _ => return Err(invalid()),
+            };
+            priorities.insert(measurement.trim().to_owned(), priority);
+        }
+        Ok(Priorities(priorities))
+    }
+
+    fn of(&self, line: &str) -> Priority {
+        let series = split_unescaped(line, ' ')[0];
+        let measurement = split_unescaped(series, ',')[0];
+        self.0.get(measurement).copied().unwrap_or(Priority::Normal)
+    }
+}
+
+/// Bounds of the spool file.
+#[derive(Debug, Clone)]
+struct SpoolLimits {
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    overflow: Overflow,
+    priorities: Priorities,
+}
+
+impl SpoolLimits {
+    /// Reads `SPOOL_MAX_SIZE` (megabytes, default 100, 0 for no limit),
+    /// `SPOOL_MAX_AGE` (hours), `SPOOL_OVERFLOW` and `SPOOL_PRIORITIES`.
+    fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let max_size_mb = match std::env::var("SPOOL_MAX_SIZE") {
+            Ok(val) => val.parse()?,
+            Err(_) => DEFAULT_SPOOL_MAX_SIZE_MB,
+        };
+        let max_size = max_size_mb.checked_mul(1024 * 1024).ok_or(LimitTooLarge("SPOOL_MAX_SIZE", max_size_mb))?;
+        let max_age = match std::env::var("SPOOL_MAX_AGE") {
+            Ok(val) => {
+                let hours = val.parse::<u64>()?;
+                Some(Duration::from_secs(hours.checked_mul(3600).ok_or(LimitTooLarge("SPOOL_MAX_AGE", hours))?))
+            }
+            Err(_) => None,
+        };
+        let overflow = match std::env::var("SPOOL_OVERFLOW").as_deref() {
+            Ok("drop-oldest") | Err(_) => Overflow::DropOldest,
+            Ok("drop-newest") => Overflow::DropNewest,
+            Ok("block") => Overflow::Block,
+            Ok(other) => return Err(InvalidOverflow(other.to_owned()).into()),
+        };
+        Ok(SpoolLimits {
+            max_size: Some(max_size).filter(|max_size| *max_size > 0),
+            max_age,
+            overflow,
+            priorities: Priorities::from_env()?,
+        })
+    }
+
+    /// Whether the line is older than the maximum age.
+    fn expired(&self, line: &str) -> bool {
+        let Some(max_age) = self.max_age else {
+            return false;
+        };
+        let cutoff = chrono::Duration::from_std(max_age)
+            .ok()
+            .and_then(|max_age| Utc::now().checked_sub_signed(max_age))
+            .and_then(|cutoff| cutoff.timestamp_nanos_opt())
+            .unwrap_or(i64::MIN);
+        line_time(line).is_some_and(|time| time < cutoff)
+    }
+
+    /// Drops the spooled lines that are older than the maximum age and those
+    /// beyond `max_size`. The lines of low priority measurements are dropped
+    /// first, within a priority the oldest or newest ones.
+    fn apply(&self, mut lines: Vec<(String, String)>, max_size: u64) -> Vec<(String, String)> {
+        let count = lines.len();
+        lines.retain(|(_, line)| !self.expired(line));
+        let mut size: u64 = lines.iter().map(|(_, line)| line.len() as u64 + 1).sum();
+        let priorities: Vec<_> = lines.iter().map(|(_, line)| self.priorities.of(line)).collect();
+        let mut keep = vec![true; lines.len()];
+        let mut order: Vec<usize> = (0..lines.len()).collect();
+        if self.overflow == Overflow::DropNewest {
+            order.reverse();
+        }
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            for &index in order.iter().filter(|&&index| priorities[index] == priority) {
+                if size <= max_size {
+                    break;
+                }
+                size -= lines[index].1.len() as u64 + 1;
+                keep[index] = false;
+            }
+        }
+        let mut keep = keep.into_iter();
+        lines.retain(|_| keep.next().unwrap_or(true));
+        let dropped = count - lines.len();
+        if dropped > 0 {
+            METRICS.spool_dropped(dropped as u64);
+            warn!("Dropped {dropped} spooled points that exceeded the limits of the spool");
+        }
+        lines
+    }
+}
+
+/// How far a spool file was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Drained {
+    /// Nothing could be written, the file is kept.
+    Nothing,
+    /// All points were written.
+    All,
+    /// Some points were written, the others spooled anew.
+    Respooled,
+}
+
+/// The file the spool is moved to while it is sent.
+fn inflight_path(spool: &Path) -> PathBuf {
+    let mut path = spool.to_owned().into_os_string();
+    path.push(".inflight");
+    PathBuf::from(path)
+}
+
+/// The nanosecond timestamp of a line.
+fn line_time(line: &str) -> Option<i64> {
+    line.rsplit_once(' ')?.1.parse().ok()
+}
+
+/// The InfluxDB client and the runtime it is driven by. Compressed writes
+/// are sent with the plain HTTP client, the InfluxDB client can't set the
+/// content encoding. The InfluxDB client is replaced when the token changes.
+struct Connection {
+    client: Mutex<Client>,
+    http: reqwest::Client,
+    runtime: tokio::runtime::Runtime,
+}
+
+pub struct InfluxSink {
+    url: String,
+    org: String,
+    token: Secret,
+    bucket: String,
+    routes: Routes,
+    connection: OnceLock<Connection>,
+    low_memory: bool,
+    retries: u32,
+    rejected_file: Option<PathBuf>,
+    spool_file: Option<PathBuf>,
+    spool_limits: SpoolLimits,
+    /// Key the spool is encrypted with.
+    spool_key: Option<FileKey>,
+    /// Points in the spool and the file being sent, for the metrics.
+    spool_points: AtomicU64,
+    gzip: bool,
+}
+
+impl InfluxSink {
+    /// Creates the sink from `INFLUX_DB_URL`, `INFLUX_DB_ORG`, `INFLUX_DB_TOKEN`
+    /// (or `INFLUX_DB_TOKEN_FILE`, see the `secret` module) and
+    /// `INFLUX_DB_BUCKET`. Optional are `INFLUX_DB_RETRIES` (default 2),
+    /// `INFLUX_DB_REJECTED_FILE`, the file rejected points are appended to, and
+    /// `SPOOL_FILE`, the file points are kept in while InfluxDB is unreachable,
+    /// with its limits `SPOOL_MAX_SIZE`, `SPOOL_MAX_AGE` and `SPOOL_OVERFLOW`
+    /// and encrypted with `FILE_ENCRYPTION_KEY`, and `INFLUX_DB_GZIP`, which compresses the written data. The buckets of
+    /// the measurements are taken from `ROUTES`.
+    ///
+    /// In low-memory mode the client is only created on the first write and
+    /// runs on a single-threaded runtime.
+    pub fn from_env(low_memory: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let retries = match std::env::var("INFLUX_DB_RETRIES") {
+            Ok(val) => val.parse()?,
+            Err(_) => 2,
+        };
+        let sink = InfluxSink {
+            url: std::env::var("INFLUX_DB_URL")?,
+            org: std::env::var("INFLUX_DB_ORG")?,
+            token: Secret::from_env("INFLUX_DB_TOKEN")?.ok_or("INFLUX_DB_TOKEN or INFLUX_DB_TOKEN_FILE has to be set")?,
+            bucket: std::env::var("INFLUX_DB_BUCKET")?,
+            routes: Routes::from_env()?,
+            connection: OnceLock::new(),
+            low_memory,
+            retries,
+            rejected_file: std::env::var_os("INFLUX_DB_REJECTED_FILE").map(PathBuf::from),
+            spool_file: std::env::var_os("SPOOL_FILE").map(PathBuf::from),
+            spool_limits: SpoolLimits::from_env()?,
+            spool_key: FileKey::from_env()?,
+            spool_points: AtomicU64::new(0),
+            gzip: crate::flag_from_env("INFLUX_DB_GZIP")?,
+        };
+        sink.spool_points.store(sink.count_spooled(), Ordering::Relaxed);
+        sink.report_spool();
+        if !low_memory {
+            sink.connection()?;
+        }
+        Ok(sink)
+    }
+
+    /// A sink that writes all points to `bucket` at `url` and keeps failed
+    /// points in `spool_file`, instead of the configured one.
+    #[cfg(test)]
+    pub fn new(url: &str, bucket: &str, retries: u32, spool_file: Option<PathBuf>) -> Self {
+        InfluxSink {
+            url: url.to_owned(),
+            org: "fronius".to_owned(),
+            token: Secret::fixed("token"),
+            bucket: bucket.to_owned(),
+            routes: Routes::default(),
+            connection: OnceLock::new(),
+            low_memory: false,
+            retries,
+            rejected_file: None,
+            spool_file,
+            spool_limits: SpoolLimits {
+                max_size: None,
+                max_age: None,
+                overflow: Overflow::DropOldest,
+                priorities: Priorities::default(),
+            },
+            spool_key: None,
+            spool_points: AtomicU64::new(0),
+            gzip: false,
+        }
+    }
+
+    fn connection(&self) -> std::io::Result<&Connection> {
+        if let Some(connection) = self.connection.get() {
+            return Ok(connection);
+        }
+        let runtime = if self.low_memory {
+            tokio::runtime::Builder::new_current_thread().enable_all().build()?
+        } else {
+            tokio::runtime::Builder::new_multi_thread().enable_all().build()?
+        };
+        METRICS.add_runtime(runtime.handle().clone());
+        let client = Mutex::new(Client::new(&self.url, &self.org, self.token.get()));
+        let http = reqwest::Client::new();
+        Ok(self.connection.get_or_init(|| Connection { client, http, runtime }))
+    }
+
+    /// The bucket `measurement` is written to, the first one if there are
+    /// several, `None` if it isn't written to InfluxDB.
+    pub fn bucket(&self, measurement: &str) -> Option<&str> {
+        self.routes.influx_buckets(measurement, &self.bucket).next()
+    }
+
+    /// Checks that InfluxDB is reachable, for the `check` command, and
+    /// returns its version.
+    pub fn check_health(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let connection = self.connection()?;
+        let request = connection.http.get(format!("{}/health", self.url.trim_end_matches('/')));
+        let health: serde_json::Value = connection.runtime.block_on(async { request.send().await?.error_for_status()?.json().await })?;
+        Ok(health["version"].as_str().unwrap_or("unknown version").to_owned())
+    }
+
+    /// Checks that the token may write to all buckets of the routes, for the
+    /// `check` command. An empty write is sent to each bucket, so nothing is
+    /// stored. Returns the buckets.
+    pub fn check_buckets(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let connection = self.connection()?;
+        let buckets = self.routes.all_influx_buckets(&self.bucket);
+        for bucket in &buckets {
+            let request = connection
+                .http
+                .post(format!("{}/api/v2/write", self.url.trim_end_matches('/')))
+                .query(&[("org", self.org.as_str()), ("bucket", bucket)])
+                .header(reqwest::header::AUTHORIZATION, format!("Token {}", self.token.get()));
+            let response = connection.runtime.block_on(request.send())?;
+            let status = response.status();
+            // the permissions are checked before the (missing) points, so a
+            // bad request means the write would be allowed
+            if !status.is_success() && status != reqwest::StatusCode::BAD_REQUEST {
+                let text = connection.runtime.block_on(response.text())?;
+                return Err(format!("write to bucket {bucket:?} failed with {status}: {text}").into());
+            }
+        }
+        Ok(buckets.into_iter().map(str::to_owned).collect())
+    }
+
+    /// Runs a Flux query and returns all records.
+    pub fn query(&self, flux: String) -> Result<Vec<FluxRecord>, Box<dyn std::error::Error>> {
+        let connection = self.connection()?;
+        let client = connection.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let query = client.query_raw(Some(Query::new(flux)));
+        Ok(connection.runtime.block_on(query)?)
+    }
+
+    /// Writes the batch in one request. Invalid points are quarantined before
+    /// sending. If InfluxDB still rejects the batch, it is split in halves
+    /// until the offending points are isolated, so only those get lost.
+    ///
+    /// Points that could not be written because of network or server errors
+    /// are spooled (if enabled). Once a write succeeds, the spooled points are
+    /// sent again, see `drain_spool`.
+    ///
+    /// The points are written to the buckets given by their routes, one
+    /// request per bucket. Returns an error if not all points were written,
+    /// even if they were spooled.
+    pub fn write(&self, batch: &Batch) -> Result<(), NotWritten> {
+        let mut buckets: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for point in batch.points() {
+            let line = point.to_line();
+            if let Err(error) = validate(&line) {
+                self.quarantine(&line, &error.to_string());
+                continue;
+            }
+            for bucket in self.routes.influx_buckets(&point.measurement, &self.bucket) {
+                buckets.entry(bucket).or_default().push(line.clone());
+            }
+        }
+        if buckets.is_empty() && !self.has_spool() {
+            return Ok(());
+        }
+        let connection = match self.connection() {
+            Ok(connection) => connection,
+            Err(error) => {
+                METRICS.write_failed();
+                error!("Error during creation of influxdb client occured: {:?}", error);
+                for (bucket, lines) in &buckets {
+                    self.spool(bucket, lines);
+                }
+                return Err(NotWritten(buckets.values().map(Vec::len).sum()));
+            }
+        };
+        telemetry::span("write influxdb", || {
+            let mut failed = 0;
+            for (bucket, lines) in &buckets {
+                let max_lines = if self.low_memory { LOW_MEMORY_MAX_LINES } else { lines.len() };
+                for chunk in lines.chunks(max_lines) {
+                    if let Err(error) = self.write_bisect(connection, bucket, chunk) {
+                        METRICS.write_failed();
+                        error!("Error during influxdb write occured: {:?}", error);
+                        self.spool(bucket, chunk);
+                        failed += chunk.len();
+                    }
+                }
+            }
+            // while InfluxDB is unreachable the spool isn't read at all
+            if failed > 0 {
+                return Err(NotWritten(failed));
+            }
+            self.drain_spool(connection);
+            Ok(())
+        })
+    }
+
+    /// Writes the lines, a rejected request is split until the rejected
+    /// points are isolated and quarantined. Network and server errors are
+    /// returned, the caller spools the lines.
+    fn write_bisect(&self, connection: &Connection, bucket: &str, lines: &[String]) -> Result<(), WriteError> {
+        match self.write_lines(connection, bucket, lines) {
+            Ok(()) => {
+                METRICS.points_written(lines.len() as u64);
+                Ok(())
+            }
+            Err(WriteError::Http { status, text }) if is_rejection(status) => {
+                if lines.len() == 1 {
+                    self.quarantine(&lines[0], &format!("rejected by InfluxDB ({status}): {text}"));
+                    return Ok(());
+                }
+                let (first, second) = lines.split_at(lines.len() / 2);
+                self.write_bisect(connection, bucket, first)?;
+                self.write_bisect(connection, bucket, second)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Appends lines to the spool file, they are lost if spooling is disabled.
+    /// Lines of other buckets than the default one follow a `# bucket=<name>`
+    /// line. With a key the appended lines are sealed as one.
+    fn spool(&self, bucket: &str, lines: &[String]) {
+        let Some(path) = &self.spool_file else {
+            return;
+        };
+        let mut text = self.spool_text(bucket, lines);
+        if let Some(key) = &self.spool_key {
+            text = match key.seal(text.as_bytes()) {
+                Ok(sealed) => sealed + "\n",
+                Err(error) => {
+                    METRICS.spool_dropped(lines.len() as u64);
+                    error!("Error during encryption of {} points for the spool occured, dropping them: {:?}", lines.len(), error);
+                    return;
+                }
+            };
+        }
+        let res = OpenOptions::new().create(true).append(true).open(path).and_then(|mut file| file.write_all(text.as_bytes()));
+        match res {
+            Ok(()) => {
+                self.spool_points.fetch_add(lines.len() as u64, Ordering::Relaxed);
+                warn!("Spooled {} points to {:?}", lines.len(), path);
+            }
+            Err(error) => error!("Error during spooling to {:?} occured: {:?}", path, error),
+        }
+        if let Err(error) = self.limit_spool(path) {
+            error!("Error during limiting of spool {:?} occured: {:?}", path, error);
+        }
+        self.report_spool();
+    }
+
+    /// The lines as they are spooled, with the `# bucket=` lines.
+    fn spool_text(&self, bucket: &str, lines: &[String]) -> String {
+        let mut text = String::new();
+        if bucket != self.bucket {
+            text.push_str(&format!("{SPOOL_BUCKET}{bucket}\n"));
+        }
+        for line in lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        if bucket != self.bucket {
+            text.push_str(&format!("{SPOOL_BUCKET}{}\n", self.bucket));
+        }
+        text
+    }
+
+    /// Rewrites the spool within its limits once it exceeds the maximum size,
+    /// unless the polling is blocked instead. It is shrunk to 90% of the
+    /// maximum size, so it isn't rewritten again by the next append.
+    fn limit_spool(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(max_size) = self.spool_limits.max_size.filter(|_| self.spool_limits.overflow != Overflow::Block) else {
+            return Ok(());
+        };
+        if std::fs::metadata(path)?.len() <= max_size {
+            return Ok(());
+        }
+        let opened = encryption::open(&std::fs::read_to_string(path)?, self.spool_key.as_ref())?;
+        self.quarantine_damaged(path, &opened.damaged);
+        let lines = self.parse_spool(&opened.plaintext);
+        let count = lines.len();
+        let lines = self.spool_limits.apply(lines, max_size / 10 * 9);
+        self.unspool((count - lines.len()) as u64);
+        let mut text = String::new();
+        for (bucket, lines) in lines.chunk_by(|(a, _), (b, _)| a == b).map(|run| (run[0].0.as_str(), run)) {
+            let lines: Vec<String> = lines.iter().map(|(_, line)| line.clone()).collect();
+            text.push_str(&self.spool_text(bucket, &lines));
+        }
+        if let Some(key) = &self.spool_key {
+            text = key.seal(text.as_bytes())? + "\n";
+        }
+        let mut tmp = path.to_owned().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// The spooled lines with their buckets.
+    fn parse_spool(&self, content: &str) -> Vec<(String, String)> {
+        let mut bucket = self.bucket.as_str();
+        let mut lines = Vec::new();
+        for line in content.lines() {
+            if let Some(name) = line.strip_prefix(SPOOL_BUCKET) {
+                bucket = name;
+            } else if !line.is_empty() && !line.starts_with('#') {
+                lines.push((bucket.to_owned(), line.to_owned()));
+            }
+        }
+        lines
+    }
+
+    /// Moves the lines of the spool `path` that can't be decrypted to the
+    /// rejected points, so the rest of the spool can still be sent.
+    fn quarantine_damaged(&self, path: &Path, damaged: &[String]) {
+        if damaged.is_empty() {
+            return;
+        }
+        warn!("Skipping {} damaged lines of the spool {:?} that can't be decrypted", damaged.len(), path);
+        for line in damaged {
+            self.quarantine(line, "damaged spool line");
+        }
+    }
+
+    /// Removes points from the count of spooled points.
+    fn unspool(&self, count: u64) {
+        let _ = self.spool_points.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |points| Some(points.saturating_sub(count)));
+    }
+
+    /// Counts the points in the spool files, once on startup.
+    fn count_spooled(&self) -> u64 {
+        let Some(path) = &self.spool_file else {
+            return 0;
+        };
+        let mut count = 0;
+        for path in [path.clone(), inflight_path(path)] {
+            let Ok(file) = File::open(&path) else {
+                continue;
+            };
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                let Ok(opened) = encryption::open(&line, self.spool_key.as_ref()) else {
+                    continue;
+                };
+                count += opened.plaintext.lines().filter(|line| !line.is_empty() && !line.starts_with('#')).count() as u64;
+            }
+        }
+        count
+    }
+
+    /// Updates the metrics of the spool.
+    fn report_spool(&self) {
+        let Some(path) = &self.spool_file else {
+            return;
+        };
+        let bytes = [path.clone(), inflight_path(path)]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        METRICS.spool_size(self.spool_points.load(Ordering::Relaxed), bytes);
+    }
+
+    /// Whether there are spooled points to send.
+    fn has_spool(&self) -> bool {
+        self.spool_file.as_ref().is_some_and(|path| path.exists() || inflight_path(path).exists())
+    }
+
+    /// Sends the spooled points again. The spool is renamed to
+    /// `<spool>.inflight` while it is sent and only removed once all of its
+    /// points are written or spooled anew, so a failed write or a crash loses
+    /// none of them. A spool left behind by a crash is sent first.
+    fn drain_spool(&self, connection: &Connection) {
+        let Some(path) = &self.spool_file else {
+            return;
+        };
+        self.drain_files(connection, path);
+        self.report_spool();
+    }
+
+    fn drain_files(&self, connection: &Connection, path: &Path) {
+        let inflight = inflight_path(path);
+        loop {
+            if !inflight.exists() {
+                match std::fs::rename(path, &inflight) {
+                    Ok(()) => {}
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => return,
+                    Err(error) => {
+                        error!("Error during rename of spool {:?} occured: {:?}", path, error);
+                        return;
+                    }
+                }
+            }
+            let drained = match self.send_spooled(connection, &inflight) {
+                Ok(drained) => drained,
+                Err(error) => {
+                    error!("Error during read of spool {:?} occured: {:?}", inflight, error);
+                    return;
+                }
+            };
+            if drained == Drained::Nothing {
+                return;
+            }
+            if let Err(error) = std::fs::remove_file(&inflight) {
+                error!("Error during removal of spool {:?} occured: {:?}", inflight, error);
+                return;
+            }
+            if drained == Drained::Respooled {
+                return;
+            }
+        }
+    }
+
+    /// Sends the lines of a spool file in chunks, dropping those beyond the
+    /// maximum age. If the first chunk fails, the file is left as it is; if
+    /// a later one fails, it and the rest of the file are spooled anew.
+    fn send_spooled(&self, connection: &Connection, path: &Path) -> Result<Drained, Box<dyn std::error::Error>> {
+        let max_lines = if self.low_memory { LOW_MEMORY_MAX_LINES } else { SPOOL_CHUNK_LINES };
+        let mut bucket = self.bucket.clone();
+        let mut chunk: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut chunk_lines = 0;
+        let mut expired = 0;
+        // all points of the file, which are written, dropped or spooled anew once it is sent
+        let mut read = 0;
+        let mut state = Drained::Nothing;
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        loop {
+            let line = lines.next().transpose()?;
+            if let Some(line) = &line {
+                let opened = encryption::open(line, self.spool_key.as_ref())?;
+                self.quarantine_damaged(path, &opened.damaged);
+                for line in opened.plaintext.lines() {
+                    if let Some(name) = line.strip_prefix(SPOOL_BUCKET) {
+                        name.clone_into(&mut bucket);
+                        continue;
+                    }
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    read += 1;
+                    if self.spool_limits.expired(line) {
+                        expired += 1;
+                    } else if let Err(error) = validate(line) {
+                        self.quarantine(line, &error.to_string());
+                    } else {
+                        chunk.entry(bucket.clone()).or_default().push(line.to_owned());
+                        chunk_lines += 1;
+                    }
+                }
+            }
+            if chunk_lines >= max_lines || (line.is_none() && chunk_lines > 0) {
+                for (bucket, lines) in std::mem::take(&mut chunk) {
+                    if state == Drained::Respooled {
+                        self.spool(&bucket, &lines);
+                        continue;
+                    }
+                    match self.write_bisect(connection, &bucket, &lines) {
+                        Ok(()) => state = Drained::All,
+                        // nothing is written yet, the file can be sent again as it is
+                        Err(error) if state == Drained::Nothing => {
+                            warn!("Sending the spool {:?} again failed: {error}", path);
+                            return Ok(Drained::Nothing);
+                        }
+                        Err(error) => {
+                            METRICS.write_failed();
+                            error!("Error during influxdb write occured: {:?}", error);
+                            self.spool(&bucket, &lines);
+                            state = Drained::Respooled;
+                        }
+                    }
+                }
+                chunk_lines = 0;
+            }
+            if line.is_none() {
+                break;
+            }
+        }
+        if expired > 0 {
+            METRICS.spool_dropped(expired);
+            warn!("Dropped {expired} spooled points that exceeded the limits of the spool");
+        }
+        self.unspool(read);
+        // a spool without any points to send is done as well
+        Ok(if state == Drained::Nothing { Drained::All } else { state })
+    }
+
+    /// Whether the spool is full and the polling should pause
+    /// (`SPOOL_OVERFLOW=block`).
+    pub fn blocked(&self) -> bool {
+        let (Some(path), Some(max_size)) = (&self.spool_file, self.spool_limits.max_size) else {
+            return false;
+        };
+        self.spool_limits.overflow == Overflow::Block && std::fs::metadata(path).is_ok_and(|metadata| metadata.len() >= max_size)
+    }
+
+    /// Sends the lines, transient errors (network, server errors) are retried.
+    /// The timestamps are sent in the unit of `TIMESTAMP_PRECISION`, which
+    /// makes the body shorter than with nanoseconds.
+    fn write_lines(&self, connection: &Connection, bucket: &str, lines: &[String]) -> Result<(), WriteError> {
+        let precision = timestamp::precision();
+        let body = lines.iter().map(|line| with_precision(line, precision)).collect::<Vec<_>>().join("\n");
+        let mut attempt = 0;
+        loop {
+            let res = self.send(connection, bucket, &body, precision);
+            match res {
+                Err(WriteError::Http { status, .. }) if (400..500).contains(&status) => return res,
+                Err(error) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("InfluxDB write failed, retrying ({attempt}/{}): {error}", self.retries);
+                    std::thread::sleep(Duration::from_secs(attempt as u64));
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn send(&self, connection: &Connection, bucket: &str, body: &str, precision: Precision) -> Result<(), WriteError> {
+        let influx_precision = match precision {
+            Precision::Seconds => TimestampPrecision::Seconds,
+            Precision::Milliseconds => TimestampPrecision::Milliseconds,
+            Precision::Microseconds => TimestampPrecision::Microseconds,
+            Precision::Nanoseconds => TimestampPrecision::Nanoseconds,
+        };
+        if !self.gzip {
+            let client = connection.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let write = client.write_line_protocol_with_precision(&self.org, bucket, body.to_owned(), influx_precision);
+            return Ok(connection.runtime.block_on(write)?);
+        }
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body.as_bytes())?;
+        let request = connection
+            .http
+            .post(format!("{}/api/v2/write", self.url.trim_end_matches('/')))
+            .query(&[("org", self.org.as_str()), ("bucket", bucket), ("precision", precision.unit())])
+            .header(reqwest::header::AUTHORIZATION, format!("Token {}", self.token.get()))
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .body(encoder.finish()?);
+        connection.runtime.block_on(async {
+            let response = request.send().await?;
+            let status = response.status();
+            if status != reqwest::StatusCode::NO_CONTENT {
+                let text = response.text().await?;
+                return Err(WriteError::Http { status: status.as_u16(), text });
+            }
+            Ok(())
+        })
+    }
+
+    fn quarantine(&self, line: &str, reason: &str) {
+        METRICS.point_rejected();
+        error!("Dropping point ({reason}): {line}");
+
+        let Some(path) = &self.rejected_file else {
+            return;
+        };
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "# {} {}\n{}", Utc::now().to_rfc3339(), reason, line));
+        if let Err(error) = res {
+            error!("Error during write of rejected point to {:?} occured: {:?}", path, error);
+        }
+    }
+}
+
+impl Sink for InfluxSink {
+    fn name(&self) -> &'static str {
+        "InfluxDB"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(InfluxSink::write(self, batch)?)
+    }
+
+    fn blocked(&self) -> bool {
+        InfluxSink::blocked(self)
+    }
+
+    fn refresh_credentials(&self, force: bool) {
+        if !self.token.refresh(force) {
+            return;
+        }
+        if let Some(connection) = self.connection.get() {
+            *connection.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Client::new(&self.url, &self.org, self.token.get());
+        }
+    }
+}
+
+/// Whether InfluxDB refused the data itself (and sending it again won't help).
+fn is_rejection(status: u16) -> bool {
+    status == 400 || status == 422
+}
+
+/// The line with the nanosecond timestamp converted to `precision`.
+fn with_precision(line: &str, precision: Precision) -> String {
+    match line.rsplit_once(' ').and_then(|(rest, time)| Some((rest, time.parse::<i64>().ok()?))) {
+        Some((rest, time)) if precision != Precision::Nanoseconds => format!("{rest} {}", precision.in_unit(time)),
+        _ => line.to_owned(),
+    }
+}
+
+/// Checks that a line protocol line has a measurement, at least one field,
+/// only finite numbers and an integer timestamp.
+pub fn validate(line: &str) -> Result<(), InvalidPoint> {
+    let parts = split_unescaped(line, ' ');
+    let [series, fields, timestamp] = parts.as_slice() else {
+        return Err(InvalidPoint("expected measurement, fields and timestamp"));
+    };
+
+    let measurement = split_unescaped(series, ',')[0];
+    if measurement.is_empty() {
+        return Err(InvalidPoint("empty measurement"));
+    }
+
+    for field in split_unescaped(fields, ',') {
+        let Some((key, value)) = field.split_once('=') else {
+            return Err(InvalidPoint("field without value"));
+        };
+        if key.is_empty() || value.is_empty() {
+            return Err(InvalidPoint("empty field key or value"));
+        }
+        let number = value.trim_end_matches(['i', 'u']);
+        if !value.starts_with('"') && number.parse::<f64>().is_ok_and(|n| !n.is_finite()) {
+            return Err(InvalidPoint("field value is not a finite number"));
+        }
+    }
+
+    if timestamp.parse::<i64>().is_err() {
+        return Err(InvalidPoint("invalid timestamp"));
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Writing of the points to InfluxDB 1.x over its HTTP API, for the many
+//! installations that never moved to InfluxDB 2.
+//!
+//! Only available with the `influxdb1` cargo feature and enabled by
+//! `INFLUX1_URL` (e.g. `http://influxdb:8086`). The points are written as
+//! line protocol to the database `INFLUX1_DATABASE` (default `fronius`),
+//! which is created if it doesn't exist (the user needs the admin privilege
+//! for that, or the database has to exist), and to its default
+//! retention policy unless `INFLUX1_RETENTION_POLICY` is set. With
+//! `INFLUX1_USER` and `INFLUX1_PASSWORD` the requests are authenticated.
+//! Unsigned integers are written as integers, InfluxDB 1.x has none.
+//! Unlike the InfluxDB 2 sink there is no spool: points that can't be
+//! written are lost.
+
+use std::time::Duration;
+
+use log::{error, info};
+use reqwest::blocking::{Client, RequestBuilder};
+
+use crate::{
+    point::{FieldValue, Point},
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_DATABASE: &str = "fronius";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Influx1Sink {
+    client: Client,
+    url: String,
+    database: String,
+    retention_policy: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+    routes: Routes,
+}
+
+impl Influx1Sink {
+    /// Creates the sink for `url` and the database, if InfluxDB is
+    /// reachable. Optional are `INFLUX1_DATABASE`,
+    /// `INFLUX1_RETENTION_POLICY`, `INFLUX1_USER` and `INFLUX1_PASSWORD`.
+    pub fn from_env(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let sink = Influx1Sink {
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            url: url.trim_end_matches('/').to_owned(),
+            database: std::env::var("INFLUX1_DATABASE").unwrap_or_else(|_| DEFAULT_DATABASE.to_owned()),
+            retention_policy: std::env::var("INFLUX1_RETENTION_POLICY").ok().filter(|rp| !rp.is_empty()),
+            user: std::env::var("INFLUX1_USER").ok(),
+            password: std::env::var("INFLUX1_PASSWORD").ok(),
+            routes: Routes::from_env()?,
+        };
+        // InfluxDB may start after the collector, the database is created
+        // when a write doesn't find it then
+        if let Err(error) = sink.create_database() {
+            error!("Error during creation of the InfluxDB 1 database occured: {:?}", error);
+        }
+        info!("Writing points to InfluxDB 1 database {} at {}", sink.database, sink.url);
+        Ok(sink)
+    }
+
+    fn create_database(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let query = format!("CREATE DATABASE \"{}\"", self.database.replace('\\', "\\\\").replace('"', "\\\""));
+        let request = self.client.post(format!("{}/query", self.url)).form(&[("q", query)]);
+        self.send(request)
+    }
+
+    /// Sends the request with the credentials, if any.
+    fn send(&self, mut request: RequestBuilder) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(user) = &self.user {
+            request = request.basic_auth(user, self.password.as_ref());
+        }
+        let response = request.send()?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("{status}: {}", response.text().unwrap_or_default().trim()).into());
+        }
+        Ok(())
+    }
+}
+
+/// The point with the unsigned integers as integers, which InfluxDB 1.x
+/// doesn't have.
+fn signed(point: &Point) -> Point {
+    let mut point = point.clone();
+    for (_, value) in &mut point.fields {
+        if let FieldValue::UInteger(unsigned) = *value {
+            *value = i64::try_from(unsigned).map_or(FieldValue::Float(unsigned as f64), FieldValue::Integer);
+        }
+    }
+    point
+}
+
+impl Sink for Influx1Sink {
+    fn name(&self) -> &'static str {
+        "InfluxDB 1"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut body = String::new();
+        for point in batch.points() {
+            if self.routes.targets(&point.measurement).contains(&Target::Influx1) {
+                body.push_str(&signed(point).to_line());
+                body.push('\n');
+            }
+        }
+        if body.is_empty() {
+            return Ok(());
+        }
+        let mut params = vec![("db", self.database.as_str()), ("precision", "ns")];
+        if let Some(retention_policy) = &self.retention_policy {
+            params.push(("rp", retention_policy));
+        }
+        let write = || self.send(self.client.post(format!("{}/write", self.url)).query(&params).body(body.clone()));
+        match write() {
+            Err(error) if error.to_string().contains("database not found") => {
+                self.create_database()?;
+                write()
+            }
+            result => result,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! The `inventory` measurement: one point per device of a site with its
+//! class, serial number, model and firmware, so dashboards get a device table
+//! without requesting the info endpoints of the Datamanager.
+//!
+//! The inventory is requested at startup and with every check of the
+//! firmware (`FIRMWARE_CHECK_INTERVAL`). It is only written if it changed.
+
+use std::collections::HashMap;
+
+use fronius_api::fronius::{self, blocking::Fronius, DeviceId, DeviceType};
+use point_derive::ToPoint;
+use serde::Serialize;
+
+use crate::{
+    sink::{Batch, DeviceTags},
+    timestamp::{self, TimestampError},
+};
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, ToPoint)]
+#[measurement = "inventory"]
+pub struct InventoryData {
+    #[influxdb(tag)]
+    class: String,
+    #[influxdb(field)]
+    number: Option<i64>,
+    #[influxdb(field)]
+    serial: String,
+    #[influxdb(field)]
+    device_type: Option<i64>,
+    #[influxdb(field)]
+    manufacturer: Option<String>,
+    #[influxdb(field)]
+    model: Option<String>,
+    #[influxdb(field)]
+    firmware: Option<String>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Manufacturer, model and firmware of a device, if its realtime data
+/// reports them.
+#[derive(Default)]
+struct Details {
+    manufacturer: Option<String>,
+    model: Option<String>,
+    firmware: Option<String>,
+}
+
+/// The devices of a site, as last requested.
+#[derive(Default)]
+pub struct Inventory {
+    devices: Vec<(InventoryData, DeviceTags)>,
+    /// Whether the inventory changed since it was last written.
+    changed: bool,
+}
+
+impl Inventory {
+    /// Requests the Datamanager and its devices. `tags` gives the
+    /// `device_id` and `device_name` tags of a device. Returns whether the
+    /// inventory changed.
+    pub fn update(&mut self, fronius: &Fronius, tags: impl Fn(DeviceType, &DeviceId) -> DeviceTags) -> Result<bool, fronius::Error> {
+        let logger = fronius.get_logger_info()?;
+        let infos = fronius.get_active_device_info()?;
+        let mut details = details(fronius, &infos);
+
+        let mut devices = vec![(
+            InventoryData {
+                class: "Datamanager".to_owned(),
+                number: None,
+                serial: logger.unique_id.clone(),
+                device_type: None,
+                manufacturer: Some("Fronius".to_owned()),
+                model: logger.product_id,
+                firmware: logger.sw_version,
+                time: 0,
+            },
+            DeviceTags {
+                id: logger.unique_id,
+                name: String::new(),
+            },
+        )];
+        for (device_type, infos) in infos {
+            for (id, info) in infos {
+                let (Ok(id), Some(info)) = (id.parse::<DeviceId>(), info) else {
+                    continue;
+                };
+                let details = details.remove(&(device_type, id.to_string())).unwrap_or_default();
+                devices.push((
+                    InventoryData {
+                        class: format!("{device_type:?}"),
+                        number: Some(u8::from(id).into()),
+                        serial: info.serial,
+                        device_type: Some(info.dt),
+                        manufacturer: details.manufacturer,
+                        model: details.model,
+                        firmware: details.firmware,
+                        time: 0,
+                    },
+                    tags(device_type, &id),
+                ));
+            }
+        }
+        devices.sort_by(|(a, _), (b, _)| (&a.class, a.number).cmp(&(&b.class, b.number)));
+
+        if devices == self.devices {
+            return Ok(false);
+        }
+        self.devices = devices;
+        self.changed = true;
+        Ok(true)
+    }
+
+    /// Pushes the inventory to the batch, if it changed since it was last
+    /// written.
+    pub fn push_changed(&mut self, batch: &mut Batch) -> Result<(), TimestampError> {
+        if !self.changed {
+            return Ok(());
+        }
+        let time = timestamp::now()?;
+        for (device, tags) in &self.devices {
+            batch.push_device(&InventoryData { time, ..device.clone() }, tags);
+        }
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Requests the realtime data of the meters, storages and Ohmpilots for
+/// their details. Errors are ignored, the details are only informational.
+fn details(fronius: &Fronius, infos: &fronius::DeviceInfos) -> HashMap<(DeviceType, String), Details> {
+    let installed = |device_type| infos.get(&device_type).is_some_and(|devices| !devices.is_empty());
+    let mut details = HashMap::new();
+    if installed(DeviceType::Meter) {
+        for (id, meter) in fronius.get_meter_realtime_data_system().unwrap_or_default() {
+            details.insert((DeviceType::Meter, id), Details {
+                manufacturer: Some(meter.details.manufacturer),
+                model: Some(meter.details.model),
+                firmware: None,
+            });
+        }
+    }
+    if installed(DeviceType::Storage) {
+        for (id, storage) in fronius.get_storage_realtime_data_system().unwrap_or_default() {
+            details.insert((DeviceType::Storage, id), Details {
+                manufacturer: Some(storage.controller.details.manufacturer),
+                model: Some(storage.controller.details.model),
+                firmware: None,
+            });
+        }
+    }
+    if installed(DeviceType::Ohmpilot) {
+        for (id, ohm_pilot) in fronius.get_ohm_pilot_realtime_data_system().unwrap_or_default() {
+            details.insert((DeviceType::Ohmpilot, id), Details {
+                manufacturer: Some(ohm_pilot.details.manufacturer),
+                model: Some(ohm_pilot.details.model),
+                firmware: Some(ohm_pilot.details.software),
+            });
+        }
+    }
+    details
+}
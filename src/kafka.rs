@@ -0,0 +1,121 @@
+//! Publishing of the points to Kafka via the Confluent REST Proxy, so the
+//! collector needs no Kafka client library.
+//!
+//! Only available with the `kafka` cargo feature and enabled by
+//! `KAFKA_REST_URL` (e.g. `http://kafka-rest:8082`). Every point is one
+//! record of the topic `KAFKA_TOPIC` (default `fronius`, `{measurement}` in
+//! the name gives one topic per measurement) with the series of the point as
+//! key, so the points of a device keep their order, and a JSON object with
+//! `measurement`, `time` (Unix timestamp in nanoseconds), `tags` and
+//! `fields` as value. With `KAFKA_REST_USER` and `KAFKA_REST_PASSWORD` the
+//! requests are authenticated.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use log::info;
+use reqwest::blocking::Client;
+use serde::Deserialize;
+
+use crate::{
+    point::Point,
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_TOPIC: &str = "fronius";
+const CONTENT_TYPE: &str = "application/vnd.kafka.json.v2+json";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct KafkaSink {
+    client: Client,
+    url: String,
+    topic: String,
+    user: Option<String>,
+    password: Option<String>,
+    routes: Routes,
+}
+
+/// The answer of the REST Proxy, with an offset or an error per record.
+#[derive(Deserialize)]
+struct Produced {
+    offsets: Vec<Offset>,
+}
+
+#[derive(Deserialize)]
+struct Offset {
+    error: Option<String>,
+}
+
+impl KafkaSink {
+    /// Creates the sink for `url`. Optional are `KAFKA_TOPIC`,
+    /// `KAFKA_REST_USER` and `KAFKA_REST_PASSWORD`.
+    pub fn from_env(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let sink = KafkaSink {
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            url: url.trim_end_matches('/').to_owned(),
+            topic: std::env::var("KAFKA_TOPIC").unwrap_or_else(|_| DEFAULT_TOPIC.to_owned()),
+            user: std::env::var("KAFKA_REST_USER").ok(),
+            password: std::env::var("KAFKA_REST_PASSWORD").ok(),
+            routes: Routes::from_env()?,
+        };
+        info!("Publishing points to the Kafka topic {} via {}", sink.topic, sink.url);
+        Ok(sink)
+    }
+
+    fn produce(&self, topic: &str, records: Vec<serde_json::Value>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut request = self
+            .client
+            .post(format!("{}/topics/{topic}", self.url))
+            .header(reqwest::header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(serde_json::json!({ "records": records }).to_string());
+        if let Some(user) = &self.user {
+            request = request.basic_auth(user, self.password.as_ref());
+        }
+        let response = request.send()?;
+        if !response.status().is_success() {
+            let status = response.status();
+            return Err(format!("{status}: {}", response.text().unwrap_or_default().trim()).into());
+        }
+        let produced: Produced = response.json()?;
+        let failed: Vec<String> = produced.offsets.into_iter().filter_map(|offset| offset.error).collect();
+        if let Some(error) = failed.first() {
+            return Err(format!("{} of the records to {topic} failed: {error}", failed.len()).into());
+        }
+        Ok(())
+    }
+}
+
+/// The record of a point.
+fn record(point: &Point) -> serde_json::Value {
+    let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+    let fields: serde_json::Map<_, _> = point.fields.iter().map(|(key, value)| (key.clone(), value.to_json())).collect();
+    serde_json::json!({
+        "key": point.series(),
+        "value": {
+            "measurement": point.measurement,
+            "time": point.time,
+            "tags": tags,
+            "fields": fields,
+        },
+    })
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "Kafka"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut topics: BTreeMap<String, Vec<serde_json::Value>> = BTreeMap::new();
+        for point in batch.points() {
+            if self.routes.targets(&point.measurement).contains(&Target::Kafka) {
+                let topic = self.topic.replace("{measurement}", &point.measurement);
+                topics.entry(topic).or_default().push(record(point));
+            }
+        }
+        for (topic, records) in topics {
+            self.produce(&topic, records)?;
+        }
+        Ok(())
+    }
+}
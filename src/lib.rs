@@ -0,0 +1,9 @@
+//! Library part of the collector: the client of the Fronius Solar API and
+//! the polling loop, for use in other programs.
+
+// the collector runs unattended, bad data of a device must not stop it
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used, clippy::panic))]
+
+#[cfg(feature = "blocking")]
+pub mod collector;
+pub mod fronius;
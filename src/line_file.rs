@@ -0,0 +1,157 @@
+//! Writing of the points as InfluxDB line protocol to rotating files, as
+//! backup or to capture data without any database (offline capture). The
+//! files can be loaded later with `influx write --file` or the
+//! `replay-spool` command.
+//!
+//! Enabled by `LINE_FILE`. The file is rotated daily by default
+//! (`LINE_FILE_ROTATE`: `hourly`, `daily` or `never`) and when it reaches
+//! `LINE_FILE_MAX_SIZE` megabytes: it is renamed to `<file>.<time>` and a new
+//! file is started. The file is opened again for every write, so it can be
+//! rotated by logrotate as well (with `create` or `copytruncate`).
+//!
+//! Unlike the spool, the files aren't encrypted with `FILE_ENCRYPTION_KEY`:
+//! they are an export for other tools, which can't read sealed lines.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chrono::prelude::*;
+use log::info;
+use thiserror::Error;
+
+use crate::{
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+    timezone,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid rotation {0:?} in LINE_FILE_ROTATE, expected hourly, daily or never")]
+pub struct InvalidRotation(String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl Rotation {
+    /// The suffix of the files of the period `time` is in.
+    fn period(self, time: DateTime<FixedOffset>) -> Option<String> {
+        match self {
+            Rotation::Hourly => Some(time.format("%Y%m%dT%H").to_string()),
+            Rotation::Daily => Some(time.format("%Y%m%d").to_string()),
+            Rotation::Never => None,
+        }
+    }
+}
+
+pub struct LineFileSink {
+    path: PathBuf,
+    rotation: Rotation,
+    max_size: Option<u64>,
+    /// Period of the current file, `None` until the first write.
+    period: Mutex<Option<String>>,
+    routes: Routes,
+}
+
+impl LineFileSink {
+    /// Creates the sink for `path`. Optional are `LINE_FILE_ROTATE` and
+    /// `LINE_FILE_MAX_SIZE`.
+    pub fn from_env(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let rotation = match std::env::var("LINE_FILE_ROTATE").as_deref() {
+            Ok("daily") | Err(_) => Rotation::Daily,
+            Ok("hourly") => Rotation::Hourly,
+            Ok("never") => Rotation::Never,
+            Ok(other) => return Err(InvalidRotation(other.to_owned()).into()),
+        };
+        let max_size = match std::env::var("LINE_FILE_MAX_SIZE") {
+            Ok(val) => Some(val.parse::<u64>()? * 1024 * 1024),
+            Err(_) => None,
+        };
+        info!("Writing points as line protocol to {path:?}");
+        Ok(LineFileSink {
+            path: PathBuf::from(path),
+            rotation,
+            max_size,
+            period: Mutex::new(None),
+            routes: Routes::from_env()?,
+        })
+    }
+
+    /// Renames the current file to `<file>.<suffix>`, with a counter if that
+    /// exists already.
+    fn rotate(&self, suffix: &str) -> std::io::Result<()> {
+        let mut target = suffixed(&self.path, suffix);
+        let mut counter = 1;
+        while target.exists() {
+            target = suffixed(&self.path, &format!("{suffix}-{counter}"));
+            counter += 1;
+        }
+        std::fs::rename(&self.path, &target)?;
+        info!("Rotated {:?} to {:?}", self.path, target);
+        Ok(())
+    }
+
+    /// Rotates the file if its period is over or it is too large.
+    fn rotate_if_needed(&self) -> std::io::Result<()> {
+        let metadata = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let mut period = self.period.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let current = self.rotation.period(timezone::now());
+        // after a restart the period of the existing file is taken from the
+        // time it was last written
+        let file_period = period.clone().or_else(|| {
+            let modified = DateTime::<Utc>::from(metadata.modified().ok()?);
+            self.rotation.period(timezone::local(modified))
+        });
+        if let Some(file_period) = file_period.filter(|file_period| Some(file_period) != current.as_ref()) {
+            self.rotate(&file_period)?;
+        } else if self.max_size.is_some_and(|max_size| metadata.len() >= max_size) {
+            self.rotate(&timezone::now().format("%Y%m%dT%H%M%S").to_string())?;
+        }
+        *period = current;
+        Ok(())
+    }
+
+    fn append(&self, lines: &str) -> std::io::Result<()> {
+        self.rotate_if_needed()?;
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(lines.as_bytes())
+    }
+}
+
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+impl Sink for LineFileSink {
+    fn name(&self) -> &'static str {
+        "line protocol file"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut lines = String::new();
+        for point in batch.points() {
+            if self.routes.targets(&point.measurement).contains(&Target::File) {
+                lines.push_str(&point.to_line());
+                lines.push('\n');
+            }
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.append(&lines).map_err(|error| format!("append to {:?} failed: {error}", self.path))?;
+        Ok(())
+    }
+}
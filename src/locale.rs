@@ -0,0 +1,121 @@
+//! Formatting of numbers and units for outputs read by people, e.g. the
+//! `/api/summary` endpoint. Outputs read by machines (points, `/api/latest`,
+//! metrics) always keep the raw values.
+//!
+//! `LOCALE` selects the separators (`en` by default, e.g. `de` for a
+//! decimal comma) and `POWER_KW_THRESHOLD` the power in W from which kW are
+//! shown (default 1000).
+
+use std::sync::OnceLock;
+
+use thiserror::Error;
+
+static LOCALE: OnceLock<Locale> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid LOCALE {0:?}, expected a language like en, de or fr")]
+pub struct InvalidLocale(String);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Locale {
+    decimal: char,
+    thousands: char,
+    /// Power in W from which kW are shown.
+    kw_threshold: f64,
+}
+
+impl Default for Locale {
+    fn default() -> Self {
+        Locale {
+            decimal: '.',
+            thousands: ',',
+            kw_threshold: 1000.0,
+        }
+    }
+}
+
+impl Locale {
+    /// The separators of a language or locale like `de` or `de_AT.UTF-8`.
+    pub fn new(locale: &str, kw_threshold: f64) -> Result<Self, InvalidLocale> {
+        let language = locale.split(['_', '-', '.']).next().unwrap_or_default().to_ascii_lowercase();
+        let (decimal, thousands) = match language.as_str() {
+            "en" | "ja" | "zh" | "ko" | "he" => ('.', ','),
+            "de" | "nl" | "it" | "es" | "pt" | "da" | "id" | "tr" | "el" | "sl" | "hr" | "ro" => (',', '.'),
+            "fr" | "pl" | "cs" | "sk" | "sv" | "fi" | "nb" | "no" | "hu" | "ru" | "uk" | "bg" => (',', '\u{202f}'),
+            _ => return Err(InvalidLocale(locale.to_owned())),
+        };
+        // Switzerland groups with an apostrophe, also in German
+        let (decimal, thousands) = if locale.to_ascii_uppercase().contains("_CH") { ('.', '\'') } else { (decimal, thousands) };
+        Ok(Locale {
+            decimal,
+            thousands,
+            kw_threshold,
+        })
+    }
+
+    /// A number with `decimals` digits after the separator.
+    pub fn number(&self, value: f64, decimals: usize) -> String {
+        let formatted = format!("{:.*}", decimals, value.abs());
+        let (integer, fraction) = formatted.split_once('.').unwrap_or((&formatted, ""));
+        let mut out = String::new();
+        // no minus for a value that rounds to zero
+        if value < 0.0 && formatted.bytes().any(|digit| matches!(digit, b'1'..=b'9')) {
+            out.push('-');
+        }
+        for (i, digit) in integer.chars().enumerate() {
+            if i > 0 && (integer.len() - i) % 3 == 0 {
+                out.push(self.thousands);
+            }
+            out.push(digit);
+        }
+        if !fraction.is_empty() {
+            out.push(self.decimal);
+            out.push_str(fraction);
+        }
+        out
+    }
+
+    /// A power in W, as kW from the threshold on.
+    pub fn power(&self, watts: f64) -> String {
+        if watts.abs().round() >= self.kw_threshold {
+            format!("{} kW", self.number(watts / 1000.0, 2))
+        } else {
+            format!("{} W", self.number(watts, 0))
+        }
+    }
+
+    /// An energy in Wh, as kWh or MWh if it is large enough.
+    pub fn energy(&self, watt_hours: f64) -> String {
+        match watt_hours.abs().round() {
+            wh if wh >= 1_000_000.0 => format!("{} MWh", self.number(watt_hours / 1_000_000.0, 2)),
+            wh if wh >= 1000.0 => format!("{} kWh", self.number(watt_hours / 1000.0, 1)),
+            _ => format!("{} Wh", self.number(watt_hours, 0)),
+        }
+    }
+
+    pub fn percent(&self, percent: f64) -> String {
+        format!("{} %", self.number(percent, 0))
+    }
+}
+
+/// Reads `LOCALE` and `POWER_KW_THRESHOLD`.
+pub fn init_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    let kw_threshold = match std::env::var("POWER_KW_THRESHOLD") {
+        Ok(val) => val.parse()?,
+        Err(_) => Locale::default().kw_threshold,
+    };
+    let locale = match std::env::var("LOCALE") {
+        Ok(locale) => Locale::new(&locale, kw_threshold)?,
+        Err(_) => Locale {
+            kw_threshold,
+            ..Locale::default()
+        },
+    };
+    LOCALE.get_or_init(|| locale);
+    Ok(())
+}
+
+/// The configured locale, the default one before `init_from_env`.
+pub fn get() -> Locale {
+    LOCALE.get().copied().unwrap_or_default()
+}
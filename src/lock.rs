@@ -0,0 +1,58 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum LockError {
+    #[error("another collector instance is already running (lock file {0:?} is held by pid {1})")]
+    AlreadyRunning(PathBuf, String),
+    #[error("could not use lock file {0:?}")]
+    Io(PathBuf, #[source] std::io::Error),
+}
+
+/// Exclusive lock on a file, held until the collector exits.
+pub struct InstanceLock {
+    _file: File,
+}
+
+impl InstanceLock {
+    /// Locks `path` and writes the own PID into it. Fails if another process
+    /// already holds the lock.
+    pub fn acquire(path: &Path) -> Result<Self, LockError> {
+        let io_error = |e| LockError::Io(path.to_owned(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(io_error)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut pid = String::new();
+                let _ = file.read_to_string(&mut pid);
+                return Err(LockError::AlreadyRunning(path.to_owned(), pid.trim().to_owned()));
+            }
+            Err(TryLockError::Error(e)) => return Err(io_error(e)),
+        }
+
+        file.set_len(0).map_err(io_error)?;
+        file.rewind().map_err(io_error)?;
+        writeln!(file, "{}", std::process::id()).map_err(io_error)?;
+        Ok(InstanceLock { _file: file })
+    }
+
+    /// Acquires the lock file given by `LOCK_FILE`, if set.
+    pub fn from_env() -> Result<Option<Self>, LockError> {
+        match std::env::var_os("LOCK_FILE") {
+            Some(path) => Ok(Some(InstanceLock::acquire(Path::new(&path))?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -1,29 +1,134 @@
-use std::{net::IpAddr, str::FromStr};
+// the collector runs unattended, bad data of a device must not stop it
+#![cfg_attr(not(test), deny(clippy::unwrap_used, clippy::expect_used, clippy::panic))]
 
-use fronius::{DeviceId, Fronius};
-use influxdb2::Client;
-use influxdb2_derive::WriteDataPoint;
-use chrono::prelude::*;
-mod fronius;
+use std::{borrow::Cow, collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
 
-#[derive(Debug)]
-struct OptionEmptyError {
-    variable_name: String
-}
+use fronius_api::collector::{self, Collector};
+use fronius_api::fronius::{
+    self,
+    blocking::{Fronius, FroniusBuilder},
+    DeviceId, DeviceType,
+};
+use battery::BatterySign;
+use sink::{Batch, DeviceTags};
+use point_derive::ToPoint;
+use events::Events;
+use grid_quality::GridQuality;
+use log::{debug, error, info, warn};
+use metrics::METRICS;
+use planner::Planner;
+use serde::Serialize;
+use snapshot::Snapshot;
+use thiserror::Error;
+#[cfg(feature = "adx")]
+mod adx;
+#[cfg(feature = "influxdb2")]
+mod backfill;
+mod battery;
+mod check;
+mod clock;
+#[cfg(feature = "clickhouse")]
+mod clickhouse;
+mod community;
+mod control;
+#[cfg(feature = "csv")]
+mod csv_file;
+#[cfg(feature = "display")]
+mod display;
+mod efficiency;
+mod encryption;
+mod energy;
+mod error_summary;
+mod events;
+#[cfg(feature = "influxdb2")]
+mod export;
+mod grafana;
+mod grid_quality;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "http")]
+mod http_server;
+#[cfg(feature = "influxdb2")]
+mod import;
+#[cfg(feature = "influxdb2")]
+mod influx;
+#[cfg(feature = "influxdb1")]
+mod influx1;
+mod inventory;
+#[cfg(feature = "kafka")]
+mod kafka;
+mod line_file;
+mod locale;
+mod lock;
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod peak;
+mod pipeline;
+mod planner;
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "questdb")]
+mod questdb;
+#[cfg(feature = "redis")]
+mod redis;
+mod rediscovery;
+mod replay;
+mod point;
+mod routing;
+mod schema;
+mod secret;
+mod sink;
+#[cfg(feature = "script")]
+mod script;
+mod snapshot;
+#[cfg(feature = "snmp")]
+mod snmp;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod state;
+mod status;
+#[cfg(unix)]
+mod syslog;
+mod telemetry;
+mod timestamp;
+#[cfg(feature = "timestream")]
+mod timestream;
+mod timezone;
+#[cfg(all(feature = "tray", target_os = "linux"))]
+mod tray;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "wattpilot")]
+mod wattpilot;
+#[cfg(windows)]
+mod service;
+#[cfg(test)]
+mod tests;
 
-impl std::error::Error for OptionEmptyError {}
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+/// Cycles in a row without an answer of the Datamanager after which it is
+/// connected again.
+const RECONNECT_AFTER: u32 = 4;
+/// Shortest time between two reconnects of a site.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(300);
+/// Time between the attempts to connect a site that couldn't be connected
+/// yet.
+const CONNECT_INTERVAL: Duration = Duration::from_secs(60);
 
-impl std::fmt::Display for OptionEmptyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Could not access option value for '{}'", self.variable_name)
-    }
-}
+/// The Datamanager of a site couldn't be connected yet, another attempt is
+/// made after `CONNECT_INTERVAL`.
+#[derive(Debug, Error)]
+#[error("site {0} is not connected yet")]
+struct NotConnectedError(String);
 
-#[derive(Default, Debug, WriteDataPoint)]
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "inverter"]
 struct InverterData {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
     #[influxdb(field)]
     ac_power: Option<f64>,
     #[influxdb(field)]
@@ -44,12 +149,40 @@ struct InverterData {
     time: i64,
 }
 
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "inverter_mppt"]
+struct InverterMpptData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(tag)]
+    tracker: String,
+    #[influxdb(field)]
+    dc_current: Option<f64>,
+    #[influxdb(field)]
+    dc_voltage: Option<f64>,
+    #[influxdb(field)]
+    dc_power: Option<f64>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
 
-fn get_inverter_data(fronius: &Fronius, device_id: &DeviceId) -> Result<InverterData, Box<dyn std::error::Error>> {
-    let response = fronius.get_inverter_realtime_data_device::<fronius::CommonInverterData>(device_id.to_owned())?;
+/// Returns the inverter data and the DC values of every MPPT tracker, both
+/// come from the same request.
+fn inverter_data_from(response: fronius::CommonInverterData) -> Result<(InverterData, Vec<InverterMpptData>), Box<dyn std::error::Error>> {
+    let mut mppt = Vec::new();
+    for channel in response.mppt_channels() {
+        mppt.push(InverterMpptData {
+            device: "Inverter",
+            tracker: channel.tracker.to_string(),
+            dc_current: channel.current,
+            dc_voltage: channel.voltage,
+            dc_power: channel.current.zip(channel.voltage).map(|(current, voltage)| current * voltage),
+            time: timestamp::now()?,
+        });
+    }
 
     let data = InverterData {
-        device: "Inverter".to_owned(),
+        device: "Inverter",
         ac_power: response.pac.value,
         ac_power_abs: response.sac.value,
         ac_current: response.iac.value,
@@ -61,16 +194,16 @@ fn get_inverter_data(fronius: &Fronius, device_id: &DeviceId) -> Result<Inverter
         dc_current: response.idc.value,
         dc_voltage: response.udc.value,
         total_energy: response.total_energy.value,
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        time: timestamp::now()?,
     };
-    Ok(data)
+    Ok((data, mppt))
 }
 
-#[derive(Default, Debug, WriteDataPoint)]
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "inverter_phase"]
 struct InverterPhaseData {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
     #[influxdb(field)]
     ac_l1_current: Option<f64>,
     #[influxdb(field)]
@@ -78,35 +211,107 @@ struct InverterPhaseData {
     #[influxdb(field)]
     ac_l3_current: Option<f64>,
     #[influxdb(field)]
-    dc_l1_voltage: Option<f64>,
+    ac_l1_voltage: Option<f64>,
+    #[influxdb(field)]
+    ac_l2_voltage: Option<f64>,
+    #[influxdb(field)]
+    ac_l3_voltage: Option<f64>,
+    #[influxdb(field)]
+    ac_l1_power: Option<f64>,
+    #[influxdb(field)]
+    ac_l2_power: Option<f64>,
     #[influxdb(field)]
-    dc_l2_voltage: Option<f64>,
+    ac_l3_power: Option<f64>,
     #[influxdb(field)]
-    dc_l3_voltage: Option<f64>,
+    ac_l1_apparent_power: Option<f64>,
+    #[influxdb(field)]
+    ac_l2_apparent_power: Option<f64>,
+    #[influxdb(field)]
+    ac_l3_apparent_power: Option<f64>,
     #[influxdb(timestamp)]
     time: i64,
 }
 
-fn get_inverter_phase_data(fronius: &Fronius, device_id: &DeviceId) -> Result<InverterPhaseData, Box<dyn std::error::Error>> {
-    let response = fronius.get_inverter_realtime_data_device::<fronius::ThreePhaseInverterData>(device_id.to_owned())?;
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "inverter_temperature"]
+struct InverterTemperatureData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    ambient: f64,
+    #[influxdb(field)]
+    fan_front_left_speed: Option<f64>,
+    #[influxdb(field)]
+    fan_front_right_speed: Option<f64>,
+    #[influxdb(field)]
+    fan_back_left_speed: Option<f64>,
+    #[influxdb(field)]
+    fan_back_right_speed: Option<f64>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Returns the phase data and, if the inverter reports it, its internal
+/// temperature. Both come from the same request.
+fn inverter_phase_data_from(response: fronius::ThreePhaseInverterData) -> Result<(InverterPhaseData, Option<InverterTemperatureData>), Box<dyn std::error::Error>> {
+    let temperature = match response.t_ambient.as_ref().and_then(|v| v.value) {
+        Some(ambient) => Some(InverterTemperatureData {
+            device: "Inverter",
+            ambient,
+            fan_front_left_speed: response.rotation_speed_fan_fl.as_ref().and_then(|v| v.value),
+            fan_front_right_speed: response.rotation_speed_fan_fr.as_ref().and_then(|v| v.value),
+            fan_back_left_speed: response.rotation_speed_fan_bl.as_ref().and_then(|v| v.value),
+            fan_back_right_speed: response.rotation_speed_fan_br.as_ref().and_then(|v| v.value),
+            time: timestamp::now()?,
+        }),
+        None => None,
+    };
     let data = InverterPhaseData {
-        device: "Inverter".to_owned(),
+        device: "Inverter",
         ac_l1_current: response.iac_l1.value,
         ac_l2_current: response.iac_l2.value,
         ac_l3_current: response.iac_l3.value,
-        dc_l1_voltage: response.uac_l1.value,
-        dc_l2_voltage: response.uac_l2.value,
-        dc_l3_voltage: response.uac_l3.value,
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        ac_l1_voltage: response.uac_l1.value,
+        ac_l2_voltage: response.uac_l2.value,
+        ac_l3_voltage: response.uac_l3.value,
+        ac_l1_power: response.pac_l1.and_then(|v| v.value),
+        ac_l2_power: response.pac_l2.and_then(|v| v.value),
+        ac_l3_power: response.pac_l3.and_then(|v| v.value),
+        ac_l1_apparent_power: apparent_power(response.sac_l1, &response.uac_l1, &response.iac_l1),
+        ac_l2_apparent_power: apparent_power(response.sac_l2, &response.uac_l2, &response.iac_l2),
+        ac_l3_apparent_power: apparent_power(response.sac_l3, &response.uac_l3, &response.iac_l3),
+        time: timestamp::now()?,
     };
-    Ok(data)
+    Ok((data, temperature))
 }
 
-#[derive(Default, Debug, WriteDataPoint)]
+impl InverterPhaseData {
+    /// Active power per phase, estimated from voltage and current if the
+    /// firmware doesn't report it.
+    fn phase_powers(&self) -> Option<[f64; 3]> {
+        let power = |power: Option<f64>, voltage: Option<f64>, current: Option<f64>| power.or(Some(voltage? * current?));
+        Some([
+            power(self.ac_l1_power, self.ac_l1_voltage, self.ac_l1_current)?,
+            power(self.ac_l2_power, self.ac_l2_voltage, self.ac_l2_current)?,
+            power(self.ac_l3_power, self.ac_l3_voltage, self.ac_l3_current)?,
+        ])
+    }
+}
+
+/// Apparent power of a phase, calculated from voltage and current if the
+/// firmware doesn't report it.
+fn apparent_power(reported: Option<fronius::UnitAndValue<f64>>, voltage: &fronius::UnitAndValue<f64>, current: &fronius::UnitAndValue<f64>) -> Option<f64> {
+    match reported.and_then(|v| v.value) {
+        Some(value) => Some(value),
+        None => Some(voltage.value? * current.value?),
+    }
+}
+
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "inverter_info"]
 struct InverterInfo {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
     #[influxdb(field)]
     device_type: i64,
     #[influxdb(field)]
@@ -120,37 +325,115 @@ struct InverterInfo {
     #[influxdb(field)]
     error_code: i64,
     #[influxdb(field)]
-    status_code: String,
+    status: Option<i64>,
+    #[influxdb(field)]
+    status_code: Option<String>,
     #[influxdb(field)]
-    state: String,
+    state: Option<String>,
     #[influxdb(timestamp)]
     time: i64,
 }
 
-fn get_inverter_info(fronius: &Fronius, device_id: &DeviceId) -> Result<InverterInfo, Box<dyn std::error::Error>> {
-    let device_id = u8::from(device_id.to_owned()).to_string();
-    let res = fronius.get_inverter_info()?;
-    let response = res[&device_id].as_ref().expect("Invalid device id");
+fn inverter_info_from(response: fronius::InverterInfo) -> Result<InverterInfo, Box<dyn std::error::Error>> {
+    let status_fields = schema::status_fields();
     let data = InverterInfo {
-        device: "Inverter".to_owned(),
+        device: "Inverter",
         device_type: response.dt,
         pv_power: response.pv_power,
-        name: response.custom_name.to_owned(),
+        name: response.custom_name,
         is_visualized: response.show > 0,
-        id: response.unique_id.to_owned(),
+        id: response.unique_id,
         error_code: response.error_code,
-        status_code: response.status_code.to_string(),
-        state: response.inverter_state.to_owned(),
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        status: status_fields.numeric(response.status_code as i64),
+        status_code: status_fields.label(response.status_code),
+        state: status_fields.label(&response.inverter_state),
+        time: timestamp::now()?,
     };
     Ok(data)
 }
 
-#[derive(Default, Debug, WriteDataPoint)]
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "derating"]
+struct DeratingData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    derated: Option<bool>,
+    #[influxdb(field)]
+    reason: String,
+    #[influxdb(field)]
+    error_code: i64,
+    #[influxdb(field)]
+    power: Option<f64>,
+    #[influxdb(field)]
+    power_limit: Option<f64>,
+    #[influxdb(field)]
+    relative_power: Option<f64>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// State code reported by Symo/Primo inverters while the power is reduced
+/// because of a too high temperature.
+const STATE_CODE_TEMPERATURE_DERATING: i64 = 517;
+/// Share of the power limit from which an inverter counts as limited by it.
+const POWER_LIMIT_REACHED: f64 = 0.98;
+
+/// Classifies the power limitation of an inverter. The Solar API reports
+/// neither the active power limit nor the feed-in limit, so the limit is the
+/// one of `INVERTER_POWER_LIMITS`. Besides the temperature derating no state
+/// code names a limitation, so without a limit the reason is `unknown`.
+fn derating_reason(error_code: i64, relative_power: Option<f64>) -> &'static str {
+    match (error_code, relative_power) {
+        (STATE_CODE_TEMPERATURE_DERATING, _) => "temperature",
+        (_, Some(relative_power)) if relative_power >= POWER_LIMIT_REACHED => "power_limit",
+        (0, Some(_)) => "none",
+        _ => "unknown",
+    }
+}
+
+/// The active power limits in W per inverter given by `INVERTER_POWER_LIMITS`
+/// (e.g. `1=8000,2=4600`), as set in the Datamanager.
+fn power_limits_from_env() -> Result<HashMap<DeviceId, f64>, Box<dyn std::error::Error>> {
+    let list = std::env::var("INVERTER_POWER_LIMITS").unwrap_or_default();
+    let mut limits = HashMap::new();
+    for entry in list.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let (device_id, limit) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid power limit {entry:?}, expected e.g. \"1=8000\""))?;
+        limits.insert(device_id.trim().parse()?, limit.trim().parse()?);
+    }
+    Ok(limits)
+}
+
+fn get_derating_data(inverter: &InverterData, info: &InverterInfo, power_limit: Option<f64>) -> Result<DeratingData, Box<dyn std::error::Error>> {
+    let relative_power = match (inverter.ac_power, power_limit) {
+        (Some(power), Some(limit)) if limit > 0.0 => Some(power / limit),
+        _ => None,
+    };
+    let reason = derating_reason(info.error_code, relative_power);
+    let data = DeratingData {
+        device: "Inverter",
+        derated: match reason {
+            "temperature" | "power_limit" => Some(true),
+            "none" => Some(false),
+            _ => None,
+        },
+        reason: reason.to_owned(),
+        error_code: info.error_code,
+        power: inverter.ac_power,
+        power_limit,
+        relative_power,
+        time: timestamp::now()?,
+    };
+    Ok(data)
+}
+
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "meter"]
 struct MeterData {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
     #[influxdb(field)]
     l1_current: Option<f64>,
     #[influxdb(field)]
@@ -180,15 +463,38 @@ struct MeterData {
     #[influxdb(field)]
     power: f64,
     #[influxdb(field)]
+    l1_reactive_power: Option<f64>,
+    #[influxdb(field)]
+    l2_reactive_power: Option<f64>,
+    #[influxdb(field)]
+    l3_reactive_power: Option<f64>,
+    #[influxdb(field)]
+    reactive_power: f64,
+    #[influxdb(field)]
+    l1_apparent_power: Option<f64>,
+    #[influxdb(field)]
+    l2_apparent_power: Option<f64>,
+    #[influxdb(field)]
+    l3_apparent_power: Option<f64>,
+    #[influxdb(field)]
+    apparent_power: f64,
+    #[influxdb(field)]
+    l1_power_factor: Option<f64>,
+    #[influxdb(field)]
+    l2_power_factor: Option<f64>,
+    #[influxdb(field)]
+    l3_power_factor: Option<f64>,
+    #[influxdb(field)]
+    power_factor: f64,
+    #[influxdb(field)]
     frequency_average: f64,
     #[influxdb(timestamp)]
     time: i64,
 }
 
-fn get_meter_data(fronius: &Fronius, device_id: &DeviceId) -> Result<MeterData, Box<dyn std::error::Error>> {
-    let response = fronius.get_meter_realtime_data_device(device_id)?;
+fn meter_data_from(response: fronius::MeterData) -> Result<MeterData, Box<dyn std::error::Error>> {
     let data = MeterData {
-        device: "Meter".to_owned(),
+        device: "Meter",
         l1_current: response.current_ac_phase_1,
         l2_current: response.current_ac_phase_2,
         l3_current: response.current_ac_phase_3,
@@ -203,86 +509,180 @@ fn get_meter_data(fronius: &Fronius, device_id: &DeviceId) -> Result<MeterData,
         l2_power: response.power_real_p_phase_2,
         l3_power: response.power_real_p_phase_3,
         power: response.power_real_p_sum,
+        l1_reactive_power: response.power_reactive_q_phase_1,
+        l2_reactive_power: response.power_reactive_q_phase_2,
+        l3_reactive_power: response.power_reactive_q_phase_3,
+        reactive_power: response.power_reactive_q_sum,
+        l1_apparent_power: response.power_apparent_s_phase_1,
+        l2_apparent_power: response.power_apparent_s_phase_2,
+        l3_apparent_power: response.power_apparent_s_phase_3,
+        apparent_power: response.power_apparent_s_sum,
+        l1_power_factor: response.power_factor_phase_1,
+        l2_power_factor: response.power_factor_phase_2,
+        l3_power_factor: response.power_factor_phase_3,
+        power_factor: response.power_factor_sum,
         frequency_average: response.frequency_phase_average,
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        time: timestamp::now()?,
     };
     Ok(data)
 }
 
-#[derive(Default, Debug, WriteDataPoint)]
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "load_phase"]
+struct LoadPhaseData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    l1_load: f64,
+    #[influxdb(field)]
+    l2_load: f64,
+    #[influxdb(field)]
+    l3_load: f64,
+    #[influxdb(field)]
+    load: f64,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// The household load per phase is what the inverters feed in plus what is
+/// drawn from the grid (negative while feeding in) on that phase.
+fn get_load_phase_data(inverter_power: [f64; 3], meter: &MeterData) -> Result<Option<LoadPhaseData>, Box<dyn std::error::Error>> {
+    let (Some(l1), Some(l2), Some(l3)) = (meter.l1_power, meter.l2_power, meter.l3_power) else {
+        return Ok(None);
+    };
+    let loads = [inverter_power[0] + l1, inverter_power[1] + l2, inverter_power[2] + l3];
+    let data = LoadPhaseData {
+        device: "Load",
+        l1_load: loads[0],
+        l2_load: loads[1],
+        l3_load: loads[2],
+        load: loads.iter().sum(),
+        time: timestamp::now()?,
+    };
+    Ok(Some(data))
+}
+
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "storage"]
 struct StorageData {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
+    #[influxdb(tag)]
+    manufacturer: String,
+    #[influxdb(tag)]
+    model: String,
     #[influxdb(field)]
     enabled: bool,
     #[influxdb(field)]
+    status: Option<u64>,
+    #[influxdb(field)]
     charge_percentage: f64,
     #[influxdb(field)]
     capacity: f64,
     #[influxdb(field)]
     dc_current: f64,
     #[influxdb(field)]
+    dc_current_raw: Option<f64>,
+    #[influxdb(field)]
     dc_voltage: f64,
     #[influxdb(field)]
     temperature_cell: f64,
+    #[influxdb(field)]
+    designed_capacity: Option<f64>,
+    #[influxdb(field)]
+    cell_voltage_max: Option<f64>,
+    #[influxdb(field)]
+    cell_voltage_min: Option<f64>,
     #[influxdb(timestamp)]
     time: i64,
 }
 
-fn get_storage_data(fronius: &Fronius, device_id: &DeviceId) -> Result<StorageData, Box<dyn std::error::Error>> {
-    let response = fronius.get_storage_realtime_data_device(device_id)?;
+fn storage_data_from(response: fronius::StorageData, battery: BatterySign) -> Result<StorageData, Box<dyn std::error::Error>> {
+    let details = response.controller.details;
     let data = StorageData {
-        device: "Storage".to_owned(),
+        device: "Storage",
+        manufacturer: details.manufacturer,
+        model: details.model,
         enabled: response.controller.enable > 0,
+        status: response.controller.status_battery_cell,
         charge_percentage: response.controller.state_of_charge_relative,
         capacity: response.controller.capacity_maximum,
-        dc_current: response.controller.current_dc,
+        dc_current: battery.normalize(response.controller.current_dc),
+        dc_current_raw: battery.raw(Some(response.controller.current_dc)),
         dc_voltage: response.controller.voltage_dc,
         temperature_cell: response.controller.temperature_cell,
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        designed_capacity: response.controller.designed_capacity,
+        cell_voltage_max: response.controller.voltage_dc_maximum_cell,
+        cell_voltage_min: response.controller.voltage_dc_minimum_cell,
+        time: timestamp::now()?,
     };
     Ok(data)
 }
 
-#[derive(Default, Debug, WriteDataPoint)]
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "ohm_pilot"]
 struct OhmPilotData {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
+    #[influxdb(field)]
+    state_code: Option<i64>,
     #[influxdb(field)]
-    state: String,
+    state: Option<String>,
     #[influxdb(field)]
     error_code: i64,
     #[influxdb(field)]
     power: f64,
     #[influxdb(field)]
+    l1_power: Option<f64>,
+    #[influxdb(field)]
+    l2_power: Option<f64>,
+    #[influxdb(field)]
+    l3_power: Option<f64>,
+    #[influxdb(field)]
+    l1_voltage: Option<f64>,
+    #[influxdb(field)]
+    l2_voltage: Option<f64>,
+    #[influxdb(field)]
+    l3_voltage: Option<f64>,
+    #[influxdb(field)]
+    energy_consumed: f64,
+    #[influxdb(field)]
     temperature: f64,
     #[influxdb(timestamp)]
     time: i64,
 }
 
-fn get_ohm_pilot_data(fronius: &Fronius, device_id: &DeviceId) -> Result<OhmPilotData, Box<dyn std::error::Error>> {
-    let response = fronius.get_ohm_pilot_realtime_data_device(device_id)?;
+fn ohm_pilot_data_from(response: fronius::OhmPilotData) -> Result<OhmPilotData, Box<dyn std::error::Error>> {
+    let status_fields = schema::status_fields();
     let data = OhmPilotData {
-        device: "OhmPilot".to_owned(),
-        state: response.code_of_state.to_string(),
+        device: "OhmPilot",
+        state_code: status_fields.numeric(response.code_of_state as i64),
+        state: status_fields.label(response.code_of_state),
         error_code: response.code_of_error.unwrap_or(0),
         power: response.power_real_pac_sum,
+        l1_power: response.power_real_pac_phase_1,
+        l2_power: response.power_real_pac_phase_2,
+        l3_power: response.power_real_pac_phase_3,
+        l1_voltage: response.voltage_ac_phase_1,
+        l2_voltage: response.voltage_ac_phase_2,
+        l3_voltage: response.voltage_ac_phase_3,
+        energy_consumed: response.energy_real_wac_sum_consumed,
         temperature: response.temperature_channel_1,
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        time: timestamp::now()?,
     };
     Ok(data)
 }
 
-#[derive(Default, Debug, WriteDataPoint)]
+#[derive(Default, Debug, Serialize, ToPoint)]
 #[measurement = "power_flow"]
 struct PowerFlowData {
     #[influxdb(tag)]
-    device: String,
+    device: &'static str,
     #[influxdb(field)]
     akku: Option<f64>,
     #[influxdb(field)]
+    akku_raw: Option<f64>,
+    #[influxdb(field)]
     grid: Option<f64>,
     #[influxdb(field)]
     load: Option<f64>,
@@ -292,115 +692,1131 @@ struct PowerFlowData {
     relative_autonomy: Option<f64>,
     #[influxdb(field)]
     relative_self_consumption: Option<f64>,
+    #[influxdb(field)]
+    battery_charge: Option<f64>,
+    #[influxdb(field)]
+    battery_discharge: Option<f64>,
+    #[influxdb(field)]
+    inverter_ac: f64,
+    #[influxdb(field)]
+    pv_production: f64,
+    #[influxdb(field)]
+    mode: String,
+    #[influxdb(field)]
+    meter_location: Option<String>,
+    #[influxdb(field)]
+    battery_standby: Option<bool>,
+    #[influxdb(field)]
+    energy_day: Option<f64>,
+    #[influxdb(field)]
+    energy_year: Option<f64>,
+    #[influxdb(field)]
+    energy_total: Option<f64>,
     #[influxdb(timestamp)]
     time: i64
 }
 
 
-fn get_power_flow_data(fronius: &Fronius) -> Result<PowerFlowData, Box<dyn std::error::Error>> {
-    let response = fronius.get_power_flow_realtime_data()?;
+fn power_flow_data_from(response: fronius::PowerFlowData, battery: BatterySign) -> Result<PowerFlowData, Box<dyn std::error::Error>> {
+    // on hybrid inverters the AC power includes the battery flows, the
+    // normalized P_Akku is positive while discharging and negative while
+    // charging
+    let inverter_ac: f64 = response.inverters.values().map(|inverter| inverter.p).sum();
+    let akku = response.site.p_akku.map(|p| battery.normalize(p));
     let data = PowerFlowData {
-        device: "Unknown".to_owned(),
-        akku: response.site.p_akku,
+        device: "Unknown",
+        akku,
+        akku_raw: battery.raw(response.site.p_akku),
         grid: response.site.p_grid,
         load: response.site.p_load,
         photovoltaik: response.site.p_pv,
         relative_autonomy: response.site.rel_autonomy,
         relative_self_consumption: response.site.rel_self_consumption,
-        time: Utc::now().timestamp_nanos_opt().expect("Could not fetch timestamp"),
+        battery_charge: akku.map(|p| (-p).max(0.0)),
+        battery_discharge: akku.map(|p| p.max(0.0)),
+        inverter_ac,
+        pv_production: inverter_ac - akku.unwrap_or(0.0),
+        mode: response.site.mode,
+        meter_location: response.site.meter_location,
+        battery_standby: response.site.battery_standby,
+        energy_day: response.site.e_day,
+        energy_year: response.site.e_year,
+        energy_total: response.site.e_total,
+        time: timestamp::now()?,
     };
     Ok(data)
 }
 
-fn send_to_influx(client: &Client, bucket: &str,  data: impl futures::Stream<Item = impl influxdb2::models::WriteDataPoint> + Send + Sync + 'static){
-    let res = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .unwrap()
-        .block_on(client.write(bucket, data));
+/// Logs a failed fetch. Devices in standby or night mode are expected to be
+/// unreachable, so they are only reported at debug level.
+fn report_fetch_error(dataset: &str, device: Option<&DeviceId>, error: Box<dyn std::error::Error>) {
+    METRICS.fetch_failed(dataset);
+    telemetry::record_fetch_error(dataset);
+    let device = device.map(ToString::to_string).unwrap_or_default();
+    if let Some(fronius::Error::DeviceOffline(_)) = error.downcast_ref::<fronius::Error>() {
+        debug!(endpoint = dataset, device = device.as_str(); "Skipping {dataset}, device is offline: {error}");
+    } else {
+        error_summary::report(dataset, &device, error.as_ref());
+    }
+}
+
+/// Returns the value, or reports the error and returns `None`.
+fn ok_or_report<T>(dataset: &str, device: Option<&DeviceId>, result: Result<T, Box<dyn std::error::Error>>) -> Option<T> {
+    result.map_err(|error| report_fetch_error(dataset, device, error)).ok()
+}
+
+/// Runs the fetch of `dataset` in a span and records its duration.
+fn timed_fetch<T>(dataset: &str, fetch: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = telemetry::span(format!("fetch {dataset}"), fetch);
+    METRICS.fetch_finished(dataset, start.elapsed());
+    result
+}
+
+fn duration_from_env(variable_name: &str, default: u64) -> Result<Duration, Box<dyn std::error::Error>> {
+    let secs = match std::env::var(variable_name) {
+        Ok(val) => val.parse()?,
+        Err(_) => default,
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+fn flag_from_env(variable_name: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    match std::env::var(variable_name) {
+        Ok(val) => Ok(val.parse()?),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Reads the device IDs to poll from the environment. Every variable accepts
+/// a list like `1-3` or `0,2`, or `auto` to poll all devices that are
+/// reported as active by the Datamanager.
+fn devices_from_env(fronius: &Fronius) -> Result<collector::Devices, Box<dyn std::error::Error>> {
+    Ok(collector::Devices {
+        inverters: device_ids_from_env(fronius, "FRONIUS_INVERTERS", DeviceType::Inverter, "1")?,
+        meters: device_ids_from_env(fronius, "FRONIUS_METERS", DeviceType::Meter, "0")?,
+        storages: device_ids_from_env(fronius, "FRONIUS_STORAGES", DeviceType::Storage, "0")?,
+        ohm_pilots: device_ids_from_env(fronius, "FRONIUS_OHM_PILOTS", DeviceType::Ohmpilot, "0")?,
+    })
+}
+
+/// The time given by `UNSUPPORTED_TIMEOUT` in hours (default 24) after which
+/// a request that keeps answering "not supported" is disabled, 0 never
+/// disables one.
+fn unsupported_timeout_from_env() -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+    let hours: u64 = match std::env::var("UNSUPPORTED_TIMEOUT") {
+        Ok(val) => val.parse()?,
+        Err(_) => 24,
+    };
+    Ok((hours > 0).then(|| Duration::from_secs(hours * 3600)))
+}
+
+/// The `device_id` and `device_name` tags of the polled devices.
+#[derive(Default)]
+struct DeviceRegistry {
+    tags: HashMap<(DeviceType, DeviceId), DeviceTags>,
+}
+
+impl DeviceRegistry {
+    /// Looks up the serial numbers of the devices and the unique IDs and
+    /// custom names of the inverters. Devices that can't be looked up are
+    /// identified by their device ID, e.g. `meter/0`.
+    fn resolve(fronius: &Fronius) -> Self {
+        let mut registry = DeviceRegistry::default();
+        match fronius.get_active_device_info() {
+            Ok(infos) => {
+                for (device_type, devices) in infos {
+                    for (id, info) in devices {
+                        if let (Ok(id), Some(info)) = (id.parse(), info) {
+                            registry.tags.insert((device_type, id), DeviceTags { id: info.serial, name: String::new() });
+                        }
+                    }
+                }
+            }
+            Err(error) => warn!("Error during lookup of the device serials occured: {:?}", error),
+        }
+        match fronius.get_inverter_info() {
+            Ok(infos) => {
+                for (id, info) in infos {
+                    if let (Ok(id), Some(info)) = (id.parse(), info) {
+                        registry.update_inverter(&id, &info.unique_id, &info.custom_name);
+                    }
+                }
+            }
+            Err(error) => warn!("Error during lookup of the inverter names occured: {:?}", error),
+        }
+        registry
+    }
+
+    /// The tags of a device, borrowed unless the device couldn't be looked up.
+    fn get(&self, device_type: DeviceType, id: &DeviceId) -> Cow<'_, DeviceTags> {
+        match self.tags.get(&(device_type, *id)) {
+            Some(tags) => Cow::Borrowed(tags),
+            None => Cow::Owned(DeviceTags {
+                id: format!("{device_type:?}/{id}").to_lowercase(),
+                name: String::new(),
+            }),
+        }
+    }
+
+    /// Updates the tags of an inverter, its custom name can be changed at
+    /// any time. Surrounding whitespace of the name is dropped. Unchanged
+    /// tags are kept, so the polling doesn't allocate them every cycle.
+    fn update_inverter(&mut self, id: &DeviceId, unique_id: &str, custom_name: &str) {
+        let custom_name = custom_name.trim();
+        if unique_id.is_empty() {
+            return;
+        }
+        let key = (DeviceType::Inverter, *id);
+        if self.tags.get(&key).is_some_and(|tags| tags.id == unique_id && tags.name == custom_name) {
+            return;
+        }
+        self.tags.insert(
+            key,
+            DeviceTags {
+                id: unique_id.to_owned(),
+                name: custom_name.to_owned(),
+            },
+        );
+    }
+}
+
+fn device_ids_from_env(fronius: &Fronius, variable_name: &str, device_type: DeviceType, default: &str) -> Result<Vec<DeviceId>, Box<dyn std::error::Error>> {
+    let value = std::env::var(variable_name).unwrap_or_else(|_| default.to_owned());
+    if value.trim().eq_ignore_ascii_case("auto") {
+        return Ok(fronius.active_device_ids(device_type)?.collect());
+    }
+    Ok(DeviceId::parse_list(&value)?)
+}
 
-    if let Err(error) = res {
-        println!("Error during influxdb write occured: {:?}", error);
+/// Measurements, events and controls that are derived from the fetched data
+/// over several cycles.
+struct Derived {
+    grid_quality: GridQuality,
+    events: Events,
+    planner: Option<Planner>,
+    #[cfg(feature = "wattpilot")]
+    ev_charger: Option<wattpilot::SurplusCharger>,
+    efficiency: efficiency::Efficiency,
+    energy: energy::DailyEnergy,
+    peak_demand: peak::PeakDemand,
+    power_limits: HashMap<DeviceId, f64>,
+}
+
+impl Derived {
+    /// The Wattpilot is only controlled by the surplus of the `primary` site.
+    fn from_env(site: Option<&str>, primary: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        #[cfg(not(feature = "wattpilot"))]
+        if let (true, Ok(ip)) = (primary, std::env::var("WATTPILOT_IP")) {
+            return Err(format!("WATTPILOT_IP={ip} needs a build with the wattpilot feature").into());
+        }
+        Ok(Derived {
+            grid_quality: GridQuality::from_env()?,
+            events: Events::from_env(site)?,
+            planner: Planner::from_env()?,
+            #[cfg(feature = "wattpilot")]
+            ev_charger: if primary { wattpilot::SurplusCharger::from_env()? } else { None },
+            efficiency: efficiency::Efficiency::default(),
+            energy: energy::DailyEnergy::default(),
+            peak_demand: peak::PeakDemand::from_env()?,
+            power_limits: power_limits_from_env()?,
+        })
     }
 }
 
+/// A Fronius installation that is polled, with the values derived from it.
+struct Site {
+    /// Name of the site, added as `site` tag. `None` for the single site
+    /// given by `FRONIUS_IP`, its points are not tagged.
+    name: Option<String>,
+    /// IP address of the Datamanager.
+    address: String,
+    /// Polls the Datamanager, `None` until it could be connected.
+    collector: Option<Collector>,
+    device_tags: DeviceRegistry,
+    battery: BatterySign,
+    derived: Derived,
+    /// Time after which unsupported requests are disabled.
+    unsupported_timeout: Option<Duration>,
+    inventory: inventory::Inventory,
+    /// Compatibility range of the Solar API, which changes with the firmware
+    /// of the Datamanager, to notice upgrades. Unlike the firmware version of
+    /// `GetLoggerInfo`, every generation reports it.
+    compatibility_range: Option<String>,
+    firmware_checked: Instant,
+    /// Unique IDs of the inverters, to find the Datamanager after an address
+    /// change.
+    inverter_ids: Vec<String>,
+    /// Search for the Datamanager at a new address, while it runs.
+    rediscovery: Option<rediscovery::Search>,
+    /// Cycles in a row in which the Datamanager was unreachable.
+    unreachable_cycles: u32,
+    reconnected: Instant,
+}
 
+impl Site {
+    /// The site `name` at `ip`, it is connected with the first cycle. The
+    /// Wattpilot is only controlled by the surplus of the `primary` site.
+    fn new(name: Option<String>, ip: &str, primary: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let derived = Derived::from_env(name.as_deref(), primary)?;
+        Ok(Site {
+            name,
+            address: ip.to_owned(),
+            collector: None,
+            device_tags: DeviceRegistry::default(),
+            battery: BatterySign::from_env()?,
+            derived,
+            unsupported_timeout: unsupported_timeout_from_env()?,
+            inventory: inventory::Inventory::default(),
+            compatibility_range: None,
+            firmware_checked: Instant::now(),
+            inverter_ids: Vec::new(),
+            rediscovery: None,
+            unreachable_cycles: 0,
+            reconnected: Instant::now(),
+        })
+    }
 
-fn fetch_data(fronius: &Fronius) -> Result<(), Box<dyn std::error::Error>> {
-    let interver_id = DeviceId::try_from(1).unwrap();
-    let meter_id = DeviceId::try_from(0).unwrap();
-    let storage_id = DeviceId::try_from(0).unwrap();
-    let ohm_pilot_id = DeviceId::try_from(0).unwrap();
-    let inverter_data = get_inverter_data(fronius, &interver_id);
-    let inverter_phase_data = get_inverter_phase_data(fronius, &interver_id);
-    let inverter_info = get_inverter_info(fronius, &interver_id);
-    let meter_data = get_meter_data(fronius, &meter_id);
-    let storage_data = get_storage_data(fronius, &storage_id);
-    let ohm_pilot_data = get_ohm_pilot_data(fronius, &ohm_pilot_id);
-    let power_flow_data = get_power_flow_data(fronius);
+    /// The site `name` at `ip`, connected right away.
+    #[cfg(test)]
+    fn connect(name: Option<String>, ip: &str, primary: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut site = Site::new(name, ip, primary)?;
+        site.connect_datamanager(None)?;
+        Ok(site)
+    }
 
-    let client = Client::new(std::env::var("INFLUX_DB_URL")?, std::env::var("INFLUX_DB_ORG")?, std::env::var("INFLUX_DB_TOKEN")?);
-    let bucket = std::env::var("INFLUX_DB_BUCKET")?;
+    /// Connects to the Datamanager and discovers the devices to poll, no
+    /// request runs past the `deadline`.
+    fn connect_datamanager(&mut self, deadline: Option<Instant>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut builder = fronius_builder(self.name.as_deref(), &self.address)?;
+        if let Some(deadline) = deadline {
+            builder = builder.deadline(deadline);
+        }
+        let fronius = builder.build()?;
+        let devices = devices_from_env(&fronius)?;
+        self.device_tags = DeviceRegistry::resolve(&fronius);
+        self.compatibility_range = fronius.get_api_version().ok().map(|api_version| api_version.compatibility_range);
+        self.firmware_checked = Instant::now();
+        self.inverter_ids = match fronius.get_inverter_info() {
+            Ok(infos) => rediscovery::unique_ids(infos.into_values().flatten()),
+            Err(_) => Vec::new(),
+        };
+        let mut collector = Collector::new(fronius)
+            .disable_unsupported_after(self.unsupported_timeout)
+            .wrap_requests(|dataset, request| timed_fetch(dataset, request));
+        collector.set_devices(devices);
+        self.collector = Some(collector);
+        self.log_summary();
+        self.update_inventory();
+        Ok(())
+    }
+
+    fn fronius(&self) -> Option<&Fronius> {
+        self.collector.as_ref().map(Collector::fronius)
+    }
 
-    if let Ok(val) = inverter_data {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = inverter_data {
-        println!("Error during fetch of inverter_data occured: {:?}", error);
+    /// Connects a site that isn't connected yet, the first time right away
+    /// and then every `CONNECT_INTERVAL`, so an unreachable Datamanager
+    /// doesn't stop the other sites. The attempt ends at the `deadline`.
+    fn connect_if_due(&mut self, deadline: Instant) -> Result<(), Box<dyn std::error::Error>> {
+        if self.collector.is_some() {
+            return Ok(());
+        }
+        let name = self.name.as_deref().unwrap_or("default").to_owned();
+        if self.unreachable_cycles > 0 && self.reconnected.elapsed() < CONNECT_INTERVAL {
+            return Err(NotConnectedError(name).into());
+        }
+        self.reconnected = Instant::now();
+        if let Err(error) = self.connect_datamanager(Some(deadline)) {
+            self.unreachable_cycles += 1;
+            warn!("Error during connect to site {name} at {} occured, retrying in {:?}: {:?}", self.address, CONNECT_INTERVAL, error);
+            return Err(NotConnectedError(name).into());
+        }
+        self.unreachable_cycles = 0;
+        Ok(())
     }
 
-    if let Ok(val) = inverter_phase_data {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = inverter_phase_data {
-        println!("Error during fetch of inverter_phase_data occured: {:?}", error);
+    /// Requests the inventory of the devices, it is written with the next
+    /// cycle if it changed.
+    fn update_inventory(&mut self) {
+        let name = self.name.as_deref().unwrap_or("default");
+        let Some(collector) = &self.collector else {
+            return;
+        };
+        let device_tags = &self.device_tags;
+        match self.inventory.update(collector.fronius(), |device_type, id| device_tags.get(device_type, id).into_owned()) {
+            Ok(true) => info!("Device inventory of site {name} changed"),
+            Ok(false) => {}
+            Err(error) => warn!("Error during lookup of the device inventory of site {name} occured: {:?}", error),
+        }
     }
 
-    if let Ok(val) = inverter_info {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = inverter_info {
-        println!("Error during fetch of inverter_info occured: {:?}", error);
+    /// Connects again once the Datamanager has been unreachable for
+    /// `RECONNECT_AFTER` cycles, at most every `RECONNECT_INTERVAL`. A host
+    /// name is resolved again. If that fails and `rediscover` is set, a
+    /// search of the network for the Datamanager is started in the
+    /// background, in case it got a new address.
+    fn reconnect_if_unreachable(&mut self, rediscover: bool) {
+        self.finish_rediscovery();
+        // a site that isn't connected yet is connected by `connect_if_due`
+        let Some(collector) = &mut self.collector else {
+            return;
+        };
+        if self.unreachable_cycles < RECONNECT_AFTER || self.reconnected.elapsed() < RECONNECT_INTERVAL {
+            return;
+        }
+        self.reconnected = Instant::now();
+        let name = self.name.as_deref().unwrap_or("default");
+        let error = match fronius_builder(self.name.as_deref(), &self.address).and_then(|builder| Ok(builder.build()?)) {
+            Ok(fronius) => {
+                info!("Reconnected to site {name} at {}", self.address);
+                collector.set_fronius(fronius);
+                self.unreachable_cycles = 0;
+                return;
+            }
+            Err(error) => error,
+        };
+        warn!("Error during reconnect to site {name} at {} occured: {:?}", self.address, error);
+        if !rediscover || self.inverter_ids.is_empty() || self.rediscovery.is_some() {
+            return;
+        }
+        let Ok(old) = self.address.parse() else {
+            return;
+        };
+        info!("Searching the network of {old} for the Datamanager of site {name}");
+        self.rediscovery = Some(rediscovery::Search::start(old, self.inverter_ids.clone()));
     }
 
-    if let Ok(val) = meter_data {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = meter_data {
-        println!("Error during fetch of meter_data occured: {:?}", error);
+    /// Connects to the address found by the search of the network, once it
+    /// has finished.
+    fn finish_rediscovery(&mut self) {
+        if !self.rediscovery.as_ref().is_some_and(rediscovery::Search::is_finished) {
+            return;
+        }
+        let Some(search) = self.rediscovery.take() else {
+            return;
+        };
+        let name = self.name.as_deref().unwrap_or("default");
+        let old = search.old;
+        let Some(new) = search.result() else {
+            warn!("Datamanager of site {name} not found in the network of {old}");
+            return;
+        };
+        if self.unreachable_cycles == 0 {
+            // answered at the old address meanwhile
+            return;
+        }
+        match fronius_builder(self.name.as_deref(), &new.to_string()).and_then(|builder| Ok(builder.build()?)) {
+            Ok(fronius) => {
+                let text = format!("Datamanager of site {name} moved from {old} to {new}");
+                warn!("{text}");
+                self.derived.events.annotate(&["fronius", "address"], &text);
+                if let Some(collector) = &mut self.collector {
+                    collector.set_fronius(fronius);
+                }
+                self.address = new.to_string();
+                self.unreachable_cycles = 0;
+            }
+            Err(error) => error!("Error during connect to site {name} at {new} occured: {:?}", error),
+        }
     }
 
-    if let Ok(val) = storage_data {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = storage_data {
-        println!("Error during fetch of storage_data occured: {:?}", error);
+    /// Checks the compatibility range of the Solar API every `interval`,
+    /// which changes with the firmware of the Datamanager. After a firmware
+    /// upgrade the client is replaced, the devices are discovered again and
+    /// the disabled requests are enabled, since the upgrade may have changed
+    /// what is supported.
+    fn check_firmware(&mut self, interval: Duration) {
+        let Some(collector) = &mut self.collector else {
+            return;
+        };
+        if self.firmware_checked.elapsed() < interval {
+            return;
+        }
+        self.firmware_checked = Instant::now();
+        let name = self.name.as_deref().unwrap_or("default");
+        let compatibility_range = match collector.fronius().get_api_version() {
+            Ok(api_version) => Some(api_version.compatibility_range),
+            Err(error) => {
+                error!("Error during check of the API version of site {name} occured: {:?}", error);
+                return;
+            }
+        };
+        if compatibility_range == self.compatibility_range {
+            self.update_inventory();
+            return;
+        }
+        // building a client requests the API version again, the base URL may have changed
+        let fronius = match fronius_builder(self.name.as_deref(), &self.address).and_then(|builder| Ok(builder.build()?)) {
+            Ok(fronius) => fronius,
+            Err(error) => {
+                error!("Error during reconnect of site {name} after a firmware change occured: {:?}", error);
+                return;
+            }
+        };
+        let text = format!(
+            "Firmware of site {name} changed, Solar API compatibility range {} to {}",
+            self.compatibility_range.as_deref().unwrap_or("unknown"),
+            compatibility_range.as_deref().unwrap_or("unknown")
+        );
+        warn!("{text}, discovering the devices again");
+        self.derived.events.annotate(&["fronius", "firmware"], &text);
+        match devices_from_env(&fronius) {
+            Ok(devices) => collector.set_devices(devices),
+            Err(error) => error!("Error during discovery of the devices of site {name} occured, keeping the old ones: {:?}", error),
+        }
+        self.device_tags = DeviceRegistry::resolve(&fronius);
+        collector.set_fronius(fronius);
+        collector.enable_unsupported();
+        self.compatibility_range = compatibility_range;
+        self.log_summary();
+        self.update_inventory();
     }
 
-    if let Ok(val) = ohm_pilot_data {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = ohm_pilot_data {
-        println!("Error during fetch of ohm_pilot_data occured: {:?}", error);
+    /// Logs the Datamanager, the polled devices and the endpoints, so a
+    /// misconfiguration shows in the first lines of the log.
+    fn log_summary(&self) {
+        let name = self.name.as_deref().unwrap_or("default");
+        let Some(collector) = &self.collector else {
+            info!("Site {name} at {}: not connected yet", self.address);
+            return;
+        };
+        match collector.fronius().get_logger_info() {
+            Ok(logger) => info!(
+                "Site {name} at {}: {} firmware {}, Solar API v1",
+                self.address,
+                logger.product_id.as_deref().unwrap_or("unknown Datamanager"),
+                logger.sw_version.as_deref().unwrap_or("unknown")
+            ),
+            Err(error) => warn!("Site {name} at {}: Error during lookup of the Datamanager occured: {:?}", self.address, error),
+        }
+        let devices = collector.devices();
+        let device_ids = [
+            (DeviceType::Inverter, &devices.inverters),
+            (DeviceType::Meter, &devices.meters),
+            (DeviceType::Storage, &devices.storages),
+            (DeviceType::Ohmpilot, &devices.ohm_pilots),
+        ];
+        for (device_type, ids) in device_ids {
+            for id in ids {
+                let tags = self.device_tags.get(device_type, id);
+                if tags.name.is_empty() {
+                    info!("  {device_type:?} {id}: {}", tags.id);
+                } else {
+                    info!("  {device_type:?} {id}: {} ({})", tags.id, tags.name);
+                }
+            }
+        }
+        let mut endpoints = Vec::new();
+        if !devices.inverters.is_empty() {
+            endpoints.extend(["GetInverterRealtimeData.cgi", "GetInverterInfo.cgi"]);
+        }
+        if !devices.meters.is_empty() {
+            endpoints.push("GetMeterRealtimeData.cgi");
+        }
+        if !devices.storages.is_empty() {
+            endpoints.push("GetStorageRealtimeData.cgi");
+        }
+        if !devices.ohm_pilots.is_empty() {
+            endpoints.push("GetOhmPilotRealtimeData.cgi");
+        }
+        endpoints.push("GetPowerFlowRealtimeData.cgi");
+        let cached = cached_endpoints_from_env();
+        let endpoints: Vec<String> = endpoints
+            .into_iter()
+            .map(|endpoint| {
+                if cached.iter().any(|cached| cached == endpoint) {
+                    format!("{endpoint} (cached)")
+                } else {
+                    endpoint.to_owned()
+                }
+            })
+            .collect();
+        info!("  Endpoints: {}", endpoints.join(", "));
     }
 
-    if let Ok(val) = power_flow_data {
-        send_to_influx(&client, &bucket, futures::stream::iter(vec![val]));
-    }else if let Err(error) = power_flow_data {
-        println!("Error during fetch of power_flow_data occured: {:?}", error);
+    fn state(&self) -> state::SiteState {
+        state::SiteState {
+            grid_quality: self.derived.grid_quality.accumulator().clone(),
+            energy: self.derived.energy.clone(),
+            peak_demand: self.derived.peak_demand.state().clone(),
+            planner: self.derived.planner.as_ref().map(|p| p.profiles().clone()).unwrap_or_default(),
+        }
     }
 
+    fn restore(&mut self, state: state::SiteState) {
+        self.derived.grid_quality.restore(state.grid_quality);
+        self.derived.energy = state.energy;
+        self.derived.peak_demand.restore(state.peak_demand);
+        if let Some(planner) = &mut self.derived.planner {
+            planner.restore(state.planner);
+        }
+    }
+}
+
+/// The builder for the Datamanager at `ip` of the site `name`, with the
+/// timeouts and cached endpoints of the configuration.
+fn fronius_builder(name: Option<&str>, ip: &str) -> Result<FroniusBuilder, Box<dyn std::error::Error>> {
+    let timeout = match name.map(site_timeout).transpose()?.flatten() {
+        Some(timeout) => timeout,
+        None => duration_from_env("FRONIUS_READ_TIMEOUT", 10)?,
+    };
+    // a host name is resolved again for every new connection
+    let mut builder = FroniusBuilder::new(ip.trim())
+        .connect_timeout(duration_from_env("FRONIUS_CONNECT_TIMEOUT", 5)?)
+        .timeout(timeout);
+    for endpoint in cached_endpoints_from_env() {
+        builder = builder.cache(endpoint);
+    }
+    if let Ok(user_agent) = std::env::var("FRONIUS_USER_AGENT") {
+        builder = builder.user_agent(user_agent);
+    }
+    Ok(builder)
+}
+
+/// The endpoints given by `FRONIUS_CACHED_ENDPOINTS`, whose responses are
+/// cached.
+fn cached_endpoints_from_env() -> Vec<String> {
+    let cached_endpoints = std::env::var("FRONIUS_CACHED_ENDPOINTS").unwrap_or_else(|_| "GetInverterInfo.cgi".to_owned());
+    cached_endpoints.split(',').map(str::trim).filter(|endpoint| !endpoint.is_empty()).map(str::to_owned).collect()
+}
+
+/// The request timeout of the site given by `FRONIUS_SITE_TIMEOUTS` (e.g.
+/// `parents=30`), if any.
+fn site_timeout(name: &str) -> Result<Option<Duration>, Box<dyn std::error::Error>> {
+    let list = std::env::var("FRONIUS_SITE_TIMEOUTS").unwrap_or_default();
+    for entry in list.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let (site, secs) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid site timeout {entry:?}, expected e.g. \"parents=30\""))?;
+        if site.trim() == name {
+            return Ok(Some(Duration::from_secs(secs.trim().parse()?)));
+        }
+    }
+    Ok(None)
+}
+
+/// Name and address of a site.
+type SiteAddress = (Option<String>, String);
+
+/// Reads the names and addresses of the sites to poll: `FRONIUS_SITES` as a
+/// list like `home=192.168.0.10,parents=10.0.0.20`, otherwise the single site
+/// `FRONIUS_IP` without a name.
+fn site_addresses() -> Result<Vec<SiteAddress>, Box<dyn std::error::Error>> {
+    let Ok(list) = std::env::var("FRONIUS_SITES") else {
+        return Ok(vec![(None, std::env::var("FRONIUS_IP")?)]);
+    };
+    let mut sites: Vec<SiteAddress> = Vec::new();
+    for entry in list.split(',') {
+        let (name, ip) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("invalid site {entry:?}, expected e.g. \"home=192.168.0.10\""))?;
+        let name = name.trim();
+        if name == community::SITE || sites.iter().any(|(site, _)| site.as_deref() == Some(name)) {
+            return Err(format!("site name {name:?} is reserved or used twice").into());
+        }
+        sites.push((Some(name.to_owned()), ip.trim().to_owned()));
+    }
+    Ok(sites)
+}
+
+/// The sites to poll, they are connected with the first cycle. The first
+/// site is the primary one.
+fn sites_from_env() -> Result<Vec<Site>, Box<dyn std::error::Error>> {
+    let mut sites = Vec::new();
+    for (name, ip) in site_addresses()? {
+        sites.push(Site::new(name, &ip, sites.is_empty())?);
+    }
+    Ok(sites)
+}
+
+/// Fetches the data of all sites, up to `concurrency` sites at once, so a
+/// slow or unreachable site doesn't hold up the others. The results are in
+/// the order of the sites, `None` for a site that failed.
+fn fetch_sites(sites: &mut [Site], snapshot: &Snapshot, deadline: Instant, concurrency: usize) -> Vec<Option<(Batch, Option<PowerFlowData>)>> {
+    let fetch = |site: &mut Site| match fetch_data(site, snapshot, deadline) {
+        Ok(data) => Some(data),
+        Err(error) => {
+            error!("Error during fetch of site {} occured: {:?}", site.name.as_deref().unwrap_or("default"), error);
+            None
+        }
+    };
+    let workers = concurrency.min(sites.len());
+    if workers <= 1 {
+        return sites.iter_mut().map(fetch).collect();
+    }
+    let results = Mutex::new(Vec::with_capacity(sites.len()));
+    let queue = Mutex::new(sites.iter_mut().enumerate());
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let Some((index, site)) = queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).next() else {
+                    break;
+                };
+                let result = fetch(site);
+                results.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push((index, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Polls all sites and writes their points in one batch. With several sites
+/// the combined power flow is added as the virtual `community` site.
+fn poll_sites(sites: &mut [Site], snapshot: &Snapshot, pipeline: &mut pipeline::Pipeline, sinks: &sink::Sinks, deadline: Instant, concurrency: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let mut batch = Batch::default();
+    let mut power_flows = Vec::with_capacity(sites.len());
+    for (site_batch, power_flow) in fetch_sites(sites, snapshot, deadline, concurrency).into_iter().flatten() {
+        batch.append(site_batch);
+        power_flows.extend(power_flow);
+    }
+    if sites.len() > 1 {
+        if let Some(community) = community::aggregate(&power_flows)? {
+            snapshot.for_site(Some(community::SITE)).update("community", &community);
+            let mut community_batch = Batch::for_site(Some(community::SITE));
+            community_batch.push(&community);
+            batch.append(community_batch);
+        }
+    }
+    pipeline.process(&mut batch);
+    sinks.write(&batch);
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let ip_str = std::env::var("FRONIUS_IP")?;
-    let ip = IpAddr::V4(std::net::Ipv4Addr::from_str(&ip_str)?);
-    let fronius = Fronius::connect(ip)?;
-    loop {
-        let now = Utc::now();
-        println!("Reporting data at: {now}");
-        let res = fetch_data(&fronius);
+/// Fetches all data of one site. Returns the points and the power flow, if
+/// it could be fetched, for the aggregation of the sites. No request runs
+/// past the deadline, a pending one is cut off.
+fn fetch_data(site: &mut Site, snapshot: &Snapshot, deadline: Instant) -> Result<(Batch, Option<PowerFlowData>), Box<dyn std::error::Error>> {
+    site.connect_if_due(deadline)?;
+    let set_deadline = |site: &mut Site, deadline| {
+        if let Some(collector) = &mut site.collector {
+            collector.set_deadline(deadline);
+        }
+    };
+    set_deadline(site, Some(deadline));
+    let result = fetch_site_data(site, snapshot);
+    // the requests outside of the cycle (e.g. the reconnect) have no deadline
+    set_deadline(site, None);
+    result
+}
+
+fn fetch_site_data(site: &mut Site, snapshot: &Snapshot) -> Result<(Batch, Option<PowerFlowData>), Box<dyn std::error::Error>> {
+    let mut batch = Batch::for_site(site.name.as_deref());
+    let snapshot = snapshot.for_site(site.name.as_deref());
+    site.inventory.push_changed(&mut batch)?;
+    let collector = site.collector.as_mut().ok_or_else(|| NotConnectedError(site.name.clone().unwrap_or_else(|| "default".to_owned())))?;
+    let mut cycle = collector.poll_once();
+    let devices = collector.devices();
+    let device_tags = &mut site.device_tags;
+    let derived = &mut site.derived;
+    let battery = site.battery;
+
+    // the power flow tells whether the Datamanager is reachable, unless the
+    // deadline passed before it was requested
+    let power_flow_failed = cycle.errors.iter().any(|error| error.dataset == "power_flow_data");
+    if cycle.power_flow.is_some() || power_flow_failed {
+        derived.events.flag("outage", "outage", power_flow_failed, "Datamanager unreachable");
+        site.unreachable_cycles = if power_flow_failed { site.unreachable_cycles + 1 } else { 0 };
+    }
+    for error in cycle.errors {
+        report_fetch_error(error.dataset, error.device.as_ref(), error.error);
+    }
+
+    // summed output of all inverters per phase, for the load per phase
+    let mut inverter_phase_power = Some([0.0; 3]);
+    for inverter_id in &devices.inverters {
+        // first, so a renamed inverter gets its new name on all points of the cycle
+        let inverter_info = cycle
+            .inverter_infos
+            .remove(inverter_id)
+            .and_then(|response| ok_or_report("inverter_info", Some(inverter_id), inverter_info_from(response)));
+        if let Some(inverter_info) = &inverter_info {
+            device_tags.update_inverter(inverter_id, &inverter_info.id, &inverter_info.name);
+            snapshot.update("inverter_info", inverter_info);
+        }
+        let tags = device_tags.get(DeviceType::Inverter, inverter_id);
+        if let Some(inverter_info) = &inverter_info {
+            batch.push_device(inverter_info, &tags);
+        }
+
+        let inverter_data = cycle
+            .inverters
+            .remove(inverter_id)
+            .and_then(|response| ok_or_report("inverter_data", Some(inverter_id), inverter_data_from(response)));
+        if let Some((val, mppt)) = &inverter_data {
+            snapshot.update("inverter", val);
+            batch.push_device(val, &tags);
+            for channel in mppt {
+                batch.push_device(channel, &tags);
+            }
+            let dc_power = mppt.iter().map(|channel| channel.dc_power).sum::<Option<f64>>();
+            if let Some(efficiency) = derived.efficiency.add("Inverter", &inverter_id.to_string(), val.ac_power, dc_power)? {
+                snapshot.update("inverter_efficiency", &efficiency);
+                batch.push_device(&efficiency, &tags);
+            }
+        }
+
+        let inverter_phase_data = cycle
+            .inverter_phases
+            .remove(inverter_id)
+            .and_then(|response| ok_or_report("inverter_phase_data", Some(inverter_id), inverter_phase_data_from(response)));
+        if let Some((val, temperature)) = &inverter_phase_data {
+            snapshot.update("inverter_phase", val);
+            batch.push_device(val, &tags);
+            if let Some(temperature) = temperature {
+                snapshot.update("inverter_temperature", temperature);
+                batch.push_device(temperature, &tags);
+            }
+            inverter_phase_power = inverter_phase_power
+                .zip(val.phase_powers())
+                .map(|(sum, power)| [sum[0] + power[0], sum[1] + power[1], sum[2] + power[2]]);
+        }else{
+            inverter_phase_power = None;
+        }
+
+        if let Some(inverter_info) = &inverter_info {
+            if let Some(event) = derived.events.observe("Inverter", &inverter_id.to_string(), inverter_info.error_code)? {
+                batch.push_device(&event, &tags);
+            }
+        }
+
+        // derived from the inverter data and info, so no extra request is needed
+        if let (Some((inverter_data, _)), Some(inverter_info)) = (&inverter_data, &inverter_info) {
+            match get_derating_data(inverter_data, inverter_info, derived.power_limits.get(inverter_id).copied()) {
+                Ok(val) => {
+                    derived.events.flag("curtailment", &format!("curtailment {inverter_id}"), val.derated == Some(true), &format!("Inverter {inverter_id} derated ({})", val.reason));
+                    snapshot.update("derating", &val);
+                    batch.push_device(&val, &tags);
+                }
+                Err(error) => report_fetch_error("derating", Some(inverter_id), error),
+            }
+        }
+    }
+
+    for meter_id in &devices.meters {
+        let Some(val) = cycle.meters.remove(meter_id).and_then(|response| ok_or_report("meter_data", Some(meter_id), meter_data_from(response))) else {
+            continue;
+        };
+        snapshot.update("meter", &val);
+        // only the primary meter is used for the grid quality and the load per phase
+        if devices.meters.first() == Some(meter_id) {
+            derived.grid_quality.add(&val);
+            if let Some(inverter_phase_power) = inverter_phase_power {
+                match get_load_phase_data(inverter_phase_power, &val) {
+                    Ok(Some(load)) => {
+                        snapshot.update("load_phase", &load);
+                        batch.push(&load);
+                    }
+                    Ok(None) => {}
+                    Err(error) => report_fetch_error("load_phase", Some(meter_id), error),
+                }
+            }
+        }
+        batch.push_device(&val, &device_tags.get(DeviceType::Meter, meter_id));
+    }
+    if let (Some(quality), Some(meter_id)) = (derived.grid_quality.take()?, devices.meters.first()) {
+        snapshot.update("grid_quality", &quality);
+        batch.push_device(&quality, &device_tags.get(DeviceType::Meter, meter_id));
+    }
+
+    for storage_id in &devices.storages {
+        let Some(val) = cycle.storages.remove(storage_id).and_then(|response| ok_or_report("storage_data", Some(storage_id), storage_data_from(response, battery))) else {
+            continue;
+        };
+        snapshot.update("storage", &val);
+        // only the first storage is used for the planning
+        if let Some(planner) = derived.planner.as_mut().filter(|_| devices.storages.first() == Some(storage_id)) {
+            planner.set_battery(val.charge_percentage, val.capacity);
+        }
+        batch.push_device(&val, &device_tags.get(DeviceType::Storage, storage_id));
+    }
+
+    for ohm_pilot_id in &devices.ohm_pilots {
+        let Some(val) = cycle.ohm_pilots.remove(ohm_pilot_id).and_then(|response| ok_or_report("ohm_pilot_data", Some(ohm_pilot_id), ohm_pilot_data_from(response))) else {
+            continue;
+        };
+        snapshot.update("ohm_pilot", &val);
+        batch.push_device(&val, &device_tags.get(DeviceType::Ohmpilot, ohm_pilot_id));
+    }
+
+    let power_flow = cycle.power_flow.and_then(|response| ok_or_report("power_flow_data", None, power_flow_data_from(response, battery)));
+    if let Some(val) = &power_flow {
+        snapshot.update("power_flow", val);
+        // P_Load is negative while power is consumed
+        if let (Some(planner), Some(load)) = (derived.planner.as_mut(), val.load) {
+            planner.add_power(val.photovoltaik, -load);
+        }
+        if let (Some(load), Some(grid)) = (val.load, val.grid) {
+            let energy = derived.energy.add(val.photovoltaik, -load, grid, val.akku.unwrap_or(0.0))?;
+            snapshot.update("derived_energy", &energy);
+            batch.push(&energy);
+        }
+        if let Some(grid) = val.grid {
+            let peak_demand = derived.peak_demand.add(grid)?;
+            let near_limit = derived.peak_demand.near_limit(&peak_demand);
+            derived.events.flag("peak_demand", "peak_demand", near_limit, "Grid import close to the peak demand limit");
+            snapshot.update("peak_demand", &peak_demand);
+            batch.push(&peak_demand);
+        }
+        #[cfg(feature = "wattpilot")]
+        if let (Some(ev_charger), Some(grid)) = (derived.ev_charger.as_mut(), val.grid) {
+            match telemetry::span("control ev_charging", || ev_charger.update(grid)) {
+                Ok(ev_charging) => {
+                    snapshot.update("ev_charging", &ev_charging);
+                    batch.push(&ev_charging);
+                }
+                Err(error) => report_fetch_error("ev_charging", None, error),
+            }
+        }
+        batch.push(val);
+    }
+
+    if let Some(plan) = derived.planner.as_mut().map(Planner::take).transpose()?.flatten() {
+        snapshot.update("battery_plan", &plan);
+        for slot in plan.slots() {
+            batch.push(slot);
+        }
+    }
+
+    Ok((batch, power_flow))
+}
+
+/// Logs to stderr, `LOG_FILE` or, with `LOG_TARGET`, to syslog or journald.
+fn init_logging() -> Result<(), Box<dyn std::error::Error>> {
+    match std::env::var("LOG_TARGET").as_deref() {
+        Ok("stderr") | Err(_) => {}
+        #[cfg(unix)]
+        Ok(target @ ("syslog" | "journald")) => {
+            let filter = env_filter::Builder::new()
+                .parse(&std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned()))
+                .build();
+            let max_level = filter.filter();
+            let logger = if target == "journald" {
+                syslog::SystemLogger::journald(filter)?
+            } else {
+                syslog::SystemLogger::syslog(filter)?
+            };
+            log::set_boxed_logger(Box::new(logger))?;
+            log::set_max_level(max_level);
+            return Ok(());
+        }
+        Ok(other) => return Err(format!("invalid LOG_TARGET {other:?}, expected stderr, syslog or journald (only on Unix)").into()),
+    }
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if let Some(path) = std::env::var_os("LOG_FILE") {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+    Ok(())
+}
+
+/// Runs the collector until `shutdown` is set. The running cycle is always
+/// finished and the state saved before returning.
+fn run(shutdown: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
+    timestamp::init_from_env()?;
+    schema::init_from_env()?;
+    error_summary::init_from_env()?;
+    let _lock = lock::InstanceLock::from_env()?;
+    let _telemetry = telemetry::init()?;
+    let cycle_deadline = duration_from_env("CYCLE_DEADLINE", 12)?;
+    let firmware_check_interval = duration_from_env("FIRMWARE_CHECK_INTERVAL", 3600)?;
+    let rediscover = flag_from_env("FRONIUS_REDISCOVER")?;
+    let latency_alert_factor = match std::env::var("LATENCY_ALERT_FACTOR") {
+        Ok(val) => Some(val.parse::<f64>()?),
+        Err(_) => None,
+    };
+    let concurrency = match std::env::var("SITE_CONCURRENCY") {
+        Ok(val) => val.parse::<usize>()?.max(1),
+        Err(_) => 4,
+    };
+    timezone::init_from_env()?;
+    locale::init_from_env()?;
+    let mut sites = sites_from_env()?;
+    let low_memory = flag_from_env("LOW_MEMORY")?;
+    let mut pipeline = pipeline::Pipeline::from_env()?;
+    let control = Arc::new(control::Control::new(POLL_INTERVAL, flag_from_env("READ_ONLY")?));
+    let sinks = sink::Sinks::from_env(low_memory, &control)?;
+    info!("Fronius collector {} started", env!("CARGO_PKG_VERSION"));
+    info!("Sinks: {}", sinks.names().join(", "));
+    // the first site is the primary one, its events are annotated
+    let Some(primary) = sites.first_mut() else {
+        return Err("No site to poll".into());
+    };
+    info!(
+        "Intervals: polling {:?}, cycle deadline {:?}, grid quality {:?}",
+        POLL_INTERVAL,
+        cycle_deadline,
+        primary.derived.grid_quality.interval()
+    );
+    primary.derived.events.annotate(&["fronius", "restart"], "Collector started");
+    let snapshot = Arc::new(if low_memory { Snapshot::disabled() } else { Snapshot::default() });
+    let state_file = state::StateFile::from_env()?;
+    if let Some(state_file) = &state_file {
+        let mut state = state_file.load()?;
+        snapshot.restore(state.snapshot);
+        for site in &mut sites {
+            let site_state = match &site.name {
+                Some(name) => state.sites.remove(name).unwrap_or_default(),
+                None => std::mem::take(&mut state.site),
+            };
+            site.restore(site_state);
+        }
+    }
+    #[cfg(feature = "display")]
+    display::init_from_env()?;
+    #[cfg(feature = "http")]
+    if let Ok(addr) = std::env::var("HTTP_LISTEN") {
+        http_server::spawn(&addr, snapshot.clone(), control.clone(), low_memory)?;
+    }
+    #[cfg(not(feature = "http"))]
+    if let Ok(addr) = std::env::var("HTTP_LISTEN") {
+        return Err(format!("HTTP_LISTEN={addr} needs a build with the http feature").into());
+    }
+    #[cfg(feature = "display")]
+    if let Some(path) = std::env::var_os("DISPLAY_FRAMEBUFFER") {
+        display::spawn(std::path::Path::new(&path), snapshot.clone(), duration_from_env("DISPLAY_INTERVAL", 60)?)?;
+    }
+    #[cfg(not(feature = "display"))]
+    if std::env::var_os("DISPLAY_FRAMEBUFFER").is_some() {
+        return Err("DISPLAY_FRAMEBUFFER needs a build with the display feature".into());
+    }
+    while !shutdown.load(Ordering::Relaxed) {
+        let now = clock::now();
+        info!("Reporting data at: {now}");
+        sinks.refresh_credentials();
+        if control.take_enable() {
+            for site in &mut sites {
+                if let Some(collector) = &mut site.collector {
+                    collector.enable_unsupported();
+                }
+            }
+        }
+        if control.take_ack() {
+            let count: usize = sites.iter_mut().map(|site| site.derived.events.acknowledge()).sum();
+            info!("Acknowledged {count} alerts");
+        }
+        if control.take_reload() {
+            match pipeline::Pipeline::from_env() {
+                Ok(reloaded) => {
+                    pipeline = reloaded;
+                    info!("Reloaded the processing pipeline");
+                }
+                Err(error) => error!("Error during reload of the pipeline occured, keeping the old one: {:?}", error),
+            }
+        }
+        if let Some(jump) = timestamp::start_cycle() {
+            let text = format!("Clock was set back by {:.3}s, timestamps of the last cycle are in the future", jump.num_milliseconds() as f64 / 1000.0);
+            warn!("{text}");
+            if let Some(primary) = sites.first_mut() {
+                primary.derived.events.annotate(&["fronius", "clock"], &text);
+            }
+        }
+        let cycle_start = Instant::now();
+        if sinks.blocked() {
+            warn!("The spool is full, pausing the polling until InfluxDB is reachable again");
+            sinks.write(&Batch::default());
+            control.wait_for_cycle(cycle_start, &shutdown);
+            continue;
+        }
+        let deadline = cycle_start + cycle_deadline;
+        // the primary site gives the time zone
+        if let Some(fronius) = sites.first().and_then(Site::fronius) {
+            timezone::refresh(fronius);
+        }
+        let res = telemetry::span("poll cycle", || poll_sites(&mut sites, &snapshot, &mut pipeline, &sinks, deadline, concurrency));
+        error_summary::log_summaries();
+        METRICS.cycle_finished(cycle_start.elapsed());
+        telemetry::record_cycle(cycle_start.elapsed());
 
         if let Err(error) = res {
-            println!("Error during fetch occured: {:?}", error);
+            error!("Error during fetch occured: {:?}", error);
         }else{
             res?;
         }
-        std::thread::sleep(std::time::Duration::from_secs(15));
+
+        for site in &mut sites {
+            site.reconnect_if_unreachable(rediscover);
+            site.check_firmware(firmware_check_interval);
+        }
+        if let (Some(factor), Some(primary)) = (latency_alert_factor, sites.first_mut()) {
+            for (dataset, slow) in METRICS.slow_fetches(factor) {
+                let text = match slow {
+                    Some((recent, usual)) => format!("Slow fetch of {dataset}: {recent:.1}s, usually {usual:.1}s"),
+                    None => format!("Slow fetch of {dataset}"),
+                };
+                primary.derived.events.flag("latency", &format!("latency {dataset}"), slow.is_some(), &text);
+            }
+        }
+        let (active, unacknowledged) = sites.iter().map(|site| site.derived.events.alerts()).fold((0, 0), |(active, unacknowledged), alerts| (active + alerts.0, unacknowledged + alerts.1));
+        METRICS.alerts(active, unacknowledged);
+
+        if let Some(state_file) = &state_file {
+            let mut state = state::State {
+                snapshot: snapshot.entries(),
+                ..Default::default()
+            };
+            for site in &sites {
+                match &site.name {
+                    Some(name) => {
+                        state.sites.insert(name.clone(), site.state());
+                    }
+                    None => state.site = site.state(),
+                }
+            }
+            if let Err(error) = state_file.save(&state) {
+                error!("Error during save of state occured: {:?}", error);
+            }
+        }
+        // sleep until the next cycle is due, so slow cycles don't shift the cadence
+        control.wait_for_cycle(cycle_start, &shutdown);
+    }
+    sinks.flush();
+    info!("Shutting down");
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_logging()?;
+
+    match std::env::args().nth(1).as_deref() {
+        Some("schema") => return schema::print(),
+        Some("check") => return check::run(),
+        Some("status") => return status::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        Some("replay-spool") => return replay::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        #[cfg(feature = "influxdb2")]
+        Some("import") => return import::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        #[cfg(feature = "influxdb2")]
+        Some("export") => return export::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        #[cfg(feature = "influxdb2")]
+        Some("backfill") => return backfill::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        #[cfg(not(feature = "influxdb2"))]
+        Some(command @ ("import" | "export" | "backfill")) => return Err(format!("{command} needs a build with the influxdb2 feature").into()),
+        #[cfg(feature = "tui")]
+        Some("tui") => return tui::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        #[cfg(not(feature = "tui"))]
+        Some("tui") => return Err("tui needs a build with the tui feature".into()),
+        #[cfg(all(feature = "tray", target_os = "linux"))]
+        Some("tray") => return tray::run(&std::env::args().skip(2).collect::<Vec<_>>()),
+        #[cfg(not(all(feature = "tray", target_os = "linux")))]
+        Some("tray") => return Err("tray needs a build with the tray feature on Linux".into()),
+        Some(command) if !command.starts_with('-') => return Err(format!("unknown command {command:?}").into()),
+        _ => {}
+    }
+
+    #[cfg(windows)]
+    if std::env::args().any(|arg| arg == "--service") {
+        return Ok(service::run()?);
+    }
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        signal_hook::flag::register(signal, shutdown.clone())?;
     }
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, secret::reload_flag())?;
+    run(shutdown)
 }
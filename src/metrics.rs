@@ -0,0 +1,354 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(feature = "http")]
+use std::fmt::Write;
+
+/// Fetch durations kept per dataset for the quantiles, one hour of cycles.
+const LATENCY_WINDOW: usize = 240;
+/// The latest fetches that are compared with the whole window.
+const LATENCY_RECENT: usize = 20;
+/// Fetches faster than this are never reported as slow.
+const MIN_SLOW_LATENCY: f64 = 1.0;
+
+/// Durations of the fetches of one dataset, in seconds.
+#[derive(Default)]
+struct Latency {
+    window: VecDeque<f64>,
+    count: u64,
+    sum: f64,
+}
+
+impl Latency {
+    fn add(&mut self, seconds: f64) {
+        if self.window.len() == LATENCY_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(seconds);
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// The `q` quantile of the values, which must not be empty.
+fn quantile(values: impl Iterator<Item = f64>, q: f64) -> f64 {
+    let mut values: Vec<f64> = values.collect();
+    values.sort_by(f64::total_cmp);
+    values[((values.len() - 1) as f64 * q).round() as usize]
+}
+
+/// Counters about the collector itself, exported in the Prometheus text format.
+pub struct Metrics {
+    cycles: AtomicU64,
+    fetch_errors: AtomicU64,
+    /// Failed fetches per dataset, e.g. `storage_data`.
+    dataset_fetch_errors: Mutex<BTreeMap<String, u64>>,
+    /// Durations of the fetches per dataset.
+    latencies: Mutex<BTreeMap<String, Latency>>,
+    #[cfg(feature = "influxdb2")]
+    points_written: AtomicU64,
+    points_rejected: AtomicU64,
+    #[cfg(feature = "influxdb2")]
+    write_errors: AtomicU64,
+    #[cfg(feature = "influxdb2")]
+    spool_dropped: AtomicU64,
+    /// Points collected for the next write (`WRITE_EVERY_CYCLES`).
+    pending_points: AtomicU64,
+    #[cfg(feature = "influxdb2")]
+    spool_points: AtomicU64,
+    #[cfg(feature = "influxdb2")]
+    spool_bytes: AtomicU64,
+    alerts: AtomicU64,
+    unacknowledged_alerts: AtomicU64,
+    /// The tokio runtimes of the sinks, their tasks are counted.
+    #[cfg(any(feature = "influxdb2", feature = "grpc"))]
+    runtimes: Mutex<Vec<tokio::runtime::Handle>>,
+    last_cycle_duration_ms: AtomicU64,
+    last_cycle_end: Mutex<Option<Instant>>,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            cycles: AtomicU64::new(0),
+            fetch_errors: AtomicU64::new(0),
+            dataset_fetch_errors: Mutex::new(BTreeMap::new()),
+            latencies: Mutex::new(BTreeMap::new()),
+            #[cfg(feature = "influxdb2")]
+            points_written: AtomicU64::new(0),
+            points_rejected: AtomicU64::new(0),
+            #[cfg(feature = "influxdb2")]
+            write_errors: AtomicU64::new(0),
+            #[cfg(feature = "influxdb2")]
+            spool_dropped: AtomicU64::new(0),
+            pending_points: AtomicU64::new(0),
+            #[cfg(feature = "influxdb2")]
+            spool_points: AtomicU64::new(0),
+            #[cfg(feature = "influxdb2")]
+            spool_bytes: AtomicU64::new(0),
+            alerts: AtomicU64::new(0),
+            unacknowledged_alerts: AtomicU64::new(0),
+            #[cfg(any(feature = "influxdb2", feature = "grpc"))]
+            runtimes: Mutex::new(Vec::new()),
+            last_cycle_duration_ms: AtomicU64::new(0),
+            last_cycle_end: Mutex::new(None),
+        }
+    }
+
+    pub fn cycle_finished(&self, duration: Duration) {
+        self.cycles.fetch_add(1, Ordering::Relaxed);
+        self.last_cycle_duration_ms
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+        *self.last_cycle_end.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now());
+    }
+
+    #[cfg(feature = "http")]
+    pub fn cycles(&self) -> u64 {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    /// Time since the last cycle finished, `None` before the first one.
+    #[cfg(feature = "http")]
+    pub fn last_cycle_age(&self) -> Option<Duration> {
+        self.last_cycle_end
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .map(|end| end.elapsed())
+    }
+
+    pub fn fetch_failed(&self, dataset: &str) {
+        self.fetch_errors.fetch_add(1, Ordering::Relaxed);
+        let mut errors = self.dataset_fetch_errors.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *errors.entry(dataset.to_owned()).or_default() += 1;
+    }
+
+    pub fn fetch_finished(&self, dataset: &str, duration: Duration) {
+        let mut latencies = self.latencies.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        latencies.entry(dataset.to_owned()).or_default().add(duration.as_secs_f64());
+    }
+
+    /// Compares the median duration of the latest fetches of every dataset
+    /// with the median of the last hour. Returns per dataset both medians if
+    /// the latest fetches are more than `factor` times slower, `None`
+    /// otherwise.
+    pub fn slow_fetches(&self, factor: f64) -> Vec<(String, Option<(f64, f64)>)> {
+        let latencies = self.latencies.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        latencies
+            .iter()
+            // the window needs some fetches before the degradation can be told
+            .filter(|(_, latency)| latency.window.len() >= 3 * LATENCY_RECENT)
+            .map(|(dataset, latency)| {
+                let usual = quantile(latency.window.iter().copied(), 0.5);
+                let recent = quantile(latency.window.iter().rev().take(LATENCY_RECENT).copied(), 0.5);
+                let slow = recent >= MIN_SLOW_LATENCY && recent > factor * usual;
+                (dataset.clone(), slow.then_some((recent, usual)))
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "influxdb2")]
+    pub fn points_written(&self, count: u64) {
+        self.points_written.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn point_rejected(&self) {
+        self.points_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "influxdb2")]
+    pub fn write_failed(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "influxdb2")]
+    pub fn spool_dropped(&self, count: u64) {
+        self.spool_dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn pending_points(&self, count: usize) {
+        self.pending_points.store(count as u64, Ordering::Relaxed);
+    }
+
+    /// The points and bytes in the spool files.
+    #[cfg(feature = "influxdb2")]
+    pub fn spool_size(&self, points: u64, bytes: u64) {
+        self.spool_points.store(points, Ordering::Relaxed);
+        self.spool_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    /// The active alerts of all sites and those not acknowledged yet.
+    pub fn alerts(&self, active: usize, unacknowledged: usize) {
+        self.alerts.store(active as u64, Ordering::Relaxed);
+        self.unacknowledged_alerts.store(unacknowledged as u64, Ordering::Relaxed);
+    }
+
+    /// Adds a runtime whose tasks are counted.
+    #[cfg(any(feature = "influxdb2", feature = "grpc"))]
+    pub fn add_runtime(&self, runtime: tokio::runtime::Handle) {
+        self.runtimes.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(runtime);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    #[cfg(feature = "http")]
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        };
+
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed) as f64;
+        metric("fronius_cycles_total", "counter", "Number of finished poll cycles.", load(&self.cycles));
+        metric("fronius_fetch_errors_total", "counter", "Number of failed fetches from the Fronius API.", load(&self.fetch_errors));
+        #[cfg(feature = "influxdb2")]
+        metric("fronius_points_written_total", "counter", "Number of points written to InfluxDB.", load(&self.points_written));
+        metric("fronius_points_rejected_total", "counter", "Number of invalid points that were dropped.", load(&self.points_rejected));
+        #[cfg(feature = "influxdb2")]
+        metric("fronius_write_errors_total", "counter", "Number of failed InfluxDB writes.", load(&self.write_errors));
+        #[cfg(feature = "influxdb2")]
+        metric("fronius_spool_dropped_total", "counter", "Number of spooled points dropped by the limits of the spool.", load(&self.spool_dropped));
+        metric("fronius_last_cycle_duration_seconds", "gauge", "Duration of the last poll cycle.", load(&self.last_cycle_duration_ms) / 1000.0);
+        metric("fronius_pending_points", "gauge", "Number of points collected for the next write.", load(&self.pending_points));
+        #[cfg(feature = "influxdb2")]
+        metric("fronius_spool_points", "gauge", "Number of points in the spool.", load(&self.spool_points));
+        #[cfg(feature = "influxdb2")]
+        metric("fronius_spool_bytes", "gauge", "Size of the spool in bytes.", load(&self.spool_bytes));
+        metric("fronius_alerts", "gauge", "Number of active alerts, e.g. an outage.", load(&self.alerts));
+        metric("fronius_alerts_unacknowledged", "gauge", "Number of active alerts that weren't acknowledged.", load(&self.unacknowledged_alerts));
+        #[cfg(any(feature = "influxdb2", feature = "grpc"))]
+        {
+            let tasks: usize = self
+                .runtimes
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .iter()
+                .map(|runtime| runtime.metrics().num_alive_tasks())
+                .sum();
+            metric("fronius_runtime_tasks", "gauge", "Number of tasks of the async runtimes of the sinks.", tasks as f64);
+        }
+
+        let process = ProcessMetrics::read();
+        if let Some(value) = process.resident_memory_bytes {
+            metric("process_resident_memory_bytes", "gauge", "Resident memory size in bytes.", value as f64);
+        }
+        if let Some(value) = process.virtual_memory_bytes {
+            metric("process_virtual_memory_bytes", "gauge", "Virtual memory size in bytes.", value as f64);
+        }
+        if let Some(value) = process.cpu_seconds {
+            metric("process_cpu_seconds_total", "counter", "Total user and system CPU time spent in seconds.", value);
+        }
+        if let Some(value) = process.threads {
+            metric("process_threads", "gauge", "Number of OS threads of the process.", value as f64);
+        }
+        if let Some(value) = process.open_fds {
+            metric("process_open_fds", "gauge", "Number of open file descriptors.", value as f64);
+        }
+        if let Some(value) = process.open_sockets {
+            metric("process_open_sockets", "gauge", "Number of open sockets (HTTP connections).", value as f64);
+        }
+
+        let latencies = self.latencies.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !latencies.is_empty() {
+            let name = "fronius_fetch_duration_seconds";
+            let _ = writeln!(out, "# HELP {name} Duration of the fetches from the Fronius API per dataset, quantiles of the last hour.");
+            let _ = writeln!(out, "# TYPE {name} summary");
+            for (dataset, latency) in latencies.iter() {
+                for q in [0.5, 0.9, 0.99] {
+                    let value = quantile(latency.window.iter().copied(), q);
+                    let _ = writeln!(out, "{name}{{dataset=\"{dataset}\",quantile=\"{q}\"}} {value}");
+                }
+                let _ = writeln!(out, "{name}_sum{{dataset=\"{dataset}\"}} {}", latency.sum);
+                let _ = writeln!(out, "{name}_count{{dataset=\"{dataset}\"}} {}", latency.count);
+            }
+        }
+
+        let errors = self.dataset_fetch_errors.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !errors.is_empty() {
+            let name = "fronius_dataset_fetch_errors_total";
+            let _ = writeln!(out, "# HELP {name} Number of failed fetches from the Fronius API per dataset.");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (dataset, count) in errors.iter() {
+                let _ = writeln!(out, "{name}{{dataset=\"{dataset}\"}} {count}");
+            }
+        }
+        out
+    }
+}
+
+/// Process metrics read from `/proc/self`. On other platforms than Linux
+/// all values are `None`.
+#[cfg(feature = "http")]
+#[derive(Default)]
+struct ProcessMetrics {
+    resident_memory_bytes: Option<u64>,
+    virtual_memory_bytes: Option<u64>,
+    cpu_seconds: Option<f64>,
+    threads: Option<u64>,
+    open_fds: Option<u64>,
+    open_sockets: Option<u64>,
+}
+
+#[cfg(feature = "http")]
+impl ProcessMetrics {
+    /// Clock ticks per second used by `/proc/self/stat`, this is 100 on all
+    /// common Linux platforms.
+    const CLOCK_TICKS: f64 = 100.0;
+
+    fn read() -> Self {
+        let mut metrics = ProcessMetrics::default();
+
+        if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+            for line in status.lines() {
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                let value = value.trim().trim_end_matches(" kB");
+                match key {
+                    "VmRSS" => metrics.resident_memory_bytes = value.parse::<u64>().ok().map(|kb| kb * 1024),
+                    "VmSize" => metrics.virtual_memory_bytes = value.parse::<u64>().ok().map(|kb| kb * 1024),
+                    "Threads" => metrics.threads = value.parse().ok(),
+                    _ => {}
+                }
+            }
+        }
+
+        if let Ok(stat) = std::fs::read_to_string("/proc/self/stat") {
+            // the process name may contain spaces, so start after its closing bracket
+            let fields: Vec<&str> = stat
+                .rsplit_once(')')
+                .map(|(_, rest)| rest.split_whitespace().collect())
+                .unwrap_or_default();
+            // utime and stime are the 14th and 15th field, the first two are cut off
+            if let (Some(utime), Some(stime)) = (fields.get(11), fields.get(12)) {
+                if let (Ok(utime), Ok(stime)) = (utime.parse::<f64>(), stime.parse::<f64>()) {
+                    metrics.cpu_seconds = Some((utime + stime) / Self::CLOCK_TICKS);
+                }
+            }
+        }
+
+        if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+            let mut open_fds = 0;
+            let mut open_sockets = 0;
+            for fd in fds.flatten() {
+                open_fds += 1;
+                if let Ok(target) = std::fs::read_link(fd.path()) {
+                    if target.to_string_lossy().starts_with("socket:") {
+                        open_sockets += 1;
+                    }
+                }
+            }
+            metrics.open_fds = Some(open_fds);
+            metrics.open_sockets = Some(open_sockets);
+        }
+
+        metrics
+    }
+}
@@ -0,0 +1,382 @@
+//! Publishing of the points to an MQTT broker.
+//!
+//! The topics are built from the template `MQTT_TOPIC`, its placeholders are
+//! replaced per point: `{measurement}`, `{field}`, `{device_class}` (the
+//! `device` tag in lower case) and any tag like `{site}`. Levels that end up
+//! empty, e.g. `{site}` without `FRONIUS_SITES`, are left out. With
+//! `MQTT_PAYLOAD=json` one message with all fields is published per point
+//! (without the `{field}` level), otherwise one message per field with the
+//! bare value.
+//!
+//! The availability topic (`MQTT_AVAILABILITY_TOPIC`) is set to `online` on
+//! every connect and, as last will, to `offline` by the broker once the
+//! connection is lost, both retained.
+//!
+//! Commands for the collector (see the `control` module) are received on
+//! `MQTT_COMMAND_TOPIC` and acknowledged on the same topic with `/ack`
+//! appended.
+//!
+//! With `MQTT_HA_ENERGY` the energies of the day (`derived_energy`) are also
+//! published as Home Assistant sensors, announced by MQTT discovery below
+//! `MQTT_HA_DISCOVERY_PREFIX`, so the Energy Dashboard can use them.
+
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use log::{debug, info, warn};
+use rumqttc::{Client, Connection, Event, LastWill, MqttOptions, Packet, QoS};
+use thiserror::Error;
+
+use crate::{
+    control::Control,
+    point::{FieldValue, Point},
+    routing::{Routes, Target},
+    secret::Secret,
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_TOPIC: &str = "fronius/{site}/{device_class}/{device_id}/{measurement}/{field}";
+const DEFAULT_AVAILABILITY_TOPIC: &str = "fronius/status";
+const DEFAULT_COMMAND_TOPIC: &str = "fronius/command";
+const DEFAULT_HA_DISCOVERY_PREFIX: &str = "homeassistant";
+/// State topic of the Home Assistant energy sensors, a JSON object per site.
+const HA_ENERGY_TOPIC: &str = "fronius/{site}/energy";
+/// Fields of `derived_energy` that are announced, with the sensor names.
+const HA_ENERGY_SENSORS: &[(&str, &str)] = &[
+    ("pv_energy", "Solar production"),
+    ("load_energy", "Consumption"),
+    ("grid_import_energy", "Grid import"),
+    ("grid_export_energy", "Grid export"),
+    ("battery_charge_energy", "Battery charge"),
+    ("battery_discharge_energy", "Battery discharge"),
+];
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Messages that are queued while the broker is unreachable.
+const QUEUE_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid MQTT payload format {0:?}, expected value or json")]
+pub struct InvalidPayload(String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Payload {
+    /// One message per field.
+    Value,
+    /// One JSON message per point.
+    Json,
+}
+
+pub struct MqttSink {
+    client: Client,
+    topic: String,
+    payload: Payload,
+    retain: bool,
+    routes: Routes,
+    /// Shared with the event loop, which connects with the current one.
+    password: Option<Arc<Secret>>,
+    ha_energy: Option<HaEnergy>,
+}
+
+/// The Home Assistant energy sensors.
+struct HaEnergy {
+    discovery_prefix: String,
+    availability_topic: String,
+    /// Sites whose sensors were announced.
+    announced: Mutex<HashSet<String>>,
+}
+
+impl MqttSink {
+    /// Creates the sink if `MQTT_HOST` is set. Optional are `MQTT_PORT`
+    /// (default 1883), `MQTT_CLIENT_ID`, `MQTT_USERNAME`, `MQTT_PASSWORD` (or
+    /// `MQTT_PASSWORD_FILE`, see the `secret` module), `MQTT_TOPIC`,
+    /// `MQTT_PAYLOAD` (`value` or `json`), `MQTT_RETAIN`,
+    /// `MQTT_AVAILABILITY_TOPIC`, `MQTT_COMMAND_TOPIC`, `MQTT_HA_ENERGY` and
+    /// `MQTT_HA_DISCOVERY_PREFIX`.
+    pub fn from_env(control: Arc<Control>) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(host) = std::env::var("MQTT_HOST") else {
+            return Ok(None);
+        };
+        let client_id = std::env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "fronius-api".to_owned());
+        let payload = match std::env::var("MQTT_PAYLOAD").as_deref() {
+            Ok("value") | Err(_) => Payload::Value,
+            Ok("json") => Payload::Json,
+            Ok(other) => return Err(InvalidPayload(other.to_owned()).into()),
+        };
+        let topics = Topics {
+            availability: std::env::var("MQTT_AVAILABILITY_TOPIC").unwrap_or_else(|_| DEFAULT_AVAILABILITY_TOPIC.to_owned()),
+            command: std::env::var("MQTT_COMMAND_TOPIC").unwrap_or_else(|_| DEFAULT_COMMAND_TOPIC.to_owned()),
+        };
+        let ha_energy = crate::flag_from_env("MQTT_HA_ENERGY")?.then(|| HaEnergy {
+            discovery_prefix: std::env::var("MQTT_HA_DISCOVERY_PREFIX").unwrap_or_else(|_| DEFAULT_HA_DISCOVERY_PREFIX.to_owned()),
+            availability_topic: topics.availability.clone(),
+            announced: Mutex::new(HashSet::new()),
+        });
+        let password = Secret::from_env("MQTT_PASSWORD")?.map(Arc::new);
+        let mut options = options_from_env(client_id, host, password.as_deref())?;
+        options.set_last_will(LastWill::new(&topics.availability, OFFLINE, QoS::AtLeastOnce, true));
+        let (client, connection) = Client::new(options, QUEUE_CAPACITY);
+        let event_client = client.clone();
+        let credentials = std::env::var("MQTT_USERNAME").ok().map(|username| (username, password.clone()));
+        std::thread::Builder::new()
+            .name("mqtt".to_owned())
+            .spawn(move || drive(connection, &event_client, &topics, &control, credentials))?;
+        Ok(Some(MqttSink {
+            client,
+            topic: std::env::var("MQTT_TOPIC").unwrap_or_else(|_| DEFAULT_TOPIC.to_owned()),
+            payload,
+            retain: crate::flag_from_env("MQTT_RETAIN")?,
+            routes: Routes::from_env()?,
+            password,
+            ha_energy,
+        }))
+    }
+
+    fn publish(&self, topic: String, payload: String) {
+        if let Err(error) = self.client.try_publish(topic, QoS::AtMostOnce, self.retain, payload) {
+            debug!("Dropping MQTT message: {error}");
+        }
+    }
+
+    /// Publishes the energies of a `derived_energy` point in kWh, after the
+    /// sensors of its site were announced.
+    fn publish_ha_energy(&self, ha_energy: &HaEnergy, point: &Point) {
+        let site = point.tag("site").unwrap_or_default();
+        let state_topic = topic(HA_ENERGY_TOPIC, point, None);
+        let newly_announced = ha_energy
+            .announced
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(site.to_owned());
+        if newly_announced {
+            for (topic, config) in ha_energy.discovery(site, &state_topic) {
+                if let Err(error) = self.client.try_publish(topic, QoS::AtLeastOnce, true, config) {
+                    warn!("Error during announcement of the Home Assistant energy sensors occured: {error}");
+                }
+            }
+        }
+        let mut state = serde_json::Map::new();
+        for (field, _) in HA_ENERGY_SENSORS {
+            if let Some(FieldValue::Float(wh)) = point.field(field) {
+                state.insert((*field).to_owned(), (wh / 1000.0).into());
+            }
+        }
+        // retained, so the sensors have a value right after a restart of Home Assistant
+        if let Err(error) = self.client.try_publish(state_topic, QoS::AtMostOnce, true, serde_json::Value::Object(state).to_string()) {
+            debug!("Dropping MQTT message: {error}");
+        }
+    }
+}
+
+impl HaEnergy {
+    /// The discovery topics and configs of the sensors of `site`. The values
+    /// are the energies of the day, Home Assistant takes the drop at midnight
+    /// as a reset of the `total_increasing` counters.
+    fn discovery(&self, site: &str, state_topic: &str) -> Vec<(String, String)> {
+        let node = match site {
+            "" => "fronius".to_owned(),
+            site => format!("fronius_{}", site.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '-', "_")),
+        };
+        let device_name = match site {
+            "" => "Fronius".to_owned(),
+            site => format!("Fronius {site}"),
+        };
+        HA_ENERGY_SENSORS
+            .iter()
+            .map(|(field, name)| {
+                let config = serde_json::json!({
+                    "name": name,
+                    "unique_id": format!("{node}_{field}"),
+                    "state_topic": state_topic,
+                    "value_template": format!("{{{{ value_json.{field} }}}}"),
+                    "unit_of_measurement": "kWh",
+                    "device_class": "energy",
+                    "state_class": "total_increasing",
+                    "availability_topic": self.availability_topic,
+                    "device": {
+                        "identifiers": [node],
+                        "name": device_name,
+                        "manufacturer": "Fronius",
+                    },
+                });
+                (format!("{}/sensor/{node}/{field}/config", self.discovery_prefix), config.to_string())
+            })
+            .collect()
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        for point in batch.points() {
+            if let Some(ha_energy) = self.ha_energy.as_ref().filter(|_| point.measurement == "derived_energy") {
+                self.publish_ha_energy(ha_energy, point);
+            }
+            if !self.routes.targets(&point.measurement).contains(&Target::Mqtt) {
+                continue;
+            }
+            match self.payload {
+                Payload::Value => {
+                    for (field, value) in &point.fields {
+                        self.publish(topic(&self.topic, point, Some(field)), value_payload(value));
+                    }
+                }
+                Payload::Json => self.publish(topic(&self.topic, point, None), json_payload(point)),
+            }
+        }
+        // MQTT only passes the points on, messages that can't be queued are dropped
+        Ok(())
+    }
+
+    fn live(&self) -> bool {
+        true
+    }
+
+    /// The event loop connects with the new password the next time the
+    /// connection is lost.
+    fn refresh_credentials(&self, force: bool) {
+        if let Some(password) = &self.password {
+            password.refresh(force);
+        }
+    }
+}
+
+struct Topics {
+    availability: String,
+    command: String,
+}
+
+/// The connection options for the broker `host`, with `MQTT_PORT` and the
+/// credentials.
+fn options_from_env(client_id: String, host: String, password: Option<&Secret>) -> Result<MqttOptions, Box<dyn std::error::Error>> {
+    let port = match std::env::var("MQTT_PORT") {
+        Ok(val) => val.parse()?,
+        Err(_) => 1883,
+    };
+    let mut options = MqttOptions::new(client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Ok(username) = std::env::var("MQTT_USERNAME") {
+        options.set_credentials(username, password.map(Secret::get).unwrap_or_default());
+    }
+    Ok(options)
+}
+
+/// Connects once to the broker of `MQTT_HOST` for the `check` command and
+/// returns its address, `None` if MQTT isn't configured. A client ID of its
+/// own is used, so a running collector isn't disconnected.
+pub fn check(timeout: Duration) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Ok(host) = std::env::var("MQTT_HOST") else {
+        return Ok(None);
+    };
+    let client_id = format!("{}-check", std::env::var("MQTT_CLIENT_ID").unwrap_or_else(|_| "fronius-api".to_owned()));
+    let password = Secret::from_env("MQTT_PASSWORD")?;
+    let options = options_from_env(client_id, host, password.as_ref())?;
+    let (address, port) = options.broker_address();
+    let (_client, mut connection) = Client::new(options, QUEUE_CAPACITY);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match connection.recv_timeout(remaining) {
+            Ok(Ok(Event::Incoming(Packet::ConnAck(_)))) => break,
+            Ok(Ok(_)) => {}
+            Ok(Err(error)) => return Err(error.into()),
+            Err(_) => return Err(format!("no answer within {timeout:?}").into()),
+        }
+    }
+    Ok(Some(format!("{address}:{port}")))
+}
+
+/// Runs the event loop of the client, which reconnects on its own. After
+/// every connect the availability is set to online again, as the broker has
+/// published the last will if the previous connection was lost, and the
+/// command topic is subscribed again.
+/// Before a reconnect the `credentials` (user name and password) are set
+/// again, as the password may have been rotated.
+fn drive(mut connection: Connection, client: &Client, topics: &Topics, control: &Control, credentials: Option<(String, Option<Arc<Secret>>)>) {
+    while let Ok(notification) = connection.recv() {
+        match notification {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                let res = client
+                    .try_publish(&topics.availability, QoS::AtLeastOnce, true, ONLINE)
+                    .and_then(|()| client.try_subscribe(&topics.command, QoS::AtLeastOnce));
+                if let Err(error) = res {
+                    warn!("Error during MQTT setup occured: {error}");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == topics.command => {
+                let ack = control.execute(&String::from_utf8_lossy(&publish.payload), "MQTT");
+                let ack = serde_json::to_string(&ack).unwrap_or_default();
+                if let Err(error) = client.try_publish(format!("{}/ack", topics.command), QoS::AtLeastOnce, false, ack) {
+                    warn!("Error during publishing of the command acknowledgement occured: {error}");
+                }
+            }
+            Ok(_) => {}
+            Err(error) => {
+                warn!("MQTT connection failed: {error}");
+                if let Some((username, password)) = &credentials {
+                    let password = password.as_deref().map(Secret::get).unwrap_or_default();
+                    connection.eventloop.mqtt_options.set_credentials(username.clone(), password);
+                }
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+    }
+}
+
+/// Fills in the template for a point and, in the `value` mode, a field.
+fn topic(template: &str, point: &Point, field: Option<&str>) -> String {
+    template
+        .split('/')
+        .map(|level| {
+            let mut level = level.to_owned();
+            let mut pos = 0;
+            while let Some(start) = level[pos..].find('{').map(|start| pos + start) {
+                let Some(end) = level[start..].find('}').map(|end| start + end) else {
+                    break;
+                };
+                let value = match &level[start + 1..end] {
+                    "measurement" => point.measurement.clone(),
+                    "field" => field.unwrap_or_default().to_owned(),
+                    "device_class" => point.tag("device").unwrap_or_default().to_lowercase(),
+                    tag => point.tag(tag).unwrap_or_default().to_owned(),
+                };
+                // the values must not add levels or wildcards
+                let value = value.replace(['/', '+', '#'], "_");
+                level.replace_range(start..=end, &value);
+                pos = start + value.len();
+            }
+            level
+        })
+        .filter(|level| !level.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn value_payload(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Float(value) => value.to_string(),
+        FieldValue::Integer(value) => value.to_string(),
+        FieldValue::UInteger(value) => value.to_string(),
+        FieldValue::Boolean(value) => value.to_string(),
+        FieldValue::String(value) => value.clone(),
+    }
+}
+
+/// The fields of the point and the time (RFC 3339) as JSON object.
+fn json_payload(point: &Point) -> String {
+    let mut object = serde_json::Map::new();
+    object.insert("time".to_owned(), Utc.timestamp_nanos(point.time).to_rfc3339().into());
+    for (field, value) in &point.fields {
+        object.insert(field.clone(), value.to_json());
+    }
+    serde_json::Value::Object(object).to_string()
+}
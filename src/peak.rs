@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+use chrono::{prelude::*, Duration};
+use point_derive::ToPoint;
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp::{self, TimestampError};
+
+const WINDOW_MINUTES: i64 = 15;
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "peak_demand"]
+pub struct PeakDemandData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    rolling_average: f64,
+    #[influxdb(field)]
+    quarter_average: f64,
+    #[influxdb(field)]
+    month_peak: f64,
+    #[influxdb(field)]
+    limit: Option<f64>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Peak of the month and the running quarter-hour, kept across restarts.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct PeakState {
+    month: Option<(i32, u32)>,
+    peak: f64,
+    peak_time: Option<DateTime<Utc>>,
+    quarter: Option<DateTime<FixedOffset>>,
+    quarter_sum: f64,
+    quarter_samples: u32,
+}
+
+/// Tracks the average grid import per 15 minutes as used by capacity tariffs:
+/// the live average of the last 15 minutes and the highest average of a
+/// quarter-hour (:00, :15, :30, :45) in the current month.
+pub struct PeakDemand {
+    window: VecDeque<(DateTime<Utc>, f64)>,
+    state: PeakState,
+    limit: Option<f64>,
+    warning: f64,
+}
+
+impl PeakDemand {
+    /// Reads the optional `PEAK_DEMAND_LIMIT` in W and `PEAK_DEMAND_WARNING`,
+    /// the share of the limit that triggers a warning (default 0.9).
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let limit = match std::env::var("PEAK_DEMAND_LIMIT") {
+            Ok(val) => Some(val.parse()?),
+            Err(_) => None,
+        };
+        let warning = match std::env::var("PEAK_DEMAND_WARNING") {
+            Ok(val) => val.parse()?,
+            Err(_) => 0.9,
+        };
+        Ok(PeakDemand::new(limit, warning))
+    }
+
+    pub fn new(limit: Option<f64>, warning: f64) -> Self {
+        PeakDemand {
+            window: VecDeque::new(),
+            state: PeakState::default(),
+            limit,
+            warning,
+        }
+    }
+
+    pub fn state(&self) -> &PeakState {
+        &self.state
+    }
+
+    pub fn restore(&mut self, state: PeakState) {
+        self.state = state;
+    }
+
+    /// Whether the rolling average is close to (or above) the configured limit.
+    pub fn near_limit(&self, data: &PeakDemandData) -> bool {
+        self.limit.is_some_and(|limit| data.rolling_average >= limit * self.warning)
+    }
+
+    /// Adds the current grid power in W (positive while importing).
+    pub fn add(&mut self, grid: f64) -> Result<PeakDemandData, TimestampError> {
+        self.add_at(grid, crate::timezone::now())
+    }
+
+    /// Adds the grid power measured at `local`, the time in the time zone of
+    /// the site, which the quarter-hours and months are counted in.
+    pub fn add_at(&mut self, grid: f64, local: DateTime<FixedOffset>) -> Result<PeakDemandData, TimestampError> {
+        let now = local.with_timezone(&Utc);
+        let import = grid.max(0.0);
+
+        self.window.push_back((now, import));
+        while self.window.front().is_some_and(|(time, _)| now - *time > Duration::minutes(WINDOW_MINUTES)) {
+            self.window.pop_front();
+        }
+        let rolling_average = self.window.iter().map(|(_, power)| power).sum::<f64>() / self.window.len() as f64;
+
+        let month = (local.year(), local.month());
+        if self.state.month != Some(month) {
+            self.state = PeakState {
+                month: Some(month),
+                ..Default::default()
+            };
+        }
+
+        let quarter = quarter_start(local);
+        if self.state.quarter != Some(quarter) {
+            self.finish_quarter();
+            self.state.quarter = Some(quarter);
+        }
+        self.state.quarter_sum += import;
+        self.state.quarter_samples += 1;
+        let quarter_average = self.state.quarter_sum / self.state.quarter_samples as f64;
+
+        Ok(PeakDemandData {
+            device: "Grid",
+            rolling_average,
+            quarter_average,
+            month_peak: self.state.peak,
+            limit: self.limit,
+            time: timestamp::now()?,
+        })
+    }
+
+    /// Takes the average of the finished quarter-hour as peak, if it is higher.
+    fn finish_quarter(&mut self) {
+        if self.state.quarter_samples > 0 {
+            let average = self.state.quarter_sum / self.state.quarter_samples as f64;
+            if average > self.state.peak {
+                self.state.peak = average;
+                self.state.peak_time = self.state.quarter.map(|quarter| quarter.with_timezone(&Utc));
+            }
+        }
+        self.state.quarter_sum = 0.0;
+        self.state.quarter_samples = 0;
+    }
+}
+
+fn quarter_start(time: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    let minute = time.minute() - time.minute() % WINDOW_MINUTES as u32;
+    time.with_minute(minute)
+        .and_then(|t| t.with_second(0))
+        .and_then(|t| t.with_nanosecond(0))
+        .unwrap_or(time)
+}
@@ -0,0 +1,329 @@
+//! Processing of the points of a cycle before they are written.
+//!
+//! The points pass the stages given by `PIPELINE` in order (default:
+//! `validate,derive,rename,filter`). Every stage is configured by its own
+//! variables and does nothing if they are not set, except for `validate`.
+//! The `script` stage (see the `script` module) is only available with the
+//! `script` cargo feature.
+//!
+//! Afterwards the points are routed to the sinks, see the `routing` module.
+
+use std::collections::HashMap;
+
+use log::warn;
+use thiserror::Error;
+
+use crate::{
+    metrics::METRICS,
+    point::{FieldValue, Point},
+    schema,
+    sink::Batch,
+    telemetry,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PipelineError {
+    #[error("unknown pipeline stage {0:?}, expected one of: validate, derive, rename, filter, script")]
+    UnknownStage(String),
+    #[cfg(not(feature = "script"))]
+    #[error("the script stage is not supported by this build, enable the script feature")]
+    ScriptDisabled,
+    #[error("invalid rule {0:?} in {1}")]
+    InvalidRule(String, &'static str),
+}
+
+/// A processing step. Stages can change, add and remove points.
+pub trait Stage {
+    fn name(&self) -> &'static str;
+
+    fn process(&mut self, points: &mut Vec<Point>);
+}
+
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Self {
+        Pipeline { stages }
+    }
+
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let order = std::env::var("PIPELINE").unwrap_or_else(|_| "validate,derive,rename,filter".to_owned());
+        let stages = order
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(stage_from_env)
+            .collect::<Result<_, _>>()?;
+        Ok(Pipeline::new(stages))
+    }
+
+    /// Runs all stages on the points of the batch.
+    pub fn process(&mut self, batch: &mut Batch) {
+        for stage in &mut self.stages {
+            telemetry::span(format!("stage {}", stage.name()), || stage.process(batch.points_mut()));
+        }
+    }
+}
+
+fn stage_from_env(name: &str) -> Result<Box<dyn Stage>, Box<dyn std::error::Error>> {
+    Ok(match name {
+        "validate" => Box::new(Validate),
+        "derive" => Box::new(Derive::from_env()?),
+        "rename" => Box::new(Rename::from_env()?),
+        "filter" => Box::new(Filter::from_env()?),
+        #[cfg(feature = "script")]
+        "script" => Box::new(crate::script::Script::from_env()?),
+        #[cfg(not(feature = "script"))]
+        "script" => return Err(PipelineError::ScriptDisabled.into()),
+        _ => return Err(PipelineError::UnknownStage(name.to_owned()).into()),
+    })
+}
+
+/// Parses a list of rules like `measurement.field=value` given by
+/// `variable_name`.
+fn parse_rules(list: &str, variable_name: &'static str) -> Result<Vec<(String, String, String)>, PipelineError> {
+    list.split(',')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(|rule| {
+            let invalid = || PipelineError::InvalidRule(rule.to_owned(), variable_name);
+            let (target, value) = rule.split_once('=').ok_or_else(invalid)?;
+            let (measurement, field) = target.trim().split_once('.').ok_or_else(invalid)?;
+            Ok((measurement.to_owned(), field.to_owned(), value.trim().to_owned()))
+        })
+        .collect()
+}
+
+/// Drops fields that are not finite numbers and points without fields.
+pub struct Validate;
+
+impl Stage for Validate {
+    fn name(&self) -> &'static str {
+        "validate"
+    }
+
+    fn process(&mut self, points: &mut Vec<Point>) {
+        points.retain_mut(|point| {
+            point.fields.retain(|(key, value)| {
+                let valid = value.as_f64().is_none_or(f64::is_finite);
+                if !valid {
+                    warn!("Dropping field {key} of {}, it is not a finite number", point.measurement);
+                }
+                valid
+            });
+            if point.fields.is_empty() {
+                METRICS.point_rejected();
+                warn!("Dropping point of {} without fields", point.measurement);
+            }
+            !point.fields.is_empty()
+        });
+    }
+}
+
+/// Adds fields calculated from other fields of the same point, e.g. for unit
+/// conversions. Configured by `DERIVE_FIELDS` as a list of rules like
+/// `power_flow.photovoltaik_kw=photovoltaik/1000`.
+pub struct Derive {
+    /// Measurement, new field, source field and factor.
+    rules: Vec<(String, String, String, f64)>,
+}
+
+impl Derive {
+    pub fn from_env() -> Result<Self, PipelineError> {
+        Derive::parse(&std::env::var("DERIVE_FIELDS").unwrap_or_default())
+    }
+
+    /// Parses the rules in the format of `DERIVE_FIELDS`.
+    pub fn parse(list: &str) -> Result<Self, PipelineError> {
+        let rules = parse_rules(list, "DERIVE_FIELDS")?
+            .into_iter()
+            .map(|(measurement, field, expression)| {
+                let invalid = || PipelineError::InvalidRule(expression.clone(), "DERIVE_FIELDS");
+                let (source, factor) = if let Some((source, factor)) = expression.split_once('*') {
+                    (source, factor.trim().parse::<f64>().map_err(|_| invalid())?)
+                } else if let Some((source, divisor)) = expression.split_once('/') {
+                    (source, 1.0 / divisor.trim().parse::<f64>().map_err(|_| invalid())?)
+                } else {
+                    (expression.as_str(), 1.0)
+                };
+                Ok((measurement, field, source.trim().to_owned(), factor))
+            })
+            .collect::<Result<_, PipelineError>>()?;
+        Ok(Derive { rules })
+    }
+}
+
+impl Stage for Derive {
+    fn name(&self) -> &'static str {
+        "derive"
+    }
+
+    fn process(&mut self, points: &mut Vec<Point>) {
+        for point in points.iter_mut() {
+            for (measurement, field, source, factor) in &self.rules {
+                if point.measurement != *measurement {
+                    continue;
+                }
+                if let Some(value) = point.field(source).and_then(FieldValue::as_f64) {
+                    point.set_field(field, FieldValue::Float(value * factor));
+                }
+            }
+        }
+    }
+}
+
+/// Renames fields given by `RENAME_FIELDS` as a list of rules like
+/// `power_flow.photovoltaik=pv`. Also applies the schema options: the
+/// `schema` tag and the old names of renamed fields (`SCHEMA_COMPAT`).
+pub struct Rename {
+    /// Measurement, field and new name.
+    rules: Vec<(String, String, String)>,
+}
+
+impl Rename {
+    pub fn from_env() -> Result<Self, PipelineError> {
+        Rename::parse(&std::env::var("RENAME_FIELDS").unwrap_or_default())
+    }
+
+    /// Parses the rules in the format of `RENAME_FIELDS`.
+    pub fn parse(list: &str) -> Result<Self, PipelineError> {
+        Ok(Rename {
+            rules: parse_rules(list, "RENAME_FIELDS")?,
+        })
+    }
+}
+
+impl Stage for Rename {
+    fn name(&self) -> &'static str {
+        "rename"
+    }
+
+    fn process(&mut self, points: &mut Vec<Point>) {
+        for point in points.iter_mut() {
+            let measurement = point.measurement.clone();
+            for (old, new) in schema::compat_renames(&measurement) {
+                if let Some(value) = point.field(new).cloned() {
+                    point.set_field(old, value);
+                }
+            }
+            for (measurement, field, name) in &self.rules {
+                if point.measurement != *measurement {
+                    continue;
+                }
+                if let Some((key, _)) = point.fields.iter_mut().find(|(key, _)| key == field) {
+                    *key = name.clone();
+                }
+            }
+            if schema::version_tag() {
+                point.set_tag("schema", &schema::VERSION.to_string());
+            }
+        }
+    }
+}
+
+/// Drops measurements or single fields given by `FILTER_DROP` (e.g.
+/// `inverter_info,meter.l1_power_factor`) and fields that changed less than
+/// their deadband given by `DEADBAND` (e.g. `meter.power=10`) since they were
+/// last written. Points without fields left are dropped, as are points of the
+/// measurements of `FILTER_UNCHANGED` whose fields are all the same as the last
+/// time.
+pub struct Filter {
+    drop: Vec<(String, Option<String>)>,
+    /// Deadband by measurement and field.
+    deadbands: Vec<(String, String, f64)>,
+    /// Last written value by series and field.
+    written: HashMap<(String, String), f64>,
+    unchanged: Vec<String>,
+    /// Last written fields by series, of the `unchanged` measurements.
+    last_fields: HashMap<String, Vec<(String, FieldValue)>>,
+}
+
+impl Filter {
+    pub fn from_env() -> Result<Self, PipelineError> {
+        let var = |name| std::env::var(name).unwrap_or_default();
+        Filter::parse(&var("FILTER_DROP"), &var("DEADBAND"), &var("FILTER_UNCHANGED"))
+    }
+
+    /// Parses the lists in the format of `FILTER_DROP`, `DEADBAND` and
+    /// `FILTER_UNCHANGED`.
+    pub fn parse(drop: &str, deadband: &str, unchanged: &str) -> Result<Self, PipelineError> {
+        let drop = drop
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once('.') {
+                Some((measurement, field)) => (measurement.to_owned(), Some(field.to_owned())),
+                None => (entry.to_owned(), None),
+            })
+            .collect();
+        let deadbands = parse_rules(deadband, "DEADBAND")?
+            .into_iter()
+            .map(|(measurement, field, deadband)| match deadband.parse() {
+                Ok(deadband) => Ok((measurement, field, deadband)),
+                Err(_) => Err(PipelineError::InvalidRule(deadband, "DEADBAND")),
+            })
+            .collect::<Result<_, _>>()?;
+        let unchanged = unchanged
+            .split(',')
+            .map(str::trim)
+            .filter(|measurement| !measurement.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Ok(Filter {
+            drop,
+            deadbands,
+            written: HashMap::new(),
+            unchanged,
+            last_fields: HashMap::new(),
+        })
+    }
+}
+
+impl Stage for Filter {
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    fn process(&mut self, points: &mut Vec<Point>) {
+        let drop = &self.drop;
+        let deadbands = &self.deadbands;
+        let written = &mut self.written;
+        let unchanged = &self.unchanged;
+        let last_fields = &mut self.last_fields;
+        points.retain_mut(|point| {
+            if drop.iter().any(|(measurement, field)| *measurement == point.measurement && field.is_none()) {
+                return false;
+            }
+            let series = point.series();
+            if unchanged.contains(&point.measurement) {
+                if last_fields.get(&series) == Some(&point.fields) {
+                    return false;
+                }
+                last_fields.insert(series.clone(), point.fields.clone());
+            }
+            point.fields.retain(|(key, value)| {
+                if drop.iter().any(|(measurement, field)| *measurement == point.measurement && field.as_deref() == Some(key)) {
+                    return false;
+                }
+                let deadband = deadbands
+                    .iter()
+                    .find(|(measurement, field, _)| *measurement == point.measurement && field == key)
+                    .map(|(_, _, deadband)| *deadband);
+                match (deadband, value.as_f64()) {
+                    (Some(deadband), Some(value)) => {
+                        let last = written.entry((series.clone(), key.clone())).or_insert(f64::NAN);
+                        // NAN as last value is never within the deadband
+                        if (value - *last).abs() < deadband {
+                            return false;
+                        }
+                        *last = value;
+                        true
+                    }
+                    _ => true,
+                }
+            });
+            !point.fields.is_empty()
+        });
+    }
+}
@@ -0,0 +1,269 @@
+//! Simple battery charge planning for time-of-use tariffs.
+//!
+//! The PV production and the load are forecast per hour of the day from the
+//! values seen on the previous days. Together with the tariff and the state of
+//! charge of the battery, a plan for the next 24 hours is computed: the battery
+//! is charged from the grid in the cheapest hours if PV alone won't cover the
+//! expensive hours, and is kept for the expensive hours otherwise.
+
+use std::str::FromStr;
+
+use chrono::{prelude::*, Duration as ChronoDuration};
+use point_derive::ToPoint;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::timestamp::{self, TimestampError};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid tariff schedule {0:?}, expected e.g. \"0-6=0.18,6-22=0.32,22-24=0.18\"")]
+pub struct InvalidTariff(String);
+
+/// Price per kWh for every hour of the day (local time).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tariff([f64; 24]);
+
+impl FromStr for Tariff {
+    type Err = InvalidTariff;
+
+    /// Parses windows of full hours like `0-6=0.18,6-22=0.32,22-24=0.18`.
+    /// Every hour of the day has to be covered by exactly one window.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidTariff(s.to_owned());
+        let mut prices = [None; 24];
+        for window in s.split(',') {
+            let (hours, price) = window.split_once('=').ok_or_else(invalid)?;
+            let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+            let start: usize = start.trim().parse().map_err(|_| invalid())?;
+            let end: usize = end.trim().parse().map_err(|_| invalid())?;
+            let price: f64 = price.trim().parse().map_err(|_| invalid())?;
+            if start >= end || end > 24 || prices[start..end].iter().any(Option::is_some) {
+                return Err(invalid());
+            }
+            prices[start..end].fill(Some(price));
+        }
+        let mut tariff = [0.0; 24];
+        for (price, hour) in tariff.iter_mut().zip(prices) {
+            *price = hour.ok_or_else(invalid)?;
+        }
+        Ok(Tariff(tariff))
+    }
+}
+
+/// Average value per hour of the day, the past days are weighted less.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    hours: [Option<f64>; 24],
+    /// Hour, sum and count of the samples of the running hour.
+    current: Option<(u32, f64, u32)>,
+}
+
+impl Profile {
+    /// Weight of the newest day in the average.
+    const NEW_DAY_WEIGHT: f64 = 0.3;
+
+    /// Adds a sample of `hour`, the average of the hour is updated once a
+    /// sample of another hour is added.
+    pub fn add(&mut self, hour: u32, value: f64) {
+        match &mut self.current {
+            Some((current_hour, sum, count)) if *current_hour == hour => {
+                *sum += value;
+                *count += 1;
+            }
+            current => {
+                if let Some((finished_hour, sum, count)) = current.take() {
+                    let mean = sum / count as f64;
+                    let average = &mut self.hours[finished_hour as usize];
+                    *average = Some(match *average {
+                        Some(average) => average * (1.0 - Self::NEW_DAY_WEIGHT) + mean * Self::NEW_DAY_WEIGHT,
+                        None => mean,
+                    });
+                }
+                *current = Some((hour, value, 1));
+            }
+        }
+    }
+
+    pub fn forecast(&self, hour: u32) -> f64 {
+        self.hours[hour as usize].unwrap_or(0.0)
+    }
+}
+
+/// Forecast profiles, kept across restarts in the state file.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct Profiles {
+    pub pv: Profile,
+    pub load: Profile,
+}
+
+#[derive(Default, Debug, Clone, Serialize, ToPoint)]
+#[measurement = "battery_plan"]
+pub struct PlanSlot {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    action: String,
+    #[influxdb(field)]
+    price: f64,
+    #[influxdb(field)]
+    pv_forecast: f64,
+    #[influxdb(field)]
+    load_forecast: f64,
+    #[influxdb(field)]
+    charge_percentage: Option<f64>,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// The plan for the next 24 hours, one slot per hour.
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct Plan {
+    device: &'static str,
+    created: DateTime<Utc>,
+    slots: Vec<PlanSlot>,
+}
+
+impl Plan {
+    pub fn slots(&self) -> &[PlanSlot] {
+        &self.slots
+    }
+}
+
+pub struct Planner {
+    tariff: Tariff,
+    charge_power: f64,
+    profiles: Profiles,
+    charge_percentage: Option<f64>,
+    capacity: Option<f64>,
+    planned_hour: Option<DateTime<FixedOffset>>,
+}
+
+impl Planner {
+    pub fn new(tariff: Tariff, charge_power: f64) -> Self {
+        Planner {
+            tariff,
+            charge_power,
+            profiles: Profiles::default(),
+            charge_percentage: None,
+            capacity: None,
+            planned_hour: None,
+        }
+    }
+
+    /// Creates the planner if `TARIFF_SCHEDULE` is set. `BATTERY_CHARGE_POWER`
+    /// is the power the battery is charged from the grid with in W (default 3000).
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(tariff) = std::env::var("TARIFF_SCHEDULE") else {
+            return Ok(None);
+        };
+        let charge_power = match std::env::var("BATTERY_CHARGE_POWER") {
+            Ok(val) => val.parse()?,
+            Err(_) => 3000.0,
+        };
+        Ok(Some(Planner::new(tariff.parse()?, charge_power)))
+    }
+
+    pub fn profiles(&self) -> &Profiles {
+        &self.profiles
+    }
+
+    pub fn restore(&mut self, profiles: Profiles) {
+        self.profiles = profiles;
+    }
+
+    /// Adds the current PV production and load in W.
+    pub fn add_power(&mut self, pv: f64, load: f64) {
+        let hour = crate::timezone::now().hour();
+        self.profiles.pv.add(hour, pv);
+        self.profiles.load.add(hour, load);
+    }
+
+    /// Sets the state of the battery, the capacity is given in Wh.
+    pub fn set_battery(&mut self, charge_percentage: f64, capacity: f64) {
+        self.charge_percentage = Some(charge_percentage);
+        self.capacity = Some(capacity);
+    }
+
+    /// Returns a new plan once per hour, starting with the current hour.
+    pub fn take(&mut self) -> Result<Option<Plan>, TimestampError> {
+        let now = crate::timezone::now();
+        let hour = now
+            .with_minute(0)
+            .and_then(|t| t.with_second(0))
+            .and_then(|t| t.with_nanosecond(0))
+            .unwrap_or(now);
+        if self.planned_hour == Some(hour) {
+            return Ok(None);
+        }
+        self.planned_hour = Some(hour);
+        Ok(Some(self.plan(hour.with_timezone(&Utc), crate::timezone::local)?))
+    }
+
+    /// The plan for the 24 hours from `start`. The hours are stepped in UTC
+    /// and converted to the time zone of the site by `local`, so the hour
+    /// that is skipped or repeated by a daylight saving time switch gets the
+    /// price of the right hour of the tariff.
+    pub fn plan(&self, start: DateTime<Utc>, local: impl Fn(DateTime<Utc>) -> DateTime<FixedOffset>) -> Result<Plan, TimestampError> {
+        let hours: Vec<DateTime<FixedOffset>> = (0..24).map(|i| local(start + ChronoDuration::hours(i))).collect();
+        let price = |time: &DateTime<FixedOffset>| self.tariff.0[time.hour() as usize];
+        let pv: Vec<f64> = hours.iter().map(|t| self.profiles.pv.forecast(t.hour())).collect();
+        let load: Vec<f64> = hours.iter().map(|t| self.profiles.load.forecast(t.hour())).collect();
+        let prices: Vec<f64> = hours.iter().map(price).collect();
+        let cheapest = prices.iter().copied().fold(f64::INFINITY, f64::min);
+        let highest = prices.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+
+        // energy in Wh the battery has to deliver in the hours that are more
+        // expensive than the cheapest one, reduced by the expected PV surplus
+        let capacity = self.capacity.unwrap_or(0.0);
+        let stored = self.charge_percentage.unwrap_or(0.0) / 100.0 * capacity;
+        let needed: f64 = (0..24).filter(|&i| prices[i] > cheapest).map(|i| (load[i] - pv[i]).max(0.0)).sum();
+        let surplus: f64 = (0..24).map(|i| (pv[i] - load[i]).max(0.0)).sum();
+        let mut grid_charge = (needed - stored - surplus).clamp(0.0, (capacity - stored).max(0.0));
+
+        // charge from the grid in the cheapest hours first, this only pays off
+        // in hours that are cheaper than the most expensive one
+        let mut charge_hours = [false; 24];
+        let mut by_price: Vec<usize> = (0..24).filter(|&i| prices[i] < highest).collect();
+        by_price.sort_by(|&a, &b| prices[a].total_cmp(&prices[b]));
+        for i in by_price {
+            if grid_charge <= 0.0 {
+                break;
+            }
+            charge_hours[i] = true;
+            grid_charge -= self.charge_power;
+        }
+
+        let mut energy = stored;
+        let mut slots = Vec::with_capacity(24);
+        for i in 0..24 {
+            let net = pv[i] - load[i];
+            let action = if charge_hours[i] {
+                energy += self.charge_power;
+                "charge_grid"
+            } else if net > 0.0 {
+                energy += net;
+                "charge_pv"
+            } else if prices[i] > cheapest {
+                energy += net;
+                "discharge"
+            } else {
+                "hold"
+            };
+            energy = energy.clamp(0.0, capacity);
+            slots.push(PlanSlot {
+                device: "Battery",
+                action: action.to_owned(),
+                price: prices[i],
+                pv_forecast: pv[i],
+                load_forecast: load[i],
+                charge_percentage: self.capacity.filter(|c| *c > 0.0).map(|c| energy / c * 100.0),
+                time: timestamp::from_datetime(hours[i].with_timezone(&Utc))?,
+            });
+        }
+        Ok(Plan {
+            device: "Battery",
+            created: crate::clock::now(),
+            slots,
+        })
+    }
+}
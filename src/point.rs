@@ -0,0 +1,265 @@
+//! Structured representation of a point, so the processing stages and the
+//! sinks don't have to work on line protocol.
+
+use std::fmt::Write;
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid line protocol: {0}")]
+pub struct ParseError(&'static str);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    UInteger(u64),
+    Boolean(bool),
+    String(String),
+}
+
+impl FieldValue {
+    /// The value as number, if it is one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            FieldValue::Float(value) => Some(*value),
+            FieldValue::Integer(value) => Some(*value as f64),
+            FieldValue::UInteger(value) => Some(*value as f64),
+            FieldValue::Boolean(_) | FieldValue::String(_) => None,
+        }
+    }
+
+    /// The value for a table with a `value` and a `text` column: numbers
+    /// and booleans (as 0 or 1) go to `value`, strings to `text`.
+    #[cfg(any(feature = "clickhouse", feature = "adx", feature = "postgres", feature = "prometheus", feature = "csv"))]
+    pub fn to_columns(&self) -> (Option<f64>, Option<&str>) {
+        match self {
+            FieldValue::String(text) => (None, Some(text)),
+            FieldValue::Boolean(value) => (Some(f64::from(u8::from(*value))), None),
+            value => (value.as_f64(), None),
+        }
+    }
+
+    /// The value as JSON, `null` for a float that isn't finite.
+    #[cfg(any(feature = "mqtt", feature = "redis", feature = "kafka"))]
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            FieldValue::Float(value) => (*value).into(),
+            FieldValue::Integer(value) => (*value).into(),
+            FieldValue::UInteger(value) => (*value).into(),
+            FieldValue::Boolean(value) => (*value).into(),
+            FieldValue::String(value) => value.clone().into(),
+        }
+    }
+}
+
+/// A measurement struct that is written as a point, implemented by
+/// `#[derive(ToPoint)]` of the `point-derive` crate.
+pub trait ToPoint {
+    fn to_point(&self) -> Point;
+}
+
+/// A value of a measurement struct that is written as a field, `None` if it
+/// is left out.
+pub trait ToFieldValue {
+    fn to_field_value(&self) -> Option<FieldValue>;
+}
+
+impl ToFieldValue for f64 {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Float(*self))
+    }
+}
+
+impl ToFieldValue for i64 {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Integer(*self))
+    }
+}
+
+impl ToFieldValue for u64 {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::UInteger(*self))
+    }
+}
+
+impl ToFieldValue for bool {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::Boolean(*self))
+    }
+}
+
+impl ToFieldValue for String {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        Some(FieldValue::String(self.clone()))
+    }
+}
+
+impl<T: ToFieldValue> ToFieldValue for Option<T> {
+    fn to_field_value(&self) -> Option<FieldValue> {
+        self.as_ref()?.to_field_value()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    /// Nanosecond timestamp.
+    pub time: i64,
+}
+
+impl Point {
+    #[cfg(any(test, feature = "influxdb2", feature = "mqtt", feature = "snmp"))]
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    }
+
+    pub fn field(&self, key: &str) -> Option<&FieldValue> {
+        self.fields.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+    }
+
+    /// Sets a tag, replacing an existing one with the same key.
+    pub fn set_tag(&mut self, key: &str, value: &str) {
+        match self.tags.iter_mut().find(|(k, _)| k == key) {
+            Some((_, existing)) => *existing = value.to_owned(),
+            None => self.tags.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    /// Sets a field, replacing an existing one with the same key.
+    pub fn set_field(&mut self, key: &str, value: FieldValue) {
+        match self.fields.iter_mut().find(|(k, _)| k == key) {
+            Some((_, existing)) => *existing = value,
+            None => self.fields.push((key.to_owned(), value)),
+        }
+    }
+
+    /// Parses a line of line protocol with a timestamp.
+    pub fn from_line(line: &str) -> Result<Self, ParseError> {
+        let parts = split_unescaped(line.trim_end(), ' ');
+        let [series, fields, time] = parts.as_slice() else {
+            return Err(ParseError("expected measurement, fields and timestamp"));
+        };
+        let mut series = split_unescaped(series, ',').into_iter();
+        let measurement = unescape(series.next().unwrap_or_default());
+        if measurement.is_empty() {
+            return Err(ParseError("empty measurement"));
+        }
+        let tags = series
+            .map(|tag| match split_unescaped(tag, '=').as_slice() {
+                [key, value] => Ok((unescape(key), unescape(value))),
+                _ => Err(ParseError("tag without value")),
+            })
+            .collect::<Result<_, _>>()?;
+        let fields = split_unescaped(fields, ',')
+            .into_iter()
+            .map(|field| {
+                let (key, value) = field.split_once('=').ok_or(ParseError("field without value"))?;
+                Ok((unescape(key), parse_value(value)?))
+            })
+            .collect::<Result<_, _>>()?;
+        let time = time.parse().map_err(|_| ParseError("invalid timestamp"))?;
+        Ok(Point {
+            measurement,
+            tags,
+            fields,
+            time,
+        })
+    }
+
+    /// The measurement and the tags in line protocol, which identify the
+    /// series of the point.
+    pub fn series(&self) -> String {
+        let mut series = escape(&self.measurement, &[',', ' ']);
+        for (key, value) in &self.tags {
+            let _ = write!(series, ",{}={}", escape(key, &[',', '=', ' ']), escape(value, &[',', '=', ' ']));
+        }
+        series
+    }
+
+    /// Encodes the point as a line of line protocol.
+    pub fn to_line(&self) -> String {
+        let mut line = self.series();
+        for (i, (key, value)) in self.fields.iter().enumerate() {
+            line.push(if i == 0 { ' ' } else { ',' });
+            line.push_str(&escape(key, &[',', '=', ' ']));
+            line.push('=');
+            let _ = match value {
+                FieldValue::Float(value) => write!(line, "{value}"),
+                FieldValue::Integer(value) => write!(line, "{value}i"),
+                FieldValue::UInteger(value) => write!(line, "{value}u"),
+                FieldValue::Boolean(value) => write!(line, "{value}"),
+                FieldValue::String(value) => write!(line, "\"{}\"", escape(value, &['"'])),
+            };
+        }
+        let _ = write!(line, " {}", self.time);
+        line
+    }
+}
+
+fn parse_value(value: &str) -> Result<FieldValue, ParseError> {
+    let invalid = ParseError("invalid field value");
+    if let Some(string) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        return Ok(FieldValue::String(unescape(string)));
+    }
+    if let Some(integer) = value.strip_suffix('i') {
+        return integer.parse().map(FieldValue::Integer).map_err(|_| invalid);
+    }
+    if let Some(integer) = value.strip_suffix('u') {
+        return integer.parse().map(FieldValue::UInteger).map_err(|_| invalid);
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Ok(FieldValue::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => Ok(FieldValue::Boolean(false)),
+        _ => value.parse().map(FieldValue::Float).map_err(|_| invalid),
+    }
+}
+
+/// Escapes the given characters and backslashes with a backslash.
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Splits at `separator`, ignoring escaped separators and separators
+/// within double quoted strings.
+pub fn split_unescaped(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
@@ -0,0 +1,139 @@
+//! Writing of the points to PostgreSQL, e.g. with TimescaleDB or as the
+//! database that is there anyway.
+//!
+//! Only available with the `postgres` cargo feature and enabled by
+//! `POSTGRES_URL` (e.g. `postgresql://fronius:secret@db/fronius`, TLS isn't
+//! supported). All measurements go to one table (`POSTGRES_TABLE`, default
+//! `fronius`) with one row per field, the same layout as the ClickHouse
+//! table, which is created when the connection is opened:
+//!
+//! ```sql
+//! CREATE TABLE IF NOT EXISTS fronius (
+//!     time timestamptz NOT NULL,
+//!     measurement text NOT NULL,
+//!     tags jsonb NOT NULL,
+//!     field text NOT NULL,
+//!     value double precision,
+//!     text text
+//! )
+//! ```
+//!
+//! Numbers and booleans are written to `value`, strings to `text`. The rows
+//! of a write are inserted with one statement.
+
+use std::{sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use postgres::{Client, Config, NoTls};
+
+use crate::{
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_TABLE: &str = "fronius";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct PostgresSink {
+    config: Config,
+    /// Table name, quoted.
+    table: String,
+    client: Mutex<Option<Client>>,
+    routes: Routes,
+}
+
+/// The columns of the rows of a write.
+#[derive(Default)]
+struct Rows {
+    time: Vec<DateTime<Utc>>,
+    measurement: Vec<String>,
+    tags: Vec<serde_json::Value>,
+    field: Vec<String>,
+    value: Vec<Option<f64>>,
+    text: Vec<Option<String>>,
+}
+
+impl PostgresSink {
+    /// Creates the sink for `url`, optional is `POSTGRES_TABLE`. The
+    /// connection is opened at the first write.
+    pub fn from_env(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config: Config = url.parse()?;
+        config.connect_timeout(TIMEOUT);
+        let table = std::env::var("POSTGRES_TABLE").unwrap_or_else(|_| DEFAULT_TABLE.to_owned());
+        let sink = PostgresSink {
+            config,
+            table: format!("\"{}\"", table.replace('"', "\"\"")),
+            client: Mutex::new(None),
+            routes: Routes::from_env()?,
+        };
+        info!("Writing points to PostgreSQL table {}", sink.table);
+        Ok(sink)
+    }
+
+    /// Opens a connection and creates the table, if it doesn't exist.
+    fn connect(&self) -> Result<Client, postgres::Error> {
+        let mut client = self.config.connect(NoTls)?;
+        client.batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\
+             time timestamptz NOT NULL, \
+             measurement text NOT NULL, \
+             tags jsonb NOT NULL, \
+             field text NOT NULL, \
+             value double precision, \
+             text text\
+             )",
+            self.table
+        ))?;
+        Ok(client)
+    }
+
+    fn insert(&self, client: &mut Option<Client>, rows: &Rows) -> Result<(), postgres::Error> {
+        let client = match client {
+            Some(client) => client,
+            None => client.insert(self.connect()?),
+        };
+        let statement = format!(
+            "INSERT INTO {} (time, measurement, tags, field, value, text) \
+             SELECT * FROM UNNEST($1::timestamptz[], $2::text[], $3::jsonb[], $4::text[], $5::float8[], $6::text[])",
+            self.table
+        );
+        client.execute(&statement, &[&rows.time, &rows.measurement, &rows.tags, &rows.field, &rows.value, &rows.text])?;
+        Ok(())
+    }
+}
+
+impl Sink for PostgresSink {
+    fn name(&self) -> &'static str {
+        "PostgreSQL"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut rows = Rows::default();
+        for point in batch.points() {
+            if !self.routes.targets(&point.measurement).contains(&Target::Postgres) {
+                continue;
+            }
+            let time = DateTime::from_timestamp_nanos(point.time);
+            let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+            for (field, value) in &point.fields {
+                let (value, text) = value.to_columns();
+                rows.time.push(time);
+                rows.measurement.push(point.measurement.clone());
+                rows.tags.push(serde_json::Value::Object(tags.clone()));
+                rows.field.push(field.clone());
+                rows.value.push(value);
+                rows.text.push(text.map(str::to_owned));
+            }
+        }
+        if rows.time.is_empty() {
+            return Ok(());
+        }
+        let mut client = self.client.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(error) = self.insert(&mut client, &rows) {
+            *client = None;
+            return Err(error.into());
+        }
+        Ok(())
+    }
+}
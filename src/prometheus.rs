@@ -0,0 +1,167 @@
+//! Prometheus endpoint with the latest values of the plant, to be scraped
+//! instead of writing the points to a database.
+//!
+//! Only available with the `prometheus` cargo feature and enabled by
+//! `PROMETHEUS_LISTEN` (e.g. `0.0.0.0:9184`). `GET /metrics` returns every
+//! numeric field of the last points as gauge `fronius_<measurement>_<field>`
+//! with the tags as labels, booleans as 0 or 1, strings are left out. A
+//! series without a new value for 15 minutes is dropped, so a device that is
+//! gone doesn't report its last value forever. If one of the REST API tokens
+//! is set, the scrapes need it as bearer token. The metrics about the
+//! collector itself stay at `/metrics` of the REST API.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use tiny_http::{Header, Response, Server};
+
+use crate::{
+    point::Point,
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The values by metric name and labels, with the time they were set.
+type Latest = BTreeMap<String, BTreeMap<String, (f64, Instant)>>;
+
+pub struct PrometheusSink {
+    latest: Arc<Mutex<Latest>>,
+    routes: Routes,
+}
+
+impl PrometheusSink {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(PrometheusSink {
+            latest: Arc::default(),
+            routes: Routes::from_env()?,
+        })
+    }
+
+    /// The values in the Prometheus text format.
+    #[cfg(test)]
+    pub fn render(&self) -> String {
+        render(&self.latest)
+    }
+
+    fn update(&self, point: &Point) {
+        let mut latest = self.latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let labels = labels(point);
+        let now = Instant::now();
+        for (field, value) in &point.fields {
+            let Some(value) = value.to_columns().0.filter(|value| value.is_finite()) else {
+                continue;
+            };
+            let name = format!("fronius_{}_{}", sanitize(&point.measurement), sanitize(field));
+            latest.entry(name).or_default().insert(labels.clone(), (value, now));
+        }
+    }
+}
+
+fn render(latest: &Mutex<Latest>) -> String {
+    let latest = latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut out = String::new();
+    for (name, series) in latest.iter() {
+        let mut series = series.iter().filter(|(_, (_, updated))| updated.elapsed() < STALE_AFTER).peekable();
+        if series.peek().is_none() {
+            continue;
+        }
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        for (labels, (value, _)) in series {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    }
+    out
+}
+
+/// The tags of the point as labels, e.g. `{device="Inverter",site="home"}`.
+fn labels(point: &Point) -> String {
+    if point.tags.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = point
+        .tags
+        .iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{value}\"", sanitize(key))
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// A metric or label name, characters other than letters, digits and `_`
+/// are replaced by `_`.
+fn sanitize(name: &str) -> String {
+    let mut sanitized: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+impl Sink for PrometheusSink {
+    fn name(&self) -> &'static str {
+        "Prometheus"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        for point in batch.points() {
+            if self.routes.targets(&point.measurement).contains(&Target::Prometheus) {
+                self.update(point);
+            }
+        }
+        let mut latest = self.latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for series in latest.values_mut() {
+            series.retain(|_, (_, updated)| updated.elapsed() < STALE_AFTER);
+        }
+        latest.retain(|_, series| !series.is_empty());
+        Ok(())
+    }
+
+    fn live(&self) -> bool {
+        true
+    }
+}
+
+/// Starts the endpoint on `addr` in a background thread and returns the
+/// sink that feeds it.
+pub fn spawn(addr: &str, tokens: Vec<String>) -> Result<PrometheusSink, Box<dyn std::error::Error>> {
+    let server = Server::http(addr).map_err(|e| e.to_string())?;
+    let sink = PrometheusSink::new()?;
+    let latest = sink.latest.clone();
+    info!("Prometheus endpoint listening on {addr}");
+    std::thread::Builder::new().name("prometheus".to_owned()).spawn(move || {
+        for request in server.incoming_requests() {
+            let authorized = tokens.is_empty()
+                || request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Authorization"))
+                    .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+                    .is_some_and(|given| tokens.iter().any(|token| crate::secret::token_eq(given, token)));
+            let result = if !authorized {
+                request.respond(Response::empty(401))
+            } else if request.url().split('?').next() != Some("/metrics") {
+                request.respond(Response::empty(404))
+            } else {
+                let mut response = Response::from_string(render(&latest));
+                if let Ok(header) = Header::from_bytes("Content-Type", CONTENT_TYPE) {
+                    response.add_header(header);
+                }
+                request.respond(response)
+            };
+            if let Err(error) = result {
+                error!("Error during Prometheus response occured: {:?}", error);
+            }
+        }
+    })?;
+    Ok(sink)
+}
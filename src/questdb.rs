@@ -0,0 +1,150 @@
+//! Writing of the points to QuestDB via InfluxDB line protocol over TCP.
+//!
+//! Only available with the `questdb` cargo feature and enabled by
+//! `QUESTDB_ADDR` (e.g. `questdb:9009`). QuestDB creates the tables and
+//! columns itself. If `QUESTDB_KEY_ID` and `QUESTDB_PRIVATE_KEY` are set, the
+//! connection is authenticated with QuestDB's challenge-response handshake:
+//! the server sends a challenge, which is signed with the ECDSA P-256 key
+//! (the `d` parameter of the JWK, base64url encoded).
+//!
+//! The protocol has no acknowledgements, a broken connection only shows up
+//! at the next write. It is then opened again and the batch is sent once
+//! more, if that fails too the points are dropped.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+
+use base64::Engine;
+use log::{info, warn};
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use thiserror::Error;
+
+use crate::{
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Error)]
+pub enum QuestDbError {
+    #[error("QUESTDB_PRIVATE_KEY is no valid P-256 key")]
+    InvalidKey,
+    #[error("QUESTDB_KEY_ID and QUESTDB_PRIVATE_KEY have to be set together")]
+    IncompleteAuth,
+    #[error("address {0:?} can't be resolved")]
+    UnresolvableAddress(String),
+}
+
+struct Auth {
+    key_id: String,
+    key: SigningKey,
+}
+
+pub struct QuestDbSink {
+    addr: String,
+    auth: Option<Auth>,
+    stream: Mutex<Option<TcpStream>>,
+    routes: Routes,
+}
+
+impl QuestDbSink {
+    /// Creates the sink for `addr`. Optional are `QUESTDB_KEY_ID` and
+    /// `QUESTDB_PRIVATE_KEY`. The connection is opened at the first write.
+    pub fn from_env(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let auth = match (std::env::var("QUESTDB_KEY_ID"), std::env::var("QUESTDB_PRIVATE_KEY")) {
+            (Ok(key_id), Ok(key)) => {
+                let key = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(key.trim().trim_end_matches('='))
+                    .map_err(|_| QuestDbError::InvalidKey)?;
+                Some(Auth {
+                    key_id,
+                    key: SigningKey::from_slice(&key).map_err(|_| QuestDbError::InvalidKey)?,
+                })
+            }
+            (Err(_), Err(_)) => None,
+            _ => return Err(QuestDbError::IncompleteAuth.into()),
+        };
+        info!("Writing points to QuestDB at {addr}");
+        Ok(QuestDbSink {
+            addr: addr.to_owned(),
+            auth,
+            stream: Mutex::new(None),
+            routes: Routes::from_env()?,
+        })
+    }
+
+    fn connect(&self) -> Result<TcpStream, Box<dyn std::error::Error>> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| QuestDbError::UnresolvableAddress(self.addr.clone()))?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        if let Some(auth) = &self.auth {
+            authenticate(&stream, auth)?;
+        }
+        Ok(stream)
+    }
+
+    fn send(&self, stream: &mut Option<TcpStream>, lines: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let stream = match stream {
+            Some(stream) => stream,
+            None => stream.insert(self.connect()?),
+        };
+        stream.write_all(lines)?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Sends the key ID and answers the challenge of the server with its
+/// signature.
+fn authenticate(mut stream: &TcpStream, auth: &Auth) -> Result<(), Box<dyn std::error::Error>> {
+    stream.write_all(format!("{}\n", auth.key_id).as_bytes())?;
+    let mut challenge = Vec::new();
+    BufReader::new(stream).read_until(b'\n', &mut challenge)?;
+    if challenge.pop() != Some(b'\n') {
+        return Err("connection closed during the QuestDB authentication".into());
+    }
+    let signature: Signature = auth.key.sign(&challenge);
+    let signature = base64::engine::general_purpose::STANDARD.encode(signature.to_der());
+    stream.write_all(format!("{signature}\n").as_bytes())?;
+    Ok(())
+}
+
+impl Sink for QuestDbSink {
+    fn name(&self) -> &'static str {
+        "QuestDB"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut lines = String::new();
+        for point in batch.points() {
+            if self.routes.targets(&point.measurement).contains(&Target::QuestDb) {
+                lines.push_str(&point.to_line());
+                lines.push('\n');
+            }
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let mut stream = self.stream.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(error) = self.send(&mut stream, lines.as_bytes()) {
+            warn!("QuestDB write failed, reconnecting: {:?}", error);
+            *stream = None;
+            if let Err(error) = self.send(&mut stream, lines.as_bytes()) {
+                *stream = None;
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+}
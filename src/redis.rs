@@ -0,0 +1,113 @@
+//! Publishing of the points to a Redis Stream, so small consumers (scripts,
+//! bots) can tail the recent values without a time series database.
+//!
+//! Only available with the `redis` cargo feature and enabled by `REDIS_URL`
+//! (e.g. `redis://localhost:6379/0`). Every point is added as one entry to
+//! the stream `REDIS_STREAM` (default `fronius`, `{measurement}` in the name
+//! gives one stream per measurement) with the entry fields `measurement`,
+//! `time` (Unix timestamp in nanoseconds), `tags` and `fields` (both JSON
+//! objects). The streams are capped at about `REDIS_MAXLEN` entries (default
+//! 10000), older entries are trimmed by Redis.
+
+use std::{sync::Mutex, time::Duration};
+
+use log::info;
+use redis::{Client, Connection};
+
+use crate::{
+    point::Point,
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_STREAM: &str = "fronius";
+const DEFAULT_MAXLEN: u64 = 10_000;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct RedisSink {
+    client: Client,
+    stream: String,
+    maxlen: u64,
+    connection: Mutex<Option<Connection>>,
+    routes: Routes,
+}
+
+impl RedisSink {
+    /// Creates the sink for `url`. Optional are `REDIS_STREAM` and
+    /// `REDIS_MAXLEN`. The connection is opened at the first write.
+    pub fn from_env(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let maxlen = match std::env::var("REDIS_MAXLEN") {
+            Ok(val) => val.parse()?,
+            Err(_) => DEFAULT_MAXLEN,
+        };
+        let sink = RedisSink {
+            client: Client::open(url)?,
+            stream: std::env::var("REDIS_STREAM").unwrap_or_else(|_| DEFAULT_STREAM.to_owned()),
+            maxlen,
+            connection: Mutex::new(None),
+            routes: Routes::from_env()?,
+        };
+        info!("Publishing points to the Redis stream {} at {url}", sink.stream);
+        Ok(sink)
+    }
+
+    fn send(&self, connection: &mut Option<Connection>, pipeline: &redis::Pipeline) -> redis::RedisResult<()> {
+        let connection = match connection {
+            Some(connection) => connection,
+            None => {
+                let connection = connection.insert(self.client.get_connection_with_timeout(TIMEOUT)?);
+                connection.set_read_timeout(Some(TIMEOUT))?;
+                connection.set_write_timeout(Some(TIMEOUT))?;
+                connection
+            }
+        };
+        pipeline.query(connection)
+    }
+}
+
+fn entry(point: &Point) -> [(&'static str, String); 4] {
+    let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+    let fields: serde_json::Map<_, _> = point.fields.iter().map(|(key, value)| (key.clone(), value.to_json())).collect();
+    [
+        ("measurement", point.measurement.clone()),
+        ("time", point.time.to_string()),
+        ("tags", serde_json::Value::Object(tags).to_string()),
+        ("fields", serde_json::Value::Object(fields).to_string()),
+    ]
+}
+
+impl Sink for RedisSink {
+    fn name(&self) -> &'static str {
+        "Redis"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut pipeline = redis::pipe();
+        let mut empty = true;
+        for point in batch.points() {
+            if !self.routes.targets(&point.measurement).contains(&Target::Redis) {
+                continue;
+            }
+            let stream = self.stream.replace("{measurement}", &point.measurement);
+            pipeline
+                .cmd("XADD")
+                .arg(stream)
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(self.maxlen)
+                .arg("*")
+                .arg(&entry(point)[..])
+                .ignore();
+            empty = false;
+        }
+        if empty {
+            return Ok(());
+        }
+        let mut connection = self.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Err(error) = self.send(&mut connection, &pipeline) {
+            *connection = None;
+            return Err(error.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! Search for a Datamanager that got a new address, e.g. from a new DHCP
+//! lease. The hosts of the /24 network of the old address are asked for
+//! their inverters and the one with an inverter of the site, by its unique
+//! ID, is taken. Unlike the logger info, every generation reports the
+//! inverters, GEN24 as well.
+//!
+//! The search takes up to half a minute, it runs in the background so the
+//! cycles of the other sites go on meanwhile.
+
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use fronius_api::fronius::blocking::FroniusBuilder;
+
+/// Hosts that are asked at the same time.
+const WORKERS: usize = 32;
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A search in the background.
+pub struct Search {
+    pub old: Ipv4Addr,
+    thread: JoinHandle<Option<Ipv4Addr>>,
+}
+
+impl Search {
+    /// Starts searching the network of `old` for the Datamanager with one of
+    /// the inverters `unique_ids`.
+    pub fn start(old: Ipv4Addr, unique_ids: Vec<String>) -> Self {
+        Search {
+            old,
+            thread: std::thread::spawn(move || find(old, &unique_ids)),
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// The new address, `None` if the Datamanager wasn't found. Waits for
+    /// the search if it hasn't finished yet.
+    pub fn result(self) -> Option<Ipv4Addr> {
+        self.thread.join().ok().flatten()
+    }
+}
+
+/// Searches the network of `old` for the Datamanager with one of the
+/// inverters `unique_ids`.
+fn find(old: Ipv4Addr, unique_ids: &[String]) -> Option<Ipv4Addr> {
+    let [a, b, c, _] = old.octets();
+    let candidates = Mutex::new((1..=254).map(|host| Ipv4Addr::new(a, b, c, host)).filter(|ip| *ip != old));
+    let found = Mutex::new(None);
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    let Some(ip) = candidates.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).next() else {
+                        break;
+                    };
+                    if inverter_ids(ip).iter().any(|id| unique_ids.contains(id)) {
+                        *found.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(ip);
+                        done.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    found.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// The unique IDs of the inverters of the Datamanager at `ip`, none if there
+/// is no Datamanager.
+fn inverter_ids(ip: Ipv4Addr) -> Vec<String> {
+    let Ok(fronius) = FroniusBuilder::new(ip.to_string())
+        .api_version(1)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(TIMEOUT)
+        .build()
+    else {
+        return Vec::new();
+    };
+    fronius.get_inverter_info().map(|infos| unique_ids(infos.into_values().flatten())).unwrap_or_default()
+}
+
+/// The unique IDs of `infos`, without empty ones.
+pub fn unique_ids(infos: impl IntoIterator<Item = fronius_api::fronius::InverterInfo>) -> Vec<String> {
+    infos.into_iter().map(|info| info.unique_id).filter(|id| !id.is_empty()).collect()
+}
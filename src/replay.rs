@@ -0,0 +1,114 @@
+//! Replay of the spool file or of line protocol files (see the `line_file`
+//! module) to the configured sinks, e.g. to recover after a long outage of
+//! the database.
+//!
+//! The points are written in batches at a limited rate, so a large backlog
+//! doesn't overload the database. They were processed before they were
+//! written to the file, so they don't pass the pipeline again; the routes
+//! decide where they go, the `# bucket=` lines of the spool are ignored.
+//! Points that can't be written are handled by the sinks as usual, e.g.
+//! spooled again. An encrypted spool is decrypted with `FILE_ENCRYPTION_KEY`.
+
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::{
+    control::Control,
+    encryption::{self, FileKey},
+    point::Point,
+    sink::{Batch, Sinks},
+    timestamp,
+};
+
+const DEFAULT_BATCH: usize = 1000;
+/// Points per second.
+const DEFAULT_RATE: u32 = 1000;
+
+const USAGE: &str = "usage: replay-spool [--rate <points/s>] [--batch <points>] [<file>...]";
+
+/// Runs the `replay-spool` command. Without files, `SPOOL_FILE` is
+/// replayed.
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    timestamp::init_from_env()?;
+    crate::schema::init_from_env()?;
+    let mut rate = DEFAULT_RATE;
+    let mut batch_size = DEFAULT_BATCH;
+    let mut files = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(USAGE);
+        match arg.as_str() {
+            "--rate" => rate = value()?.parse::<u32>()?.max(1),
+            "--batch" => batch_size = value()?.parse::<usize>()?.max(1),
+            _ if arg.starts_with('-') => return Err(USAGE.into()),
+            file => files.push(PathBuf::from(file)),
+        }
+    }
+    let spool_file = std::env::var_os("SPOOL_FILE").map(PathBuf::from);
+    if files.is_empty() {
+        files.extend(spool_file.clone());
+    }
+    if files.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    // the commands received by the MQTT sink don't apply to a replay
+    let control = Arc::new(Control::new(Duration::ZERO, false));
+    let sinks = Sinks::from_env(false, &control)?;
+    let key = FileKey::from_env()?;
+    let start = Instant::now();
+    let mut written = 0u64;
+    for file in files {
+        // the spool is moved aside, so the InfluxDB sink doesn't send it as
+        // well and can spool the points that fail again
+        let is_spool = spool_file.as_ref() == Some(&file);
+        let path = if is_spool {
+            let mut replayed = file.clone().into_os_string();
+            replayed.push(".replay");
+            let replayed = PathBuf::from(replayed);
+            std::fs::rename(&file, &replayed)?;
+            replayed
+        } else {
+            file.clone()
+        };
+        let opened = encryption::open(&std::fs::read_to_string(&path)?, key.as_ref())?;
+        if !opened.damaged.is_empty() {
+            warn!("Skipping {} damaged lines of {:?} that can't be decrypted", opened.damaged.len(), file);
+        }
+        let content = opened.plaintext;
+        info!("Replaying {:?}", file);
+        let mut points = Vec::new();
+        let mut invalid = 0;
+        for line in content.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            match Point::from_line(line) {
+                Ok(point) => points.push(point),
+                Err(_) => invalid += 1,
+            }
+        }
+        if invalid > 0 {
+            warn!("Skipping {invalid} invalid lines of {:?}", file);
+        }
+        for chunk in points.chunks(batch_size) {
+            let mut batch = Batch::default();
+            batch.points_mut().extend_from_slice(chunk);
+            sinks.write(&batch);
+            written += chunk.len() as u64;
+            // keep below the rate
+            let due = Duration::from_secs_f64(written as f64 / f64::from(rate));
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        info!("Replayed {} points of {:?}", points.len(), file);
+        if is_spool {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    sinks.flush();
+    Ok(())
+}
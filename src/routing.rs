@@ -0,0 +1,151 @@
+//! Routing of the measurements to the sinks, the last step after the
+//! processing pipeline.
+//!
+//! `ROUTES` is a list of rules like `power_flow=influx:fronius_raw`, every
+//! rule gives the targets of a measurement joined by `+`. The rule for `*`
+//! applies to the measurements without a rule, by default they are written
+//! to the default InfluxDB bucket, published via MQTT and written to the
+//! other enabled sinks.
+
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid route {0:?} in ROUTES, expected e.g. power_flow=influx:<bucket>")]
+pub struct InvalidRoute(String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// InfluxDB, the bucket or the default bucket (`INFLUX_DB_BUCKET`).
+    Influx(Option<String>),
+    /// InfluxDB 1.x, if `INFLUX1_URL` is set.
+    Influx1,
+    /// The MQTT broker, if `MQTT_HOST` is set.
+    Mqtt,
+    /// The SQLite database, if `SQLITE_FILE` is set.
+    Sqlite,
+    /// QuestDB, if `QUESTDB_ADDR` is set.
+    QuestDb,
+    /// ClickHouse, if `CLICKHOUSE_URL` is set.
+    ClickHouse,
+    /// Amazon Timestream, if `TIMESTREAM_DATABASE` is set.
+    Timestream,
+    /// Azure Data Explorer, if `ADX_CLUSTER_URL` is set.
+    Adx,
+    /// The Redis stream, if `REDIS_URL` is set.
+    Redis,
+    /// PostgreSQL, if `POSTGRES_URL` is set.
+    Postgres,
+    /// Kafka via the REST Proxy, if `KAFKA_REST_URL` is set.
+    Kafka,
+    /// The Prometheus endpoint, if `PROMETHEUS_LISTEN` is set.
+    Prometheus,
+    /// The CSV file, if `CSV_FILE` is set.
+    Csv,
+    /// The line protocol file, if `LINE_FILE` is set.
+    File,
+    /// Not written at all.
+    None,
+}
+
+impl Target {
+    fn parse(text: &str) -> Option<Self> {
+        match text.split_once(':') {
+            Some(("influx", bucket)) if !bucket.is_empty() => Some(Target::Influx(Some(bucket.to_owned()))),
+            None if text == "influx" => Some(Target::Influx(None)),
+            None if text == "influx1" => Some(Target::Influx1),
+            None if text == "mqtt" => Some(Target::Mqtt),
+            None if text == "sqlite" => Some(Target::Sqlite),
+            None if text == "questdb" => Some(Target::QuestDb),
+            None if text == "clickhouse" => Some(Target::ClickHouse),
+            None if text == "timestream" => Some(Target::Timestream),
+            None if text == "adx" => Some(Target::Adx),
+            None if text == "redis" => Some(Target::Redis),
+            None if text == "postgres" => Some(Target::Postgres),
+            None if text == "kafka" => Some(Target::Kafka),
+            None if text == "prometheus" => Some(Target::Prometheus),
+            None if text == "csv" => Some(Target::Csv),
+            None if text == "file" => Some(Target::File),
+            None if text == "none" => Some(Target::None),
+            _ => None,
+        }
+    }
+}
+
+/// Targets by measurement.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    rules: Vec<(String, Vec<Target>)>,
+}
+
+const DEFAULT: &[Target] = &[
+    Target::Influx(None),
+    Target::Influx1,
+    Target::Mqtt,
+    Target::Sqlite,
+    Target::QuestDb,
+    Target::ClickHouse,
+    Target::Timestream,
+    Target::Adx,
+    Target::Redis,
+    Target::Postgres,
+    Target::Kafka,
+    Target::Prometheus,
+    Target::Csv,
+    Target::File,
+];
+
+impl Routes {
+    pub fn from_env() -> Result<Self, InvalidRoute> {
+        let Ok(list) = std::env::var("ROUTES") else {
+            return Ok(Routes::default());
+        };
+        let rules = list
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let invalid = || InvalidRoute(rule.to_owned());
+                let (measurement, targets) = rule.split_once('=').ok_or_else(invalid)?;
+                let targets = targets
+                    .split('+')
+                    .map(|target| Target::parse(target.trim()).ok_or_else(invalid))
+                    .collect::<Result<_, _>>()?;
+                Ok((measurement.trim().to_owned(), targets))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Routes { rules })
+    }
+
+    pub fn targets(&self, measurement: &str) -> &[Target] {
+        self.rules
+            .iter()
+            .find(|(m, _)| m == measurement)
+            .or_else(|| self.rules.iter().find(|(m, _)| m == "*"))
+            .map_or(DEFAULT, |(_, targets)| targets.as_slice())
+    }
+
+    /// The InfluxDB buckets `measurement` is written to.
+    #[cfg(feature = "influxdb2")]
+    pub fn influx_buckets<'a>(&'a self, measurement: &str, default: &'a str) -> impl Iterator<Item = &'a str> {
+        self.targets(measurement).iter().filter_map(move |target| match target {
+            Target::Influx(bucket) => Some(bucket.as_deref().unwrap_or(default)),
+            _ => None,
+        })
+    }
+
+    /// All InfluxDB buckets that may be written to, `default` included.
+    #[cfg(feature = "influxdb2")]
+    pub fn all_influx_buckets<'a>(&'a self, default: &'a str) -> Vec<&'a str> {
+        let mut buckets = vec![default];
+        for (_, targets) in &self.rules {
+            for target in targets {
+                if let Target::Influx(Some(bucket)) = target {
+                    if !buckets.contains(&bucket.as_str()) {
+                        buckets.push(bucket);
+                    }
+                }
+            }
+        }
+        buckets
+    }
+}
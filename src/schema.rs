@@ -0,0 +1,555 @@
+//! Machine-readable description of all measurements the collector writes,
+//! printed by the `schema` subcommand.
+//!
+//! The description is maintained by hand, so it has to be updated together
+//! with the measurement structs and the tables in the README. Changes that
+//! break existing queries (renamed or removed fields and tags) increase [`VERSION`],
+//! renamed fields are added to [`RENAMES`].
+
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use thiserror::Error;
+
+/// Version of the output schema.
+pub const VERSION: u32 = 3;
+
+/// A field that got a new name with a schema version.
+#[derive(Debug, Serialize)]
+pub struct Rename {
+    measurement: &'static str,
+    old: &'static str,
+    new: &'static str,
+    /// Schema version that introduced the new name.
+    version: u32,
+}
+
+pub const RENAMES: &[Rename] = &[
+    Rename { measurement: "inverter_phase", old: "dc_l1_voltage", new: "ac_l1_voltage", version: 2 },
+    Rename { measurement: "inverter_phase", old: "dc_l2_voltage", new: "ac_l2_voltage", version: 2 },
+    Rename { measurement: "inverter_phase", old: "dc_l3_voltage", new: "ac_l3_voltage", version: 2 },
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid status fields {0:?}, expected both, numeric or label")]
+pub struct InvalidStatusFields(String);
+
+/// Which fields are written for the status codes of the devices: the code as
+/// number, which can be alerted on, and/or its text.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFields {
+    #[default]
+    Both,
+    Numeric,
+    Label,
+}
+
+impl std::str::FromStr for StatusFields {
+    type Err = InvalidStatusFields;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "both" => Ok(StatusFields::Both),
+            "numeric" => Ok(StatusFields::Numeric),
+            "label" => Ok(StatusFields::Label),
+            _ => Err(InvalidStatusFields(s.to_owned())),
+        }
+    }
+}
+
+impl StatusFields {
+    /// Returns the numeric code, if it is written.
+    pub fn numeric<T>(self, code: T) -> Option<T> {
+        (self != StatusFields::Label).then_some(code)
+    }
+
+    /// Returns the text of the code, if it is written.
+    pub fn label(self, label: impl ToString) -> Option<String> {
+        (self != StatusFields::Numeric).then(|| label.to_string())
+    }
+}
+
+/// How the schema is written, see [`init_from_env`].
+#[derive(Debug, Default)]
+struct Options {
+    version_tag: bool,
+    compat_version: Option<u32>,
+    status_fields: StatusFields,
+}
+
+static OPTIONS: OnceLock<Options> = OnceLock::new();
+
+/// Reads `SCHEMA_VERSION_TAG`, which adds the `schema` tag with the schema
+/// version to every point, and `SCHEMA_COMPAT`, the oldest schema version
+/// whose field names and tags are written as well. `STATUS_FIELDS` selects the
+/// fields written for status codes. Has to be called before the first point
+/// is written.
+pub fn init_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    let compat_version = match std::env::var("SCHEMA_COMPAT") {
+        Ok(val) => Some(val.parse()?),
+        Err(_) => None,
+    };
+    let status_fields = match std::env::var("STATUS_FIELDS") {
+        Ok(val) => val.parse()?,
+        Err(_) => StatusFields::default(),
+    };
+    let options = Options {
+        version_tag: crate::flag_from_env("SCHEMA_VERSION_TAG")?,
+        compat_version,
+        status_fields,
+    };
+    OPTIONS.get_or_init(|| options);
+    Ok(())
+}
+
+/// Whether the `schema` tag is added to the points.
+pub fn version_tag() -> bool {
+    OPTIONS.get().is_some_and(|options| options.version_tag)
+}
+
+/// Which fields are written for status codes.
+pub fn status_fields() -> StatusFields {
+    OPTIONS.get().map(|options| options.status_fields).unwrap_or_default()
+}
+
+/// Whether the points of devices keep the `device` tag, which was replaced by
+/// `device_id` and `device_name` with schema version 3.
+pub fn device_tag_kept() -> bool {
+    OPTIONS.get().and_then(|options| options.compat_version).is_some_and(|version| version < 3)
+}
+
+/// Old and new names of the fields of `measurement` that are written under
+/// their old name as well, for the schema version given by `SCHEMA_COMPAT`.
+pub fn compat_renames(measurement: &str) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+    let compat_version = OPTIONS.get().and_then(|options| options.compat_version);
+    RENAMES
+        .iter()
+        .filter(move |rename| rename.measurement == measurement && compat_version.is_some_and(|version| version < rename.version))
+        .map(|rename| (rename.old, rename.new))
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Float,
+    Integer,
+    Boolean,
+    String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Field {
+    name: &'static str,
+    #[serde(rename = "type")]
+    kind: FieldType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit: Option<&'static str>,
+    /// Whether the field is missing if the device doesn't report it.
+    optional: bool,
+}
+
+#[cfg(feature = "influxdb2")]
+impl Field {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn kind(&self) -> FieldType {
+        self.kind
+    }
+}
+
+const fn field(name: &'static str, kind: FieldType, unit: &'static str, optional: bool) -> Field {
+    Field {
+        name,
+        kind,
+        unit: if unit.is_empty() { None } else { Some(unit) },
+        optional,
+    }
+}
+
+const fn float(name: &'static str, unit: &'static str) -> Field {
+    field(name, FieldType::Float, unit, false)
+}
+
+const fn opt_float(name: &'static str, unit: &'static str) -> Field {
+    field(name, FieldType::Float, unit, true)
+}
+
+const fn integer(name: &'static str, unit: &'static str) -> Field {
+    field(name, FieldType::Integer, unit, false)
+}
+
+const fn boolean(name: &'static str) -> Field {
+    field(name, FieldType::Boolean, "", false)
+}
+
+const fn string(name: &'static str) -> Field {
+    field(name, FieldType::String, "", false)
+}
+
+#[derive(Debug, Serialize)]
+pub struct Measurement {
+    name: &'static str,
+    description: &'static str,
+    tags: &'static [&'static str],
+    fields: &'static [Field],
+}
+
+#[cfg(feature = "influxdb2")]
+impl Measurement {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn tags(&self) -> &'static [&'static str] {
+        self.tags
+    }
+
+    pub fn fields(&self) -> &'static [Field] {
+        self.fields
+    }
+}
+
+/// Returns the measurement with the given name.
+#[cfg(feature = "influxdb2")]
+pub fn measurement(name: &str) -> Option<&'static Measurement> {
+    MEASUREMENTS.iter().find(|measurement| measurement.name == name)
+}
+
+/// Tags that are added to the points of all measurements if enabled.
+pub const COMMON_TAGS: &[&str] = &["site", "schema"];
+
+#[derive(Debug, Serialize)]
+pub struct Schema {
+    version: u32,
+    /// Fields that were renamed, with the version of the new name.
+    renames: &'static [Rename],
+    /// Tags that are added to the points of every measurement if enabled.
+    common_tags: &'static [&'static str],
+    /// Unit of the timestamps, they may be truncated by `TIMESTAMP_PRECISION`.
+    timestamp_unit: &'static str,
+    measurements: &'static [Measurement],
+}
+
+pub const MEASUREMENTS: &[Measurement] = &[
+    Measurement {
+        name: "inverter",
+        description: "AC and DC values of an inverter",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            opt_float("ac_power", "W"),
+            opt_float("ac_power_abs", "W"),
+            opt_float("ac_current", "A"),
+            opt_float("ac_voltage", "V"),
+            opt_float("ac_frequency", "Hz"),
+            opt_float("dc_current", "A"),
+            opt_float("dc_voltage", "V"),
+            opt_float("total_energy", "Wh"),
+        ],
+    },
+    Measurement {
+        name: "inverter_mppt",
+        description: "DC values per MPP tracker of an inverter",
+        tags: &["device_id", "device_name", "tracker"],
+        fields: &[opt_float("dc_current", "A"), opt_float("dc_voltage", "V"), opt_float("dc_power", "W")],
+    },
+    Measurement {
+        name: "inverter_efficiency",
+        description: "DC to AC conversion efficiency of an inverter",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            float("ac_power", "W"),
+            float("dc_power", "W"),
+            opt_float("efficiency", "ratio"),
+            opt_float("daily_efficiency", "ratio"),
+            boolean("plausible"),
+        ],
+    },
+    Measurement {
+        name: "inverter_phase",
+        description: "AC values per phase of a three-phase inverter",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            opt_float("ac_l1_current", "A"),
+            opt_float("ac_l2_current", "A"),
+            opt_float("ac_l3_current", "A"),
+            opt_float("ac_l1_voltage", "V"),
+            opt_float("ac_l2_voltage", "V"),
+            opt_float("ac_l3_voltage", "V"),
+            opt_float("ac_l1_power", "W"),
+            opt_float("ac_l2_power", "W"),
+            opt_float("ac_l3_power", "W"),
+            opt_float("ac_l1_apparent_power", "VA"),
+            opt_float("ac_l2_apparent_power", "VA"),
+            opt_float("ac_l3_apparent_power", "VA"),
+        ],
+    },
+    Measurement {
+        name: "inverter_temperature",
+        description: "Ambient temperature and fan speeds of an inverter",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            float("ambient", "°C"),
+            opt_float("fan_front_left_speed", "rpm"),
+            opt_float("fan_front_right_speed", "rpm"),
+            opt_float("fan_back_left_speed", "rpm"),
+            opt_float("fan_back_right_speed", "rpm"),
+        ],
+    },
+    Measurement {
+        name: "inverter_info",
+        description: "Type, name and state of an inverter",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            integer("device_type", ""),
+            integer("pv_power", "W"),
+            string("name"),
+            boolean("is_visualized"),
+            string("id"),
+            integer("error_code", ""),
+            field("status", FieldType::Integer, "", true),
+            field("status_code", FieldType::String, "", true),
+            field("state", FieldType::String, "", true),
+        ],
+    },
+    Measurement {
+        name: "inventory",
+        description: "Serial number, model and firmware of a device, written when they change",
+        tags: &["class", "device_id", "device_name"],
+        fields: &[
+            field("number", FieldType::Integer, "", true),
+            string("serial"),
+            field("device_type", FieldType::Integer, "", true),
+            field("manufacturer", FieldType::String, "", true),
+            field("model", FieldType::String, "", true),
+            field("firmware", FieldType::String, "", true),
+        ],
+    },
+    Measurement {
+        name: "derating",
+        description: "Power reduction of an inverter and its reason",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            field("derated", FieldType::Boolean, "", true),
+            string("reason"),
+            integer("error_code", ""),
+            opt_float("power", "W"),
+            opt_float("power_limit", "W"),
+            opt_float("relative_power", "ratio"),
+        ],
+    },
+    Measurement {
+        name: "events",
+        description: "State codes of an inverter that were set or cleared",
+        tags: &["device_id", "device_name", "severity"],
+        fields: &[integer("code", ""), string("text"), boolean("active")],
+    },
+    Measurement {
+        name: "meter",
+        description: "Values of a smart meter",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            opt_float("l1_current", "A"),
+            opt_float("l2_current", "A"),
+            opt_float("l3_current", "A"),
+            opt_float("current", "A"),
+            opt_float("l1_voltage", "V"),
+            opt_float("l2_voltage", "V"),
+            opt_float("l3_voltage", "V"),
+            opt_float("l12_voltage", "V"),
+            opt_float("l23_voltage", "V"),
+            opt_float("l31_voltage", "V"),
+            opt_float("l1_power", "W"),
+            opt_float("l2_power", "W"),
+            opt_float("l3_power", "W"),
+            float("power", "W"),
+            opt_float("l1_reactive_power", "var"),
+            opt_float("l2_reactive_power", "var"),
+            opt_float("l3_reactive_power", "var"),
+            float("reactive_power", "var"),
+            opt_float("l1_apparent_power", "VA"),
+            opt_float("l2_apparent_power", "VA"),
+            opt_float("l3_apparent_power", "VA"),
+            float("apparent_power", "VA"),
+            opt_float("l1_power_factor", ""),
+            opt_float("l2_power_factor", ""),
+            opt_float("l3_power_factor", ""),
+            float("power_factor", ""),
+            float("frequency_average", "Hz"),
+        ],
+    },
+    Measurement {
+        name: "load_phase",
+        description: "Household load per phase",
+        tags: &["device"],
+        fields: &[float("l1_load", "W"), float("l2_load", "W"), float("l3_load", "W"), float("load", "W")],
+    },
+    Measurement {
+        name: "grid_quality",
+        description: "Voltage and frequency statistics of the grid per interval",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            opt_float("l1_voltage_min", "V"),
+            opt_float("l1_voltage_max", "V"),
+            opt_float("l1_voltage_avg", "V"),
+            opt_float("l2_voltage_min", "V"),
+            opt_float("l2_voltage_max", "V"),
+            opt_float("l2_voltage_avg", "V"),
+            opt_float("l3_voltage_min", "V"),
+            opt_float("l3_voltage_max", "V"),
+            opt_float("l3_voltage_avg", "V"),
+            float("frequency_min", "Hz"),
+            float("frequency_max", "Hz"),
+            float("frequency_deviation", "Hz"),
+            opt_float("voltage_unbalance", "%"),
+            integer("samples", ""),
+        ],
+    },
+    Measurement {
+        name: "storage",
+        description: "State of a battery",
+        tags: &["device_id", "device_name", "manufacturer", "model"],
+        fields: &[
+            boolean("enabled"),
+            field("status", FieldType::Integer, "", true),
+            float("charge_percentage", "%"),
+            float("capacity", "Wh"),
+            float("dc_current", "A"),
+            opt_float("dc_current_raw", "A"),
+            float("dc_voltage", "V"),
+            float("temperature_cell", "°C"),
+            opt_float("designed_capacity", "Wh"),
+            opt_float("cell_voltage_max", "V"),
+            opt_float("cell_voltage_min", "V"),
+        ],
+    },
+    Measurement {
+        name: "ohm_pilot",
+        description: "State of an OhmPilot",
+        tags: &["device_id", "device_name"],
+        fields: &[
+            field("state_code", FieldType::Integer, "", true),
+            field("state", FieldType::String, "", true),
+            integer("error_code", ""),
+            float("power", "W"),
+            opt_float("l1_power", "W"),
+            opt_float("l2_power", "W"),
+            opt_float("l3_power", "W"),
+            opt_float("l1_voltage", "V"),
+            opt_float("l2_voltage", "V"),
+            opt_float("l3_voltage", "V"),
+            float("energy_consumed", "Wh"),
+            float("temperature", "°C"),
+        ],
+    },
+    Measurement {
+        name: "power_flow",
+        description: "Power flow of the whole site",
+        tags: &["device"],
+        fields: &[
+            opt_float("akku", "W"),
+            opt_float("akku_raw", "W"),
+            opt_float("grid", "W"),
+            opt_float("load", "W"),
+            float("photovoltaik", "W"),
+            opt_float("relative_autonomy", "%"),
+            opt_float("relative_self_consumption", "%"),
+            opt_float("battery_charge", "W"),
+            opt_float("battery_discharge", "W"),
+            float("inverter_ac", "W"),
+            float("pv_production", "W"),
+            string("mode"),
+            field("meter_location", FieldType::String, "", true),
+            field("battery_standby", FieldType::Boolean, "", true),
+            opt_float("energy_day", "Wh"),
+            opt_float("energy_year", "Wh"),
+            opt_float("energy_total", "Wh"),
+        ],
+    },
+    Measurement {
+        name: "derived_energy",
+        description: "Energies of the current day, integrated from the power flow",
+        tags: &["device"],
+        fields: &[
+            float("pv_energy", "Wh"),
+            float("load_energy", "Wh"),
+            float("grid_import_energy", "Wh"),
+            float("grid_export_energy", "Wh"),
+            float("battery_charge_energy", "Wh"),
+            float("battery_discharge_energy", "Wh"),
+            opt_float("self_consumption", "ratio"),
+            opt_float("autonomy", "ratio"),
+        ],
+    },
+    Measurement {
+        name: "archive_data",
+        description: "Values of the Datamanager archive, written by the backfill command",
+        tags: &["device", "device_name", "channel", "unit"],
+        fields: &[float("value", "")],
+    },
+    Measurement {
+        name: "peak_demand",
+        description: "Quarter-hour averages of the grid import for capacity tariffs",
+        tags: &["device"],
+        fields: &[
+            float("rolling_average", "W"),
+            float("quarter_average", "W"),
+            float("month_peak", "W"),
+            opt_float("limit", "W"),
+        ],
+    },
+    Measurement {
+        name: "battery_plan",
+        description: "Battery charge plan per hour, timestamped in the future",
+        tags: &["device"],
+        fields: &[
+            string("action"),
+            float("price", "currency/kWh"),
+            float("pv_forecast", "W"),
+            float("load_forecast", "W"),
+            opt_float("charge_percentage", "%"),
+        ],
+    },
+    Measurement {
+        name: "ev_charging",
+        description: "State of the PV surplus charging with a Wattpilot",
+        tags: &["device"],
+        fields: &[
+            float("surplus", "W"),
+            float("available_current", "A"),
+            float("charger_power", "W"),
+            boolean("charging"),
+            boolean("controlled"),
+            integer("current", "A"),
+        ],
+    },
+    Measurement {
+        name: "community",
+        description: "Summed power flow of all sites",
+        tags: &["device"],
+        fields: &[
+            integer("sites", ""),
+            float("photovoltaik", "W"),
+            float("load", "W"),
+            float("grid", "W"),
+            float("akku", "W"),
+        ],
+    },
+];
+
+pub fn schema() -> Schema {
+    Schema {
+        version: VERSION,
+        renames: RENAMES,
+        common_tags: COMMON_TAGS,
+        timestamp_unit: "ns",
+        measurements: MEASUREMENTS,
+    }
+}
+
+/// Prints the schema as JSON to stdout.
+pub fn print() -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", serde_json::to_string_pretty(&schema())?);
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! The `script` stage of the pipeline: runs a user supplied Rhai script for
+//! every point.
+//!
+//! Only available with the `script` cargo feature. The script given by
+//! `SCRIPT_FILE` sees the point as the variables `measurement`, `tags`,
+//! `fields` and `time` and can change `tags` and `fields` or set `keep` to
+//! `false` to drop the point.
+
+use log::warn;
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+
+use crate::{
+    pipeline::Stage,
+    point::{FieldValue, Point},
+};
+
+/// Limit of operations per point, so a faulty loop doesn't stall the cycle.
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub struct Script {
+    engine: Engine,
+    ast: Option<AST>,
+}
+
+impl Script {
+    pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = match std::env::var("SCRIPT_FILE") {
+            Ok(path) => Some(engine.compile_file(path.into()).map_err(|error| format!("SCRIPT_FILE: {error}"))?),
+            Err(_) => None,
+        };
+        Ok(Script { engine, ast })
+    }
+
+    /// Runs the script for a point, returns whether the point is kept.
+    fn run(&self, ast: &AST, point: &mut Point) -> bool {
+        let tags: Map = point.tags.iter().map(|(key, value)| (key.into(), value.into())).collect();
+        let fields: Map = point.fields.iter().map(|(key, value)| (key.into(), to_dynamic(value))).collect();
+        let mut scope = Scope::new();
+        scope.push_constant("measurement", point.measurement.clone());
+        scope.push_constant("time", point.time);
+        scope.push("tags", tags);
+        scope.push("fields", fields);
+        scope.push("keep", true);
+        if let Err(error) = self.engine.run_ast_with_scope(&mut scope, ast) {
+            warn!("Script failed for a point of {}, it is kept unchanged: {error}", point.measurement);
+            return true;
+        }
+        if let Some(tags) = scope.get_value::<Map>("tags") {
+            point.tags = tags
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        }
+        if let Some(fields) = scope.get_value::<Map>("fields") {
+            let old = std::mem::take(&mut point.fields);
+            for (key, value) in fields {
+                let unsigned = matches!(old.iter().find(|(k, _)| *k == key.as_str()), Some((_, FieldValue::UInteger(_))));
+                match from_dynamic(value, unsigned) {
+                    Some(value) => point.fields.push((key.to_string(), value)),
+                    None => warn!("Script set field {key} of {} to an unsupported type, it is dropped", point.measurement),
+                }
+            }
+        }
+        scope.get_value::<bool>("keep").unwrap_or(true) && !point.fields.is_empty()
+    }
+}
+
+fn to_dynamic(value: &FieldValue) -> Dynamic {
+    match value {
+        FieldValue::Float(value) => Dynamic::from_float(*value),
+        FieldValue::Integer(value) => Dynamic::from_int(*value),
+        FieldValue::UInteger(value) => i64::try_from(*value).map_or_else(|_| Dynamic::from_float(*value as f64), Dynamic::from_int),
+        FieldValue::Boolean(value) => Dynamic::from_bool(*value),
+        FieldValue::String(value) => value.into(),
+    }
+}
+
+/// Converts a value set by the script, integers stay unsigned if the field
+/// was unsigned before.
+fn from_dynamic(value: Dynamic, unsigned: bool) -> Option<FieldValue> {
+    if let Ok(value) = value.as_float() {
+        return Some(FieldValue::Float(value));
+    }
+    if let Ok(value) = value.as_int() {
+        return Some(match u64::try_from(value) {
+            Ok(value) if unsigned => FieldValue::UInteger(value),
+            _ => FieldValue::Integer(value),
+        });
+    }
+    if let Ok(value) = value.as_bool() {
+        return Some(FieldValue::Boolean(value));
+    }
+    value.into_string().ok().map(FieldValue::String)
+}
+
+impl Stage for Script {
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn process(&mut self, points: &mut Vec<Point>) {
+        let Some(ast) = &self.ast else {
+            return;
+        };
+        points.retain_mut(|point| self.run(ast, point));
+    }
+}
@@ -0,0 +1,117 @@
+//! Credentials that can be rotated without a restart. A credential is taken
+//! from its variable (e.g. `INFLUX_DB_TOKEN`) or read from the file given by
+//! the variable with `_FILE` appended (e.g. `INFLUX_DB_TOKEN_FILE`, as with
+//! Docker secrets). The file is read again before a cycle if it changed, and
+//! always after `SIGHUP`.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, LazyLock,
+};
+#[cfg(any(feature = "influxdb2", feature = "mqtt"))]
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+#[cfg(any(feature = "influxdb2", feature = "mqtt"))]
+use log::{info, warn};
+
+/// Set by `SIGHUP`, all credential files are read again.
+static RELOAD: LazyLock<Arc<AtomicBool>> = LazyLock::new(|| Arc::new(AtomicBool::new(false)));
+
+/// The flag to register for `SIGHUP`.
+pub fn reload_flag() -> Arc<AtomicBool> {
+    RELOAD.clone()
+}
+
+/// Whether the credential files are to be read again, resets the flag.
+pub fn take_reload() -> bool {
+    RELOAD.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(any(feature = "influxdb2", feature = "mqtt"))]
+pub struct Secret {
+    name: &'static str,
+    /// The file and its modification time when it was read.
+    file: Option<(PathBuf, Mutex<Option<SystemTime>>)>,
+    value: Mutex<String>,
+}
+
+#[cfg(any(feature = "influxdb2", feature = "mqtt"))]
+impl Secret {
+    /// The credential of the variable `name` or of the file given by
+    /// `<name>_FILE`, which takes precedence. `None` if neither is set.
+    pub fn from_env(name: &'static str) -> std::io::Result<Option<Self>> {
+        if let Some(path) = std::env::var_os(format!("{name}_FILE")).map(PathBuf::from) {
+            let modified = std::fs::metadata(&path)?.modified().ok();
+            let value = read(&path)?;
+            return Ok(Some(Secret {
+                name,
+                file: Some((path, Mutex::new(modified))),
+                value: Mutex::new(value),
+            }));
+        }
+        Ok(std::env::var(name).ok().map(|value| Secret {
+            name,
+            file: None,
+            value: Mutex::new(value),
+        }))
+    }
+
+    /// A credential that never changes.
+    #[cfg(all(test, feature = "influxdb2"))]
+    pub fn fixed(value: &str) -> Self {
+        Secret {
+            name: "fixed",
+            file: None,
+            value: Mutex::new(value.to_owned()),
+        }
+    }
+
+    pub fn get(&self) -> String {
+        self.value.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+
+    /// Reads the file again if it was modified since it was read, or in any
+    /// case with `force`. Returns whether the credential changed. If the file
+    /// can't be read, the old credential is kept.
+    pub fn refresh(&self, force: bool) -> bool {
+        let Some((path, read_modified)) = &self.file else {
+            return false;
+        };
+        let modified = std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        let mut read_modified = read_modified.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !force && modified == *read_modified {
+            return false;
+        }
+        let value = match read(path) {
+            Ok(value) => value,
+            Err(error) => {
+                warn!("Error during read of {} from {:?} occured, keeping the old one: {:?}", self.name, path, error);
+                return false;
+            }
+        };
+        *read_modified = modified;
+        let mut current = self.value.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *current == value {
+            return false;
+        }
+        *current = value;
+        info!("Reloaded {} from {:?}", self.name, path);
+        true
+    }
+}
+
+/// The content of a secret file without the trailing line break.
+#[cfg(any(feature = "influxdb2", feature = "mqtt"))]
+fn read(path: &Path) -> std::io::Result<String> {
+    Ok(std::fs::read_to_string(path)?.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Compares two tokens in a time that doesn't depend on where they differ.
+#[cfg(any(feature = "http", feature = "prometheus"))]
+pub fn token_eq(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
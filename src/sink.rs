@@ -0,0 +1,413 @@
+//! The destinations the points of a cycle are written to. Which measurements
+//! a sink gets is given by the routes, see the `routing` module.
+//!
+//! The InfluxDB and MQTT sinks can be left out of the build with the
+//! `influxdb2` and `mqtt` cargo features, the other sinks (`influxdb1`,
+//! `grpc`, `sqlite`, `questdb`, `clickhouse`, `timestream`, `adx`, `redis`,
+//! `postgres`, `kafka`, `prometheus`, `csv`) need the cargo feature of the
+//! same name.
+//!
+//! With `WRITE_EVERY_CYCLES` the points of several cycles are collected and
+//! written at once, e.g. for cloud databases that charge per request. A batch
+//! is written early once it has `WRITE_MAX_POINTS` points, and at shutdown.
+//! The live sinks (MQTT, gRPC) still get every cycle.
+
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+
+use crate::{
+    control::Control,
+    metrics::METRICS,
+    point::{Point, ToPoint},
+    schema,
+};
+
+/// Identity of a physical device: the serial number (or the unique ID of an
+/// inverter) and the custom name, if the device has one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceTags {
+    pub id: String,
+    pub name: String,
+}
+
+/// Points collected during one cycle.
+#[derive(Default)]
+pub struct Batch {
+    points: Vec<Point>,
+    site: Option<String>,
+}
+
+impl Batch {
+    /// A batch that adds a `site` tag to every point, if `site` is given.
+    pub fn for_site(site: Option<&str>) -> Self {
+        Batch {
+            points: Vec::new(),
+            site: site.map(str::to_owned),
+        }
+    }
+
+    pub fn push(&mut self, point: &impl ToPoint) {
+        let mut point = point.to_point();
+        if let Some(site) = &self.site {
+            point.set_tag("site", site);
+        }
+        self.points.push(point);
+    }
+
+    /// Pushes a point of a physical device. Instead of the `device` tag,
+    /// which only names the kind of device, it gets the `device_id` and
+    /// `device_name` tags, the old tag is only kept for `SCHEMA_COMPAT` < 3.
+    pub fn push_device(&mut self, point: &impl ToPoint, device: &DeviceTags) {
+        let len = self.points.len();
+        self.push(point);
+        if let Some(point) = self.points.get_mut(len) {
+            if !schema::device_tag_kept() {
+                point.tags.retain(|(key, _)| key != "device");
+            }
+            point.set_tag("device_id", &device.id);
+            if !device.name.is_empty() {
+                point.set_tag("device_name", &device.name);
+            }
+        }
+    }
+
+    /// Moves all points of `other` into this batch.
+    pub fn append(&mut self, other: Batch) {
+        self.points.extend(other.points);
+    }
+
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    pub fn points_mut(&mut self) -> &mut Vec<Point> {
+        &mut self.points
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+pub trait Sink {
+    /// Name of the sink for the logs, e.g. `InfluxDB`.
+    fn name(&self) -> &'static str;
+
+    /// Writes the points of the batch that are routed to this sink. An error
+    /// means the points aren't stored, though the sink may have spooled them.
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Whether the sink can't take any more points, so the polling should
+    /// pause. Empty batches are still written to let it catch up.
+    fn blocked(&self) -> bool {
+        false
+    }
+
+    /// Whether the sink passes the points on as they come, e.g. to MQTT
+    /// subscribers. Live sinks get every cycle, even if the others are
+    /// written every few cycles.
+    fn live(&self) -> bool {
+        false
+    }
+
+    /// Reads the credentials of the sink again if their files changed, or
+    /// in any case with `force` (see the `secret` module).
+    fn refresh_credentials(&self, _force: bool) {}
+}
+
+/// Points of several cycles that are written at once.
+struct Buffer {
+    every_cycles: u32,
+    max_points: Option<usize>,
+    /// The pending points and the cycles they were collected in.
+    pending: Mutex<(Batch, u32)>,
+}
+
+impl Buffer {
+    /// Reads `WRITE_EVERY_CYCLES` (default 1, every cycle is written) and
+    /// `WRITE_MAX_POINTS`. `None` if every cycle is written anyway.
+    fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let every_cycles = match std::env::var("WRITE_EVERY_CYCLES") {
+            Ok(val) => val.parse::<u32>()?.max(1),
+            Err(_) => 1,
+        };
+        let max_points = match std::env::var("WRITE_MAX_POINTS") {
+            Ok(val) => Some(val.parse::<usize>()?.max(1)),
+            Err(_) => None,
+        };
+        if every_cycles == 1 {
+            return Ok(None);
+        }
+        Ok(Some(Buffer {
+            every_cycles,
+            max_points,
+            pending: Mutex::new((Batch::default(), 0)),
+        }))
+    }
+
+    /// Adds the points of a cycle. Returns the collected points once they
+    /// are due.
+    fn add(&self, batch: &Batch) -> Option<Batch> {
+        let mut pending = self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (points, cycles) = &mut *pending;
+        points.points_mut().extend_from_slice(batch.points());
+        *cycles += 1;
+        let full = self.max_points.is_some_and(|max_points| points.len() >= max_points);
+        if *cycles < self.every_cycles && !full {
+            METRICS.pending_points(points.len());
+            return None;
+        }
+        *cycles = 0;
+        METRICS.pending_points(0);
+        Some(std::mem::take(points))
+    }
+
+    /// Takes the collected points, whether they are due or not.
+    fn take(&self) -> Batch {
+        let mut pending = self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        pending.1 = 0;
+        METRICS.pending_points(0);
+        std::mem::take(&mut pending.0)
+    }
+}
+
+/// All configured sinks.
+pub struct Sinks {
+    sinks: Vec<Box<dyn Sink>>,
+    buffer: Option<Buffer>,
+}
+
+impl Sinks {
+    /// Creates the InfluxDB sink and, if `MQTT_HOST` is set, the MQTT sink,
+    /// which passes the received commands to `control`. With `INFLUX1_URL`
+    /// the points are written to InfluxDB 1.x, with `SQLITE_FILE` stored in
+    /// SQLite, with `QUESTDB_ADDR` written to QuestDB, with `CLICKHOUSE_URL`
+    /// to ClickHouse, with `TIMESTREAM_DATABASE` to Amazon Timestream, with
+    /// `ADX_CLUSTER_URL` to Azure Data Explorer, with `REDIS_URL` to a Redis
+    /// stream, with `POSTGRES_URL` to PostgreSQL, with `KAFKA_REST_URL` to
+    /// Kafka, with `CSV_FILE` to a CSV file, with `LINE_FILE` to line
+    /// protocol files and with `GRAFANA_LIVE_STREAM` to Grafana Live. With
+    /// `GRPC_LISTEN` the gRPC server, with `PROMETHEUS_LISTEN` the Prometheus
+    /// endpoint and with `SNMP_LISTEN` the SNMP agent is started, which are
+    /// fed like a sink.
+    /// Settings for a sink that isn't part of the build are an error.
+    ///
+    /// Without `INFLUX_DB_URL` the InfluxDB sink is left out if `LINE_FILE`
+    /// is set, to capture the data without a database.
+    ///
+    /// The points are collected over `WRITE_EVERY_CYCLES` cycles, see the
+    /// module documentation.
+    // whether sinks are pushed depends on the enabled features
+    #[allow(unused_mut, clippy::vec_init_then_push)]
+    pub fn from_env(low_memory: bool, control: &Arc<Control>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+        #[cfg(feature = "influxdb2")]
+        if std::env::var_os("INFLUX_DB_URL").is_some() || std::env::var_os("LINE_FILE").is_none() {
+            sinks.push(Box::new(crate::influx::InfluxSink::from_env(low_memory)?));
+        }
+        #[cfg(not(feature = "influxdb2"))]
+        {
+            let _ = low_memory;
+            if let Ok(url) = std::env::var("INFLUX_DB_URL") {
+                return Err(format!("INFLUX_DB_URL={url} needs a build with the influxdb2 feature").into());
+            }
+        }
+        if let Ok(url) = std::env::var("INFLUX1_URL") {
+            #[cfg(feature = "influxdb1")]
+            sinks.push(Box::new(crate::influx1::Influx1Sink::from_env(&url)?));
+            #[cfg(not(feature = "influxdb1"))]
+            return Err(format!("INFLUX1_URL={url} needs a build with the influxdb1 feature").into());
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt) = crate::mqtt::MqttSink::from_env(control.clone())? {
+            sinks.push(Box::new(mqtt));
+        }
+        #[cfg(not(feature = "mqtt"))]
+        {
+            let _ = control;
+            if let Ok(host) = std::env::var("MQTT_HOST") {
+                return Err(format!("MQTT_HOST={host} needs a build with the mqtt feature").into());
+            }
+        }
+        if let Ok(path) = std::env::var("SQLITE_FILE") {
+            #[cfg(feature = "sqlite")]
+            sinks.push(Box::new(crate::sqlite::SqliteSink::from_env(&path)?));
+            #[cfg(not(feature = "sqlite"))]
+            return Err(format!("SQLITE_FILE={path} needs a build with the sqlite feature").into());
+        }
+        if let Ok(addr) = std::env::var("QUESTDB_ADDR") {
+            #[cfg(feature = "questdb")]
+            sinks.push(Box::new(crate::questdb::QuestDbSink::from_env(&addr)?));
+            #[cfg(not(feature = "questdb"))]
+            return Err(format!("QUESTDB_ADDR={addr} needs a build with the questdb feature").into());
+        }
+        if let Ok(url) = std::env::var("CLICKHOUSE_URL") {
+            #[cfg(feature = "clickhouse")]
+            sinks.push(Box::new(crate::clickhouse::ClickHouseSink::from_env(&url)?));
+            #[cfg(not(feature = "clickhouse"))]
+            return Err(format!("CLICKHOUSE_URL={url} needs a build with the clickhouse feature").into());
+        }
+        if let Ok(database) = std::env::var("TIMESTREAM_DATABASE") {
+            #[cfg(feature = "timestream")]
+            sinks.push(Box::new(crate::timestream::TimestreamSink::from_env(&database)?));
+            #[cfg(not(feature = "timestream"))]
+            return Err(format!("TIMESTREAM_DATABASE={database} needs a build with the timestream feature").into());
+        }
+        if let Ok(url) = std::env::var("ADX_CLUSTER_URL") {
+            #[cfg(feature = "adx")]
+            sinks.push(Box::new(crate::adx::AdxSink::from_env(&url)?));
+            #[cfg(not(feature = "adx"))]
+            return Err(format!("ADX_CLUSTER_URL={url} needs a build with the adx feature").into());
+        }
+        if let Ok(url) = std::env::var("REDIS_URL") {
+            #[cfg(feature = "redis")]
+            sinks.push(Box::new(crate::redis::RedisSink::from_env(&url)?));
+            #[cfg(not(feature = "redis"))]
+            return Err(format!("REDIS_URL={url} needs a build with the redis feature").into());
+        }
+        if let Ok(url) = std::env::var("POSTGRES_URL") {
+            #[cfg(feature = "postgres")]
+            sinks.push(Box::new(crate::postgres::PostgresSink::from_env(&url)?));
+            // the URL may contain the password
+            #[cfg(not(feature = "postgres"))]
+            {
+                let _ = url;
+                return Err("POSTGRES_URL needs a build with the postgres feature".into());
+            }
+        }
+        if let Ok(url) = std::env::var("KAFKA_REST_URL") {
+            #[cfg(feature = "kafka")]
+            sinks.push(Box::new(crate::kafka::KafkaSink::from_env(&url)?));
+            #[cfg(not(feature = "kafka"))]
+            return Err(format!("KAFKA_REST_URL={url} needs a build with the kafka feature").into());
+        }
+        if let Ok(path) = std::env::var("CSV_FILE") {
+            #[cfg(feature = "csv")]
+            sinks.push(Box::new(crate::csv_file::CsvFileSink::from_env(&path)?));
+            #[cfg(not(feature = "csv"))]
+            return Err(format!("CSV_FILE={path} needs a build with the csv feature").into());
+        }
+        if let Ok(path) = std::env::var("LINE_FILE") {
+            sinks.push(Box::new(crate::line_file::LineFileSink::from_env(&path)?));
+        }
+        if let Some(live) = crate::grafana::LiveSink::from_env()? {
+            sinks.push(Box::new(live));
+        }
+        if let Ok(addr) = std::env::var("GRPC_LISTEN") {
+            #[cfg(feature = "grpc")]
+            {
+                let tokens = ["HTTP_TOKEN", "HTTP_READ_TOKEN"]
+                    .into_iter()
+                    .filter_map(|variable_name| std::env::var(variable_name).ok().filter(|token| !token.is_empty()))
+                    .collect();
+                sinks.push(Box::new(crate::grpc::spawn(&addr, tokens)?));
+            }
+            #[cfg(not(feature = "grpc"))]
+            return Err(format!("GRPC_LISTEN={addr} needs a build with the grpc feature").into());
+        }
+        if let Ok(addr) = std::env::var("PROMETHEUS_LISTEN") {
+            #[cfg(feature = "prometheus")]
+            {
+                let tokens = ["HTTP_TOKEN", "HTTP_READ_TOKEN"]
+                    .into_iter()
+                    .filter_map(|variable_name| std::env::var(variable_name).ok().filter(|token| !token.is_empty()))
+                    .collect();
+                sinks.push(Box::new(crate::prometheus::spawn(&addr, tokens)?));
+            }
+            #[cfg(not(feature = "prometheus"))]
+            return Err(format!("PROMETHEUS_LISTEN={addr} needs a build with the prometheus feature").into());
+        }
+        if let Ok(addr) = std::env::var("SNMP_LISTEN") {
+            #[cfg(feature = "snmp")]
+            sinks.push(Box::new(crate::snmp::spawn(&addr)?));
+            #[cfg(not(feature = "snmp"))]
+            return Err(format!("SNMP_LISTEN={addr} needs a build with the snmp feature").into());
+        }
+        Ok(Sinks {
+            sinks,
+            buffer: Buffer::from_env()?,
+        })
+    }
+
+    /// The given sinks, instead of the configured ones.
+    #[cfg(test)]
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> Self {
+        Sinks { sinks, buffer: None }
+    }
+
+    /// Collects the points of `every_cycles` cycles, instead of the
+    /// configured number.
+    #[cfg(test)]
+    pub fn collecting(mut self, every_cycles: u32, max_points: Option<usize>) -> Self {
+        self.buffer = Some(Buffer {
+            every_cycles,
+            max_points,
+            pending: Mutex::new((Batch::default(), 0)),
+        });
+        self
+    }
+
+    /// The names of the sinks.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.sinks.iter().map(|sink| sink.name()).collect()
+    }
+
+    /// Writes the points of a cycle, to the sinks that aren't live only once
+    /// the points of `WRITE_EVERY_CYCLES` cycles are collected.
+    pub fn write(&self, batch: &Batch) {
+        let Some(buffer) = &self.buffer else {
+            for sink in &self.sinks {
+                write_logged(sink.as_ref(), batch);
+            }
+            return;
+        };
+        for sink in self.sinks.iter().filter(|sink| sink.live()) {
+            write_logged(sink.as_ref(), batch);
+        }
+        if let Some(collected) = buffer.add(batch) {
+            self.write_collected(&collected);
+        }
+    }
+
+    /// Writes the points collected so far, e.g. at shutdown.
+    pub fn flush(&self) {
+        let Some(buffer) = &self.buffer else {
+            return;
+        };
+        let collected = buffer.take();
+        if !collected.is_empty() {
+            info!("Writing {} collected points", collected.len());
+            self.write_collected(&collected);
+        }
+    }
+
+    fn write_collected(&self, collected: &Batch) {
+        for sink in self.sinks.iter().filter(|sink| !sink.live()) {
+            write_logged(sink.as_ref(), collected);
+        }
+    }
+
+    pub fn blocked(&self) -> bool {
+        self.sinks.iter().any(|sink| sink.blocked())
+    }
+
+    /// Reads the changed credentials of the sinks, all after `SIGHUP`.
+    pub fn refresh_credentials(&self) {
+        let force = crate::secret::take_reload();
+        for sink in &self.sinks {
+            sink.refresh_credentials(force);
+        }
+    }
+}
+
+/// Writes the batch to the sink, a failure is logged.
+fn write_logged(sink: &dyn Sink, batch: &Batch) {
+    if let Err(error) = sink.write(batch) {
+        error!("Error during write to {} occured: {:?}", sink.name(), error);
+    }
+}
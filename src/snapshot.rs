@@ -0,0 +1,121 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    updated: DateTime<Utc>,
+    value: Value,
+}
+
+/// Entries by measurement and device.
+pub type Entries = BTreeMap<String, BTreeMap<String, Entry>>;
+
+/// Last known value of every measurement and device, shared between the
+/// polling loop and the HTTP server.
+#[derive(Default)]
+pub struct Snapshot {
+    entries: Mutex<Entries>,
+    disabled: bool,
+}
+
+impl Snapshot {
+    /// A snapshot that doesn't keep any values, used in low-memory mode.
+    pub fn disabled() -> Self {
+        Snapshot {
+            entries: Mutex::default(),
+            disabled: true,
+        }
+    }
+
+    /// Returns a view that files all points under `site`, if given.
+    pub fn for_site<'a>(&'a self, site: Option<&'a str>) -> SiteSnapshot<'a> {
+        SiteSnapshot { snapshot: self, site }
+    }
+
+    /// Stores `point` as the latest value of `measurement`. The point is
+    /// filed under its `device` tag, as `site/device` if a site is given.
+    fn update_site(&self, site: Option<&str>, measurement: &str, point: &impl Serialize) {
+        if self.disabled {
+            return;
+        }
+        let value = match serde_json::to_value(point) {
+            Ok(value) => value,
+            Err(_) => return,
+        };
+        let device = value.get("device").and_then(Value::as_str).unwrap_or("Unknown");
+        let device = match site {
+            Some(site) => format!("{site}/{device}"),
+            None => device.to_owned(),
+        };
+
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.entry(measurement.to_owned()).or_default().insert(
+            device,
+            Entry {
+                updated: crate::clock::now(),
+                value,
+            },
+        );
+    }
+
+    pub fn entries(&self) -> Entries {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn restore(&self, entries: Entries) {
+        if self.disabled {
+            return;
+        }
+        *self.entries.lock().unwrap_or_else(|e| e.into_inner()) = entries;
+    }
+
+    /// Returns the snapshot as JSON, optionally limited to one measurement.
+    /// Returns `None` if the measurement has not been seen yet.
+    #[cfg(any(feature = "http", feature = "tui", feature = "display"))]
+    pub fn to_json(&self, measurement: Option<&str>) -> Option<Value> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let now = crate::clock::now();
+        let devices_to_json = |devices: &BTreeMap<String, Entry>| -> Value {
+            devices
+                .iter()
+                .map(|(device, entry)| {
+                    let age = (now - entry.updated).num_milliseconds() as f64 / 1000.0;
+                    let value = serde_json::json!({
+                        "updated": entry.updated.to_rfc3339(),
+                        "age_seconds": age,
+                        "values": entry.value,
+                    });
+                    (device.clone(), value)
+                })
+                .collect::<serde_json::Map<_, _>>()
+                .into()
+        };
+
+        match measurement {
+            Some(measurement) => entries.get(measurement).map(devices_to_json),
+            None => Some(
+                entries
+                    .iter()
+                    .map(|(measurement, devices)| (measurement.clone(), devices_to_json(devices)))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into(),
+            ),
+        }
+    }
+}
+
+/// The snapshot as seen by the polling of one site.
+#[derive(Clone, Copy)]
+pub struct SiteSnapshot<'a> {
+    snapshot: &'a Snapshot,
+    site: Option<&'a str>,
+}
+
+impl SiteSnapshot<'_> {
+    pub fn update(&self, measurement: &str, point: &impl Serialize) {
+        self.snapshot.update_site(self.site, measurement, point);
+    }
+}
@@ -0,0 +1,462 @@
+//! A small read-only SNMP agent (v1 and v2c) with the key values of the
+//! plant, for network monitoring systems like LibreNMS or Zabbix.
+//!
+//! Only available with the `snmp` cargo feature and enabled by
+//! `SNMP_LISTEN`. Requests need the community `SNMP_COMMUNITY` (default
+//! `public`), others are ignored. The values are scalars below
+//! `SNMP_BASE_OID` (default the Net-SNMP playpen `1.3.6.1.4.1.8072.9999.9999`),
+//! see `OBJECTS`. Like the gRPC service the agent is fed like a sink, with
+//! the points of `SNMP_SITE` if there are several sites.
+
+use std::{
+    net::UdpSocket,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use log::{debug, error, info};
+
+use crate::{
+    point::{FieldValue, Point},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_BASE_OID: &str = "1.3.6.1.4.1.8072.9999.9999";
+/// Variable bindings returned for one GetBulk request at most.
+const MAX_BULK_BINDINGS: usize = 64;
+
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const GAUGE32: u8 = 0x42;
+const NO_SUCH_OBJECT: u8 = 0x80;
+const NO_SUCH_INSTANCE: u8 = 0x81;
+const END_OF_MIB_VIEW: u8 = 0x82;
+
+const GET_REQUEST: u8 = 0xa0;
+const GET_NEXT_REQUEST: u8 = 0xa1;
+const RESPONSE: u8 = 0xa2;
+const SET_REQUEST: u8 = 0xa3;
+const GET_BULK_REQUEST: u8 = 0xa5;
+
+const VERSION_1: i64 = 0;
+const VERSION_2C: i64 = 1;
+
+const NO_SUCH_NAME: i64 = 2;
+const READ_ONLY: i64 = 4;
+const NOT_WRITABLE: i64 = 17;
+
+/// How a field is exposed.
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    /// Integer32, rounded.
+    Integer,
+    /// Gauge32, rounded and at least 0.
+    Gauge,
+    /// OCTET STRING.
+    Text,
+}
+
+/// The scalars `<base>.<n>.0` with the measurement and field they show.
+const OBJECTS: &[(u32, &str, &str, Kind)] = &[
+    (1, "power_flow", "photovoltaik", Kind::Integer),
+    (2, "power_flow", "grid", Kind::Integer),
+    (3, "power_flow", "load", Kind::Integer),
+    (4, "power_flow", "akku", Kind::Integer),
+    (5, "storage", "charge_percentage", Kind::Gauge),
+    (6, "inverter_info", "state", Kind::Text),
+    (7, "inverter_info", "error_code", Kind::Integer),
+    (8, "power_flow", "energy_day", Kind::Gauge),
+];
+/// The scalar with the seconds since the last cycle.
+const AGE_OBJECT: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Integer(i64),
+    Gauge(u32),
+    Text(String),
+}
+
+impl Value {
+    fn from_field(value: &FieldValue, kind: Kind) -> Option<Self> {
+        match (kind, value) {
+            (Kind::Text, FieldValue::String(text)) => Some(Value::Text(text.clone())),
+            (Kind::Text, value) => value.as_f64().map(|value| Value::Text(value.to_string())),
+            (Kind::Integer, value) => value.as_f64().map(|value| Value::Integer(value.round() as i64)),
+            (Kind::Gauge, value) => value.as_f64().map(|value| Value::Gauge(value.round().clamp(0.0, u32::MAX as f64) as u32)),
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Integer(value) => write_integer(INTEGER, *value, out),
+            Value::Gauge(value) => write_integer(GAUGE32, (*value).into(), out),
+            Value::Text(text) => write_tlv(OCTET_STRING, text.as_bytes(), out),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Latest {
+    /// Value per object number.
+    values: Vec<(u32, Value)>,
+    updated: Option<Instant>,
+}
+
+/// Feeds the latest values to the SNMP agent.
+pub struct SnmpSink {
+    latest: Arc<Mutex<Latest>>,
+    site: Option<String>,
+}
+
+impl Sink for SnmpSink {
+    fn name(&self) -> &'static str {
+        "SNMP"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut latest = self.latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let points = batch
+            .points()
+            .iter()
+            .filter(|point| self.site.is_none() || point.tag("site") == self.site.as_deref());
+        for point in points {
+            update(&mut latest, point);
+        }
+        Ok(())
+    }
+
+    fn live(&self) -> bool {
+        true
+    }
+}
+
+fn update(latest: &mut Latest, point: &Point) {
+    for (number, measurement, field, kind) in OBJECTS {
+        if point.measurement != *measurement {
+            continue;
+        }
+        let Some(value) = point.field(field).and_then(|value| Value::from_field(value, *kind)) else {
+            continue;
+        };
+        match latest.values.iter_mut().find(|(existing, _)| existing == number) {
+            Some((_, existing)) => *existing = value,
+            None => latest.values.push((*number, value)),
+        }
+    }
+    latest.updated = Some(Instant::now());
+}
+
+/// Answers the requests, separate from the socket so it can be tested.
+pub struct Agent {
+    latest: Arc<Mutex<Latest>>,
+    community: String,
+    base: Vec<u32>,
+}
+
+impl Agent {
+    /// The objects that have a value, ordered by their OID.
+    fn objects(&self) -> Vec<(Vec<u32>, Value)> {
+        let latest = self.latest.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut objects: Vec<_> = latest.values.iter().map(|(number, value)| (self.oid(*number), value.clone())).collect();
+        if let Some(updated) = latest.updated {
+            let age = updated.elapsed().as_secs().min(u32::MAX.into()) as u32;
+            objects.push((self.oid(AGE_OBJECT), Value::Gauge(age)));
+        }
+        objects.sort_by(|(a, _), (b, _)| a.cmp(b));
+        objects
+    }
+
+    fn oid(&self, number: u32) -> Vec<u32> {
+        let mut oid = self.base.clone();
+        oid.extend([number, 0]);
+        oid
+    }
+
+    fn is_object(&self, oid: &[u32]) -> bool {
+        OBJECTS.iter().map(|(number, ..)| *number).chain([AGE_OBJECT]).any(|number| self.oid(number) == oid)
+    }
+
+    /// The response to a request, `None` for messages that aren't answered,
+    /// e.g. with a wrong community or damaged.
+    pub fn respond(&self, request: &[u8]) -> Option<Vec<u8>> {
+        let mut message = Reader::new(Reader::new(request).read(SEQUENCE)?);
+        let version = read_integer(message.read(INTEGER)?)?;
+        if version != VERSION_1 && version != VERSION_2C {
+            return None;
+        }
+        if message.read(OCTET_STRING)? != self.community.as_bytes() {
+            debug!("Ignoring SNMP request with a wrong community");
+            return None;
+        }
+        let (pdu_type, pdu) = message.next()?;
+        let mut pdu = Reader::new(pdu);
+        let request_id = read_integer(pdu.read(INTEGER)?)?;
+        let non_repeaters = read_integer(pdu.read(INTEGER)?)?;
+        let max_repetitions = read_integer(pdu.read(INTEGER)?)?;
+        let mut oids = Vec::new();
+        let mut bindings = Reader::new(pdu.read(SEQUENCE)?);
+        while !bindings.is_empty() {
+            let mut binding = Reader::new(bindings.read(SEQUENCE)?);
+            oids.push(read_oid(binding.read(OBJECT_IDENTIFIER)?)?);
+        }
+
+        let objects = self.objects();
+        let next = |oid: &[u32]| objects.iter().find(|(candidate, _)| candidate.as_slice() > oid);
+        let mut error = (0, 0);
+        let mut results: Vec<(Vec<u32>, Result<Value, u8>)> = Vec::new();
+        match pdu_type {
+            GET_REQUEST => {
+                for (index, oid) in oids.iter().enumerate() {
+                    let result = match objects.iter().find(|(candidate, _)| candidate == oid) {
+                        Some((_, value)) => Ok(value.clone()),
+                        None if self.is_object(oid) => Err(NO_SUCH_INSTANCE),
+                        None => Err(NO_SUCH_OBJECT),
+                    };
+                    if result.is_err() && version == VERSION_1 && error.0 == 0 {
+                        error = (NO_SUCH_NAME, index + 1);
+                    }
+                    results.push((oid.clone(), result));
+                }
+            }
+            GET_NEXT_REQUEST => {
+                for (index, oid) in oids.iter().enumerate() {
+                    match next(oid) {
+                        Some((next_oid, value)) => results.push((next_oid.clone(), Ok(value.clone()))),
+                        None => {
+                            if version == VERSION_1 && error.0 == 0 {
+                                error = (NO_SUCH_NAME, index + 1);
+                            }
+                            results.push((oid.clone(), Err(END_OF_MIB_VIEW)));
+                        }
+                    }
+                }
+            }
+            GET_BULK_REQUEST if version == VERSION_2C => {
+                let non_repeaters = (non_repeaters.max(0) as usize).min(oids.len());
+                let (single, repeated) = oids.split_at(non_repeaters);
+                for oid in single {
+                    results.push(next_or_end(oid, next(oid)));
+                }
+                let mut cursors = repeated.to_vec();
+                for _ in 0..max_repetitions.max(0) {
+                    if results.len() + cursors.len() > MAX_BULK_BINDINGS || cursors.is_empty() {
+                        break;
+                    }
+                    for cursor in &mut cursors {
+                        let result = next_or_end(cursor, next(cursor));
+                        cursor.clone_from(&result.0);
+                        results.push(result);
+                    }
+                    if results.iter().rev().take(cursors.len()).all(|(_, result)| result.is_err()) {
+                        break;
+                    }
+                }
+            }
+            SET_REQUEST => {
+                error = (if version == VERSION_1 { READ_ONLY } else { NOT_WRITABLE }, 1);
+            }
+            _ => return None,
+        }
+
+        // in SNMPv1 an error returns the bindings of the request unchanged
+        if error.0 != 0 {
+            results = oids.into_iter().map(|oid| (oid, Err(NULL))).collect();
+        }
+        let mut encoded_bindings = Vec::new();
+        for (oid, result) in &results {
+            let mut binding = Vec::new();
+            write_tlv(OBJECT_IDENTIFIER, &encode_oid(oid), &mut binding);
+            match result {
+                Ok(value) => value.encode(&mut binding),
+                Err(exception) => write_tlv(*exception, &[], &mut binding),
+            }
+            write_tlv(SEQUENCE, &binding, &mut encoded_bindings);
+        }
+        let mut pdu = Vec::new();
+        write_integer(INTEGER, request_id, &mut pdu);
+        write_integer(INTEGER, error.0, &mut pdu);
+        write_integer(INTEGER, error.1 as i64, &mut pdu);
+        write_tlv(SEQUENCE, &encoded_bindings, &mut pdu);
+        let mut message = Vec::new();
+        write_integer(INTEGER, version, &mut message);
+        write_tlv(OCTET_STRING, self.community.as_bytes(), &mut message);
+        write_tlv(RESPONSE, &pdu, &mut message);
+        let mut response = Vec::new();
+        write_tlv(SEQUENCE, &message, &mut response);
+        Some(response)
+    }
+}
+
+fn next_or_end(oid: &[u32], next: Option<&(Vec<u32>, Value)>) -> (Vec<u32>, Result<Value, u8>) {
+    match next {
+        Some((next_oid, value)) => (next_oid.clone(), Ok(value.clone())),
+        None => (oid.to_vec(), Err(END_OF_MIB_VIEW)),
+    }
+}
+
+/// Starts the agent on `addr` in a background thread and returns the sink
+/// that feeds it.
+pub fn spawn(addr: &str) -> Result<SnmpSink, Box<dyn std::error::Error>> {
+    let socket = UdpSocket::bind(addr)?;
+    let (sink, agent) = agent_from_env()?;
+    info!("SNMP agent listening on {addr}");
+    std::thread::Builder::new().name("snmp".to_owned()).spawn(move || {
+        let mut buf = [0; 1500];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(error) => {
+                    error!("Error during receive of an SNMP request occured: {:?}", error);
+                    continue;
+                }
+            };
+            if let Some(response) = agent.respond(&buf[..len]) {
+                if let Err(error) = socket.send_to(&response, peer) {
+                    error!("Error during SNMP response occured: {:?}", error);
+                }
+            }
+        }
+    })?;
+    Ok(sink)
+}
+
+/// The sink and the agent it feeds, configured by the environment.
+pub fn agent_from_env() -> Result<(SnmpSink, Agent), Box<dyn std::error::Error>> {
+    let base = std::env::var("SNMP_BASE_OID").unwrap_or_else(|_| DEFAULT_BASE_OID.to_owned());
+    let base = base
+        .trim_start_matches('.')
+        .split('.')
+        .map(str::parse)
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| format!("invalid SNMP_BASE_OID {base:?}"))?;
+    if base.len() < 2 {
+        return Err(format!("invalid SNMP_BASE_OID {base:?}").into());
+    }
+    let latest = Arc::new(Mutex::new(Latest::default()));
+    let sink = SnmpSink {
+        latest: latest.clone(),
+        site: std::env::var("SNMP_SITE").ok(),
+    };
+    let agent = Agent {
+        latest,
+        community: std::env::var("SNMP_COMMUNITY").unwrap_or_else(|_| "public".to_owned()),
+        base,
+    };
+    Ok((sink, agent))
+}
+
+/// Reads BER encoded values one after the other.
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The type and the content of the next value.
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.data.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = if first < 0x80 {
+            (usize::from(first), rest)
+        } else {
+            let count = usize::from(first & 0x7f);
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let len = rest[..count].iter().fold(0, |len, byte| (len << 8) | usize::from(*byte));
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (content, rest) = rest.split_at(len);
+        self.data = rest;
+        Some((tag, content))
+    }
+
+    /// The content of the next value, which has to be of type `tag`.
+    fn read(&mut self, tag: u8) -> Option<&'a [u8]> {
+        self.next().filter(|(actual, _)| *actual == tag).map(|(_, content)| content)
+    }
+}
+
+fn read_integer(content: &[u8]) -> Option<i64> {
+    if content.is_empty() || content.len() > 8 {
+        return None;
+    }
+    let sign = if content[0] & 0x80 != 0 { -1 } else { 0 };
+    Some(content.iter().fold(sign, |value, byte| (value << 8) | i64::from(*byte)))
+}
+
+fn read_oid(content: &[u8]) -> Option<Vec<u32>> {
+    let (&first, rest) = content.split_first()?;
+    let mut oid = vec![u32::from(first / 40), u32::from(first % 40)];
+    let mut value: u32 = 0;
+    for byte in rest {
+        value = value.checked_mul(128)? | u32::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            oid.push(value);
+            value = 0;
+        }
+    }
+    Some(oid)
+}
+
+fn write_tlv(tag: u8, content: &[u8], out: &mut Vec<u8>) {
+    out.push(tag);
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend(&bytes[skip..]);
+    }
+    out.extend(content);
+}
+
+/// An integer in the fewest bytes of two's complement. A Gauge32 with the
+/// highest bit set keeps its leading 0, it would be negative otherwise.
+fn write_integer(tag: u8, value: i64, out: &mut Vec<u8>) {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1 {
+        let redundant = (bytes[start] == 0 && bytes[start + 1] & 0x80 == 0) || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    write_tlv(tag, &bytes[start..], out);
+}
+
+fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let [first, second, rest @ ..] = oid {
+        out.push((first * 40 + second) as u8);
+        for arc in rest {
+            let mut chunk = Vec::new();
+            let mut arc = *arc;
+            chunk.push((arc & 0x7f) as u8);
+            arc >>= 7;
+            while arc > 0 {
+                chunk.push(0x80 | (arc & 0x7f) as u8);
+                arc >>= 7;
+            }
+            out.extend(chunk.iter().rev());
+        }
+    }
+    out
+}
@@ -0,0 +1,256 @@
+//! Local SQLite database, for installations without a database server.
+//!
+//! Only available with the `sqlite` cargo feature and enabled by
+//! `SQLITE_FILE`. Every measurement gets its own table with the columns
+//! `time` (Unix timestamp in nanoseconds), `tags` (the tags as JSON object)
+//! and one column per field, which are added as new fields show up. Rows
+//! older than `SQLITE_RETENTION` days (default 7) are deleted once an hour.
+//!
+//! The REST API serves the recent history from the database, see
+//! [`History`].
+
+#[cfg(feature = "http")]
+use std::path::{Path, PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+#[cfg(feature = "http")]
+use rusqlite::OpenFlags;
+use rusqlite::{params_from_iter, types::Value, Connection};
+
+use crate::{
+    point::{FieldValue, Point},
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_RETENTION_DAYS: u64 = 7;
+/// How often the rows older than the retention window are deleted.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+/// Rows returned by one history query at most.
+#[cfg(feature = "http")]
+const HISTORY_LIMIT: usize = 10_000;
+
+/// Quotes a table or column name.
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn to_sql(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Float(value) => Value::Real(*value),
+        FieldValue::Integer(value) => Value::Integer(*value),
+        // SQLite has no unsigned integers
+        FieldValue::UInteger(value) => i64::try_from(*value).map_or(Value::Real(*value as f64), Value::Integer),
+        FieldValue::Boolean(value) => Value::Integer(i64::from(*value)),
+        FieldValue::String(value) => Value::Text(value.clone()),
+    }
+}
+
+#[cfg(feature = "http")]
+fn to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null | Value::Blob(_) => serde_json::Value::Null,
+        Value::Integer(value) => value.into(),
+        Value::Real(value) => value.into(),
+        Value::Text(value) => value.into(),
+    }
+}
+
+struct State {
+    connection: Connection,
+    /// Known columns by table.
+    columns: HashMap<String, HashSet<String>>,
+    last_cleanup: Option<Instant>,
+}
+
+impl State {
+    /// Creates the table of `point` and the columns of its fields, if they
+    /// don't exist yet.
+    fn prepare_table(&mut self, point: &Point) -> rusqlite::Result<()> {
+        if !self.columns.contains_key(&point.measurement) {
+            let table = quote(&point.measurement);
+            self.connection.execute_batch(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (time INTEGER NOT NULL, tags TEXT NOT NULL);
+                 CREATE INDEX IF NOT EXISTS {} ON {table} (time);",
+                quote(&format!("{}_time", point.measurement))
+            ))?;
+            let mut statement = self.connection.prepare(&format!("PRAGMA table_info({table})"))?;
+            let columns = statement.query_map([], |row| row.get::<_, String>(1))?.collect::<Result<_, _>>()?;
+            self.columns.insert(point.measurement.clone(), columns);
+        }
+        let Some(columns) = self.columns.get_mut(&point.measurement) else {
+            return Ok(());
+        };
+        for (field, _) in &point.fields {
+            if !columns.contains(field) {
+                self.connection
+                    .execute(&format!("ALTER TABLE {} ADD COLUMN {}", quote(&point.measurement), quote(field)), [])?;
+                columns.insert(field.clone());
+            }
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, point: &Point) -> rusqlite::Result<()> {
+        self.prepare_table(point)?;
+        let tags: serde_json::Map<_, _> = point.tags.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+        let columns: String = point.fields.iter().map(|(field, _)| format!(", {}", quote(field))).collect();
+        let placeholders = ", ?".repeat(point.fields.len());
+        let sql = format!("INSERT INTO {} (time, tags{columns}) VALUES (?, ?{placeholders})", quote(&point.measurement));
+        let values = [Value::Integer(point.time), Value::Text(serde_json::Value::Object(tags).to_string())]
+            .into_iter()
+            .chain(point.fields.iter().map(|(_, value)| to_sql(value)));
+        self.connection.prepare_cached(&sql)?.execute(params_from_iter(values))?;
+        Ok(())
+    }
+
+    /// Deletes the rows older than `retention` from all tables.
+    fn cleanup(&mut self, retention: Duration) -> rusqlite::Result<()> {
+        let cutoff = chrono::Utc::now() - retention;
+        let cutoff = cutoff.timestamp_nanos_opt().unwrap_or(i64::MIN);
+        let tables: Vec<String> = self
+            .connection
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let mut deleted = 0;
+        for table in tables {
+            deleted += self.connection.execute(&format!("DELETE FROM {} WHERE time < ?", quote(&table)), [cutoff])?;
+        }
+        if deleted > 0 {
+            info!("Deleted {deleted} rows older than the retention window from SQLite");
+        }
+        Ok(())
+    }
+}
+
+pub struct SqliteSink {
+    state: Mutex<State>,
+    retention: Duration,
+    routes: Routes,
+}
+
+impl SqliteSink {
+    /// Opens (or creates) the database `path`. Optional is
+    /// `SQLITE_RETENTION`, the days the rows are kept.
+    pub fn from_env(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let days = match std::env::var("SQLITE_RETENTION") {
+            Ok(val) => val.parse()?,
+            Err(_) => DEFAULT_RETENTION_DAYS,
+        };
+        let connection = Connection::open(path)?;
+        // the REST API reads while the collector writes
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        info!("Storing points in SQLite database {path:?} for {days} days");
+        Ok(SqliteSink {
+            state: Mutex::new(State {
+                connection,
+                columns: HashMap::new(),
+                last_cleanup: None,
+            }),
+            retention: Duration::from_secs(days * 24 * 3600),
+            routes: Routes::from_env()?,
+        })
+    }
+}
+
+impl Sink for SqliteSink {
+    fn name(&self) -> &'static str {
+        "SQLite"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let result = (|| {
+            state.connection.execute_batch("BEGIN")?;
+            for point in batch.points() {
+                if self.routes.targets(&point.measurement).contains(&Target::Sqlite) {
+                    state.insert(point)?;
+                }
+            }
+            state.connection.execute_batch("COMMIT")
+        })();
+        if result.is_err() {
+            if !state.connection.is_autocommit() {
+                let _ = state.connection.execute_batch("ROLLBACK");
+            }
+            // a failed ALTER TABLE may have left the cache stale
+            state.columns.clear();
+        }
+        if state.last_cleanup.is_none_or(|last| last.elapsed() >= CLEANUP_INTERVAL) {
+            state.last_cleanup = Some(Instant::now());
+            if let Err(error) = state.cleanup(self.retention) {
+                error!("Error during cleanup of SQLite occured: {:?}", error);
+            }
+        }
+        Ok(result?)
+    }
+}
+
+/// Read access to the database for the REST API.
+#[cfg(feature = "http")]
+pub struct History {
+    path: PathBuf,
+}
+
+#[cfg(feature = "http")]
+impl History {
+    /// Read access to `SQLITE_FILE`, if it is set.
+    pub fn from_env() -> Option<Self> {
+        std::env::var("SQLITE_FILE").ok().map(|path| History { path: PathBuf::from(path) })
+    }
+
+    /// Returns the rows of `measurement` since `since` (Unix timestamp in
+    /// nanoseconds) as JSON array of objects with `time`, `tags` and the
+    /// fields, only `field` if given. `None` if there is no such
+    /// measurement or field.
+    pub fn query(&self, measurement: &str, field: Option<&str>, since: i64) -> rusqlite::Result<Option<serde_json::Value>> {
+        let connection = Connection::open_with_flags(Path::new(&self.path), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let table = quote(measurement);
+        let exists: bool = connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+            [measurement],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(None);
+        }
+        let columns = match field {
+            Some(field) => {
+                let has_field: bool =
+                    connection.query_row(&format!("SELECT EXISTS (SELECT 1 FROM pragma_table_info({}) WHERE name = ?)", quote(measurement)), [field], |row| {
+                        row.get(0)
+                    })?;
+                if !has_field {
+                    return Ok(None);
+                }
+                format!("time, tags, {}", quote(field))
+            }
+            None => "*".to_owned(),
+        };
+        let mut statement = connection.prepare(&format!("SELECT {columns} FROM {table} WHERE time >= ? ORDER BY time LIMIT {HISTORY_LIMIT}"))?;
+        let names: Vec<String> = statement.column_names().into_iter().map(str::to_owned).collect();
+        let rows = statement
+            .query_map([since], |row| {
+                let mut object = serde_json::Map::new();
+                for (index, name) in names.iter().enumerate() {
+                    let value = match name.as_str() {
+                        "tags" => serde_json::from_str(&row.get::<_, String>(index)?).unwrap_or_default(),
+                        _ => to_json(row.get(index)?),
+                    };
+                    // fields that didn't exist yet are NULL
+                    if !value.is_null() {
+                        object.insert(name.clone(), value);
+                    }
+                }
+                Ok(serde_json::Value::Object(object))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(rows.into()))
+    }
+}
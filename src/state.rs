@@ -0,0 +1,87 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encryption::{self, FileKey},
+    energy, grid_quality, peak, planner, snapshot,
+};
+
+/// Collector state that is kept across restarts. The spooled points are kept
+/// in the spool file, counter values and reboot windows aren't tracked.
+#[derive(Default, Serialize, Deserialize)]
+pub struct State {
+    /// Last successful poll (and its values) per measurement and device.
+    #[serde(default)]
+    pub snapshot: snapshot::Entries,
+    /// State of the site given by `FRONIUS_IP`.
+    #[serde(flatten)]
+    pub site: SiteState,
+    /// State of the sites given by `FRONIUS_SITES`, by name.
+    #[serde(default)]
+    pub sites: BTreeMap<String, SiteState>,
+}
+
+/// State of the derived values of one site.
+#[derive(Default, Serialize, Deserialize)]
+pub struct SiteState {
+    /// Samples of the current `grid_quality` interval.
+    #[serde(default)]
+    pub grid_quality: grid_quality::Accumulator,
+    /// Energies of the current day for `derived_energy`.
+    #[serde(default)]
+    pub energy: energy::DailyEnergy,
+    /// Monthly peak of the grid import.
+    #[serde(default)]
+    pub peak_demand: peak::PeakState,
+    /// PV and load forecast profiles of the battery planner.
+    #[serde(default)]
+    pub planner: planner::Profiles,
+}
+
+/// JSON file the state is persisted to after every cycle, encrypted if a
+/// key is given (see the `encryption` module).
+pub struct StateFile {
+    path: PathBuf,
+    key: Option<FileKey>,
+}
+
+impl StateFile {
+    /// Returns the state file given by `STATE_FILE`, if set.
+    pub fn from_env() -> Result<Option<Self>, encryption::EncryptionError> {
+        let Some(path) = std::env::var_os("STATE_FILE") else {
+            return Ok(None);
+        };
+        Ok(Some(StateFile {
+            path: path.into(),
+            key: FileKey::from_env()?,
+        }))
+    }
+
+    /// Loads the state, a missing file results in an empty state.
+    pub fn load(&self) -> Result<State, Box<dyn std::error::Error>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(content) => {
+                info!("Restoring state from {:?}", self.path);
+                Ok(serde_json::from_str(&encryption::open(&content, self.key.as_ref())?.complete()?)?)
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Saves the state. The file is replaced atomically, so a crash during
+    /// the write does not leave a broken state behind.
+    pub fn save(&self, state: &State) -> Result<(), Box<dyn std::error::Error>> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let json = serde_json::to_vec(state)?;
+        match &self.key {
+            Some(key) => std::fs::write(&tmp, key.seal(&json)? + "\n")?,
+            None => std::fs::write(&tmp, json)?,
+        }
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
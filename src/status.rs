@@ -0,0 +1,240 @@
+//! The `status` command: a compact overview of every site for the terminal,
+//! e.g. over SSH. It asks the running collector through its REST API and only
+//! polls the Datamanagers itself if the collector isn't reachable, has no data
+//! yet or `--poll` is given.
+//!
+//! The API is found by `HTTP_LISTEN` and `HTTP_PATH_PREFIX` of the same
+//! environment as the collector, or given by `STATUS_URL` (e.g.
+//! `http://raspberrypi:8080/fronius`). `HTTP_READ_TOKEN` or `HTTP_TOKEN` is
+//! sent as bearer token.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use fronius_api::{
+    collector::{Collector, CycleSnapshot},
+    fronius::blocking::Fronius,
+};
+use serde_json::Value;
+
+use crate::battery::BatterySign;
+
+const USAGE: &str = "usage: status [--poll]";
+/// Timeout of the request to the collector, before the Datamanagers are
+/// polled instead.
+const API_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The values of the overview of a site, powers in W with the signs of the
+/// power flow, except for the load which is positive while consumed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Overview {
+    pub pv: Option<f64>,
+    pub load: Option<f64>,
+    pub grid: Option<f64>,
+    pub battery: Option<f64>,
+    pub state_of_charge: Option<f64>,
+    pub inverter_state: Option<String>,
+    pub energy_day: Option<f64>,
+    /// Seconds since the power flow was fetched.
+    pub age: Option<f64>,
+}
+
+/// Runs the `status` command.
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let poll = match args {
+        [] => false,
+        [flag] if flag == "--poll" => true,
+        _ => return Err(USAGE.into()),
+    };
+    crate::locale::init_from_env()?;
+
+    // without the address of the collector there is nothing to ask
+    let from_api = match api_url().filter(|_| !poll) {
+        None => None,
+        Some(url) => match fetch_latest(&url) {
+            Ok(Some(latest)) => Some((url, overviews(&latest))),
+            Ok(None) => {
+                eprintln!("The collector at {url} has no data yet, polling the Datamanagers");
+                None
+            }
+            Err(error) => {
+                eprintln!("The collector at {url} isn't reachable ({error}), polling the Datamanagers");
+                None
+            }
+        },
+    };
+    let (source, sites) = match from_api {
+        Some((url, sites)) => (format!("from the collector at {url}"), sites),
+        None => ("polled just now".to_owned(), poll_sites()?),
+    };
+    if sites.is_empty() {
+        return Err("no power flow of any site".into());
+    }
+    print(&source, &sites);
+    Ok(())
+}
+
+/// The base URL of the REST API of the collector, `None` if it doesn't
+/// serve one.
+pub fn api_url() -> Option<String> {
+    if let Ok(url) = std::env::var("STATUS_URL") {
+        return Some(url.trim_end_matches('/').to_owned());
+    }
+    let addr = std::env::var("HTTP_LISTEN").ok()?;
+    // a wildcard address is reached locally
+    let addr = match addr.rsplit_once(':') {
+        Some(("0.0.0.0" | "", port)) => format!("127.0.0.1:{port}"),
+        Some(("[::]", port)) => format!("[::1]:{port}"),
+        _ => addr,
+    };
+    let prefix = std::env::var("HTTP_PATH_PREFIX").unwrap_or_default();
+    Some(format!("http://{addr}{}", prefix.trim_end_matches('/')))
+}
+
+/// The snapshot of the collector, `None` if it has no data yet.
+pub fn fetch_latest(url: &str) -> Result<Option<Value>, Box<dyn std::error::Error>> {
+    let client = reqwest::blocking::Client::builder().timeout(API_TIMEOUT).build()?;
+    let mut request = client.get(format!("{url}/api/latest"));
+    let token = ["HTTP_READ_TOKEN", "HTTP_TOKEN"].iter().find_map(|name| std::env::var(name).ok().filter(|token| !token.is_empty()));
+    if let Some(token) = token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send()?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let latest: Value = response.error_for_status()?.json()?;
+    Ok(latest.get("power_flow").is_some().then_some(latest))
+}
+
+/// The overview of every site in the snapshot of `/api/latest`, by site name
+/// (empty for a single site).
+pub fn overviews(latest: &Value) -> BTreeMap<String, Overview> {
+    let mut sites = BTreeMap::new();
+    let Some(power_flows) = latest["power_flow"].as_object() else {
+        return sites;
+    };
+    for (device, entry) in power_flows {
+        let values = &entry["values"];
+        let overview = Overview {
+            pv: values["photovoltaik"].as_f64(),
+            // P_Load is negative while power is consumed
+            load: values["load"].as_f64().map(|watts| -watts),
+            grid: values["grid"].as_f64(),
+            battery: values["akku"].as_f64(),
+            energy_day: values["energy_day"].as_f64(),
+            age: entry["age_seconds"].as_f64(),
+            ..Overview::default()
+        };
+        sites.insert(site_of(device), overview);
+    }
+    let devices = |measurement: &str| latest[measurement].as_object().into_iter().flatten();
+    for (device, entry) in devices("storage") {
+        if let Some(overview) = sites.get_mut(&site_of(device)) {
+            overview.state_of_charge = overview.state_of_charge.or(entry["values"]["charge_percentage"].as_f64());
+        }
+    }
+    for (device, entry) in devices("inverter_info") {
+        let values = &entry["values"];
+        let state = values["state"].as_str().or(values["status_code"].as_str());
+        if let (Some(overview), Some(state)) = (sites.get_mut(&site_of(device)), state) {
+            add_state(&mut overview.inverter_state, state);
+        }
+    }
+    sites
+}
+
+/// The site a device of the snapshot belongs to, empty for a single site.
+pub fn site_of(device: &str) -> String {
+    // filed as `<site>/<device>` with several sites
+    device.split_once('/').map(|(site, _)| site.to_owned()).unwrap_or_default()
+}
+
+/// Adds the state of an inverter, the states of several inverters are
+/// listed if they differ.
+fn add_state(states: &mut Option<String>, state: &str) {
+    match states {
+        Some(states) if states.split(", ").any(|known| known == state) => {}
+        Some(states) => *states = format!("{states}, {state}"),
+        None => *states = Some(state.to_owned()),
+    }
+}
+
+/// Polls the power flow and the inverters of every site once.
+fn poll_sites() -> Result<BTreeMap<String, Overview>, Box<dyn std::error::Error>> {
+    let battery = BatterySign::from_env()?;
+    let mut sites = BTreeMap::new();
+    for (name, ip) in crate::site_addresses()? {
+        let mut collector = overview_collector(crate::fronius_builder(name.as_deref(), &ip)?.build()?)?;
+        sites.insert(name.unwrap_or_default(), overview_of(&collector.poll_once(), battery)?);
+    }
+    Ok(sites)
+}
+
+/// A collector for the overview, which only needs the power flow and the
+/// inverter states: of the devices only the inverters are polled.
+pub fn overview_collector(fronius: Fronius) -> Result<Collector, Box<dyn std::error::Error>> {
+    let inverters = crate::devices_from_env(&fronius)?.inverters;
+    Ok(Collector::new(fronius).inverters(inverters).meters(Vec::new()).storages(Vec::new()).ohm_pilots(Vec::new()))
+}
+
+/// The overview of a site from a cycle of its collector, an error if the
+/// power flow couldn't be fetched. The overview is still useful without the
+/// inverter states.
+pub fn overview_of(cycle: &CycleSnapshot, battery: BatterySign) -> Result<Overview, Box<dyn std::error::Error>> {
+    let Some(power_flow) = &cycle.power_flow else {
+        let error = cycle.errors.iter().find(|error| error.dataset == "power_flow_data");
+        return Err(error.map_or_else(|| "no power flow".to_owned(), |error| error.error.to_string()).into());
+    };
+    let site = &power_flow.site;
+    let mut overview = Overview {
+        pv: Some(site.p_pv),
+        load: site.p_load.map(|watts| -watts),
+        grid: site.p_grid,
+        battery: site.p_akku.map(|watts| battery.normalize(watts)),
+        // the state of charge of the first battery, as in the snapshot
+        state_of_charge: power_flow.inverters.values().find_map(|inverter| inverter.soc),
+        energy_day: site.e_day,
+        age: Some(0.0),
+        ..Overview::default()
+    };
+    for inverter in cycle.inverter_infos.values() {
+        add_state(&mut overview.inverter_state, &inverter.inverter_state);
+    }
+    Ok(overview)
+}
+
+fn print(source: &str, sites: &BTreeMap<String, Overview>) {
+    let locale = crate::locale::get();
+    let power = |watts: Option<f64>| watts.map_or_else(|| "-".to_owned(), |watts| locale.power(watts));
+    for (i, (site, overview)) in sites.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        let age = match overview.age {
+            Some(age) if age >= 1.0 => format!(", {} s ago", locale.number(age, 0)),
+            _ => String::new(),
+        };
+        match site.as_str() {
+            "" => println!("Fronius ({source}{age})"),
+            site => println!("Fronius {site} ({source}{age})"),
+        }
+        println!("  {:<10} {}", "PV", power(overview.pv));
+        println!("  {:<10} {}", "Load", power(overview.load));
+        let direction = match overview.grid {
+            Some(grid) if grid.round() > 0.0 => " (import)",
+            Some(grid) if grid.round() < 0.0 => " (export)",
+            _ => "",
+        };
+        println!("  {:<10} {}{direction}", "Grid", power(overview.grid));
+        if overview.battery.is_some() || overview.state_of_charge.is_some() {
+            let state_of_charge = overview.state_of_charge.map(|percent| format!(", {}", locale.percent(percent))).unwrap_or_default();
+            println!("  {:<10} {}{state_of_charge}", "Battery", power(overview.battery));
+        }
+        if let Some(state) = &overview.inverter_state {
+            println!("  {:<10} {state}", "Inverter");
+        }
+        if let Some(energy_day) = overview.energy_day {
+            println!("  {:<10} {}", "Today", locale.energy(energy_day));
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Logging to syslog or journald instead of stderr, for installations
+//! without Docker, so the system takes care of the rotation and filtering.
+//!
+//! Selected by `LOG_TARGET` (`syslog` or `journald`), the level is still set
+//! by `RUST_LOG`. The key-values of a log record (e.g. `endpoint`, `device`
+//! and `error_kind` of a failed fetch) are passed as structured fields:
+//! journald gets them as fields of their own (`ENDPOINT=...`), syslog as
+//! structured data of RFC 5424. Syslog messages go to `/dev/log`, or with
+//! `SYSLOG_ADDR` via UDP to a remote server.
+
+use std::{
+    fmt::Write,
+    net::UdpSocket,
+    os::unix::net::UnixDatagram,
+};
+
+use chrono::prelude::*;
+use log::{kv::VisitSource, Level, Log, Metadata, Record};
+
+const IDENTIFIER: &str = "fronius-api";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Facility `daemon` of syslog.
+const FACILITY: u8 = 3;
+/// Private enterprise number of the structured data, the one RFC 5612
+/// reserves for documentation, as the fields aren't registered anywhere.
+const ENTERPRISE: u32 = 32473;
+
+enum Socket {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    fn send(&self, message: &[u8]) -> std::io::Result<()> {
+        match self {
+            Socket::Unix(socket) => socket.send(message).map(|_| ()),
+            Socket::Udp(socket) => socket.send(message).map(|_| ()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Syslog,
+    Journald,
+}
+
+pub struct SystemLogger {
+    socket: Socket,
+    format: Format,
+    filter: env_filter::Filter,
+}
+
+impl SystemLogger {
+    pub fn journald(filter: env_filter::Filter) -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(SystemLogger {
+            socket: Socket::Unix(socket),
+            format: Format::Journald,
+            filter,
+        })
+    }
+
+    pub fn syslog(filter: env_filter::Filter) -> std::io::Result<Self> {
+        let socket = match std::env::var("SYSLOG_ADDR") {
+            Ok(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                Socket::Udp(socket)
+            }
+            Err(_) => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(SYSLOG_SOCKET)?;
+                Socket::Unix(socket)
+            }
+        };
+        Ok(SystemLogger {
+            socket,
+            format: Format::Syslog,
+            filter,
+        })
+    }
+}
+
+impl Log for SystemLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut fields = Fields::default();
+        // only fails if a visitor returns an error, ours doesn't
+        let _ = record.key_values().visit(&mut fields);
+        let message = match self.format {
+            Format::Syslog => syslog_message(record, &fields.0),
+            Format::Journald => journald_message(record, &fields.0),
+        };
+        if let Err(error) = self.socket.send(&message) {
+            // the log itself is gone, stderr is the last resort
+            eprintln!("Error during logging occured: {error:?}: {}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// The key-values of a record, without the empty ones.
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+        let value = value.to_string();
+        if !value.is_empty() {
+            self.0.push((key.to_string(), value));
+        }
+        Ok(())
+    }
+}
+
+/// The severity of syslog, also used by journald.
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// A message in the format of RFC 5424, the fields and the target as
+/// structured data.
+fn syslog_message(record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+    let mut message = format!(
+        "<{}>1 {} - {IDENTIFIER} {} - [fields@{ENTERPRISE} target=\"{}\"",
+        FACILITY * 8 + severity(record.level()),
+        Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        std::process::id(),
+        escape_param(record.target()),
+    );
+    for (key, value) in fields {
+        // names are printable ASCII without `=`, ` `, `]` and `"`
+        let key: String = key.chars().filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"')).take(32).collect();
+        let _ = write!(message, " {key}=\"{}\"", escape_param(value));
+    }
+    let _ = write!(message, "] {}", record.args());
+    message.into_bytes()
+}
+
+fn escape_param(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+/// A message of the native journald protocol, every field on a line of its
+/// own or, if it contains a line break, with its length.
+fn journald_message(record: &Record, fields: &[(String, String)]) -> Vec<u8> {
+    let mut message = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        if value.contains('\n') {
+            message.extend(key.as_bytes());
+            message.push(b'\n');
+            message.extend((value.len() as u64).to_le_bytes());
+            message.extend(value.as_bytes());
+            message.push(b'\n');
+        } else {
+            message.extend(format!("{key}={value}\n").as_bytes());
+        }
+    };
+    field("MESSAGE", &record.args().to_string());
+    field("PRIORITY", &severity(record.level()).to_string());
+    field("SYSLOG_IDENTIFIER", IDENTIFIER);
+    field("TARGET", record.target());
+    for (key, value) in fields {
+        // journald takes upper case letters, digits and `_`, starting with a letter
+        let key: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        let key = key.trim_start_matches(|c: char| !c.is_ascii_alphabetic());
+        if !key.is_empty() {
+            field(key, value);
+        }
+    }
+    message
+}
@@ -0,0 +1,140 @@
+//! Optional OpenTelemetry export of traces and metrics via OTLP/HTTP.
+//!
+//! Only available with the `otlp` cargo feature and enabled at runtime by
+//! setting `OTEL_EXPORTER_OTLP_ENDPOINT`. Without the feature all functions
+//! in this module are no-ops.
+
+use std::time::Duration;
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use std::sync::OnceLock;
+
+    use opentelemetry::{
+        global,
+        metrics::{Counter, Histogram},
+        trace::Tracer,
+        KeyValue,
+    };
+    use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{metrics::SdkMeterProvider, trace::SdkTracerProvider, Resource};
+
+    struct Instruments {
+        cycle_duration: Histogram<f64>,
+        fetch_errors: Counter<u64>,
+    }
+
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+
+    pub struct Telemetry {
+        tracer_provider: SdkTracerProvider,
+        meter_provider: SdkMeterProvider,
+    }
+
+    pub fn init() -> Result<Option<Telemetry>, Box<dyn std::error::Error>> {
+        if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+            return Ok(None);
+        }
+
+        let resource = Resource::builder().with_service_name("fronius-api").build();
+
+        let span_exporter = SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .build()?;
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_resource(resource.clone())
+            .with_batch_exporter(span_exporter)
+            .build();
+        global::set_tracer_provider(tracer_provider.clone());
+
+        let metric_exporter = MetricExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpBinary)
+            .build()?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_resource(resource)
+            .with_periodic_exporter(metric_exporter)
+            .build();
+        global::set_meter_provider(meter_provider.clone());
+
+        let meter = global::meter("fronius-api");
+        INSTRUMENTS.get_or_init(|| Instruments {
+            cycle_duration: meter
+                .f64_histogram("fronius.cycle.duration")
+                .with_unit("s")
+                .with_description("Duration of a poll cycle")
+                .build(),
+            fetch_errors: meter
+                .u64_counter("fronius.fetch.errors")
+                .with_description("Number of failed fetches from the Fronius API")
+                .build(),
+        });
+
+        Ok(Some(Telemetry {
+            tracer_provider,
+            meter_provider,
+        }))
+    }
+
+    impl Drop for Telemetry {
+        fn drop(&mut self) {
+            let _ = self.tracer_provider.shutdown();
+            let _ = self.meter_provider.shutdown();
+        }
+    }
+
+    pub fn span<T>(name: String, f: impl FnOnce() -> T) -> T {
+        global::tracer("fronius-api").in_span(name, |_cx| f())
+    }
+
+    pub fn record_cycle(duration: std::time::Duration) {
+        if let Some(instruments) = INSTRUMENTS.get() {
+            instruments.cycle_duration.record(duration.as_secs_f64(), &[]);
+        }
+    }
+
+    pub fn record_fetch_error(dataset: &str) {
+        if let Some(instruments) = INSTRUMENTS.get() {
+            instruments
+                .fetch_errors
+                .add(1, &[KeyValue::new("dataset", dataset.to_owned())]);
+        }
+    }
+}
+
+/// Keeps the exporters alive, pending data is flushed when it is dropped.
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    _inner: Option<otlp::Telemetry>,
+}
+
+/// Sets up the OTLP exporters if `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+/// The standard `OTEL_*` variables can be used for further configuration.
+pub fn init() -> Result<Telemetry, Box<dyn std::error::Error>> {
+    Ok(Telemetry {
+        #[cfg(feature = "otlp")]
+        _inner: otlp::init()?,
+    })
+}
+
+/// Runs `f` inside a new span. Spans started within `f` become its children.
+#[allow(unused_variables)]
+pub fn span<T>(name: impl Into<String>, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "otlp")]
+    return otlp::span(name.into(), f);
+    #[cfg(not(feature = "otlp"))]
+    f()
+}
+
+#[allow(unused_variables)]
+pub fn record_cycle(duration: Duration) {
+    #[cfg(feature = "otlp")]
+    otlp::record_cycle(duration);
+}
+
+#[allow(unused_variables)]
+pub fn record_fetch_error(dataset: &str) {
+    #[cfg(feature = "otlp")]
+    otlp::record_fetch_error(dataset);
+}
@@ -0,0 +1,1029 @@
+//! Polling of a site against a fake Datamanager that answers with the example
+//! responses in `tests/fixtures`, mutated at random. Whatever the Datamanager
+//! answers, a cycle has to end with data or an error, never with a panic.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    panic::AssertUnwindSafe,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicUsize,
+    thread::JoinHandle,
+};
+
+use chrono::prelude::*;
+use serde_json::Value;
+
+use super::*;
+
+mod allocations;
+mod chaos;
+mod soak;
+
+const GENERATIONS: &[&str] = &["datamanager-3", "gen24-1"];
+const CYCLES: u64 = 100;
+
+/// Values that replace a node of a response.
+const REPLACEMENTS: &[&str] = &[
+    "null",
+    "true",
+    "\"\"",
+    "\"text\"",
+    "0",
+    "-1",
+    "1e308",
+    "-9223372036854775808",
+    "18446744073709551615",
+    "[]",
+    "{}",
+    "\"9999-12-31T23:59:59+00:00\"",
+];
+
+/// Bytes allocated and not freed yet.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+thread_local! {
+    /// Allocations made by the thread so far.
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Counts the allocated bytes of the process, for the soak test, which has
+/// to run alone, and the allocations per thread, for the allocation
+/// benchmark.
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// xorshift, so a failing cycle can be repeated.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n.max(1) as u64) as usize
+    }
+}
+
+/// How the fake Datamanager answers.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Valid,
+    Mutated,
+}
+
+struct Shared {
+    /// The generation whose responses are sent and whether they are mutated.
+    mode: Mutex<(&'static str, Mode)>,
+    rng: Mutex<Rng>,
+    /// The requests and responses since the last `take_log`.
+    log: Mutex<Vec<String>>,
+    stopped: AtomicBool,
+}
+
+/// A Datamanager on a local port. Every connection gets its own thread, like
+/// the requests of the collector would get on a real one.
+struct FakeDatamanager {
+    address: String,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeDatamanager {
+    fn start(seed: u64) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("fake Datamanager can listen");
+        let address = listener.local_addr().expect("listener has an address").to_string();
+        let shared = Arc::new(Shared {
+            mode: Mutex::new((GENERATIONS[0], Mode::Valid)),
+            rng: Mutex::new(Rng(seed)),
+            log: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        });
+        let thread = std::thread::spawn({
+            let shared = shared.clone();
+            move || {
+                for stream in listener.incoming() {
+                    if shared.stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let shared = shared.clone();
+                    if let Ok(stream) = stream {
+                        std::thread::spawn(move || serve(stream, &shared));
+                    }
+                }
+            }
+        });
+        FakeDatamanager {
+            address,
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    fn set_mode(&self, generation: &'static str, mode: Mode) {
+        *self.shared.mode.lock().expect("mode is not poisoned") = (generation, mode);
+    }
+
+    fn take_log(&self) -> Vec<String> {
+        std::mem::take(&mut *self.shared.log.lock().expect("log is not poisoned"))
+    }
+}
+
+impl Drop for FakeDatamanager {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        // wakes up the listener
+        let _ = TcpStream::connect(&self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Answers the requests of a connection until the client closes it.
+fn serve(stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    // the responses are written at once, without waiting for the ACK of the headers
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 {
+                return Ok(());
+            }
+            if header.trim().is_empty() {
+                break;
+            }
+        }
+        let url = request_line.split(' ').nth(1).unwrap_or_default();
+        let (generation, mode) = *shared.mode.lock().expect("mode is not poisoned");
+        let body = respond(url, generation, mode, &mut shared.rng.lock().expect("rng is not poisoned"));
+        shared.log.lock().expect("log is not poisoned").push(format!("{url} -> {}", String::from_utf8_lossy(body.as_deref().unwrap_or_default())));
+        let (status, body) = match body {
+            Some(body) => ("200 OK", body),
+            None => ("404 Not Found", Vec::new()),
+        };
+        let mut response = format!("HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        response.extend(body);
+        writer.write_all(&response)?;
+    }
+}
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures")
+}
+
+/// The fixture that answers a request: the endpoint, followed by the
+/// requested data collection or the scope.
+fn fixture_name(url: &str) -> (String, Option<String>) {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let endpoint = path.rsplit('/').next().unwrap_or_default().split('.').next().unwrap_or_default();
+    let param = |name: &str| query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('=')).map(str::to_owned);
+    match (param("DataCollection"), param("Scope").as_deref()) {
+        (Some(collection), _) => (format!("{endpoint}.{collection}"), None),
+        (None, Some("Device")) if endpoint != "GetInverterRealtimeData" => (format!("{endpoint}.System"), param("DeviceId")),
+        (None, Some("System")) => (format!("{endpoint}.System"), None),
+        _ => (endpoint.to_owned(), None),
+    }
+}
+
+/// The response to a request, `None` if the generation has no fixture for
+/// it. A request of a single device is answered with its part of the
+/// response for the system.
+fn respond(url: &str, generation: &str, mode: Mode, rng: &mut Rng) -> Option<Vec<u8>> {
+    let (name, device_id) = fixture_name(url);
+    let body = std::fs::read(fixtures_dir().join(generation).join(format!("{name}.json"))).ok()?;
+    let mut body: Value = serde_json::from_slice(&body).ok()?;
+    if let Some(device_id) = device_id {
+        if let Some(data) = body.pointer_mut("/Body/Data") {
+            *data = data.get(&device_id).cloned().unwrap_or(Value::Null);
+        }
+    }
+    // the API version is needed to connect at all
+    if mode == Mode::Valid || name == "GetAPIVersion" || rng.below(3) == 0 {
+        return serde_json::to_vec(&body).ok();
+    }
+    for _ in 0..=rng.below(3) {
+        mutate(&mut body, rng);
+    }
+    let mut body = serde_json::to_vec(&body).ok()?;
+    if rng.below(8) == 0 {
+        body.truncate(rng.below(body.len()));
+    }
+    Some(body)
+}
+
+/// Replaces or removes a random node of `value`.
+fn mutate(value: &mut Value, rng: &mut Rng) {
+    let children = match value {
+        Value::Object(map) => map.len(),
+        Value::Array(values) => values.len(),
+        _ => 0,
+    };
+    if children == 0 || rng.below(8) == 0 {
+        let replacement = REPLACEMENTS[rng.below(REPLACEMENTS.len())];
+        *value = serde_json::from_str(replacement).expect("replacements are valid JSON");
+        return;
+    }
+    let index = rng.below(children);
+    let remove = rng.below(6) == 0;
+    match value {
+        Value::Object(map) => {
+            let key = map.keys().nth(index).cloned().unwrap_or_default();
+            if remove {
+                map.remove(&key);
+            } else if let Some(child) = map.get_mut(&key) {
+                mutate(child, rng);
+            }
+        }
+        Value::Array(values) if remove => {
+            values.remove(index);
+        }
+        Value::Array(values) => {
+            if let Some(child) = values.get_mut(index) {
+                mutate(child, rng);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn fixtures_are_polled_without_errors() {
+    let datamanager = FakeDatamanager::start(1);
+    let mut site = Site::connect(None, &datamanager.address, true).expect("fake Datamanager can be connected");
+    for generation in GENERATIONS {
+        datamanager.set_mode(generation, Mode::Valid);
+        let (batch, power_flow) = fetch_data(&mut site, &Snapshot::default(), Instant::now() + Duration::from_secs(60))
+            .unwrap_or_else(|error| panic!("{generation} can't be polled: {error:?}"));
+        assert!(!batch.is_empty(), "{generation} gave no points");
+        assert!(power_flow.is_some(), "{generation} gave no power flow");
+    }
+}
+
+#[test]
+fn unreachable_site_doesnt_hold_up_the_others() {
+    let datamanager = FakeDatamanager::start(1);
+    // accepts connections, but never answers
+    let hanging = TcpListener::bind("127.0.0.1:0").expect("listener can bind");
+    let hanging_address = hanging.local_addr().expect("listener has an address").to_string();
+    let mut sites = vec![
+        Site::new(Some("home".to_owned()), &datamanager.address, true).expect("site is valid"),
+        Site::new(Some("parents".to_owned()), &hanging_address, false).expect("site is valid"),
+    ];
+    let start = Instant::now();
+    let results = fetch_sites(&mut sites, &Snapshot::default(), start + Duration::from_secs(3), 4);
+    assert!(start.elapsed() < Duration::from_secs(5), "cycle took {:?}, past the deadline", start.elapsed());
+    assert!(results[0].is_some(), "reachable site gave no data");
+    assert!(results[1].is_none(), "unreachable site gave data");
+    assert!(sites[1].collector.is_none());
+
+    // the unreachable site is only tried again after `CONNECT_INTERVAL`
+    let start = Instant::now();
+    let results = fetch_sites(&mut sites, &Snapshot::default(), start + Duration::from_secs(3), 4);
+    assert!(start.elapsed() < Duration::from_secs(2), "unreachable site was tried again right away");
+    assert!(results[0].is_some(), "reachable site gave no data");
+}
+
+#[test]
+fn collector_polls_every_generation() {
+    let datamanager = FakeDatamanager::start(1);
+    for generation in GENERATIONS {
+        datamanager.set_mode(generation, Mode::Valid);
+        let fronius = FroniusBuilder::new(datamanager.address.clone()).build().expect("fake Datamanager can be connected");
+        let mut collector = Collector::new(fronius);
+        let cycle = collector.poll_once();
+        assert!(cycle.power_flow.is_some(), "{generation} gave no power flow: {:?}", cycle.errors);
+        assert!(cycle.inverter_infos.contains_key(&DeviceId::FIRST_INVERTER), "{generation} gave no inverter info");
+        assert!(cycle.inverters.contains_key(&DeviceId::FIRST_INVERTER), "{generation} gave no inverter data");
+        assert!(cycle.meters.contains_key(&DeviceId::FIRST_COMPONENT), "{generation} gave no meter data");
+    }
+}
+
+#[test]
+fn missing_inverter_is_an_error() {
+    let datamanager = FakeDatamanager::start(1);
+    let fronius = FroniusBuilder::new(datamanager.address.clone()).build().expect("fake Datamanager can be connected");
+    let missing = DeviceId::try_from(99).expect("99 is a valid device ID");
+    let mut collector = Collector::new(fronius).inverters(vec![missing]);
+    let cycle = collector.poll_once();
+    let error = cycle.errors.iter().find(|error| error.dataset == "inverter_info").expect("inverter 99 doesn't exist");
+    assert!(error.error.is::<collector::MissingDeviceError>(), "unexpected error {error:?}");
+}
+
+#[test]
+fn firmware_upgrade_of_a_gen24_is_noticed() {
+    let datamanager = FakeDatamanager::start(1);
+    // GEN24 has no GetLoggerInfo.cgi
+    datamanager.set_mode("gen24-1", Mode::Valid);
+    let mut site = Site::connect(None, &datamanager.address, true).expect("fake Datamanager can be connected");
+    assert_eq!(site.compatibility_range.as_deref(), Some("1.8-1"));
+    let api_version_requests = |log: Vec<String>| log.iter().filter(|request| request.contains("GetAPIVersion")).count();
+    datamanager.take_log();
+
+    site.check_firmware(Duration::ZERO);
+    assert_eq!(site.compatibility_range.as_deref(), Some("1.8-1"));
+    assert_eq!(api_version_requests(datamanager.take_log()), 1, "client was replaced without an upgrade");
+
+    // the upgraded firmware reports another compatibility range
+    datamanager.set_mode("datamanager-3", Mode::Valid);
+    site.check_firmware(Duration::ZERO);
+    assert_eq!(site.compatibility_range.as_deref(), Some("1.5-18"));
+    assert_eq!(api_version_requests(datamanager.take_log()), 2, "client wasn't replaced after the upgrade");
+}
+
+#[test]
+fn derating_is_unknown_without_a_power_limit() {
+    assert_eq!(derating_reason(0, None), "unknown");
+    // fault codes of the grid monitoring are no derating
+    assert_eq!(derating_reason(102, Some(0.4)), "unknown");
+    assert_eq!(derating_reason(0, Some(0.5)), "none");
+    assert_eq!(derating_reason(0, Some(0.99)), "power_limit");
+    assert_eq!(derating_reason(STATE_CODE_TEMPERATURE_DERATING, None), "temperature");
+}
+
+#[test]
+fn mutated_responses_never_panic() {
+    let datamanager = FakeDatamanager::start(0x5eed);
+    let mut site = Site::connect(None, &datamanager.address, true).expect("fake Datamanager can be connected");
+    let snapshot = Snapshot::default();
+    for cycle in 0..CYCLES {
+        let generation = GENERATIONS[cycle as usize % GENERATIONS.len()];
+        datamanager.set_mode(generation, Mode::Mutated);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = fetch_data(&mut site, &snapshot, Instant::now() + Duration::from_secs(60));
+            // requests the firmware and the inventory again
+            if cycle % 10 == 0 {
+                site.check_firmware(Duration::ZERO);
+            }
+        }));
+        let log = datamanager.take_log();
+        assert!(result.is_ok(), "cycle {cycle} panicked, the responses were:\n{}", log.join("\n"));
+    }
+}
+
+
+/// Records the sizes of the batches it gets.
+struct RecordingSink {
+    live: bool,
+    writes: Arc<Mutex<Vec<usize>>>,
+}
+
+impl sink::Sink for RecordingSink {
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        self.writes.lock().expect("writes are not poisoned").push(batch.len());
+        Ok(())
+    }
+
+    fn live(&self) -> bool {
+        self.live
+    }
+}
+
+#[test]
+fn collected_cycles_are_written_at_once() {
+    let mut cycle = Batch::default();
+    cycle.points_mut().push(point::Point::from_line("power_flow pv=1 1700000000000000000").expect("line is valid"));
+    cycle.points_mut().push(point::Point::from_line("meter power=2 1700000000000000000").expect("line is valid"));
+    // (cycles, points) at which the collected points are written
+    for (every_cycles, max_points, expected) in [(3, None, [6, 2]), (4, Some(3), [4, 4])] {
+        let (stored, live) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let sinks = sink::Sinks::new(vec![
+            Box::new(RecordingSink { live: false, writes: stored.clone() }),
+            Box::new(RecordingSink { live: true, writes: live.clone() }),
+        ])
+        .collecting(every_cycles, max_points);
+        for _ in 0..4 {
+            sinks.write(&cycle);
+        }
+        // the rest is written at shutdown, once
+        sinks.flush();
+        sinks.flush();
+        assert_eq!(*stored.lock().expect("writes are not poisoned"), expected, "every {every_cycles} cycles, at most {max_points:?} points");
+        assert_eq!(*live.lock().expect("writes are not poisoned"), [2, 2, 2, 2]);
+    }
+}
+
+/// A sink whose database is down.
+#[cfg(feature = "influxdb2")]
+struct FailingSink;
+
+#[cfg(feature = "influxdb2")]
+impl sink::Sink for FailingSink {
+    fn name(&self) -> &'static str {
+        "failing"
+    }
+
+    fn write(&self, _batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        Err("database is down".into())
+    }
+}
+
+#[cfg(feature = "influxdb2")]
+#[test]
+fn backfill_progress_is_saved_only_after_a_write() {
+    let progress_file = std::env::temp_dir().join(format!("fronius-backfill-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&progress_file);
+    let day = chrono::NaiveDate::from_ymd_opt(2024, 6, 15).expect("date is valid");
+    let mut progress = backfill::Progress::new(day, day);
+    let mut batch = Batch::default();
+    batch.points_mut().push(point::Point::from_line("archive_data value=1 1718409600000000000").expect("line is valid"));
+
+    assert!(backfill::write_day(&FailingSink, &batch, day, &mut progress, &progress_file).is_err());
+    assert_eq!(progress.next, day, "failed day was skipped");
+    assert!(!progress_file.exists(), "progress was saved without a write");
+
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let sink = RecordingSink { live: false, writes: writes.clone() };
+    backfill::write_day(&sink, &batch, day, &mut progress, &progress_file).expect("day is written");
+    assert_eq!(progress.next, day.succ_opt().expect("next day is valid"));
+    assert!(progress_file.exists(), "progress wasn't saved");
+    assert_eq!(*writes.lock().expect("writes are not poisoned"), [1]);
+    let _ = std::fs::remove_file(&progress_file);
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn torn_spool_line_doesnt_hide_the_others() {
+    let key = encryption::FileKey::new([7; 32]);
+    let first = key.seal(b"power value=1 1\n").expect("line is sealed");
+    let torn = key.seal(b"power value=2 2\n").expect("line is sealed");
+    let last = key.seal(b"power value=3 3\n").expect("line is sealed");
+    let content = format!("{first}\n{}\n{last}\n", &torn[..torn.len() / 2]);
+
+    let opened = encryption::open(&content, Some(&key)).expect("spool is readable");
+    assert_eq!(opened.plaintext, "power value=1 1\npower value=3 3\n");
+    assert_eq!(opened.damaged.len(), 1);
+    assert!(opened.complete().is_err(), "damaged file was complete");
+}
+
+#[test]
+fn read_only_mode_rejects_commands() {
+    let control = control::Control::new(Duration::from_secs(15), true);
+    let ack = control.execute("reload", "test");
+    assert!(!ack.ok());
+    assert!(!control.take_reload());
+
+    let control = control::Control::new(Duration::from_secs(15), false);
+    assert!(control.execute("reload", "test").ok());
+    assert!(control.take_reload());
+}
+
+#[derive(point_derive::ToPoint)]
+#[measurement = "test"]
+struct TestData {
+    #[influxdb(tag)]
+    device: String,
+    #[influxdb(field)]
+    power: Option<f64>,
+    #[influxdb(field)]
+    energy: Option<f64>,
+    #[influxdb(field)]
+    state: String,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+#[test]
+fn measurement_structs_become_points() {
+    let data = TestData {
+        device: "Roof West".to_owned(),
+        power: Some(1.5),
+        energy: None,
+        state: "say \"hi\"".to_owned(),
+        time: 1700000000000000000,
+    };
+    let mut batch = Batch::for_site(Some("home"));
+    batch.push(&data);
+    let point = &batch.points()[0];
+    assert_eq!(point.measurement, "test");
+    assert_eq!(point.tag("device"), Some("Roof West"));
+    assert_eq!(point.tag("site"), Some("home"));
+    assert_eq!(point.field("power"), Some(&point::FieldValue::Float(1.5)));
+    // missing values are left out
+    assert_eq!(point.field("energy"), None);
+    assert_eq!(point.to_line(), r#"test,device=Roof\ West,site=home power=1.5,state="say \"hi\"" 1700000000000000000"#);
+}
+
+#[test]
+fn ack_command_acknowledges_the_alerts() {
+    let control = control::Control::new(Duration::from_secs(15), false);
+    // `POST /api/ack` is the command `ack`
+    assert!(control.execute("ack", "HTTP").ok());
+    assert!(control.take_ack());
+    assert!(!control.take_ack(), "ack was applied twice");
+
+    let mut events = events::Events::default();
+    events.flag("outage", "outage", true, "Datamanager unreachable");
+    assert_eq!(events.alerts(), (1, 1));
+    assert_eq!(events.acknowledge(), 1);
+    assert_eq!(events.alerts(), (1, 0));
+    assert_eq!(events.acknowledge(), 0, "alert was acknowledged twice");
+    // the acknowledgement ends with the alert
+    events.flag("outage", "outage", false, "Datamanager unreachable");
+    events.flag("outage", "outage", true, "Datamanager unreachable");
+    assert_eq!(events.alerts(), (1, 1));
+}
+
+#[cfg(feature = "snmp")]
+#[test]
+fn snmp_agent_answers_get_requests() {
+    let (sink, agent) = snmp::agent_from_env().expect("defaults are valid");
+    let mut batch = Batch::default();
+    batch.points_mut().push(point::Point::from_line("power_flow photovoltaik=1234.4,grid=-200 1700000000000000000").expect("line is valid"));
+    sink::Sink::write(&sink, &batch).expect("points are stored");
+    // SNMPv2c GetRequest with the community public for <base>.1.0
+    let mut request = vec![
+        0x30, 0x2b, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xa0, 0x1e, 0x02, 0x01, 0x2a, 0x02, 0x01, 0x00, 0x02, 0x01,
+        0x00, 0x30, 0x13, 0x30, 0x11, 0x06, 0x0d, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xbf, 0x08, 0xce, 0x0f, 0xce, 0x0f, 0x01, 0x00, 0x05, 0x00,
+    ];
+    let response = agent.respond(&request).expect("request is answered");
+    // a response with the request ID and the PV power as Integer32
+    assert_eq!(response[13], 0xa2);
+    assert!(response.windows(3).any(|window| window == [0x02, 0x01, 0x2a]));
+    assert!(response.ends_with(&[0x02, 0x02, 0x04, 0xd2]));
+
+    request[8] = b'x';
+    assert!(agent.respond(&request).is_none(), "wrong community");
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn prometheus_endpoint_shows_the_latest_values() {
+    let sink = prometheus::PrometheusSink::new().expect("routes are valid");
+    let mut batch = Batch::default();
+    for line in [
+        "power_flow,device=Unknown,site=home photovoltaik=1234.4,grid=-200 1700000000000000000",
+        "power_flow,device=Unknown,site=home photovoltaik=1500 1700000005000000000",
+        r#"inverter_info,device=Inverter,name=Garage\ "east" state="Running",online=true 1700000000000000000"#,
+    ] {
+        batch.points_mut().push(point::Point::from_line(line).expect("line is valid"));
+    }
+    sink::Sink::write(&sink, &batch).expect("points are stored");
+    let expected = r#"# TYPE fronius_inverter_info_online gauge
+fronius_inverter_info_online{device="Inverter",name="Garage \"east\""} 1
+# TYPE fronius_power_flow_grid gauge
+fronius_power_flow_grid{device="Unknown",site="home"} -200
+# TYPE fronius_power_flow_photovoltaik gauge
+fronius_power_flow_photovoltaik{device="Unknown",site="home"} 1500
+"#;
+    assert_eq!(sink.render(), expected);
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn csv_file_has_a_row_per_field() {
+    let path = std::env::temp_dir().join(format!("fronius-{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let sink = csv_file::CsvFileSink::from_env(&path.to_string_lossy()).expect("routes are valid");
+    let mut batch = Batch::default();
+    batch.points_mut().push(point::Point {
+        measurement: "inverter_info".to_owned(),
+        tags: vec![("device".to_owned(), "Inverter".to_owned())],
+        fields: vec![
+            ("state".to_owned(), point::FieldValue::String("Running, MPPT".to_owned())),
+            ("online".to_owned(), point::FieldValue::Boolean(true)),
+            ("power".to_owned(), point::FieldValue::Integer(850)),
+        ],
+        time: 1700000000000000000,
+    });
+    sink::Sink::write(&sink, &batch).expect("points are written");
+    sink::Sink::write(&sink, &batch).expect("points are appended");
+    let written = std::fs::read_to_string(&path).expect("file is written");
+    let _ = std::fs::remove_file(&path);
+    let rows = r#"2023-11-14T22:13:20Z,inverter_info,"{""device"":""Inverter""}",state,,"Running, MPPT"
+2023-11-14T22:13:20Z,inverter_info,"{""device"":""Inverter""}",online,1,
+2023-11-14T22:13:20Z,inverter_info,"{""device"":""Inverter""}",power,850,
+"#;
+    assert_eq!(written, format!("time,measurement,tags,field,value,text\n{rows}{rows}"), "one header");
+}
+
+#[test]
+fn human_outputs_are_localized() {
+    let german = locale::Locale::new("de_AT.UTF-8", 1000.0).expect("de is known");
+    assert_eq!(german.power(850.4), "850 W");
+    assert_eq!(german.power(-1234.5), "-1,23 kW");
+    assert_eq!(german.energy(1_234_567.0), "1,23 MWh");
+    assert_eq!(german.number(1_234_567.891, 2), "1.234.567,89");
+    let english = locale::Locale::new("en_US", 2000.0).expect("en is known");
+    assert_eq!(english.power(1234.6), "1,235 W");
+    assert_eq!(english.power(999.6), "1,000 W");
+    assert!(locale::Locale::new("xx", 1000.0).is_err());
+}
+
+#[test]
+fn status_overview_from_the_snapshot() {
+    let latest = serde_json::json!({
+        "power_flow": {
+            "home/Unknown": {"age_seconds": 2.5, "values": {"photovoltaik": 4200.0, "load": -800.0, "grid": -3400.0, "akku": null, "energy_day": 18400.0}},
+            "barn/Unknown": {"age_seconds": 1.0, "values": {"photovoltaik": 0.0, "load": -150.0, "grid": 150.0}},
+        },
+        "storage": {"home/Storage": {"values": {"charge_percentage": 87.0}}},
+        "inverter_info": {
+            "home/Inverter": {"values": {"state": "Running"}},
+            "barn/Inverter": {"values": {"status": 13}},
+        },
+    });
+    let sites = status::overviews(&latest);
+    let home = &sites["home"];
+    assert_eq!(home.load, Some(800.0));
+    assert_eq!(home.battery, None);
+    assert_eq!(home.state_of_charge, Some(87.0));
+    assert_eq!(home.inverter_state.as_deref(), Some("Running"));
+    assert_eq!(home.energy_day, Some(18400.0));
+    let barn = &sites["barn"];
+    assert_eq!(barn.state_of_charge, None);
+    assert_eq!(barn.inverter_state, None, "numeric status fields only");
+}
+
+#[cfg(feature = "display")]
+#[test]
+fn display_renders_the_power_flow() {
+    let snapshot = Snapshot::default();
+    let waiting = display::render(&snapshot, 128, 64);
+    snapshot.for_site(None).update("power_flow", &serde_json::json!({"device": "Unknown", "photovoltaik": 4200.0, "load": -800.0, "grid": -3400.0}));
+    let canvas = display::render(&snapshot, 256, 128);
+    // scaled by two, so the lit pixels come in pairs
+    let lit: Vec<(u32, u32)> = (0..128).flat_map(|y| (0..256).map(move |x| (x, y))).filter(|&(x, y)| canvas.pixel(x, y)).collect();
+    assert!(!lit.is_empty());
+    assert!(lit.iter().all(|&(x, y)| canvas.pixel(x ^ 1, y) && canvas.pixel(x, y ^ 1)));
+    assert!((0..64).any(|y| (0..128).any(|x| waiting.pixel(x, y))), "waiting for data");
+
+    let png = canvas.to_png().expect("canvas is valid");
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+}
+
+#[cfg(feature = "wattpilot")]
+#[test]
+fn surplus_charging_rejects_invalid_limits() {
+    assert!(wattpilot::Control::new(6, 16, 3.0, 1.0, false).is_ok());
+    assert!(wattpilot::Control::new(6, 6, 1.0, 0.0, false).is_ok());
+    assert!(wattpilot::Control::new(5, 16, 3.0, 1.0, false).is_err(), "minimum below 6 A");
+    assert!(wattpilot::Control::new(10, 8, 3.0, 1.0, false).is_err(), "minimum above the maximum");
+    assert!(wattpilot::Control::new(6, 16, 2.0, 1.0, false).is_err(), "two phases");
+    assert!(wattpilot::Control::new(6, 16, 3.0, -1.0, false).is_err(), "negative hysteresis");
+    assert!(wattpilot::Control::new(6, 16, 3.0, f64::NAN, false).is_err(), "hysteresis isn't a number");
+}
+
+#[cfg(feature = "wattpilot")]
+#[test]
+fn surplus_charging_follows_the_surplus() {
+    const ECO: Option<i64> = Some(4);
+    let mut control = wattpilot::Control::new(6, 16, 1.0, 1.0, false).expect("limits are valid");
+    let decision = control.decide(1000.0, ECO, Some(6));
+    assert!(!decision.charging, "4.3 A is below the minimum");
+    assert!(decision.controlled);
+    assert_eq!((decision.current, decision.set), (0, None));
+
+    let decision = control.decide(2300.0, ECO, Some(6));
+    assert!(decision.charging);
+    assert_eq!((decision.current, decision.set), (10, Some(10)));
+    // within the hysteresis the minimum current is kept
+    let decision = control.decide(1265.0, ECO, Some(10));
+    assert!(decision.charging);
+    assert_eq!((decision.current, decision.set), (6, Some(6)));
+    let decision = control.decide(5000.0, ECO, Some(6));
+    assert_eq!((decision.current, decision.set), (16, Some(16)), "clamped to the maximum");
+    let decision = control.decide(5000.0, ECO, Some(16));
+    assert_eq!(decision.set, None, "current is already set");
+    let decision = control.decide(900.0, ECO, Some(16));
+    assert!(!decision.charging, "3.9 A is below the hysteresis");
+    assert_eq!((decision.current, decision.set), (0, None));
+}
+
+#[cfg(feature = "wattpilot")]
+#[test]
+fn surplus_charging_keeps_a_current_set_by_the_user() {
+    const ECO: Option<i64> = Some(4);
+    let mut control = wattpilot::Control::new(6, 16, 1.0, 1.0, false).expect("limits are valid");
+    assert_eq!(control.decide(2300.0, ECO, Some(6)).set, Some(10));
+    let decision = control.decide(2300.0, ECO, Some(12));
+    assert!(!decision.controlled, "current changed by the user");
+    assert_eq!(decision.set, None);
+    assert_eq!(control.decide(3000.0, ECO, Some(12)).set, None, "overridden until the mode changes");
+    assert!(!control.decide(3000.0, Some(3), Some(12)).controlled, "only the eco mode is controlled");
+    let decision = control.decide(3000.0, ECO, Some(12));
+    assert!(decision.controlled, "mode changed back to eco");
+    assert_eq!(decision.set, Some(13));
+
+    // in read-only mode the current isn't changed, so that's no override
+    let mut control = wattpilot::Control::new(6, 16, 1.0, 1.0, true).expect("limits are valid");
+    assert_eq!(control.decide(2300.0, ECO, Some(6)).set, Some(10));
+    let decision = control.decide(2300.0, ECO, Some(6));
+    assert!(decision.controlled);
+    assert_eq!(decision.set, Some(10));
+}
+
+#[test]
+fn tariff_has_to_cover_every_hour_once() {
+    let tariff: planner::Tariff = "0-6=0.18,6-22=0.32,22-24=0.18".parse().expect("schedule is valid");
+    assert_eq!(tariff, "22-24=0.18,0-6=0.18,6-22=0.32".parse().expect("schedule is valid"), "order of the windows");
+    assert!("0-6=0.18,7-24=0.32".parse::<planner::Tariff>().is_err(), "gap at 6");
+    assert!("0-6=0.18,5-24=0.32".parse::<planner::Tariff>().is_err(), "overlap at 5");
+    assert!("0-24=0.18,22-24=0.18".parse::<planner::Tariff>().is_err(), "overlap with the same price");
+    assert!("0-6=0.18,6-25=0.32".parse::<planner::Tariff>().is_err(), "hour 25");
+    assert!("0-6=0.18,6-6=0.32,6-24=0.32".parse::<planner::Tariff>().is_err(), "empty window");
+    assert!("0-24".parse::<planner::Tariff>().is_err(), "no price");
+}
+
+#[test]
+fn profile_averages_the_hours_of_the_days() {
+    let mut profile = planner::Profile::default();
+    profile.add(10, 1000.0);
+    profile.add(10, 3000.0);
+    assert_eq!(profile.forecast(10), 0.0, "hour isn't finished yet");
+    profile.add(11, 500.0);
+    assert_eq!(profile.forecast(10), 2000.0, "mean of the first day");
+    profile.add(10, 4000.0);
+    profile.add(11, 0.0);
+    assert!((profile.forecast(10) - 2600.0).abs() < 1e-9, "newest day weighted with 0.3");
+    assert_eq!(profile.forecast(11), 500.0);
+}
+
+/// Planner with a constant load of 1 kW and an empty 10 kWh battery.
+fn empty_battery_planner(tariff: &str) -> planner::Planner {
+    let mut profiles = planner::Profiles::default();
+    for hour in (0..24).chain([0]) {
+        profiles.load.add(hour, 1000.0);
+        profiles.pv.add(hour, 0.0);
+    }
+    let mut planner = planner::Planner::new(tariff.parse().expect("schedule is valid"), 3000.0);
+    planner.restore(profiles);
+    planner.set_battery(0.0, 10_000.0);
+    planner
+}
+
+fn plan_actions(plan: &planner::Plan) -> Vec<(String, f64)> {
+    let slots = serde_json::to_value(plan.slots()).expect("plan is serializable");
+    slots
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|slot| (slot["action"].as_str().unwrap_or_default().to_owned(), slot["price"].as_f64().unwrap_or_default()))
+        .collect()
+}
+
+#[test]
+fn plan_charges_in_the_cheapest_hours() {
+    let planner = empty_battery_planner("0-2=0.20,2-6=0.10,6-24=0.30");
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let start = Utc.with_ymd_and_hms(2024, 1, 14, 23, 0, 0).single().expect("time is valid");
+    let plan = planner.plan(start, |time| time.with_timezone(&cet)).expect("plan is computed");
+    let actions = plan_actions(&plan);
+    assert_eq!(actions.len(), 24);
+    // 10 kWh are missing for the expensive hours, 4 hours at 3 kW cover them,
+    // the cheapest hours first
+    let charged: Vec<usize> = actions.iter().enumerate().filter(|(_, (action, _))| action == "charge_grid").map(|(i, _)| i).collect();
+    assert_eq!(charged, [2, 3, 4, 5]);
+    assert_eq!(actions[0], ("discharge".to_owned(), 0.20));
+    assert!(actions[6..].iter().all(|(action, price)| action == "discharge" && *price == 0.30));
+}
+
+#[test]
+fn plan_follows_the_daylight_saving_time_switch() {
+    let planner = empty_battery_planner("0-3=0.10,3-24=0.30");
+    // CET to CEST at 01:00 UTC, local 02:00 is skipped
+    let switch = Utc.with_ymd_and_hms(2024, 3, 31, 1, 0, 0).single().expect("time is valid");
+    let local = |time: DateTime<Utc>| {
+        let offset = if time < switch { 3600 } else { 7200 };
+        time.with_timezone(&FixedOffset::east_opt(offset).expect("offset is valid"))
+    };
+    let start = Utc.with_ymd_and_hms(2024, 3, 30, 23, 0, 0).single().expect("time is valid");
+    let plan = planner.plan(start, local).expect("plan is computed");
+    let prices: Vec<f64> = plan_actions(&plan).into_iter().map(|(_, price)| price).collect();
+    assert_eq!(prices[..3], [0.10, 0.10, 0.30], "third slot is 03:00 local");
+}
+
+/// Rolling average, quarter-hour average and peak of the month.
+fn peak_demand_values(data: &peak::PeakDemandData) -> (f64, f64, f64) {
+    let data = serde_json::to_value(data).expect("data is serializable");
+    let value = |field: &str| data[field].as_f64().unwrap_or(f64::NAN);
+    (value("rolling_average"), value("quarter_average"), value("month_peak"))
+}
+
+#[test]
+fn peak_demand_is_the_highest_quarter_hour_of_the_month() {
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let at = |day: u32, hour: u32, minute: u32| cet.with_ymd_and_hms(2024, 1, day, hour, minute, 0).single().expect("time is valid");
+    let mut peak_demand = peak::PeakDemand::new(None, 0.9);
+    assert_eq!(peak_demand_values(&peak_demand.add_at(4000.0, at(31, 23, 0)).expect("time is valid")), (4000.0, 4000.0, 0.0));
+    assert_eq!(peak_demand_values(&peak_demand.add_at(2000.0, at(31, 23, 14)).expect("time is valid")), (3000.0, 3000.0, 0.0));
+    // the quarter-hour starting at :15 finishes the one before
+    assert_eq!(peak_demand_values(&peak_demand.add_at(1000.0, at(31, 23, 15)).expect("time is valid")), (7000.0 / 3.0, 1000.0, 3000.0));
+    // feed-in counts as zero import, the sample of 23:00 left the rolling window
+    assert_eq!(peak_demand_values(&peak_demand.add_at(-500.0, at(31, 23, 20)).expect("time is valid")), (1000.0, 500.0, 3000.0));
+    // a lower quarter-hour doesn't replace the peak
+    assert_eq!(peak_demand_values(&peak_demand.add_at(0.0, at(31, 23, 30)).expect("time is valid")).2, 3000.0);
+}
+
+#[test]
+fn peak_demand_starts_over_with_the_month() {
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let mut peak_demand = peak::PeakDemand::new(None, 0.9);
+    let january = cet.with_ymd_and_hms(2024, 1, 31, 23, 45, 0).single().expect("time is valid");
+    peak_demand.add_at(5000.0, january).expect("time is valid");
+    // midnight in the time zone of the site, still January in UTC
+    let february = cet.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).single().expect("time is valid");
+    let (_, quarter_average, month_peak) = peak_demand_values(&peak_demand.add_at(1000.0, february).expect("time is valid"));
+    assert_eq!((quarter_average, month_peak), (1000.0, 0.0));
+}
+
+#[test]
+fn peak_demand_is_restored_from_the_state() {
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let at = |minute: u32| cet.with_ymd_and_hms(2024, 1, 10, 12, minute, 0).single().expect("time is valid");
+    let mut peak_demand = peak::PeakDemand::new(None, 0.9);
+    peak_demand.add_at(3000.0, at(0)).expect("time is valid");
+    peak_demand.add_at(1000.0, at(15)).expect("time is valid");
+    let state = serde_json::to_string(peak_demand.state()).expect("state is serializable");
+
+    let mut restored = peak::PeakDemand::new(None, 0.9);
+    restored.restore(serde_json::from_str(&state).expect("state is valid"));
+    // the running quarter-hour is continued and the peak is kept
+    let (rolling_average, quarter_average, month_peak) = peak_demand_values(&restored.add_at(2000.0, at(20)).expect("time is valid"));
+    assert_eq!((rolling_average, quarter_average, month_peak), (2000.0, 1500.0, 3000.0));
+}
+
+/// A field of the derived energies.
+fn derived_energy(data: &energy::DerivedEnergyData, field: &str) -> Option<f64> {
+    serde_json::to_value(data).expect("data is serializable")[field].as_f64()
+}
+
+fn assert_close(value: Option<f64>, expected: f64) {
+    assert!(value.is_some_and(|value| (value - expected).abs() < 1e-9), "{value:?} isn't {expected}");
+}
+
+#[test]
+fn daily_energy_integrates_the_power_flow() {
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let at = |hour: u32, minute: u32, second: u32| cet.with_ymd_and_hms(2024, 6, 15, hour, minute, second).single().expect("time is valid");
+    let mut energy = energy::DailyEnergy::default();
+    let data = energy.add_at(2000.0, 1000.0, -1000.0, 0.0, at(12, 0, 0)).expect("time is valid");
+    assert_eq!(derived_energy(&data, "pv_energy"), Some(0.0), "nothing to integrate yet");
+    assert_eq!(derived_energy(&data, "self_consumption"), None);
+
+    // 36 s are 0.01 h
+    let data = energy.add_at(2000.0, 1000.0, -1000.0, 0.0, at(12, 0, 36)).expect("time is valid");
+    assert_close(derived_energy(&data, "pv_energy"), 20.0);
+    assert_close(derived_energy(&data, "load_energy"), 10.0);
+    assert_close(derived_energy(&data, "grid_export_energy"), 10.0);
+    assert_close(derived_energy(&data, "self_consumption"), 0.5);
+    assert_close(derived_energy(&data, "autonomy"), 1.0);
+
+    // the battery discharges, the rest is imported
+    let data = energy.add_at(0.0, 3000.0, 1000.0, 2000.0, at(12, 1, 12)).expect("time is valid");
+    assert_close(derived_energy(&data, "battery_discharge_energy"), 20.0);
+    assert_close(derived_energy(&data, "grid_import_energy"), 10.0);
+    assert_close(derived_energy(&data, "autonomy"), 0.75);
+    let data = energy.add_at(3000.0, 1000.0, 0.0, -2000.0, at(12, 1, 48)).expect("time is valid");
+    assert_close(derived_energy(&data, "battery_charge_energy"), 20.0);
+    assert_close(derived_energy(&data, "self_consumption"), 40.0 / 50.0);
+}
+
+#[test]
+fn daily_energy_skips_gaps() {
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let at = |minute: u32, second: u32| cet.with_ymd_and_hms(2024, 6, 15, 12, minute, second).single().expect("time is valid");
+    let mut energy = energy::DailyEnergy::default();
+    energy.add_at(1000.0, 0.0, -1000.0, 0.0, at(0, 0)).expect("time is valid");
+    let data = energy.add_at(1000.0, 0.0, -1000.0, 0.0, at(5, 0)).expect("time is valid");
+    assert_close(derived_energy(&data, "pv_energy"), 1000.0 / 12.0);
+    // more than MAX_GAP_SECONDS since the last sample
+    let data = energy.add_at(1000.0, 0.0, -1000.0, 0.0, at(10, 1)).expect("time is valid");
+    assert_close(derived_energy(&data, "pv_energy"), 1000.0 / 12.0);
+    let data = energy.add_at(1000.0, 0.0, -1000.0, 0.0, at(10, 37)).expect("time is valid");
+    assert_close(derived_energy(&data, "pv_energy"), 1000.0 / 12.0 + 10.0);
+}
+
+#[test]
+fn daily_energy_starts_over_at_midnight() {
+    let cet = FixedOffset::east_opt(3600).expect("offset is valid");
+    let mut energy = energy::DailyEnergy::default();
+    energy.add_at(1000.0, 1000.0, 0.0, 0.0, cet.with_ymd_and_hms(2024, 6, 15, 23, 59, 0).single().expect("time is valid")).expect("time is valid");
+    let data = energy
+        .add_at(1000.0, 1000.0, 0.0, 0.0, cet.with_ymd_and_hms(2024, 6, 15, 23, 59, 24).single().expect("time is valid"))
+        .expect("time is valid");
+    assert_close(derived_energy(&data, "pv_energy"), 400.0 / 60.0);
+    // midnight in the time zone of the site, the 36 s before it count for the new day
+    let data = energy
+        .add_at(1000.0, 1000.0, 0.0, 0.0, cet.with_ymd_and_hms(2024, 6, 16, 0, 0, 0).single().expect("time is valid"))
+        .expect("time is valid");
+    assert_close(derived_energy(&data, "pv_energy"), 10.0);
+    assert_close(derived_energy(&data, "load_energy"), 10.0);
+}
+
+#[test]
+fn pipeline_rules_are_parsed() {
+    use pipeline::{Derive, Filter, PipelineError, Rename};
+    assert!(Derive::parse("").is_ok());
+    assert!(Derive::parse("power_flow.pv_kw=photovoltaik/1000, power_flow.consumption=load*-1,").is_ok());
+    assert_eq!(
+        Derive::parse("power_flow.pv_kw=photovoltaik/kilo").err(),
+        Some(PipelineError::InvalidRule("photovoltaik/kilo".to_owned(), "DERIVE_FIELDS"))
+    );
+    assert_eq!(Derive::parse("pv_kw=photovoltaik").err(), Some(PipelineError::InvalidRule("pv_kw=photovoltaik".to_owned(), "DERIVE_FIELDS")));
+    assert!(Rename::parse("power_flow.photovoltaik=pv").is_ok());
+    assert_eq!(Rename::parse("power_flow.photovoltaik").err(), Some(PipelineError::InvalidRule("power_flow.photovoltaik".to_owned(), "RENAME_FIELDS")));
+    assert!(Filter::parse("inverter_info,meter.l1_power_factor", "meter.power=10", "inverter_info").is_ok());
+    assert_eq!(Filter::parse("", "meter.power=ten", "").err(), Some(PipelineError::InvalidRule("ten".to_owned(), "DEADBAND")));
+}
+
+/// The points of `lines` after the stages.
+fn run_pipeline(stages: Vec<Box<dyn pipeline::Stage>>, lines: &[&str]) -> Vec<point::Point> {
+    let mut batch = Batch::default();
+    batch.points_mut().extend(lines.iter().map(|line| point::Point::from_line(line).expect("line is valid")));
+    pipeline::Pipeline::new(stages).process(&mut batch);
+    batch.points().to_vec()
+}
+
+#[test]
+fn pipeline_stages_run_in_order() {
+    const LINES: &[&str] = &[
+        "power_flow,device=Site photovoltaik=2500,load=-800 1700000000000000000",
+        "meter,device_id=1234 power=100,l1_power_factor=0.9 1700000000000000000",
+        "inverter_info,device_id=1 state=\"Running\" 1700000000000000000",
+    ];
+    let derive = || Box::new(pipeline::Derive::parse("power_flow.pv_kw=photovoltaik/1000").expect("rules are valid"));
+    let rename = || Box::new(pipeline::Rename::parse("power_flow.photovoltaik=pv").expect("rules are valid"));
+    let points = run_pipeline(vec![derive(), rename()], LINES);
+    assert_eq!(points[0].field("pv"), Some(&point::FieldValue::Float(2500.0)));
+    assert_eq!(points[0].field("pv_kw"), Some(&point::FieldValue::Float(2.5)));
+    assert_eq!(points[0].field("photovoltaik"), None);
+    // renamed before the derive stage, the source field is gone
+    let points = run_pipeline(vec![rename(), derive()], LINES);
+    assert_eq!(points[0].field("pv_kw"), None);
+
+    let mut filter = pipeline::Filter::parse("inverter_info,meter.l1_power_factor", "meter.power=10", "").expect("rules are valid");
+    let mut points: Vec<_> = LINES.iter().map(|line| point::Point::from_line(line).expect("line is valid")).collect();
+    pipeline::Stage::process(&mut filter, &mut points);
+    let measurements: Vec<&str> = points.iter().map(|point| point.measurement.as_str()).collect();
+    assert_eq!(measurements, ["power_flow", "meter"]);
+    assert_eq!(points[1].fields, [("power".to_owned(), point::FieldValue::Float(100.0))]);
+    // within the deadband, the point has no fields left
+    let mut points = vec![point::Point::from_line("meter,device_id=1234 power=105 1700000015000000000").expect("line is valid")];
+    pipeline::Stage::process(&mut filter, &mut points);
+    assert!(points.is_empty());
+}
+
+/// The `post-vanilla` and `post-x-www-form-urlencoded` cases of the AWS
+/// Signature Version 4 test suite.
+#[cfg(feature = "timestream")]
+#[test]
+fn timestream_requests_are_signed_like_the_aws_test_suite() {
+    let credentials = timestream::Credentials {
+        access_key_id: "AKIDEXAMPLE".to_owned(),
+        secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned(),
+        session_token: None,
+    };
+    let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).single().expect("time is valid");
+    let mut headers = vec![("x-amz-date", "20150830T123600Z".to_owned()), ("host", "example.amazonaws.com".to_owned())];
+    assert_eq!(
+        timestream::authorization(&credentials, "us-east-1", "service", now, &mut headers, ""),
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, \
+         Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"
+    );
+    headers.push(("content-type", "application/x-www-form-urlencoded".to_owned()));
+    assert_eq!(
+        timestream::authorization(&credentials, "us-east-1", "service", now, &mut headers, "Param1=value1"),
+        "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=content-type;host;x-amz-date, \
+         Signature=ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a"
+    );
+}
@@ -0,0 +1,47 @@
+//! Allocation benchmark of the polling: the allocations of a cycle on the
+//! polling thread, once the caches are warm. The allocations of the fake
+//! Datamanager and of the HTTP client's runtime are not counted, they run on
+//! other threads. Compare the printed number before and after a change of
+//! the hot loop:
+//!
+//! ```text
+//! cargo test --release allocations -- --nocapture
+//! ```
+//!
+//! The test fails if a cycle makes more than `MAX_ALLOCATIONS`, so a change
+//! that allocates more on every cycle is noticed. A cycle of the default
+//! build made 1020 allocations in a debug build (1053 with all features, 894
+//! in a release build) when the limit was set.
+
+use super::*;
+
+const WARMUP_CYCLES: usize = 10;
+const CYCLES: usize = 100;
+const MAX_ALLOCATIONS: usize = 1_150;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+#[test]
+fn allocations_per_cycle() {
+    let datamanager = FakeDatamanager::start(1);
+    let mut site = Site::connect(None, &datamanager.address, true).expect("fake Datamanager can be connected");
+    let snapshot = Snapshot::default();
+    let mut pipeline = pipeline::Pipeline::from_env().expect("default pipeline is valid");
+    let mut cycle = || {
+        let (mut batch, _) = fetch_data(&mut site, &snapshot, Instant::now() + Duration::from_secs(60)).expect("cycle can be polled");
+        pipeline.process(&mut batch);
+        datamanager.take_log();
+    };
+    for _ in 0..WARMUP_CYCLES {
+        cycle();
+    }
+    let before = allocations();
+    for _ in 0..CYCLES {
+        cycle();
+    }
+    let per_cycle = (allocations() - before) / CYCLES;
+    println!("{per_cycle} allocations per cycle");
+    assert!(per_cycle <= MAX_ALLOCATIONS, "{per_cycle} allocations per cycle, at most {MAX_ALLOCATIONS} expected");
+}
@@ -0,0 +1,411 @@
+//! Failure injection: a proxy in front of the fake Datamanager or a fake
+//! InfluxDB answers the requests with the faults of a schedule (timeouts,
+//! server errors, truncated and slow responses), so the retries, the spool
+//! and the reconnect can be tested without a flaky network.
+
+use super::*;
+
+/// Request timeout of the Fronius client, shorter than `HOLD`.
+const CLIENT_TIMEOUT: Duration = Duration::from_millis(300);
+/// How long a request is held before the connection is closed without an
+/// answer.
+const HOLD: Duration = Duration::from_millis(600);
+/// Delay of a slow answer, shorter than `CLIENT_TIMEOUT`.
+const SLOW: Duration = Duration::from_millis(100);
+const CHAOS_CYCLES: usize = 5;
+
+/// What the proxy does instead of passing a request on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fault {
+    /// The connection is closed after `HOLD` without an answer.
+    Timeout,
+    /// `500 Internal Server Error`, the request isn't passed on.
+    ServerError,
+    /// The answer with the body cut in half.
+    Truncated,
+    /// The answer after `SLOW`.
+    Slow,
+}
+
+/// The faults of the requests in turn, `None` passes a request on
+/// unchanged. An empty schedule injects no faults.
+#[derive(Default)]
+struct Schedule {
+    faults: Vec<Option<Fault>>,
+    requests: usize,
+}
+
+impl Schedule {
+    fn next(&mut self) -> Option<Fault> {
+        let fault = self.faults.get(self.requests % self.faults.len().max(1)).copied().flatten();
+        self.requests += 1;
+        fault
+    }
+}
+
+/// A request or response: the start line and headers, and the body.
+struct Message {
+    head: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Message {
+    /// Reads the next message, `None` once the connection is closed. The body
+    /// is read by its `Content-Length` or in chunks.
+    fn read(reader: &mut impl BufRead) -> std::io::Result<Option<Self>> {
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end().to_owned();
+            if line.is_empty() {
+                break;
+            }
+            head.push(line);
+        }
+        let header = |name: &str| {
+            head.iter().skip(1).find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_owned())
+            })
+        };
+        let mut body = Vec::new();
+        if header("Transfer-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size)?;
+                let size = usize::from_str_radix(size.trim(), 16).map_err(|_| std::io::ErrorKind::InvalidData)?;
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk)?;
+                if size == 0 {
+                    break;
+                }
+                body.extend(&chunk[..size]);
+            }
+        } else if let Some(length) = header("Content-Length") {
+            body.resize(length.parse().map_err(|_| std::io::ErrorKind::InvalidData)?, 0);
+            reader.read_exact(&mut body)?;
+        }
+        Ok(Some(Message { head, body }))
+    }
+
+    /// Writes the message with the length of its body, which may have changed.
+    fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut message = Vec::new();
+        for line in &self.head {
+            let key = line.split(':').next().unwrap_or_default().trim();
+            if !key.eq_ignore_ascii_case("Content-Length") && !key.eq_ignore_ascii_case("Transfer-Encoding") {
+                message.extend(line.as_bytes());
+                message.extend(b"\r\n");
+            }
+        }
+        message.extend(format!("Content-Length: {}\r\n\r\n", self.body.len()).as_bytes());
+        message.extend(&self.body);
+        writer.write_all(&message)
+    }
+
+    fn response(status: &str, body: &[u8]) -> Self {
+        Message {
+            head: vec![format!("HTTP/1.1 {status}")],
+            body: body.to_vec(),
+        }
+    }
+}
+
+/// Passes the requests on to `upstream`, unless the schedule has a fault for
+/// them.
+struct ChaosProxy {
+    address: String,
+    schedule: Arc<Mutex<Schedule>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ChaosProxy {
+    fn start(upstream: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("proxy can listen");
+        let address = listener.local_addr().expect("listener has an address").to_string();
+        let schedule = Arc::new(Mutex::new(Schedule::default()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let (upstream, schedule, stopped) = (upstream.to_owned(), schedule.clone(), stopped.clone());
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let (upstream, schedule) = (upstream.clone(), schedule.clone());
+                    if let Ok(stream) = stream {
+                        std::thread::spawn(move || proxy(stream, &upstream, &schedule));
+                    }
+                }
+            }
+        });
+        ChaosProxy {
+            address,
+            schedule,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    fn set_schedule(&self, faults: &[Option<Fault>]) {
+        *self.schedule.lock().expect("schedule is not poisoned") = Schedule {
+            faults: faults.to_vec(),
+            requests: 0,
+        };
+    }
+
+    /// The requests since the schedule was set.
+    fn requests(&self) -> usize {
+        self.schedule.lock().expect("schedule is not poisoned").requests
+    }
+}
+
+impl Drop for ChaosProxy {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // wakes up the listener
+        let _ = TcpStream::connect(&self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Handles the requests of a connection until the client closes it or a
+/// timeout is injected.
+fn proxy(stream: TcpStream, upstream: &str, schedule: &Mutex<Schedule>) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let upstream = TcpStream::connect(upstream)?;
+    let mut upstream_reader = BufReader::new(upstream.try_clone()?);
+    let mut upstream_writer = upstream;
+    while let Some(request) = Message::read(&mut reader)? {
+        let fault = schedule.lock().expect("schedule is not poisoned").next();
+        let response = match fault {
+            Some(Fault::Timeout) => {
+                std::thread::sleep(HOLD);
+                return Ok(());
+            }
+            Some(Fault::ServerError) => Message::response("500 Internal Server Error", b"Internal Server Error"),
+            _ => {
+                request.write(&mut upstream_writer)?;
+                let Some(mut response) = Message::read(&mut upstream_reader)? else {
+                    return Ok(());
+                };
+                match fault {
+                    Some(Fault::Truncated) => response.body.truncate(response.body.len() / 2),
+                    Some(Fault::Slow) => std::thread::sleep(SLOW),
+                    _ => {}
+                }
+                response
+            }
+        };
+        response.write(&mut writer)?;
+    }
+    Ok(())
+}
+
+/// An InfluxDB that accepts all writes and counts them.
+#[cfg(feature = "influxdb2")]
+struct FakeInflux {
+    address: String,
+    writes: Arc<Mutex<usize>>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(feature = "influxdb2")]
+impl FakeInflux {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("fake InfluxDB can listen");
+        let address = listener.local_addr().expect("listener has an address").to_string();
+        let writes = Arc::new(Mutex::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::spawn({
+            let (writes, stopped) = (writes.clone(), stopped.clone());
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let writes = writes.clone();
+                    if let Ok(stream) = stream {
+                        std::thread::spawn(move || -> std::io::Result<()> {
+                            let mut reader = BufReader::new(stream.try_clone()?);
+                            let mut writer = stream;
+                            while let Some(request) = Message::read(&mut reader)? {
+                                if request.head[0].contains("/api/v2/write") {
+                                    *writes.lock().expect("writes are not poisoned") += 1;
+                                }
+                                Message::response("204 No Content", b"").write(&mut writer)?;
+                            }
+                            Ok(())
+                        });
+                    }
+                }
+            }
+        });
+        FakeInflux {
+            address,
+            writes,
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    fn writes(&self) -> usize {
+        *self.writes.lock().expect("writes are not poisoned")
+    }
+}
+
+#[cfg(feature = "influxdb2")]
+impl Drop for FakeInflux {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // wakes up the listener
+        let _ = TcpStream::connect(&self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A site polled through the proxy, with a request timeout below `HOLD`.
+fn chaos_site(proxy: &ChaosProxy) -> Site {
+    let mut site = Site::connect(None, &proxy.address, true).expect("fake Datamanager can be connected");
+    let fronius = fronius_builder(None, &proxy.address)
+        .expect("default builder is valid")
+        .timeout(CLIENT_TIMEOUT)
+        .build()
+        .expect("fake Datamanager can be connected");
+    site.collector.as_mut().expect("site is connected").set_fronius(fronius);
+    site
+}
+
+#[test]
+fn faulty_responses_end_cycles_with_errors_until_they_stop() {
+    let datamanager = FakeDatamanager::start(1);
+    let proxy = ChaosProxy::start(&datamanager.address);
+    let mut site = chaos_site(&proxy);
+    let snapshot = Snapshot::default();
+    proxy.set_schedule(&[Some(Fault::Timeout), Some(Fault::ServerError), None, Some(Fault::Truncated), Some(Fault::Slow)]);
+    for cycle in 0..CHAOS_CYCLES {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| fetch_data(&mut site, &snapshot, Instant::now() + Duration::from_secs(60))));
+        let log = datamanager.take_log();
+        assert!(result.is_ok(), "cycle {cycle} panicked, the responses were:\n{}", log.join("\n"));
+    }
+    assert!(proxy.requests() > 0, "no request went through the proxy");
+
+    proxy.set_schedule(&[]);
+    let (batch, power_flow) = fetch_data(&mut site, &snapshot, Instant::now() + Duration::from_secs(60)).expect("cycle can be polled");
+    assert!(!batch.is_empty(), "no points once the faults stopped");
+    assert!(power_flow.is_some(), "no power flow once the faults stopped");
+    assert_eq!(site.unreachable_cycles, 0);
+}
+
+#[test]
+fn unreachable_datamanager_is_reconnected() {
+    let datamanager = FakeDatamanager::start(1);
+    let proxy = ChaosProxy::start(&datamanager.address);
+    let mut site = chaos_site(&proxy);
+    let snapshot = Snapshot::default();
+    proxy.set_schedule(&[Some(Fault::ServerError)]);
+    for _ in 0..RECONNECT_AFTER {
+        let _ = fetch_data(&mut site, &snapshot, Instant::now() + Duration::from_secs(60));
+    }
+    assert_eq!(site.unreachable_cycles, RECONNECT_AFTER);
+
+    proxy.set_schedule(&[]);
+    site.reconnected = Instant::now().checked_sub(RECONNECT_INTERVAL).expect("the clock runs longer than the reconnect interval");
+    site.reconnect_if_unreachable(false);
+    assert_eq!(site.unreachable_cycles, 0, "site wasn't reconnected");
+}
+
+#[cfg(feature = "influxdb2")]
+#[test]
+fn failed_influx_writes_are_retried_and_spooled() {
+    let influx = FakeInflux::start();
+    let proxy = ChaosProxy::start(&influx.address);
+    let spool = std::env::temp_dir().join(format!("fronius-chaos-{}.lp", std::process::id()));
+    let _ = std::fs::remove_file(&spool);
+    let sink = influx::InfluxSink::new(&format!("http://{}", proxy.address), "fronius", 1, Some(spool.clone()));
+    let mut batch = Batch::default();
+    batch.points_mut().push(point::Point::from_line("power_flow pv=1 1700000000000000000").expect("line is valid"));
+
+    // the retry succeeds
+    proxy.set_schedule(&[Some(Fault::ServerError), Some(Fault::Slow)]);
+    sink.write(&batch).expect("the retry succeeds");
+    assert_eq!(proxy.requests(), 2);
+    assert_eq!(influx.writes(), 1);
+    assert!(!spool.exists(), "written points were spooled");
+
+    // the retry fails as well, the point is spooled
+    proxy.set_schedule(&[Some(Fault::Timeout), Some(Fault::ServerError)]);
+    assert!(sink.write(&batch).is_err(), "spooled points count as not written");
+    assert_eq!(proxy.requests(), 2);
+    assert_eq!(influx.writes(), 1);
+    let spooled = std::fs::read_to_string(&spool).expect("failed points are spooled");
+    assert_eq!(spooled.lines().count(), 1);
+
+    // the spooled point is sent with the next batch
+    proxy.set_schedule(&[Some(Fault::Truncated)]);
+    sink.write(&Batch::default()).expect("empty batch is written");
+    assert_eq!(influx.writes(), 2);
+    assert!(!spool.exists(), "sent points stayed in the spool");
+}
+
+#[cfg(feature = "influxdb2")]
+#[test]
+fn spooled_points_are_kept_until_they_are_written() {
+    let influx = FakeInflux::start();
+    let proxy = ChaosProxy::start(&influx.address);
+    let spool = std::env::temp_dir().join(format!("fronius-chaos-inflight-{}.lp", std::process::id()));
+    let inflight = spool.with_extension("lp.inflight");
+    let _ = std::fs::remove_file(&spool);
+    // a spool that was being sent when the collector crashed
+    std::fs::write(&inflight, "power_flow pv=1 1700000000000000000\n").expect("spool can be written");
+    let sink = influx::InfluxSink::new(&format!("http://{}", proxy.address), "fronius", 0, Some(spool.clone()));
+
+    // the spool fails again and is kept as it is
+    proxy.set_schedule(&[Some(Fault::ServerError)]);
+    sink.write(&Batch::default()).expect("the spool isn't part of the batch");
+    assert_eq!(proxy.requests(), 1);
+    assert_eq!(std::fs::read_to_string(&inflight).expect("spool is kept").lines().count(), 1);
+
+    // while the points of a cycle fail, the spool isn't sent
+    let mut batch = Batch::default();
+    batch.points_mut().push(point::Point::from_line("power_flow pv=2 1700000015000000000").expect("line is valid"));
+    proxy.set_schedule(&[Some(Fault::ServerError)]);
+    assert!(sink.write(&batch).is_err(), "failed points count as not written");
+    assert_eq!(proxy.requests(), 1);
+    assert!(spool.exists(), "failed points weren't spooled");
+
+    // once a write succeeds, both spools are sent
+    proxy.set_schedule(&[]);
+    sink.write(&batch).expect("batch is written");
+    assert_eq!(influx.writes(), 3);
+    assert!(!inflight.exists(), "sent points stayed in the spool");
+    assert!(!spool.exists(), "sent points stayed in the spool");
+}
+
+#[test]
+fn hanging_request_is_cut_off_at_the_deadline() {
+    let datamanager = FakeDatamanager::start(1);
+    let proxy = ChaosProxy::start(&datamanager.address);
+    // the default request timeout is longer than `HOLD`
+    let mut site = Site::connect(None, &proxy.address, true).expect("fake Datamanager can be connected");
+    let snapshot = Snapshot::default();
+    proxy.set_schedule(&[Some(Fault::Timeout)]);
+    let start = Instant::now();
+    let _ = fetch_data(&mut site, &snapshot, start + HOLD / 4);
+    assert!(start.elapsed() < HOLD, "cycle took {:?}, longer than the hanging request", start.elapsed());
+
+    proxy.set_schedule(&[]);
+    let fronius = site.fronius().expect("site is connected");
+    fronius.get_power_flow_realtime_data().expect("requests after the cycle have no deadline");
+}
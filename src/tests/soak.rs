@@ -0,0 +1,123 @@
+//! Soak test: the collector polls the fake Datamanager for `SOAK_DAYS` days
+//! (default 3) at accelerated time, a 15 second cycle takes as long as the
+//! requests do. After the first day the allocated memory and the threads
+//! must not grow anymore, and the daily values have to start again at every
+//! midnight.
+//!
+//! It is ignored by `cargo test`, as it runs for minutes. Run it in release
+//! mode and on its own, the allocations of other tests would be counted:
+//!
+//! ```text
+//! cargo test --release soak -- --ignored
+//! ```
+
+use chrono::prelude::*;
+
+use super::*;
+use crate::{line_file::LineFileSink, point::Point, sink::Sink};
+
+const DEFAULT_DAYS: u64 = 3;
+/// Growth of the allocated memory that is tolerated after the first day,
+/// e.g. for a longer string in a snapshot.
+const TOLERATED_GROWTH: usize = 64 * 1024;
+
+/// Threads of the process, `None` where `/proc` isn't available.
+fn threads() -> Option<usize> {
+    Some(std::fs::read_dir("/proc/self/task").ok()?.count())
+}
+
+/// Checks the written `derived_energy` points: within a day the energies
+/// only grow, on the next day they start again.
+#[derive(Default)]
+struct Rollover {
+    /// Day and PV energy of the last point.
+    last: Option<(NaiveDate, f64)>,
+    days: u32,
+    errors: Vec<String>,
+}
+
+impl Rollover {
+    fn check(&mut self, point: &Point) {
+        let Some(pv_energy) = point.field("pv_energy").and_then(|value| value.as_f64()) else {
+            return;
+        };
+        let day = timezone::local(DateTime::from_timestamp_nanos(point.time)).date_naive();
+        match self.last {
+            Some((last_day, last_energy)) if last_day == day && pv_energy < last_energy => {
+                self.errors.push(format!("PV energy of {day} went back from {last_energy} to {pv_energy}"));
+            }
+            Some((last_day, last_energy)) if last_day != day && (pv_energy >= last_energy || last_energy == 0.0) => {
+                self.errors.push(format!("PV energy didn't start again on {day}: {last_energy} on {last_day}, then {pv_energy}"));
+            }
+            Some((last_day, _)) if last_day == day => {}
+            _ => self.days += 1,
+        }
+        self.last = Some((day, pv_energy));
+    }
+}
+
+struct RolloverSink(Arc<Mutex<Rollover>>);
+
+impl Sink for RolloverSink {
+    fn name(&self) -> &'static str {
+        "rollover check"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let mut rollover = self.0.lock().expect("rollover is not poisoned");
+        for point in batch.points().iter().filter(|point| point.measurement == "derived_energy") {
+            rollover.check(point);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+#[ignore = "runs for minutes, see the module documentation"]
+fn soak() {
+    let days = std::env::var("SOAK_DAYS").map_or(DEFAULT_DAYS, |days| days.parse().expect("SOAK_DAYS is a number"));
+    let cycles_per_day = 24 * 3600 / POLL_INTERVAL.as_secs();
+    let dir = std::env::temp_dir().join(format!("fronius-soak-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("directory for the line files can be created");
+    let line_file = dir.join("points.lp");
+
+    let datamanager = FakeDatamanager::start(1);
+    let mut sites = vec![Site::connect(None, &datamanager.address, true).expect("fake Datamanager can be connected")];
+    let rollover = Arc::new(Mutex::new(Rollover::default()));
+    let sinks = sink::Sinks::new(vec![
+        Box::new(RolloverSink(rollover.clone())),
+        Box::new(LineFileSink::from_env(&line_file.to_string_lossy()).expect("line file sink can be created")),
+    ]);
+    let mut pipeline = pipeline::Pipeline::from_env().expect("default pipeline is valid");
+    let snapshot = Snapshot::default();
+
+    let mut baseline = None;
+    for cycle in 1..=days * cycles_per_day {
+        clock::advance(POLL_INTERVAL);
+        assert_eq!(timestamp::start_cycle(), None, "the clock went back in cycle {cycle}");
+        poll_sites(&mut sites, &snapshot, &mut pipeline, &sinks, Instant::now() + Duration::from_secs(60), 4).expect("cycle can be polled");
+        error_summary::log_summaries();
+        // hourly, which replaces the clients and their connections
+        if cycle % (cycles_per_day / 24) == 0 {
+            for site in &mut sites {
+                site.check_firmware(Duration::ZERO);
+            }
+        }
+        datamanager.take_log();
+        if cycle == cycles_per_day {
+            baseline = Some((ALLOCATED.load(Ordering::Relaxed), threads()));
+        }
+    }
+
+    let rollover = rollover.lock().expect("rollover is not poisoned");
+    assert!(rollover.errors.is_empty(), "wrong daily energies:\n{}", rollover.errors.join("\n"));
+    assert!(rollover.days as u64 > days, "{} days seen in {days} days", rollover.days);
+    let rotated = std::fs::read_dir(&dir).expect("line files can be listed").count() - 1;
+    assert_eq!(rotated, rollover.days as usize - 1, "line file wasn't rotated once a day");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let (allocated, threads_after_first_day) = baseline.expect("collector ran for a day");
+    let growth = ALLOCATED.load(Ordering::Relaxed).saturating_sub(allocated);
+    assert!(growth <= TOLERATED_GROWTH, "allocated memory grew by {growth} bytes after the first day");
+    assert_eq!(threads(), threads_after_first_day, "threads were leaked");
+}
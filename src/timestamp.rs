@@ -0,0 +1,122 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        OnceLock,
+    },
+};
+
+use chrono::prelude::*;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("time can not be represented as nanosecond timestamp")]
+pub struct TimestampError;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid timestamp precision {0:?}, expected one of: s, ms, us, ns")]
+pub struct InvalidPrecision(String);
+
+/// Precision the timestamps of the written points are truncated to.
+/// The timestamps are always given in nanoseconds, only the lower digits
+/// are set to zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    #[default]
+    Nanoseconds,
+}
+
+impl Precision {
+    fn nanos_per_unit(self) -> i64 {
+        match self {
+            Precision::Seconds => 1_000_000_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Microseconds => 1_000,
+            Precision::Nanoseconds => 1,
+        }
+    }
+
+    /// Truncates a nanosecond timestamp to this precision.
+    pub fn truncate(self, nanos: i64) -> i64 {
+        nanos - nanos.rem_euclid(self.nanos_per_unit())
+    }
+
+    /// Unit of the timestamps as used by InfluxDB.
+    #[cfg(feature = "influxdb2")]
+    pub fn unit(self) -> &'static str {
+        match self {
+            Precision::Seconds => "s",
+            Precision::Milliseconds => "ms",
+            Precision::Microseconds => "us",
+            Precision::Nanoseconds => "ns",
+        }
+    }
+
+    /// Converts a nanosecond timestamp to this unit.
+    #[cfg(feature = "influxdb2")]
+    pub fn in_unit(self, nanos: i64) -> i64 {
+        nanos.div_euclid(self.nanos_per_unit())
+    }
+}
+
+impl FromStr for Precision {
+    type Err = InvalidPrecision;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "s" => Ok(Precision::Seconds),
+            "ms" => Ok(Precision::Milliseconds),
+            "us" => Ok(Precision::Microseconds),
+            "ns" => Ok(Precision::Nanoseconds),
+            _ => Err(InvalidPrecision(s.to_string())),
+        }
+    }
+}
+
+static PRECISION: OnceLock<Precision> = OnceLock::new();
+
+/// Reads the precision from `TIMESTAMP_PRECISION` (default: `ns`).
+/// Has to be called before the first point is created.
+pub fn init_from_env() -> Result<(), InvalidPrecision> {
+    let precision = match std::env::var("TIMESTAMP_PRECISION") {
+        Ok(val) => val.parse()?,
+        Err(_) => Precision::default(),
+    };
+    PRECISION.get_or_init(|| precision);
+    Ok(())
+}
+
+/// The configured precision.
+pub fn precision() -> Precision {
+    PRECISION.get().copied().unwrap_or_default()
+}
+
+/// Latest timestamp returned by [`now`] in the current cycle.
+static LATEST: AtomicI64 = AtomicI64::new(i64::MIN);
+
+/// Starts a new cycle. If the clock was set back since the timestamps of
+/// the last cycle (e.g. by an NTP step), returns by how much.
+pub fn start_cycle() -> Option<chrono::Duration> {
+    let latest = LATEST.swap(i64::MIN, Ordering::Relaxed);
+    let now = crate::clock::now().timestamp_nanos_opt()?;
+    (latest > now).then(|| chrono::Duration::nanoseconds(latest - now))
+}
+
+/// Returns the current time as nanosecond timestamp, truncated to the
+/// configured precision. Within a cycle the timestamps never go backwards,
+/// if the clock is set back they stay at the latest one until the next
+/// cycle.
+pub fn now() -> Result<i64, TimestampError> {
+    let nanos = from_datetime(crate::clock::now())?;
+    Ok(LATEST.fetch_max(nanos, Ordering::Relaxed).max(nanos))
+}
+
+/// Converts a point in time to a nanosecond timestamp, truncated to the
+/// configured precision.
+pub fn from_datetime(time: DateTime<Utc>) -> Result<i64, TimestampError> {
+    let nanos = time.timestamp_nanos_opt().ok_or(TimestampError)?;
+    Ok(precision().truncate(nanos))
+}
@@ -0,0 +1,253 @@
+//! Writing of the points to Amazon Timestream.
+//!
+//! Only available with the `timestream` cargo feature and enabled by
+//! `TIMESTREAM_DATABASE`. Every point becomes a multi-measure record of the
+//! table `TIMESTREAM_TABLE` (default `fronius`): the measurement is the
+//! measure name, the tags are the dimensions and the fields the measure
+//! values. Database and table have to exist.
+//!
+//! The requests are signed with the IAM credentials from `AWS_ACCESS_KEY_ID`,
+//! `AWS_SECRET_ACCESS_KEY` and, for temporary credentials,
+//! `AWS_SESSION_TOKEN`. The ingest endpoint of the account is discovered via
+//! `DescribeEndpoints`, like the AWS SDKs do.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use log::info;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    point::{FieldValue, Point},
+    routing::{Routes, Target},
+    sink::{Batch, Sink},
+};
+
+const DEFAULT_TABLE: &str = "fronius";
+const SERVICE: &str = "timestream";
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Records per `WriteRecords` request at most.
+const MAX_RECORDS: usize = 100;
+
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Endpoints {
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Endpoint {
+    address: String,
+    cache_period_in_minutes: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Record {
+    dimensions: Vec<serde_json::Value>,
+    measure_name: String,
+    measure_value_type: &'static str,
+    measure_values: Vec<serde_json::Value>,
+    time: String,
+    time_unit: &'static str,
+}
+
+pub struct TimestreamSink {
+    client: Client,
+    region: String,
+    credentials: Credentials,
+    database: String,
+    table: String,
+    /// Discovered ingest endpoint and until when it may be used.
+    endpoint: Mutex<Option<(String, Instant)>>,
+    routes: Routes,
+}
+
+impl TimestreamSink {
+    /// Creates the sink for `database`. Required are `AWS_REGION`,
+    /// `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, optional are
+    /// `AWS_SESSION_TOKEN` and `TIMESTREAM_TABLE`.
+    pub fn from_env(database: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let sink = TimestreamSink {
+            client: Client::builder().timeout(TIMEOUT).build()?,
+            region: std::env::var("AWS_REGION")?,
+            credentials: Credentials {
+                access_key_id: std::env::var("AWS_ACCESS_KEY_ID")?,
+                secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY")?,
+                session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+            },
+            database: database.to_owned(),
+            table: std::env::var("TIMESTREAM_TABLE").unwrap_or_else(|_| DEFAULT_TABLE.to_owned()),
+            endpoint: Mutex::new(None),
+            routes: Routes::from_env()?,
+        };
+        info!("Writing points to Timestream table {}.{} in {}", sink.database, sink.table, sink.region);
+        Ok(sink)
+    }
+
+    /// Sends a signed request of the Timestream JSON protocol to `host`.
+    fn call(&self, host: &str, action: &str, body: String) -> Result<String, Box<dyn std::error::Error>> {
+        let target = format!("Timestream_20181101.{action}");
+        let now = Utc::now();
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.0".to_owned()),
+            ("host", host.to_owned()),
+            ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+            ("x-amz-target", target),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = authorization(&self.credentials, &self.region, SERVICE, now, &mut headers, &body);
+
+        let mut request = self.client.post(format!("https://{host}/")).header("Authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send()?;
+        let status = response.status();
+        let text = response.text()?;
+        if !status.is_success() {
+            return Err(format!("{action} failed with {status}: {text}").into());
+        }
+        Ok(text)
+    }
+
+    /// The ingest endpoint, discovered again once its cache period is over.
+    fn endpoint(&self) -> Result<String, Box<dyn std::error::Error>> {
+        let mut endpoint = self.endpoint.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((address, valid_until)) = endpoint.as_ref() {
+            if Instant::now() < *valid_until {
+                return Ok(address.clone());
+            }
+        }
+        let host = format!("ingest.timestream.{}.amazonaws.com", self.region);
+        let response: Endpoints = serde_json::from_str(&self.call(&host, "DescribeEndpoints", "{}".to_owned())?)?;
+        let discovered = response.endpoints.into_iter().next().ok_or("no Timestream endpoint discovered")?;
+        let valid_until = Instant::now() + Duration::from_secs(discovered.cache_period_in_minutes * 60);
+        *endpoint = Some((discovered.address.clone(), valid_until));
+        Ok(discovered.address)
+    }
+
+    fn write_records(&self, records: &[Record]) -> Result<(), Box<dyn std::error::Error>> {
+        let body = json!({
+            "DatabaseName": self.database,
+            "TableName": self.table,
+            "Records": records,
+        });
+        let endpoint = self.endpoint()?;
+        if let Err(error) = self.call(&endpoint, "WriteRecords", body.to_string()) {
+            // the endpoint may have moved
+            *self.endpoint.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+            return Err(error);
+        }
+        Ok(())
+    }
+}
+
+/// The `Authorization` header of a POST request to `/` signed with AWS
+/// Signature Version 4. The `headers` are sorted, all of them are signed.
+pub fn authorization(
+    credentials: &Credentials,
+    region: &str,
+    service: &str,
+    now: DateTime<Utc>,
+    headers: &mut [(&str, String)],
+    body: &str,
+) -> String {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    headers.sort();
+    let canonical_headers: String = headers.iter().map(|(name, value)| format!("{name}:{}\n", value.trim())).collect();
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_request = format!("POST\n/\n\n{canonical_headers}\n{signed_headers}\n{}", hex(&Sha256::digest(body)));
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!("AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}", hex(&Sha256::digest(&canonical_request)));
+    let mut key = hmac_sha256(format!("AWS4{}", credentials.secret_access_key).as_bytes(), date.as_bytes());
+    for part in [region, service, "aws4_request"] {
+        key = hmac_sha256(&key, part.as_bytes());
+    }
+    let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+    format!("AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}", credentials.access_key_id)
+}
+
+// HMAC accepts keys of any length, `new_from_slice` can't fail
+#[allow(clippy::expect_used)]
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC should accept keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn to_record(point: &Point) -> Record {
+    let dimensions = point
+        .tags
+        .iter()
+        // dimensions can't be empty
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| json!({ "Name": key, "Value": value }))
+        .collect();
+    let measure_values = point
+        .fields
+        .iter()
+        .map(|(key, value)| {
+            let (value, kind) = match value {
+                FieldValue::Float(value) => (value.to_string(), "DOUBLE"),
+                FieldValue::Integer(value) => (value.to_string(), "BIGINT"),
+                FieldValue::UInteger(value) => (value.to_string(), "BIGINT"),
+                FieldValue::Boolean(value) => (value.to_string(), "BOOLEAN"),
+                FieldValue::String(value) => (value.clone(), "VARCHAR"),
+            };
+            json!({ "Name": key, "Value": value, "Type": kind })
+        })
+        .collect();
+    Record {
+        dimensions,
+        measure_name: point.measurement.clone(),
+        measure_value_type: "MULTI",
+        measure_values,
+        time: point.time.to_string(),
+        time_unit: "NANOSECONDS",
+    }
+}
+
+impl Sink for TimestreamSink {
+    fn name(&self) -> &'static str {
+        "Timestream"
+    }
+
+    fn write(&self, batch: &Batch) -> Result<(), Box<dyn std::error::Error>> {
+        let records: Vec<Record> = batch
+            .points()
+            .iter()
+            .filter(|point| self.routes.targets(&point.measurement).contains(&Target::Timestream))
+            .map(to_record)
+            .collect();
+        // the other chunks are still written if one fails
+        let mut result = Ok(());
+        for chunk in records.chunks(MAX_RECORDS) {
+            if let Err(error) = self.write_records(chunk) {
+                result = Err(error);
+            }
+        }
+        result
+    }
+}
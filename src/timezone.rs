@@ -0,0 +1,115 @@
+//! Time zone of the site. Everything that starts over per day, hour or
+//! quarter-hour (the daily energies and efficiencies, the peak demand, the
+//! battery plan, the days of a backfill) is calculated in it, so e.g. the
+//! daily energy matches the display of the inverter even if the collector
+//! runs in UTC, like in most containers.
+//!
+//! By default the UTC offset configured on the Datamanager is used (from
+//! `GetLoggerInfo.cgi`). It is read again every hour to follow the changes
+//! of the daylight saving time.
+
+use std::{
+    sync::{OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use chrono::prelude::*;
+use fronius_api::fronius::blocking::Fronius;
+use log::{info, warn};
+use thiserror::Error;
+
+/// How often the offset of the Datamanager is read again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid time zone {0:?}, expected logger, host or an offset like +01:00")]
+pub struct InvalidTimezone(String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Source {
+    /// The offset of the Datamanager.
+    Logger,
+    /// The time zone of the host the collector runs on.
+    Host,
+    /// A fixed offset.
+    Fixed(FixedOffset),
+}
+
+static SOURCE: OnceLock<Source> = OnceLock::new();
+/// The current offset, `None` for the time zone of the host.
+static OFFSET: RwLock<Option<FixedOffset>> = RwLock::new(None);
+static REFRESHED: RwLock<Option<Instant>> = RwLock::new(None);
+
+/// Reads `TIMEZONE`: `logger` (default), `host` or a fixed offset like
+/// `+01:00`. Until the offset of the Datamanager is read by [`refresh`], the
+/// time zone of the host is used.
+pub fn init_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    let source = match std::env::var("TIMEZONE") {
+        Ok(val) => match val.trim() {
+            "logger" => Source::Logger,
+            "host" => Source::Host,
+            offset => Source::Fixed(offset.parse().map_err(|_| InvalidTimezone(val.clone()))?),
+        },
+        Err(_) => Source::Logger,
+    };
+    if let Source::Fixed(offset) = source {
+        *OFFSET.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(offset);
+    }
+    SOURCE.get_or_init(|| source);
+    Ok(())
+}
+
+/// Reads the offset of the Datamanager, if it is used and wasn't read within
+/// the last hour. If it can't be read, the last offset is kept.
+pub fn refresh(fronius: &Fronius) {
+    if SOURCE.get() != Some(&Source::Logger) {
+        return;
+    }
+    let mut refreshed = REFRESHED.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if refreshed.is_some_and(|refreshed| refreshed.elapsed() < REFRESH_INTERVAL) {
+        return;
+    }
+    *refreshed = Some(Instant::now());
+    let offset = fronius
+        .get_logger_info()
+        .map_err(Box::<dyn std::error::Error>::from)
+        .and_then(|info| FixedOffset::east_opt(info.utc_offset).map(|offset| (info, offset)).ok_or_else(|| "invalid UTC offset".into()));
+    match offset {
+        Ok((info, offset)) => {
+            let mut current = OFFSET.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if *current != Some(offset) {
+                info!("Using the time zone of the Datamanager: {} ({}, UTC{offset})", info.timezone_location, info.timezone_name);
+                *current = Some(offset);
+            }
+        }
+        Err(error) => warn!("Error during read of the time zone occured, keeping the current one: {:?}", error),
+    }
+}
+
+/// `time` in the time zone of the site.
+pub fn local(time: DateTime<Utc>) -> DateTime<FixedOffset> {
+    match *OFFSET.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+        Some(offset) => time.with_timezone(&offset),
+        None => time.with_timezone(&Local).fixed_offset(),
+    }
+}
+
+/// The current time in the time zone of the site.
+pub fn now() -> DateTime<FixedOffset> {
+    local(crate::clock::now())
+}
+
+/// A time given in the time zone of the site.
+#[cfg(feature = "influxdb2")]
+pub fn from_local(time: NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+    match *OFFSET.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+        Some(offset) => time.and_local_timezone(offset).single(),
+        None => time.and_local_timezone(Local).earliest().map(|time| time.fixed_offset()),
+    }
+}
+
+/// Start of `day` in the time zone of the site.
+#[cfg(feature = "influxdb2")]
+pub fn midnight(day: NaiveDate) -> Option<DateTime<FixedOffset>> {
+    from_local(day.and_time(NaiveTime::MIN))
+}
@@ -0,0 +1,261 @@
+//! The `tray` command: a lightweight mode for the desktop that shows the
+//! current PV power, grid and battery of every site in the system tray and
+//! notifies on alerts, i.e. state codes of the inverters and Datamanagers
+//! that aren't reachable. It polls the power flow and the inverters of
+//! `FRONIUS_INVERTERS` itself with the `Collector` of the library, nothing
+//! else of the collector runs.
+//!
+//! The tray icon uses the StatusNotifierItem protocol of KDE, which GNOME
+//! (with the AppIndicator extension), Xfce and most other panels on Linux
+//! support as well.
+
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use fronius_api::collector::Collector;
+use ksni::{
+    blocking::TrayMethods,
+    menu::{MenuItem, StandardItem},
+    Status, ToolTip, Tray,
+};
+use log::{info, warn};
+use notify_rust::{Notification, Urgency};
+
+use crate::{battery::BatterySign, events::Events, locale::Locale, status::Overview};
+
+const USAGE: &str = "usage: tray [--interval <seconds>]";
+const APP_NAME: &str = "Fronius";
+/// Icons of the freedesktop.org icon naming specification.
+const ICON: &str = "weather-clear";
+const ALERT_ICON: &str = "dialog-warning";
+
+enum Command {
+    Refresh,
+    Quit,
+}
+
+struct PlantTray {
+    title: String,
+    description: String,
+    alert: bool,
+    commands: Sender<Command>,
+}
+
+impl Tray for PlantTray {
+    fn id(&self) -> String {
+        "fronius-api".to_owned()
+    }
+
+    fn title(&self) -> String {
+        self.title.clone()
+    }
+
+    fn icon_name(&self) -> String {
+        if self.alert { ALERT_ICON } else { ICON }.to_owned()
+    }
+
+    fn status(&self) -> Status {
+        if self.alert {
+            Status::NeedsAttention
+        } else {
+            Status::Active
+        }
+    }
+
+    fn tool_tip(&self) -> ToolTip {
+        ToolTip {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn menu(&self) -> Vec<MenuItem<Self>> {
+        vec![
+            StandardItem {
+                label: "Refresh".to_owned(),
+                icon_name: "view-refresh".to_owned(),
+                activate: Box::new(|tray: &mut Self| {
+                    let _ = tray.commands.send(Command::Refresh);
+                }),
+                ..Default::default()
+            }
+            .into(),
+            MenuItem::Separator,
+            StandardItem {
+                label: "Quit".to_owned(),
+                icon_name: "application-exit".to_owned(),
+                activate: Box::new(|tray: &mut Self| {
+                    let _ = tray.commands.send(Command::Quit);
+                }),
+                ..Default::default()
+            }
+            .into(),
+        ]
+    }
+}
+
+/// A polled site and the state of its alerts.
+struct Site {
+    name: Option<String>,
+    ip: String,
+    /// `None` until the Datamanager could be connected.
+    collector: Option<Collector>,
+    /// Inverter state codes, without Grafana annotations.
+    events: Events,
+    unreachable: bool,
+    /// An inverter reports a state code.
+    inverter_code: bool,
+}
+
+impl Site {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{APP_NAME} {name}"),
+            None => APP_NAME.to_owned(),
+        }
+    }
+
+    /// Polls the power flow and the inverters, notifies on alerts. Returns
+    /// `None` if the Datamanager isn't reachable.
+    fn poll(&mut self, battery: BatterySign) -> Option<Overview> {
+        let overview = self.fetch(battery);
+        let unreachable = overview.is_err();
+        if unreachable != self.unreachable {
+            self.unreachable = unreachable;
+            match &overview {
+                Err(error) => notify(&self.label(), &format!("Datamanager unreachable: {error}"), Urgency::Critical),
+                Ok(_) => notify(&self.label(), "Datamanager reachable again", Urgency::Normal),
+            }
+        }
+        overview.ok()
+    }
+
+    fn fetch(&mut self, battery: BatterySign) -> Result<Overview, Box<dyn std::error::Error>> {
+        let collector = match &mut self.collector {
+            Some(collector) => collector,
+            None => {
+                let fronius = crate::fronius_builder(self.name.as_deref(), &self.ip)?.build()?;
+                self.collector.insert(crate::status::overview_collector(fronius)?)
+            }
+        };
+        let cycle = collector.poll_once();
+        let overview = match crate::status::overview_of(&cycle, battery) {
+            Ok(overview) => overview,
+            Err(error) => {
+                // a host name is resolved again with a new connection
+                self.collector = None;
+                return Err(error);
+            }
+        };
+        self.inverter_code = cycle.inverter_infos.values().any(|info| info.error_code != 0);
+        for (id, info) in &cycle.inverter_infos {
+            let Some(event) = self.events.observe("Inverter", &id.to_string(), info.error_code)? else {
+                continue;
+            };
+            let (body, urgency) = match (event.is_active(), event.severity()) {
+                (true, "error") => (format!("Inverter {id}: {} ({})", event.text(), event.code()), Urgency::Critical),
+                (true, _) => (format!("Inverter {id}: {} ({})", event.text(), event.code()), Urgency::Normal),
+                (false, _) => (format!("Inverter {id}: {} ({}) cleared", event.text(), event.code()), Urgency::Low),
+            };
+            notify(&self.label(), &body, urgency);
+        }
+        Ok(overview)
+    }
+}
+
+fn notify(summary: &str, body: &str, urgency: Urgency) {
+    info!("{summary}: {body}");
+    let icon = if urgency == Urgency::Critical { ALERT_ICON } else { ICON };
+    if let Err(error) = Notification::new().appname(APP_NAME).summary(summary).body(body).icon(icon).urgency(urgency).show() {
+        warn!("Error during notification occured: {:?}", error);
+    }
+}
+
+/// The title of the tray: PV power, grid and battery of a site.
+fn title(locale: &Locale, overview: &Overview) -> String {
+    let mut parts = Vec::new();
+    if let Some(pv) = overview.pv {
+        parts.push(format!("PV {}", locale.power(pv)));
+    }
+    if let Some(grid) = overview.grid {
+        parts.push(format!("Grid {}", locale.power(grid)));
+    }
+    match (overview.battery, overview.state_of_charge) {
+        (Some(battery), Some(percent)) => parts.push(format!("Battery {} ({})", locale.power(battery), locale.percent(percent))),
+        (Some(battery), None) => parts.push(format!("Battery {}", locale.power(battery))),
+        (None, Some(percent)) => parts.push(format!("Battery {}", locale.percent(percent))),
+        (None, None) => {}
+    }
+    parts.join(" · ")
+}
+
+/// Runs the `tray` command.
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut interval = crate::POLL_INTERVAL;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--interval" => interval = Duration::from_secs(args.next().ok_or(USAGE)?.parse::<u64>()?.max(1)),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    crate::locale::init_from_env()?;
+    let locale = crate::locale::get();
+    let battery = BatterySign::from_env()?;
+    let mut sites: Vec<Site> = crate::site_addresses()?
+        .into_iter()
+        .map(|(name, ip)| Site {
+            name,
+            ip,
+            collector: None,
+            events: Events::default(),
+            unreachable: false,
+            inverter_code: false,
+        })
+        .collect();
+
+    let (commands_tx, commands) = mpsc::channel();
+    let tray = PlantTray {
+        title: APP_NAME.to_owned(),
+        description: "Waiting for data".to_owned(),
+        alert: false,
+        commands: commands_tx,
+    };
+    let handle = tray.spawn()?;
+    loop {
+        let mut titles = Vec::new();
+        let mut description = Vec::new();
+        for site in &mut sites {
+            let line = match site.poll(battery) {
+                Some(overview) => {
+                    let title = title(&locale, &overview);
+                    let today = overview.energy_day.map(|energy| format!(", today {}", locale.energy(energy))).unwrap_or_default();
+                    let state = overview.inverter_state.map(|state| format!(", inverter {state}")).unwrap_or_default();
+                    titles.push(title.clone());
+                    format!("{title}{today}{state}")
+                }
+                None => "Datamanager unreachable".to_owned(),
+            };
+            description.push(match &site.name {
+                Some(name) => format!("{name}: {line}"),
+                None => line,
+            });
+        }
+        let alert = sites.iter().any(|site| site.unreachable || site.inverter_code);
+        handle.update(|tray| {
+            // the title shows the primary site, the tooltip all of them
+            tray.title = titles.first().cloned().unwrap_or_else(|| APP_NAME.to_owned());
+            tray.description = description.join("\n");
+            tray.alert = alert;
+        });
+        match commands.recv_timeout(interval) {
+            Ok(Command::Refresh) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Command::Quit) | Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    handle.shutdown().wait();
+    Ok(())
+}
@@ -0,0 +1,367 @@
+//! The `tui` command: a live dashboard in the terminal with the power flow,
+//! the phases of the meter and the battery of every site, with sparklines of
+//! the last values. Like the `status` command it reads the snapshot of the
+//! running collector through its REST API, or polls the Datamanagers itself
+//! if the collector isn't reachable or with `--poll`.
+//!
+//! Keys: `Tab` switches the site, `r` refreshes at once, `q` quits.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use chrono::prelude::*;
+use fronius_api::collector::Collector;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, Paragraph, Row, Sparkline, Table},
+    DefaultTerminal, Frame,
+};
+use serde_json::Value;
+
+use crate::{battery::BatterySign, locale::Locale, snapshot::Snapshot, status, SiteAddress};
+
+const USAGE: &str = "usage: tui [--poll] [--interval <seconds>]";
+/// Values kept for the sparklines, more than any terminal is wide.
+const HISTORY: usize = 300;
+/// How often the keys are checked between refreshes.
+const INPUT_TIMEOUT: Duration = Duration::from_millis(250);
+const PHASES: [&str; 3] = ["l1", "l2", "l3"];
+
+/// Where the snapshot comes from.
+enum Source {
+    Api(String),
+    Poll(Vec<SiteAddress>),
+}
+
+impl Source {
+    fn describe(&self) -> String {
+        match self {
+            Source::Api(url) => format!("from the collector at {url}"),
+            Source::Poll(_) => "polled directly".to_owned(),
+        }
+    }
+}
+
+/// The sites connected for polling, connected again after an error.
+#[derive(Default)]
+struct Connections(Option<Vec<(Option<String>, Collector)>>);
+
+impl Connections {
+    fn poll(&mut self, sites: &[SiteAddress], battery: BatterySign) -> Result<Value, Box<dyn std::error::Error>> {
+        let connections = match &mut self.0 {
+            Some(connections) => connections,
+            None => {
+                let mut connections = Vec::new();
+                for (name, ip) in sites {
+                    let fronius = crate::fronius_builder(name.as_deref(), ip)?.build()?;
+                    let devices = crate::devices_from_env(&fronius)?;
+                    let mut collector = Collector::new(fronius);
+                    collector.set_devices(devices);
+                    connections.push((name.clone(), collector));
+                }
+                self.0.insert(connections)
+            }
+        };
+        let result = poll_sites(connections, battery);
+        if result.is_err() {
+            self.0 = None;
+        }
+        result
+    }
+}
+
+/// Polls every site once into a snapshot of its own, so the values look like
+/// those of `/api/latest`.
+fn poll_sites(connections: &mut [(Option<String>, Collector)], battery: BatterySign) -> Result<Value, Box<dyn std::error::Error>> {
+    let snapshot = Snapshot::default();
+    for (name, collector) in connections {
+        let snapshot = snapshot.for_site(name.as_deref());
+        let cycle = collector.poll_once();
+        let Some(power_flow) = cycle.power_flow else {
+            let error: Box<dyn std::error::Error> = match cycle.errors.into_iter().find(|error| error.dataset == "power_flow_data") {
+                Some(error) => error.error,
+                None => "no power flow".into(),
+            };
+            return Err(error);
+        };
+        snapshot.update("power_flow", &crate::power_flow_data_from(power_flow, battery)?);
+        // the dashboard shows what it got, the power flow is enough
+        for response in cycle.meters.into_values() {
+            if let Ok(meter) = crate::meter_data_from(response) {
+                snapshot.update("meter", &meter);
+            }
+        }
+        for response in cycle.storages.into_values() {
+            if let Ok(storage) = crate::storage_data_from(response, battery) {
+                snapshot.update("storage", &storage);
+            }
+        }
+        for response in cycle.inverter_infos.into_values() {
+            if let Ok(info) = crate::inverter_info_from(response) {
+                snapshot.update("inverter_info", &info);
+            }
+        }
+    }
+    snapshot.to_json(None).ok_or_else(|| "no data".into())
+}
+
+/// The last values of a site for the sparklines.
+#[derive(Default)]
+struct History {
+    pv: VecDeque<u64>,
+    load: VecDeque<u64>,
+    grid: VecDeque<u64>,
+    state_of_charge: VecDeque<u64>,
+}
+
+impl History {
+    fn push(&mut self, overview: &status::Overview) {
+        for (values, value) in [
+            (&mut self.pv, overview.pv),
+            (&mut self.load, overview.load),
+            // import and export alike, the sign is in the title
+            (&mut self.grid, overview.grid.map(f64::abs)),
+            (&mut self.state_of_charge, overview.state_of_charge),
+        ] {
+            if values.len() == HISTORY {
+                values.pop_front();
+            }
+            values.push_back(value.unwrap_or_default().max(0.0).round() as u64);
+        }
+    }
+}
+
+struct Dashboard {
+    source: String,
+    latest: Value,
+    sites: BTreeMap<String, status::Overview>,
+    history: BTreeMap<String, History>,
+    selected: usize,
+    updated: Option<DateTime<Local>>,
+    error: Option<String>,
+    locale: Locale,
+}
+
+impl Dashboard {
+    fn update(&mut self, latest: Result<Value, String>) {
+        match latest {
+            Ok(latest) => {
+                self.sites = status::overviews(&latest);
+                for (site, overview) in &self.sites {
+                    self.history.entry(site.clone()).or_default().push(overview);
+                }
+                self.latest = latest;
+                self.selected = self.selected.min(self.sites.len().saturating_sub(1));
+                self.updated = Some(Local::now());
+                self.error = None;
+            }
+            Err(error) => self.error = Some(error),
+        }
+    }
+}
+
+/// Runs the `tui` command.
+pub fn run(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut poll = false;
+    let mut interval = crate::POLL_INTERVAL;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--poll" => poll = true,
+            "--interval" => interval = Duration::from_secs(args.next().ok_or(USAGE)?.parse::<u64>()?.max(1)),
+            _ => return Err(USAGE.into()),
+        }
+    }
+    crate::locale::init_from_env()?;
+    crate::schema::init_from_env()?;
+
+    // without a reachable collector the dashboard polls for itself
+    let source = match status::api_url().filter(|_| !poll) {
+        Some(url) if status::fetch_latest(&url).is_ok() => Source::Api(url),
+        _ => Source::Poll(crate::site_addresses()?),
+    };
+    let battery = BatterySign::from_env()?;
+    let mut dashboard = Dashboard {
+        source: source.describe(),
+        latest: Value::Null,
+        sites: BTreeMap::new(),
+        history: BTreeMap::new(),
+        selected: 0,
+        updated: None,
+        error: None,
+        locale: crate::locale::get(),
+    };
+
+    let (latest_tx, latest_rx) = mpsc::channel();
+    let (refresh_tx, refresh_rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("tui-fetch".to_owned())
+        .spawn(move || fetch_loop(source, battery, interval, &refresh_rx, &latest_tx))?;
+
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut dashboard, &latest_rx, &refresh_tx);
+    ratatui::restore();
+    result
+}
+
+/// Fetches the snapshot every interval or when asked to, until the dashboard
+/// is closed.
+fn fetch_loop(source: Source, battery: BatterySign, interval: Duration, refresh: &Receiver<()>, latest: &Sender<Result<Value, String>>) {
+    let mut connections = Connections::default();
+    loop {
+        let snapshot = match &source {
+            Source::Api(url) => match status::fetch_latest(url) {
+                Ok(Some(snapshot)) => Ok(snapshot),
+                Ok(None) => Err("the collector has no data yet".to_owned()),
+                Err(error) => Err(format!("the collector isn't reachable: {error}")),
+            },
+            Source::Poll(sites) => connections.poll(sites, battery).map_err(|error| format!("poll failed: {error}")),
+        };
+        if latest.send(snapshot).is_err() {
+            return;
+        }
+        if let Err(RecvTimeoutError::Disconnected) = refresh.recv_timeout(interval) {
+            return;
+        }
+    }
+}
+
+fn event_loop(
+    terminal: &mut DefaultTerminal,
+    dashboard: &mut Dashboard,
+    latest: &Receiver<Result<Value, String>>,
+    refresh: &Sender<()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        while let Ok(snapshot) = latest.try_recv() {
+            dashboard.update(snapshot);
+        }
+        terminal.draw(|frame| draw(frame, dashboard))?;
+        if !event::poll(INPUT_TIMEOUT)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('r') => {
+                let _ = refresh.send(());
+            }
+            KeyCode::Tab => dashboard.selected = (dashboard.selected + 1) % dashboard.sites.len().max(1),
+            _ => {}
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard) {
+    let [header, body, footer] = Layout::vertical([Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+    let updated = dashboard.updated.map(|updated| format!(", updated {}", updated.format("%H:%M:%S"))).unwrap_or_default();
+    let keys = if dashboard.sites.len() > 1 { "Tab site  r refresh  q quit" } else { "r refresh  q quit" };
+    let Some((site, overview)) = dashboard.sites.iter().nth(dashboard.selected) else {
+        frame.render_widget(Paragraph::new(format!("Fronius ({}) - waiting for data   {keys}", dashboard.source)), header);
+        render_footer(frame, dashboard, footer);
+        return;
+    };
+    let title = match site.as_str() {
+        "" => format!("Fronius ({}{updated})   {keys}", dashboard.source),
+        site => format!("Fronius {site} ({}{updated})   {keys}", dashboard.source),
+    };
+    frame.render_widget(Paragraph::new(title).bold(), header);
+    render_footer(frame, dashboard, footer);
+
+    let [left, right] = Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(body);
+    let [flow_area, pv_area, load_area, grid_area] =
+        Layout::vertical([Constraint::Length(8), Constraint::Min(3), Constraint::Min(3), Constraint::Min(3)]).areas(left);
+    let [gauge_area, meter_area, soc_area] = Layout::vertical([Constraint::Length(3), Constraint::Length(6), Constraint::Min(3)]).areas(right);
+
+    let locale = dashboard.locale;
+    let power = |watts: Option<f64>| watts.map_or_else(|| "-".to_owned(), |watts| locale.power(watts));
+    let grid_direction = match overview.grid {
+        Some(grid) if grid.round() > 0.0 => "import",
+        Some(grid) if grid.round() < 0.0 => "export",
+        _ => "",
+    };
+    let battery_direction = match overview.battery {
+        Some(battery) if battery.round() > 0.0 => "discharging",
+        Some(battery) if battery.round() < 0.0 => "charging",
+        _ => "",
+    };
+    let flow = vec![
+        Line::from(format!("PV        {}", power(overview.pv))).yellow(),
+        Line::from(format!("Load      {}", power(overview.load))),
+        Line::from(format!("Grid      {} {grid_direction}", power(overview.grid))),
+        Line::from(format!("Battery   {} {battery_direction}", power(overview.battery))),
+        Line::from(format!("Inverter  {}", overview.inverter_state.as_deref().unwrap_or("-"))),
+        Line::from(format!("Today     {}", overview.energy_day.map_or_else(|| "-".to_owned(), |energy| locale.energy(energy)))),
+    ];
+    frame.render_widget(Paragraph::new(flow).block(Block::bordered().title("Power flow")), flow_area);
+
+    let history = dashboard.history.get(site);
+    let sparkline = |title: &str, values: Option<&VecDeque<u64>>, style: Style, area: Rect| {
+        // the latest values, as many as fit
+        let width = area.width.saturating_sub(2) as usize;
+        let values: Vec<u64> = values.map(|values| values.iter().skip(values.len().saturating_sub(width)).copied().collect()).unwrap_or_default();
+        Sparkline::default().block(Block::bordered().title(title.to_owned())).data(values).style(style)
+    };
+    frame.render_widget(sparkline("PV", history.map(|history| &history.pv), Style::new().yellow(), pv_area), pv_area);
+    frame.render_widget(sparkline("Load", history.map(|history| &history.load), Style::new().cyan(), load_area), load_area);
+    let grid_title = format!("Grid {grid_direction}");
+    frame.render_widget(sparkline(&grid_title, history.map(|history| &history.grid), Style::new().magenta(), grid_area), grid_area);
+
+    let gauge = match overview.state_of_charge {
+        Some(percent) => Gauge::default()
+            .percent(percent.clamp(0.0, 100.0).round() as u16)
+            .label(locale.percent(percent)),
+        None => Gauge::default().percent(0).label("no battery"),
+    };
+    frame.render_widget(gauge.gauge_style(Style::new().green()).block(Block::bordered().title("Battery")), gauge_area);
+    render_meter(frame, dashboard, site, meter_area);
+    frame.render_widget(
+        sparkline("State of charge", history.map(|history| &history.state_of_charge), Style::new().green(), soc_area),
+        soc_area,
+    );
+}
+
+/// The phases of the first meter of the site.
+fn render_meter(frame: &mut Frame, dashboard: &Dashboard, site: &str, area: Rect) {
+    let locale = dashboard.locale;
+    let meter = dashboard.latest["meter"]
+        .as_object()
+        .and_then(|meters| meters.iter().find(|(device, _)| status::site_of(device) == site))
+        .map(|(_, meter)| &meter["values"]);
+    let Some(meter) = meter else {
+        frame.render_widget(Paragraph::new("no meter").block(Block::bordered().title("Meter")), area);
+        return;
+    };
+    let value = |phase: &str, field: &str, format: &dyn Fn(f64) -> String| meter[format!("{phase}_{field}")].as_f64().map_or_else(|| "-".to_owned(), format);
+    let rows = PHASES.iter().map(|phase| {
+        Row::new(vec![
+            phase.to_uppercase(),
+            value(phase, "voltage", &|volts| format!("{} V", locale.number(volts, 1))),
+            value(phase, "current", &|amperes| format!("{} A", locale.number(amperes, 2))),
+            value(phase, "power", &|watts| locale.power(watts)),
+            value(phase, "power_factor", &|factor| locale.number(factor, 2)),
+        ])
+    });
+    let widths = [Constraint::Length(3), Constraint::Length(9), Constraint::Length(9), Constraint::Length(11), Constraint::Length(5)];
+    let table = Table::new(rows, widths)
+        .header(Row::new(vec!["", "Voltage", "Current", "Power", "PF"]).style(Style::new().bold()))
+        .block(Block::bordered().title("Meter"));
+    frame.render_widget(table, area);
+}
+
+fn render_footer(frame: &mut Frame, dashboard: &Dashboard, area: Rect) {
+    if let Some(error) = &dashboard.error {
+        frame.render_widget(Paragraph::new(error.as_str()).red(), area);
+    }
+}
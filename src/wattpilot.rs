@@ -0,0 +1,438 @@
+//! PV surplus charging with a Fronius Wattpilot.
+//!
+//! The Wattpilot is controlled via its local WebSocket API. In the eco mode
+//! the charging current follows the PV surplus, with a minimum current and a
+//! hysteresis so the current isn't changed all the time. The Wattpilot itself
+//! starts and stops the charging, and a current set by the user in the app is
+//! kept until the mode is changed.
+//!
+//! In read-only mode (`READ_ONLY`) the charging current is only calculated
+//! and written to the `ev_charging` measurement, the Wattpilot is not changed.
+
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, TcpStream},
+    time::Duration,
+};
+
+use base64::Engine;
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use point_derive::ToPoint;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256, Sha512};
+use thiserror::Error;
+use tungstenite::{HandshakeError, Message, WebSocket};
+
+use crate::timestamp;
+
+/// Grid voltage used to convert the surplus power into a current.
+const VOLTAGE: f64 = 230.0;
+/// Value of the `lmo` (logic mode) key in the eco mode, the only mode in
+/// which the current is controlled.
+const MODE_ECO: i64 = 4;
+/// Lowest charging current of IEC 61851 in A.
+const MIN_CURRENT: i64 = 6;
+
+#[derive(Debug, Error)]
+pub enum WattpilotError {
+    #[error("connection to the Wattpilot failed")]
+    Io(#[from] std::io::Error),
+    #[error("WebSocket error")]
+    WebSocket(#[source] Box<tungstenite::Error>),
+    #[error("invalid message from the Wattpilot")]
+    Json(#[from] serde_json::Error),
+    #[error("authentication at the Wattpilot failed: {0}")]
+    AuthFailed(String),
+    #[error("Wattpilot closed the connection")]
+    Closed,
+}
+
+impl From<tungstenite::Error> for WattpilotError {
+    fn from(error: tungstenite::Error) -> Self {
+        WattpilotError::WebSocket(Box::new(error))
+    }
+}
+
+/// Connection to the local WebSocket API of a Wattpilot.
+pub struct Wattpilot {
+    address: SocketAddr,
+    password: String,
+    socket: Option<WebSocket<TcpStream>>,
+    hashed_password: String,
+    request_id: u64,
+    status: Map<String, Value>,
+}
+
+impl Wattpilot {
+    pub fn new(ip: IpAddr, password: String) -> Self {
+        Wattpilot {
+            address: SocketAddr::new(ip, 80),
+            password,
+            socket: None,
+            hashed_password: String::new(),
+            request_id: 0,
+            status: Map::new(),
+        }
+    }
+
+    /// Returns the last known value of a status key.
+    pub fn status(&self, key: &str) -> Option<&Value> {
+        self.status.get(key)
+    }
+
+    /// Connects and authenticates, if not connected yet.
+    fn connect(&mut self) -> Result<(), WattpilotError> {
+        if self.socket.is_none() {
+            let stream = TcpStream::connect_timeout(&self.address, Duration::from_secs(5))?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let (socket, _) = tungstenite::client(format!("ws://{}/ws", self.address), stream).map_err(|error| match error {
+                HandshakeError::Failure(error) => WattpilotError::from(error),
+                HandshakeError::Interrupted(_) => WattpilotError::Io(ErrorKind::TimedOut.into()),
+            })?;
+            self.socket = Some(socket);
+            if let Err(error) = self.authenticate() {
+                self.socket = None;
+                return Err(error);
+            }
+            info!("Connected to Wattpilot at {}", self.address);
+        }
+        Ok(())
+    }
+
+    fn authenticate(&mut self) -> Result<(), WattpilotError> {
+        let mut serial = String::new();
+        loop {
+            let message = self.read()?;
+            match message["type"].as_str() {
+                Some("hello") => serial = message["serial"].as_str().unwrap_or_default().to_owned(),
+                Some("authRequired") => {
+                    self.hashed_password = hash_password(&self.password, &serial);
+                    let token1 = message["token1"].as_str().unwrap_or_default();
+                    let token2 = message["token2"].as_str().unwrap_or_default();
+                    let token3 = random_token();
+                    let hash1 = hex_sha256(&format!("{token1}{}", self.hashed_password));
+                    let hash = hex_sha256(&format!("{token3}{token2}{hash1}"));
+                    self.send(json!({"type": "auth", "token3": token3, "hash": hash}))?;
+                }
+                Some("authSuccess") => break,
+                Some("authError") => {
+                    return Err(WattpilotError::AuthFailed(message["message"].to_string()));
+                }
+                _ => self.apply(&message),
+            }
+        }
+        // status updates are polled, so reads should not block the cycle
+        if let Some(socket) = &self.socket {
+            socket.get_ref().set_read_timeout(Some(Duration::from_millis(200)))?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Value, WattpilotError> {
+        let socket = self.socket.as_mut().ok_or(WattpilotError::Closed)?;
+        loop {
+            match socket.read()? {
+                Message::Text(text) => return Ok(serde_json::from_str(&text)?),
+                Message::Close(_) => return Err(WattpilotError::Closed),
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, message: Value) -> Result<(), WattpilotError> {
+        let socket = self.socket.as_mut().ok_or(WattpilotError::Closed)?;
+        socket.send(Message::Text(message.to_string()))?;
+        Ok(())
+    }
+
+    /// Applies a status message to the known status.
+    fn apply(&mut self, message: &Value) {
+        if let Some(status) = message["status"].as_object() {
+            self.status.extend(status.clone());
+        }
+    }
+
+    /// Reads all pending status updates. The connection is dropped on errors
+    /// and established again with the next call.
+    pub fn update(&mut self) -> Result<(), WattpilotError> {
+        let res = self.update_inner();
+        if res.is_err() {
+            self.socket = None;
+        }
+        res
+    }
+
+    fn update_inner(&mut self) -> Result<(), WattpilotError> {
+        self.connect()?;
+        loop {
+            match self.read() {
+                Ok(message) => self.apply(&message),
+                Err(WattpilotError::WebSocket(error)) if is_timeout(&error) => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Sets a status key. The message is signed with the hashed password, as
+    /// the Wattpilot requires it for changes.
+    pub fn set_value(&mut self, key: &str, value: Value) -> Result<(), WattpilotError> {
+        self.connect()?;
+        self.request_id += 1;
+        let data = json!({"type": "setValue", "requestId": self.request_id, "key": key, "value": value}).to_string();
+        // HMAC accepts keys of any length, `new_from_slice` can't fail
+        #[allow(clippy::expect_used)]
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.hashed_password.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(data.as_bytes());
+        let signature = hex(&mac.finalize().into_bytes());
+        let res = self.send(json!({
+            "type": "securedMsg",
+            "data": data,
+            "requestId": format!("{}sm", self.request_id),
+            "hmac": signature,
+        }));
+        match res {
+            Ok(()) => {
+                self.status.insert(key.to_owned(), value);
+                Ok(())
+            }
+            Err(error) => {
+                self.socket = None;
+                Err(error)
+            }
+        }
+    }
+}
+
+/// Whether a read ended because no message arrived within the read timeout.
+fn is_timeout(error: &tungstenite::Error) -> bool {
+    matches!(error, tungstenite::Error::Io(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// The password hash the Wattpilot expects: PBKDF2-SHA512 with the serial as
+/// salt, base64 encoded and cut to 32 characters.
+fn hash_password(password: &str, serial: &str) -> String {
+    let mut key = [0u8; 256];
+    pbkdf2::pbkdf2_hmac::<Sha512>(password.as_bytes(), serial.as_bytes(), 100_000, &mut key);
+    let mut hashed = base64::engine::general_purpose::STANDARD.encode(key);
+    hashed.truncate(32);
+    hashed
+}
+
+fn hex_sha256(input: &str) -> String {
+    hex(&Sha256::digest(input.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Random client token for the authentication, from the random number
+/// generator of the OS.
+fn random_token() -> String {
+    let mut token = [0u8; 16];
+    OsRng.fill_bytes(&mut token);
+    hex(&token)
+}
+
+#[derive(Default, Debug, Serialize, ToPoint)]
+#[measurement = "ev_charging"]
+pub struct EvChargingData {
+    #[influxdb(tag)]
+    device: &'static str,
+    #[influxdb(field)]
+    surplus: f64,
+    #[influxdb(field)]
+    available_current: f64,
+    #[influxdb(field)]
+    charger_power: f64,
+    #[influxdb(field)]
+    charging: bool,
+    #[influxdb(field)]
+    controlled: bool,
+    #[influxdb(field)]
+    current: i64,
+    #[influxdb(timestamp)]
+    time: i64,
+}
+
+/// Controls the charging current of the Wattpilot by the PV surplus.
+pub struct SurplusCharger {
+    wattpilot: Wattpilot,
+    control: Control,
+}
+
+/// The decisions of the surplus charging, without the Wattpilot.
+#[derive(Debug)]
+pub struct Control {
+    min_current: i64,
+    max_current: i64,
+    phases: f64,
+    hysteresis: f64,
+    read_only: bool,
+    charging: bool,
+    /// Mode of the Wattpilot in the last update.
+    mode: Option<i64>,
+    /// Current set by the last update, to notice changes by the user.
+    set_current: Option<i64>,
+    /// Whether the user changed the current, it isn't controlled until the
+    /// mode is changed.
+    overridden: bool,
+}
+
+/// What the control decided in an update.
+#[derive(Debug, PartialEq)]
+pub struct Decision {
+    pub available_current: f64,
+    pub charging: bool,
+    pub controlled: bool,
+    /// Charging current while charging, clamped to the limits.
+    pub current: i64,
+    /// Current to set at the Wattpilot, if it has to be changed.
+    pub set: Option<i64>,
+}
+
+impl Control {
+    /// Checks the limits: the minimum current can't be below 6 A, the lowest
+    /// current of IEC 61851, or above the maximum, the Wattpilot charges with
+    /// 1 or 3 phases and the hysteresis can't be negative.
+    pub fn new(min_current: i64, max_current: i64, phases: f64, hysteresis: f64, read_only: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        if min_current < MIN_CURRENT {
+            return Err(format!("invalid EV_MIN_CURRENT {min_current}, expected at least {MIN_CURRENT} A").into());
+        }
+        if max_current < min_current {
+            return Err(format!("invalid EV_MAX_CURRENT {max_current}, expected at least EV_MIN_CURRENT {min_current} A").into());
+        }
+        if phases != 1.0 && phases != 3.0 {
+            return Err(format!("invalid EV_PHASES {phases}, expected 1 or 3").into());
+        }
+        if !(0.0..).contains(&hysteresis) {
+            return Err(format!("invalid EV_HYSTERESIS {hysteresis}, expected at least 0 A").into());
+        }
+        Ok(Control {
+            min_current,
+            max_current,
+            phases,
+            hysteresis,
+            read_only,
+            charging: false,
+            mode: None,
+            set_current: None,
+            overridden: false,
+        })
+    }
+
+    /// Decides the charging current for the `surplus` in W, with the `mode`
+    /// (`lmo`) and the current (`amp`) the Wattpilot reports.
+    pub fn decide(&mut self, surplus: f64, mode: Option<i64>, amp: Option<i64>) -> Decision {
+        let available_current = surplus / (VOLTAGE * self.phases);
+
+        // follow the surplus from the minimum current on, until it dropped
+        // clearly below it
+        self.charging = if self.charging {
+            available_current >= self.min_current as f64 - self.hysteresis
+        } else {
+            available_current >= self.min_current as f64
+        };
+        let current = (available_current.floor() as i64).clamp(self.min_current, self.max_current);
+
+        if self.set_current.is_some_and(|set_current| amp != Some(set_current)) {
+            info!("Charging current of the Wattpilot changed by the user, it isn't controlled until the mode is changed");
+            self.overridden = true;
+        }
+        if mode != self.mode {
+            self.mode = mode;
+            self.overridden = false;
+            self.set_current = None;
+        }
+        let controlled = mode == Some(MODE_ECO) && !self.overridden;
+        let mut set = None;
+        if !controlled {
+            self.set_current = None;
+        } else if self.charging && amp != Some(current) {
+            set = Some(current);
+            // in read-only mode the current stays unchanged
+            self.set_current = (!self.read_only).then_some(current);
+        }
+
+        Decision {
+            available_current,
+            charging: self.charging,
+            controlled,
+            current: if self.charging { current } else { 0 },
+            set,
+        }
+    }
+}
+
+impl SurplusCharger {
+    /// Creates the controller if `WATTPILOT_IP` is set, the password is read
+    /// from `WATTPILOT_PASSWORD`. Optional are `EV_MIN_CURRENT` (default 6),
+    /// `EV_MAX_CURRENT` (default 16), `EV_PHASES` (default 3) and
+    /// `EV_HYSTERESIS` in A (default 1).
+    pub fn from_env() -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Ok(ip) = std::env::var("WATTPILOT_IP") else {
+            return Ok(None);
+        };
+        let number = |name: &str, default: f64| -> Result<f64, Box<dyn std::error::Error>> {
+            match std::env::var(name) {
+                Ok(val) => Ok(val.parse()?),
+                Err(_) => Ok(default),
+            }
+        };
+        let control = Control::new(
+            number("EV_MIN_CURRENT", 6.0)? as i64,
+            number("EV_MAX_CURRENT", 16.0)? as i64,
+            number("EV_PHASES", 3.0)?,
+            number("EV_HYSTERESIS", 1.0)?,
+            crate::flag_from_env("READ_ONLY")?,
+        )?;
+        Ok(Some(SurplusCharger {
+            wattpilot: Wattpilot::new(ip.parse()?, std::env::var("WATTPILOT_PASSWORD")?),
+            control,
+        }))
+    }
+
+    /// Adjusts the charging current to the surplus while the Wattpilot is in
+    /// the eco mode. `grid_power` is the power drawn from the grid in W,
+    /// negative while feeding in.
+    pub fn update(&mut self, grid_power: f64) -> Result<EvChargingData, Box<dyn std::error::Error>> {
+        self.wattpilot.update()?;
+        // the power the car already draws is part of the surplus
+        let charger_power = self
+            .wattpilot
+            .status("nrg")
+            .and_then(|nrg| nrg.get(11))
+            .and_then(Value::as_f64)
+            .unwrap_or(0.0);
+        let surplus = charger_power - grid_power;
+        let mode = self.wattpilot.status("lmo").and_then(Value::as_i64);
+        let amp = self.wattpilot.status("amp").and_then(Value::as_i64);
+        let decision = self.control.decide(surplus, mode, amp);
+        if let Some(current) = decision.set {
+            self.set_value("amp", json!(current))?;
+        }
+
+        Ok(EvChargingData {
+            device: "Wattpilot",
+            surplus,
+            available_current: decision.available_current,
+            charger_power,
+            charging: decision.charging,
+            controlled: decision.controlled,
+            current: decision.current,
+            time: timestamp::now()?,
+        })
+    }
+
+    /// Sets a status key of the Wattpilot, unless in read-only mode.
+    fn set_value(&mut self, key: &str, value: Value) -> Result<(), WattpilotError> {
+        if self.control.read_only {
+            debug!("Read-only mode, not setting {key} of the Wattpilot to {value}");
+            return Ok(());
+        }
+        self.wattpilot.set_value(key, value)
+    }
+}